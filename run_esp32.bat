@@ -1,2 +1,3 @@
 cargo build -r --target=wasm32-unknown-unknown --bin app-example
+cargo run --bin uwasm-image -- target/wasm32-unknown-unknown/release/app-example.wasm target/wasm32-unknown-unknown/release/app-example.uwim
 cargo +nightly-2024-06-01 run -r --bin uwasm-uc --target riscv32imc-unknown-none-elf
\ No newline at end of file
@@ -0,0 +1,24 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+#[panic_handler]
+unsafe fn panic(_: &PanicInfo) -> ! {
+    unsafe { core::hint::unreachable_unchecked() }
+}
+
+extern "C" {
+    fn read_sensor(channel: u32) -> u32;
+}
+
+#[export_name = "sum_sensors"]
+pub fn sum_sensors(n: u32) -> u32 {
+    let mut sum = 0u32;
+    for channel in 0..n {
+        sum = sum.wrapping_add(unsafe { read_sensor(channel) });
+    }
+    sum
+}
+
+// rustc --target=wasm32-unknown-unknown tests/async_import.rs -O -C panic=abort -o tests/async_import.wasm
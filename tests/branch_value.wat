@@ -0,0 +1,13 @@
+;; Value that reaches `local.set` both by falling through and by a branch.
+(module
+  (func (export "pick") (param i32) (result i32) (local i32)
+    (block (result i32)
+      i32.const 7
+      local.get 0
+      br_if 0
+      drop
+      i32.const 5)
+    local.set 1
+    local.get 1))
+
+;; wat2wasm branch_value.wat -o branch_value.wasm
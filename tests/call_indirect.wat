@@ -0,0 +1,23 @@
+;; Functions called through a table whose elements start at 1, so they differ from the function indices.
+(module
+  (type $unary (func (param i32) (result i32)))
+  (table 4 funcref)
+  (elem (i32.const 1) $double $add $negate)
+  (func $double (type $unary)
+    local.get 0
+    i32.const 2
+    i32.mul)
+  (func $add (param i32 i32) (result i32)
+    local.get 0
+    local.get 1
+    i32.add)
+  (func $negate (type $unary)
+    i32.const 0
+    local.get 0
+    i32.sub)
+  (func (export "apply") (param $idx i32) (param $x i32) (result i32)
+    local.get $x
+    local.get $idx
+    call_indirect (type $unary)))
+
+;; wat2wasm call_indirect.wat -o call_indirect.wasm
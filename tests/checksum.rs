@@ -0,0 +1,37 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+#[panic_handler]
+unsafe fn panic(_: &PanicInfo) -> ! {
+    unsafe { core::hint::unreachable_unchecked() }
+}
+
+/// Scrambles the bytes in place and returns their FNV-1a hash.
+#[export_name = "scramble"]
+pub fn scramble(data: &mut [u8]) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for byte in data.iter_mut() {
+        hash ^= u32::from(*byte);
+        hash = hash.wrapping_mul(0x01000193);
+        *byte = (hash >> 24) as u8;
+    }
+    hash
+}
+
+/// Counts how many of the words are below `limit`, and sums up all of them as 64-bit numbers.
+#[export_name = "count_below"]
+pub fn count_below(words: &[u32], limit: u32) -> u64 {
+    let mut count = 0u64;
+    let mut sum = 0u64;
+    for &word in words {
+        if word < limit {
+            count += 1;
+        }
+        sum += u64::from(word);
+    }
+    (count << 32) | (sum & 0xffff_ffff)
+}
+
+// rustc --target=wasm32-unknown-unknown tests/checksum.rs -O -C panic=abort -o tests/checksum.wasm
@@ -0,0 +1,19 @@
+;; Sequences that the stack interpreter fuses into superinstructions.
+(module
+  (memory (export "memory") 1)
+  (func (export "count_up") (param $n i32) (result i32) (local $i i32)
+    (loop $next
+      local.get $i
+      i32.const 1
+      i32.add
+      local.set $i
+      local.get $i
+      local.get $n
+      i32.lt_u
+      br_if $next)
+    local.get $i)
+  (func (export "load_at") (param $ptr i32) (result i32)
+    local.get $ptr
+    i32.load offset=4))
+
+;; wat2wasm fused.wat -o fused.wasm
@@ -0,0 +1,12 @@
+;; Global and segment offset initialized from an imported global.
+(module
+  (import "env" "base" (global $base i32))
+  (memory 1)
+  (global $end (export "end") i32 (global.get $base))
+  (global $limit (export "limit") (mut i64) (i64.const 5))
+  (data (global.get $base) "hi")
+  (func (export "load") (result i32)
+    global.get $end
+    i32.load8_u))
+
+;; wat2wasm global_init.wat -o global_init.wasm
@@ -0,0 +1,17 @@
+;; Globals shared with the host, one imported and the others exported.
+(module
+  (import "env" "scale" (global $scale (mut i32)))
+  (global $counter (export "counter") (mut i64) (i64.const 0))
+  (global (export "limit") f32 (f32.const 1.5))
+  (func (export "tick") (result i32)
+    global.get $counter
+    i64.const 1
+    i64.add
+    global.set $counter
+    global.get $scale
+    global.get $scale
+    i32.const 1
+    i32.add
+    global.set $scale))
+
+;; wat2wasm globals.wat -o globals.wasm
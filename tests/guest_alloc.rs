@@ -0,0 +1,50 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use core::ptr::{addr_of, addr_of_mut};
+
+#[panic_handler]
+unsafe fn panic(_: &PanicInfo) -> ! {
+    unsafe { core::hint::unreachable_unchecked() }
+}
+
+// bump allocator that never reuses memory, but counts allocations that haven't been freed
+static mut HEAP: [u8; 1024] = [0; 1024];
+static mut NEXT: usize = 0;
+static mut LIVE: u32 = 0;
+
+#[export_name = "malloc"]
+pub unsafe extern "C" fn malloc(size: usize) -> *mut u8 {
+    if NEXT + size > 1024 {
+        return core::ptr::null_mut();
+    }
+    let ptr = addr_of_mut!(HEAP).cast::<u8>().add(NEXT);
+    NEXT += size;
+    LIVE += 1;
+    ptr
+}
+
+#[export_name = "free"]
+pub unsafe extern "C" fn free(ptr: *mut u8) {
+    if !ptr.is_null() {
+        LIVE -= 1;
+    }
+}
+
+#[export_name = "live_allocations"]
+pub fn live_allocations() -> u32 {
+    unsafe { *addr_of!(LIVE) }
+}
+
+#[export_name = "checksum"]
+pub fn checksum(data: &[u8]) -> u32 {
+    data.iter().map(|&b| u32::from(b)).sum()
+}
+
+#[export_name = "count_words"]
+pub fn count_words(text: &str) -> u32 {
+    text.split_whitespace().count() as u32
+}
+
+// rustc --target=wasm32-unknown-unknown tests/guest_alloc.rs -O -C panic=abort -C strip=debuginfo -o tests/guest_alloc.wasm
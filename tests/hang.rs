@@ -0,0 +1,27 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+#[panic_handler]
+unsafe fn panic(_: &PanicInfo) -> ! {
+    unsafe { core::hint::unreachable_unchecked() }
+}
+
+extern "C" {
+    fn tick();
+}
+
+#[export_name = "spin"]
+pub fn spin() -> ! {
+    loop {}
+}
+
+#[export_name = "poll"]
+pub fn poll() -> ! {
+    loop {
+        unsafe { tick() }
+    }
+}
+
+// rustc --target=wasm32-unknown-unknown tests/hang.rs -O -C panic=abort -o tests/hang.wasm
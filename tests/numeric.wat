@@ -0,0 +1,126 @@
+;; Edge cases of numeric and memory instructions that all engines have to agree on.
+(module
+  (memory (export "memory") 1)
+  (data (i32.const 0) "\01\02\03\84")
+  (func (export "div_u") (param i32 i32) (result i32)
+    local.get 0
+    local.get 1
+    i32.div_u)
+  (func (export "div_s") (param i32 i32) (result i32)
+    local.get 0
+    local.get 1
+    i32.div_s)
+  (func (export "rem_u") (param i32 i32) (result i32)
+    local.get 0
+    local.get 1
+    i32.rem_u)
+  (func (export "div_u64") (param i64 i64) (result i64)
+    local.get 0
+    local.get 1
+    i64.div_u)
+  (func (export "shl") (param i32 i32) (result i32)
+    local.get 0
+    local.get 1
+    i32.shl)
+  (func (export "shr_u") (param i32 i32) (result i32)
+    local.get 0
+    local.get 1
+    i32.shr_u)
+  (func (export "shl64") (param i64 i64) (result i64)
+    local.get 0
+    local.get 1
+    i64.shl)
+  (func (export "wrap") (param i64) (result i32)
+    local.get 0
+    i32.wrap_i64)
+  (func (export "extend_u") (param i32) (result i64)
+    local.get 0
+    i64.extend_i32_u)
+  (func (export "extend8_s") (param i32) (result i32)
+    local.get 0
+    i32.extend8_s)
+  (func (export "add") (param i32 i32) (result i32)
+    local.get 0
+    local.get 1
+    i32.add)
+  (func (export "mul64") (param i64 i64) (result i64)
+    local.get 0
+    local.get 1
+    i64.mul)
+  (func (export "load") (param i32) (result i32)
+    local.get 0
+    i32.load)
+  (func (export "load8_s") (param i32) (result i32)
+    local.get 0
+    i32.load8_s offset=3)
+  (func (export "store") (param i32 i32)
+    local.get 0
+    local.get 1
+    i32.store)
+  (func (export "rem_s") (param i32 i32) (result i32)
+    local.get 0
+    local.get 1
+    i32.rem_s)
+  (func (export "shr_s64") (param i64 i64) (result i64)
+    local.get 0
+    local.get 1
+    i64.shr_s)
+  (func (export "rotl") (param i32 i32) (result i32)
+    local.get 0
+    local.get 1
+    i32.rotl)
+  (func (export "clz64") (param i64) (result i64)
+    local.get 0
+    i64.clz)
+  (func (export "trunc_s") (param f32) (result i32)
+    local.get 0
+    i32.trunc_f32_s)
+  (func (export "trunc_u64") (param f64) (result i64)
+    local.get 0
+    i64.trunc_f64_u)
+  (func (export "convert_u64") (param i64) (result f32)
+    local.get 0
+    f32.convert_i64_u)
+  (func (export "min") (param f32 f32) (result f32)
+    local.get 0
+    local.get 1
+    f32.min)
+  (func (export "copysign") (param f64 f64) (result f64)
+    local.get 0
+    local.get 1
+    f64.copysign)
+  (func (export "size") (result i32)
+    memory.size)
+  (func (export "grow") (param i32) (result i32)
+    local.get 0
+    memory.grow)
+  (func (export "copy") (param i32 i32 i32) (result i32)
+    local.get 0
+    local.get 1
+    local.get 2
+    memory.copy
+    local.get 0
+    i32.load)
+  (func (export "fill") (param i32 i32 i32) (result i32)
+    local.get 0
+    local.get 1
+    local.get 2
+    memory.fill
+    local.get 0
+    i32.load)
+  (func (export "gt_u") (param i32 i32) (result i32)
+    local.get 0
+    local.get 1
+    i32.gt_u)
+  (func (export "le_s") (param i32 i32) (result i32)
+    local.get 0
+    local.get 1
+    i32.le_s)
+  (func (export "trunc_sat") (param f32) (result i32)
+    local.get 0
+    i32.trunc_sat_f32_s)
+  (func (export "trunc_sat_u64") (param f64) (result i64)
+    local.get 0
+    i64.trunc_sat_f64_u))
+
+;; wat2wasm numeric.wat -o numeric.wasm
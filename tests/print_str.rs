@@ -0,0 +1,41 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+#[panic_handler]
+unsafe fn panic(_: &PanicInfo) -> ! {
+    unsafe { core::hint::unreachable_unchecked() }
+}
+
+#[repr(C)]
+struct Str {
+    data: *const u8,
+    len: usize,
+}
+
+impl Str {
+    fn new(s: &str) -> Self {
+        Str { data: s.as_ptr(), len: s.len() }
+    }
+}
+
+extern "C" {
+    fn print(s: Str);
+    fn print_ref(s: &Str);
+}
+
+#[export_name = "greet"]
+pub fn greet() {
+    unsafe {
+        print(Str::new("hello"));
+        print_ref(&Str::new("world"));
+    }
+}
+
+#[export_name = "print_bad"]
+pub fn print_bad(len: u32) {
+    unsafe { print(Str { data: 0xffff_fff0 as *const u8, len: len as usize }) }
+}
+
+// rustc --target=wasm32-unknown-unknown tests/print_str.rs -O -C panic=abort -o tests/print_str.wasm
@@ -0,0 +1,16 @@
+;; Reference globals, kept as whole slots.
+(module
+  (global $func (mut funcref) (ref.func $get_func))
+  (global $null externref (ref.null extern))
+  (global $after (mut i32) (i32.const 7))
+  (func $get_func (export "get_func") (result funcref)
+    global.get $func)
+  (func (export "set_func") (param funcref)
+    local.get 0
+    global.set $func)
+  (func (export "get_null") (result externref)
+    global.get $null)
+  (func (export "get_after") (result i32)
+    global.get $after))
+
+;; wat2wasm refs.wat -o refs.wasm
@@ -0,0 +1,36 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+#[panic_handler]
+unsafe fn panic(_: &PanicInfo) -> ! {
+    unsafe { core::hint::unreachable_unchecked() }
+}
+
+extern "C" {
+    fn sleep_ms(ms: u32);
+    fn report(id: u32);
+}
+
+#[export_name = "blink"]
+pub fn blink(id: u32, times: u32) {
+    for _ in 0..times {
+        unsafe {
+            report(id);
+            sleep_ms(10);
+        }
+    }
+}
+
+#[export_name = "spin"]
+pub fn spin(id: u32, n: u32) -> u32 {
+    let mut acc = 0u32;
+    for i in 0..n {
+        acc = acc.wrapping_mul(31).wrapping_add(i);
+    }
+    unsafe { report(id) }
+    acc
+}
+
+// rustc --target=wasm32-unknown-unknown tests/sleep.rs -O -C panic=abort -o tests/sleep.wasm
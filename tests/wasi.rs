@@ -0,0 +1,118 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use core::ptr::{addr_of, addr_of_mut};
+
+#[panic_handler]
+unsafe fn panic(_: &PanicInfo) -> ! {
+    unsafe { core::hint::unreachable_unchecked() }
+}
+
+#[repr(C)]
+struct Iovec {
+    buf: *const u8,
+    len: usize,
+}
+
+#[link(wasm_import_module = "wasi_snapshot_preview1")]
+extern "C" {
+    fn fd_write(fd: u32, iovs: *const Iovec, iovs_len: usize, written: *mut usize) -> u32;
+    fn fd_close(fd: u32) -> u32;
+    fn fd_prestat_get(fd: u32, prestat: *mut u8) -> u32;
+    fn args_sizes_get(count: *mut usize, size: *mut usize) -> u32;
+    fn args_get(ptrs: *mut *const u8, buf: *mut u8) -> u32;
+    fn clock_time_get(clock: u32, precision: u64, time: *mut u64) -> u32;
+    fn random_get(buf: *mut u8, len: usize) -> u32;
+    fn sched_yield() -> u32;
+    fn proc_exit(code: u32) -> !;
+}
+
+static mut ARGS: [u8; 64] = [0; 64];
+static mut ARG_PTRS: [*const u8; 8] = [core::ptr::null(); 8];
+
+fn write(fd: u32, parts: &[&[u8]]) -> u32 {
+    let mut iovs = [const { Iovec { buf: core::ptr::null(), len: 0 } }; 4];
+    for (iov, part) in iovs.iter_mut().zip(parts) {
+        *iov = Iovec { buf: part.as_ptr(), len: part.len() };
+    }
+    let mut written = 0;
+    unsafe { fd_write(fd, iovs.as_ptr(), parts.len(), &mut written) };
+    written as u32
+}
+
+#[export_name = "hello"]
+pub fn hello() -> u32 {
+    write(1, &[b"hello, ", b"wasi\n"])
+}
+
+#[export_name = "bad_fd"]
+pub fn bad_fd() -> u32 {
+    let mut written = 0;
+    unsafe { fd_write(5, core::ptr::null(), 0, &mut written) }
+}
+
+// prints arguments separated by spaces
+#[export_name = "print_args"]
+pub fn print_args() -> u32 {
+    unsafe {
+        let (mut count, mut size) = (0, 0);
+        args_sizes_get(&mut count, &mut size);
+        if count > 8 || size > 64 {
+            return 1;
+        }
+        args_get(addr_of_mut!(ARG_PTRS).cast(), addr_of_mut!(ARGS).cast());
+        for (idx, &ptr) in (*addr_of!(ARG_PTRS))[..count].iter().enumerate() {
+            let arg = core::ffi::CStr::from_ptr(ptr.cast()).to_bytes();
+            write(2, &[if idx == 0 { b"" } else { b" " }, arg]);
+        }
+        0
+    }
+}
+
+#[export_name = "now"]
+pub fn now() -> u64 {
+    let mut time = 0;
+    unsafe { clock_time_get(1, 0, &mut time) };
+    time
+}
+
+#[export_name = "random"]
+pub fn random() -> u32 {
+    let mut bytes = [0; 4];
+    match unsafe { random_get(bytes.as_mut_ptr(), bytes.len()) } {
+        0 => u32::from_le_bytes(bytes),
+        errno => errno,
+    }
+}
+
+#[export_name = "unsupported"]
+pub fn unsupported() -> u32 {
+    unsafe { fd_close(3) + sched_yield() }
+}
+
+// the first directory preopened by the host would have descriptor 3
+#[export_name = "preopens"]
+pub fn preopens() -> u32 {
+    let mut prestat = [0u8; 8];
+    unsafe { fd_prestat_get(3, prestat.as_mut_ptr()) }
+}
+
+// lengths whose sum doesn't fit into `written`
+#[export_name = "overflow"]
+pub fn overflow() -> u32 {
+    let text = b"never printed";
+    let iovs = [
+        Iovec { buf: text.as_ptr(), len: text.len() },
+        Iovec { buf: text.as_ptr(), len: usize::MAX - 4 },
+    ];
+    let mut written = 0;
+    unsafe { fd_write(1, iovs.as_ptr(), iovs.len(), &mut written) }
+}
+
+#[export_name = "exit"]
+pub fn exit(code: u32) {
+    unsafe { proc_exit(code) }
+}
+
+// rustc --target=wasm32-unknown-unknown tests/wasi.rs -O -C panic=abort -C strip=debuginfo -o tests/wasi.wasm
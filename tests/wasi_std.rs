@@ -0,0 +1,8 @@
+// Guest using the standard library, which reaches the host only through WASI.
+
+fn main() {
+    let greeting = String::from("hello");
+    println!("{greeting} from std, {} args", std::env::args().count());
+}
+
+// rustc +stable --target=wasm32-wasip1 tests/wasi_std.rs -O -C panic=abort -C strip=symbols -o tests/wasi_std.wasm
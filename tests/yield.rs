@@ -0,0 +1,26 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+#[panic_handler]
+unsafe fn panic(_: &PanicInfo) -> ! {
+    unsafe { core::hint::unreachable_unchecked() }
+}
+
+extern "C" {
+    #[link_name = "yield"]
+    fn yield_now();
+}
+
+#[export_name = "count"]
+pub fn count(n: u32) -> u32 {
+    let mut sum = 0u32;
+    for i in 0..n {
+        sum = sum.wrapping_add(i);
+        unsafe { yield_now() }
+    }
+    sum
+}
+
+// rustc --target=wasm32-unknown-unknown tests/yield.rs -O -C panic=abort -o tests/yield.wasm
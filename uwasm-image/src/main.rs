@@ -0,0 +1,15 @@
+use uwasm::{write_image, ParserError};
+
+fn main() -> Result<(), ParserError> {
+    let mut args = std::env::args_os().skip(1);
+    let (Some(input), Some(output)) = (args.next(), args.next()) else {
+        panic!("usage: uwasm-image <input.wasm> <output.uwim> [--no-bytecode]")
+    };
+    let with_bytecode = !args.any(|arg| arg == "--no-bytecode");
+
+    let code = std::fs::read(input).expect("read module");
+    let image = write_image(&code, with_bytecode)?;
+    std::fs::write(output, &image).expect("write image");
+    println!("Written {} bytes of image for {} bytes of module", image.len(), code.len());
+    Ok(())
+}
@@ -2,7 +2,9 @@ extern crate core;
 
 use std::fmt::Arguments;
 use std::io::Write;
-use uwasm::{parse, Environment, ParserError, execute_function, VmContext, ImportedFunc, ByteStr, init_globals, init_memory};
+use uwasm::{parse, Environment, NoLog, ParserError, HostError, Linker, Caller, WasmPtr, WasmSlice, Engine, Instance, TypeKind, Value};
+
+const USAGE: &str = "usage: uwasm-perf <file.wasm> [runs] [export] [args...] [--fill-f32 <count>]";
 
 struct MyEnv;
 
@@ -17,58 +19,81 @@ impl Environment for MyEnv {
     }
 }
 
+// value of an argument given on the command line for a parameter of type `ty`
+fn parse_value(ty: TypeKind, arg: &str) -> Value {
+    let parsed = match ty {
+        TypeKind::I32 => arg.parse().ok().map(Value::I32),
+        TypeKind::I64 => arg.parse().ok().map(Value::I64),
+        TypeKind::F32 => arg.parse().ok().map(Value::F32),
+        TypeKind::F64 => arg.parse().ok().map(Value::F64),
+        _ => None,
+    };
+    parsed.unwrap_or_else(|| panic!("{arg:?} is not a valid {ty:?}"))
+}
+
 fn main() -> Result<(), ParserError> {
-    let Some(path) = std::env::args_os().nth(1) else {
-        panic!("missing path to .wasm file")
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    // number of f32 values 0.0, 1.0, 2.0, ... written at the start of memory, e.g. for `sum_slice`
+    let fill_f32 = match args.iter().position(|it| it == "--fill-f32") {
+        Some(idx) => {
+            let count = args.get(idx + 1).and_then(|n| n.parse::<u32>().ok()).expect(USAGE);
+            args.drain(idx..idx + 2);
+            count
+        }
+        None => 0,
     };
-    let runs = std::env::args()
-        .nth(2)
-        .and_then(|n| n.parse().ok())
-        .unwrap_or(1);
+    let mut args = args.into_iter();
+    let path = args.next().expect(USAGE);
+    let runs = args.next().map_or(1, |n| n.parse().expect(USAGE));
+    let entry = args.next().unwrap_or_else(|| "entry".into());
+    let args: Vec<String> = args.collect();
 
     let content = std::fs::read(path).expect("read file");
 
-    let module = parse(&content, &mut MyEnv)?;
-    let mut imports: Vec<ImportedFunc<MyEnv>> = Vec::new();
-    for name in module.get_imports() {
-        imports.push(match name.as_bytes() {
-            b"halt" => |_, stack, memory| {
-                println!(">>> !!!APPLICATION HALTED!!!");
-            },
-            b"print" => |_, stack, memory| {
-                let size = stack.pop_i32().unwrap() as usize;
-                let ptr = stack.pop_i32().unwrap() as usize;
-                let s = ByteStr::from_bytes(&memory[ptr..][..size]);
-                println!(">>> PRINT FROM VM {size} {ptr}: {:?}", s);
-            },
-            b"sleep_ms" => |_, stack, _memory| {
-                let sleep = stack.pop_u32().unwrap();
-                println!(">>> sleeping for {sleep} ms")
-            },
-            b"set_output" => |_, stack, _memory| {
-                let state = stack.pop_u32().unwrap();
-                let pin = stack.pop_u32().unwrap();
-                println!(">>> setting pin {pin} to {state}")
-            },
-            _ => todo!("{:?}", name),
-        });
+    let module = parse(&content, &mut NoLog)?;
+    let entry_idx = module.get_function_index_by_name(entry.as_bytes().into()).expect("exported function");
+    let params = module.get_function_signature(entry_idx).expect("function signature").params();
+    if args.len() != params.len() {
+        panic!("{entry} takes {} arguments of types {params:?}", params.len());
     }
+    let values: Vec<Value> = params.iter().zip(&args).map(|(&ty, arg)| parse_value(ty, arg)).collect();
 
-    let mut globals = Vec::new();
-    init_globals(&mut globals, &module).unwrap();
+    let mut linker = Linker::new();
+    linker
+        .func("env", "halt", |_: &mut MyEnv| println!(">>> !!!APPLICATION HALTED!!!"))
+        .func("env", "print", |caller: Caller<MyEnv>, ptr: WasmPtr<u8>, len: u32| -> Result<(), HostError> {
+            let s = caller.memory.str(WasmSlice::new(ptr, len))?;
+            println!(">>> PRINT FROM VM {len} {ptr:?}: {:?}", s);
+            Ok(())
+        })
+        .func("env", "sleep_ms", |_: &mut MyEnv, sleep: u32| println!(">>> sleeping for {sleep} ms"))
+        .func("env", "set_output", |_: &mut MyEnv, pin: u32, state: u32| println!(">>> setting pin {pin} to {state}"));
 
-    let started = std::time::Instant::now();
-    let mut ctx = VmContext::new();
-    let mut mem = [0u8; 1024*8];
-    init_memory(&mut mem, &module).unwrap();
-    for _n in 0u32..runs {
-        println!(">>> Executing entry function");
-        let res = execute_function::<MyEnv, (u32, ), u32>(&mut ctx, &module, b"entry".into(), (1, ), &mut mem, &mut globals, &imports, &mut MyEnv);
-        println!(">>> Result: {:?}", res);
-    }
-    println!("time = {:?}/execution", started.elapsed() / runs);
+    for engine in [Engine::Stack, Engine::Register, Engine::Jit] {
+        let imports = match linker.link(&module) {
+            Ok(imports) => imports,
+            Err(error) => panic!("{error}"),
+        };
+        // memory has the size declared by the module
+        let mut instance = Instance::new(&module, imports).expect("instantiate module");
+        let mut memory = instance.memory();
+        for i in 0..fill_f32 {
+            memory.write(WasmPtr::new(i * 4), i as f32).expect("values fit into memory");
+        }
+        instance.ctx().set_engine(engine);
+
+        // results are printed after the runs, so that only the execution is timed
+        let mut result = None;
+        let started = std::time::Instant::now();
+        for _n in 0u32..runs {
+            result = Some(instance.call_dynamic(entry.as_bytes().into(), &values, &mut MyEnv));
+        }
+        let elapsed = started.elapsed();
+        println!(">>> Result of {entry}: {:?}", result);
+        println!("{engine:?} engine: time = {:?}/execution", elapsed / runs);
 
-    println!("{:?}", ctx.profile());
+        println!("{:?}", instance.ctx().profile());
+    }
 
     Ok(())
 }
@@ -4,7 +4,7 @@ use std::fmt::Arguments;
 use std::io::Write;
 use std::process::Command;
 use anyhow::{bail, Context};
-use uwasm::{ByteStr, evaluate, parse, VmContext};
+use uwasm::{ByteStr, Instance, NoLog, parse, Value};
 
 struct MyEnv;
 
@@ -143,6 +143,19 @@ fn parse_input(input: &[u8]) -> anyhow::Result<(Signature<'_>, Vec<Case<'_>>)> {
     Ok((signature, cases))
 }
 
+fn parse_value(ty: &ByteStr, text: &ByteStr) -> anyhow::Result<Value> {
+    let text = std::str::from_utf8(text)?;
+    Ok(match ty.as_bytes() {
+        b"u32" => Value::I32(text.parse::<u32>()? as i32),
+        b"i32" => Value::I32(text.parse()?),
+        b"u64" => Value::I64(text.parse::<u64>()? as i64),
+        b"i64" => Value::I64(text.parse()?),
+        b"f32" => Value::F32(text.parse()?),
+        b"f64" => Value::F64(text.parse()?),
+        _ => bail!("unsupported type: {:?}", ty),
+    })
+}
+
 fn main() -> anyhow::Result<()> {
     let Some(path) = std::env::args_os().nth(1) else {
         bail!("missing path to test files")
@@ -179,35 +192,21 @@ fn main() -> anyhow::Result<()> {
             }
 
             let content = std::fs::read(wasm_path).expect("read file");
-            let module = parse(&content, &mut MyEnv)?;
+            let module = parse(&content, &mut NoLog)?;
             dbg!(&module);
 
-            let idx = module.get_function_index_by_name(signature.name)
-                .context("selecting entry function")?;
-
             for case in inputs {
-                let mut mem = Vec::new();
-                for (idx, param) in signature.args.iter().enumerate() {
-                    match param.as_bytes() {
-                        b"u32" => {
-                            let val: u32 = std::str::from_utf8(&case.args[idx]).unwrap().parse().unwrap();
-                            mem.extend_from_slice(&val.to_ne_bytes())
-                        },
-                        _ => unimplemented!("other: {:?}", param),
-                    }
-                }
-
-                let mut ctx = VmContext::new();
-                evaluate(&mut ctx, &module, idx, &mem, &mut [0; 1024], &mut [], &[], &mut MyEnv).unwrap();
-                match signature.returns.as_bytes() {
-                    b"u32" => {
-                        let res = ctx.stack.pop_u32().unwrap();
-                        let expected: u32 = std::str::from_utf8(&case.expected).unwrap().parse().unwrap();
-                        assert_eq!(res, expected);
-                    },
-                    _ => unimplemented!("other: {:?}", signature.returns),
-                };
-
+                // every case starts with fresh memory and globals
+                let mut instance = Instance::new(&module, Vec::new()).unwrap();
+                let args: Vec<_> = signature.args
+                    .iter()
+                    .zip(&case.args)
+                    .map(|(ty, arg)| parse_value(ty, arg))
+                    .collect::<anyhow::Result<_>>()?;
+
+                let results = instance.call_dynamic(signature.name, &args, &mut MyEnv).unwrap();
+                let expected = parse_value(signature.returns, case.expected)?;
+                assert_eq!(results, [expected]);
             }
         }
     }
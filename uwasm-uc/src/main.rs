@@ -11,23 +11,21 @@ use esp_println::println;
 use esp_hal::{
     clock::ClockControl, peripherals::Peripherals, prelude::*,
     gpio::Io, gpio::Level,
-    delay::Delay,
 };
 use esp_hal::gpio::{AnyOutput};
 use esp_hal::system::SystemControl;
 use esp_hal::timer::systimer::SystemTimer;
-use uwasm::{Environment, parse, VmContext, execute_function, ImportedFunc, init_globals, ByteStr, init_memory};
+use uwasm::{image_arena_size, Arena, Environment, parse_image_in, NoLog, VmContext, resume, Execution, PauseReason, ImportOutcome, HostError, Linker, Caller, WasmPtr, WasmSlice, init_globals_into, init_memory, VmBuffers};
 
 #[global_allocator]
 static ALLOCATOR: esp_alloc::EspHeap = esp_alloc::EspHeap::empty();
 
-struct MyEnv<'io> {
-    led1: AnyOutput<'io>,
-    led2: AnyOutput<'io>,
-    delay: Delay,
+struct MyEnv {
+    // tick at which the pending `sleep_ms` call completes
+    wake_at: Option<u64>,
 }
 
-impl Environment for MyEnv<'_> {
+impl Environment for MyEnv {
     fn write_fmt(&mut self, args: Arguments) {
         _ = esp_println::Printer.write_fmt(args);
     }
@@ -36,70 +34,98 @@ impl Environment for MyEnv<'_> {
     fn ticks(&self) -> u64 {
         SystemTimer::now()
     }
+
+    fn ticks_per_second(&self) -> u64 {
+        SystemTimer::TICKS_PER_SECOND
+    }
 }
 
 #[entry]
 fn main() -> ! {
     let peripherals = Peripherals::take();
     let system = SystemControl::new(peripherals.SYSTEM);
-    let clocks = ClockControl::boot_defaults(system.clock_control).freeze();
+    let _clocks = ClockControl::boot_defaults(system.clock_control).freeze();
     let io = Io::new(peripherals.GPIO, peripherals.IO_MUX);
 
     init_heap();
 
     let mut env = MyEnv {
-        led1: AnyOutput::new(io.pins.gpio18, Level::High),
-        led2: AnyOutput::new(io.pins.gpio19, Level::High),
-        delay: Delay::new(&clocks),
+        wake_at: None,
     };
-
-    let module = parse(include_bytes!("../../target/wasm32-unknown-unknown/release/app-example.wasm"), &mut env).expect("parse module");
-    let mut imports: Vec<ImportedFunc<MyEnv>> = Vec::new();
-
-    for name in module.get_imports() {
-        imports.push(match name.as_bytes() {
-            b"halt" => |_, stack, memory| {
-                println!(">>> !!!APPLICATION HALTED!!!");
-            },
-            b"print" => |env, stack, memory| {
-                let size = stack.pop_i32().unwrap() as usize;
-                let ptr = stack.pop_i32().unwrap() as usize;
-                let s = ByteStr::from_bytes(&memory[ptr..][..size]);
-                println!(">>> PRINT FROM VM: {:?}", s);
-            },
-            b"sleep_ms" => |env, stack, _memory| {
-                let sleep = stack.pop_u32().unwrap();
-                env.delay.delay_millis(sleep);
-                println!(">>> sleeping for {sleep} ms");
-            },
-            b"set_output" => |env, stack, _memory| {
-                let state = stack.pop_u32().unwrap();
-                let pin = stack.pop_u32().unwrap();
-
-                match (state, pin) {
-                    (0, 0) => env.led1.set_low(),
-                    (1, 0) => env.led1.set_high(),
-                    (0, 1) => env.led2.set_low(),
-                    (1, 1) => env.led2.set_high(),
-                    _ => unimplemented!(),
-                }
-                println!(">>> setting pin {pin} to {state}")
-            },
-            _ => todo!("{:?}", name),
+    // owned by the `set_output` import instead of the environment
+    let mut leds = [
+        AnyOutput::new(io.pins.gpio18, Level::High),
+        AnyOutput::new(io.pins.gpio19, Level::High),
+    ];
+
+    let code = include_bytes!("../../target/wasm32-unknown-unknown/release/app-example.wasm");
+    // translated ahead of time by `uwasm-image`, the interpreter below has no heap to prepare bodies on
+    static IMAGE: &AlignedImage<[u8]> = &AlignedImage(*include_bytes!("../../target/wasm32-unknown-unknown/release/app-example.uwim"));
+    println!("Module needs {} bytes", image_arena_size(code, &IMAGE.0).unwrap());
+    static mut MODULE_ARENA: [u8; 16 * 1024] = [0; 16 * 1024];
+    // SAFETY: main runs only once and is the only user of the arena
+    let arena = Arena::new(unsafe { &mut *core::ptr::addr_of_mut!(MODULE_ARENA) });
+    let module = parse_image_in(code, &IMAGE.0, &arena, &mut NoLog).expect("parse module");
+    let mut linker = Linker::new();
+    linker
+        .func("env", "halt", |_: &mut MyEnv| println!(">>> !!!APPLICATION HALTED!!!"))
+        .func("env", "print", |caller: Caller<MyEnv>, ptr: WasmPtr<u8>, len: u32| -> Result<(), HostError> {
+            let s = caller.memory.str(WasmSlice::new(ptr, len))?;
+            println!(">>> PRINT FROM VM: {:?}", s);
+            Ok(())
+        })
+        .func_raw("env", "sleep_ms", |env, stack, _memory| {
+            let sleep = stack.pop_u32().unwrap();
+            env.wake_at = Some(env.ticks() + u64::from(sleep) * env.ticks_per_second() / 1000);
+            println!(">>> sleeping for {sleep} ms");
+            Ok(ImportOutcome::Pending)
+        })
+        .func("env", "set_output", move |_: &mut MyEnv, pin: u32, state: u32| -> Result<(), HostError> {
+            // both come from the guest, a bad call traps it instead of the firmware
+            let led = leds.get_mut(pin as usize).ok_or(HostError::new("no such pin"))?;
+            match state {
+                0 => led.set_low(),
+                1 => led.set_high(),
+                _ => return Err(HostError::new("invalid state of a pin")),
+            }
+            println!(">>> setting pin {pin} to {state}");
+            Ok(())
         });
-    }
-
-    let mut globals = Vec::new();
-    init_globals(&mut globals, &module).unwrap();
+    let mut imports = match linker.link(&module) {
+        Ok(imports) => imports,
+        Err(error) => panic!("{error}"),
+    };
 
-    let mut vm_ctx = VmContext::new();
+    let mut globals = [0u8; 256];
+    let globals = &mut globals[..module.globals_len_in_bytes()];
+    init_globals_into(globals, &module).unwrap();
+
+    // keep the heap out of the hot path, everything the interpreter needs lives on the stack
+    let mut stack = [0u8; 2048];
+    let mut frames = [0u8; 32 * VmBuffers::FRAME_SIZE];
+    let mut vm_ctx = VmContext::with_buffers(VmBuffers {
+        stack: &mut stack,
+        frames: &mut frames,
+    });
     let mut mem = [0u8; 1024];
     init_memory(&mut mem, &module).unwrap();
+    // looked up and type-checked once, each call below goes straight to the function
+    let entry = module.get_typed_func::<(u32, ), u32>(b"entry".into()).expect("entry function");
     loop {
         let start = SystemTimer::now();
         for _ in 0..10 {
             println!("Executing entry function...");
-            let result = execute_function::<MyEnv, (u32, ), u32>(&mut vm_ctx, &module, b"entry".into(), (12u32, ), &mut mem, &mut globals, &imports, &mut env);
+            let mut state = entry.start(&mut vm_ctx, (12, ))
+                .and_then(|_| resume(&mut vm_ctx, &module, &mut mem, globals, &mut imports, &mut env));
+            while let Ok(Execution::Paused(PauseReason::ImportPending { .. })) = state {
+                // the guest is sleeping, the CPU is free to do other work in the meantime
+                if env.wake_at.is_some_and(|wake_at| SystemTimer::now() >= wake_at) {
+                    env.wake_at = None;
+                    vm_ctx.complete_import(|_| {}).unwrap();
+                }
+                state = resume(&mut vm_ctx, &module, &mut mem, globals, &mut imports, &mut env);
+            }
+            let result = state.and_then(|_| entry.result(&mut vm_ctx));
             println!("Result: {:?}", result);
         }
         let elapsed = SystemTimer::now() - start;
@@ -107,6 +133,10 @@ fn main() -> ! {
     }
 }
 
+// keeps the image at `uwasm::IMAGE_ALIGN`
+#[repr(C, align(8))]
+struct AlignedImage<T: ?Sized>(T);
+
 fn init_heap() {
     const HEAP_SIZE: usize = 32 * 2048;
     static mut HEAP: core::mem::MaybeUninit<[u8; HEAP_SIZE]> = core::mem::MaybeUninit::uninit();
@@ -0,0 +1,54 @@
+use crate::caller::WasmSlice;
+use crate::parser::TypeKind;
+use crate::WasmModule;
+
+/// Allocator exported by a guest, used by the host to pass buffers into it, see [`crate::Instance::alloc_bytes`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GuestAllocator {
+    /// `malloc(size) -> ptr` and `free(ptr)`, the host frees memory once the guest doesn't need it anymore.
+    Malloc { malloc: usize, free: usize },
+    /// `cabi_realloc(old_ptr, old_size, align, new_size) -> ptr` of the canonical ABI, memory is owned
+    /// by the guest as soon as it is passed to it.
+    CabiRealloc { realloc: usize },
+}
+
+impl GuestAllocator {
+    /// Finds the allocator among exported functions of the module, `malloc` and `free` are preferred.
+    pub fn detect(module: &WasmModule) -> Option<Self> {
+        let export = |name: &[u8], params: &[TypeKind], results: &[TypeKind]| {
+            let func_idx = module.get_export_index(name.into())?;
+            let signature = module.get_function_signature(func_idx)?;
+            (signature.params() == params && signature.results() == results).then_some(func_idx)
+        };
+
+        let malloc = export(b"malloc", &[TypeKind::I32], &[TypeKind::I32]);
+        let free = export(b"free", &[TypeKind::I32], &[]);
+        if let (Some(malloc), Some(free)) = (malloc, free) {
+            return Some(GuestAllocator::Malloc { malloc, free });
+        }
+        export(b"cabi_realloc", &[TypeKind::I32; 4], &[TypeKind::I32])
+            .map(|realloc| GuestAllocator::CabiRealloc { realloc })
+    }
+}
+
+/// Memory allocated in a guest by [`crate::Instance::alloc_bytes`].
+///
+/// It has to be given back with [`crate::Instance::free_bytes`], unless the guest takes it over.
+#[must_use]
+#[derive(Debug)]
+pub struct GuestBuffer {
+    pub(crate) slice: WasmSlice<u8>,
+    pub(crate) allocator: GuestAllocator,
+}
+
+impl GuestBuffer {
+    /// Location of the data in memory of the guest.
+    pub fn slice(&self) -> WasmSlice<u8> {
+        self.slice
+    }
+
+    /// Gives up the memory without freeing it, for guests that free it by themselves.
+    pub fn into_guest(self) -> WasmSlice<u8> {
+        self.slice
+    }
+}
@@ -0,0 +1,158 @@
+use alloc::alloc::{AllocError, Allocator, Global, Layout};
+use alloc::collections::TryReserveError;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::ptr::NonNull;
+
+/// Alignment of the first allocation in an arena, large enough for everything stored in a module.
+const ARENA_ALIGN: usize = 16;
+
+/// Bump region for [`crate::parse_in`].
+///
+/// Memory is never reused, a freed or reallocated object keeps its space until the arena is dropped.
+pub struct Arena<'buf> {
+    // `None` when the arena only measures how much space would be used
+    start: Option<NonNull<u8>>,
+    capacity: usize,
+    used: Cell<usize>,
+    _buf: PhantomData<&'buf mut [u8]>,
+}
+
+impl<'buf> Arena<'buf> {
+    pub fn new(buf: &'buf mut [u8]) -> Self {
+        let padding = buf.as_ptr().align_offset(ARENA_ALIGN).min(buf.len());
+        let buf = &mut buf[padding..];
+        Self {
+            capacity: buf.len(),
+            start: NonNull::new(buf.as_mut_ptr()),
+            used: Cell::new(0),
+            _buf: PhantomData,
+        }
+    }
+
+    /// Creates an arena that takes memory from the global allocator, but counts bytes
+    /// as if it was a bump region with `used` bytes already taken.
+    pub(crate) fn measuring(used: usize) -> Arena<'static> {
+        Arena {
+            start: None,
+            capacity: usize::MAX,
+            used: Cell::new(used),
+            _buf: PhantomData,
+        }
+    }
+
+    /// Number of bytes taken from the arena so far.
+    pub fn used(&self) -> usize {
+        self.used.get()
+    }
+
+    /// Number of bytes the arena can hold in total.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Gives back everything allocated after `used` bytes had been taken, once nothing that has been
+    /// allocated since then is alive anymore.
+    pub(crate) fn rewind(&self, used: usize) {
+        debug_assert!(used <= self.used.get());
+        self.used.set(used);
+    }
+
+    /// Number of bytes that a buffer of any alignment must have to fit `used` bytes of an arena.
+    pub(crate) fn required_len(used: usize) -> usize {
+        used + ARENA_ALIGN - 1
+    }
+
+    fn bump(&self, layout: Layout) -> Result<usize, AllocError> {
+        // the start is aligned to `ARENA_ALIGN`, so aligning the offset aligns the pointer as well
+        debug_assert!(layout.align() <= ARENA_ALIGN);
+        let offset = self.used.get().checked_next_multiple_of(layout.align()).ok_or(AllocError)?;
+        let end = offset.checked_add(layout.size()).ok_or(AllocError)?;
+        if end > self.capacity {
+            return Err(AllocError);
+        }
+        self.used.set(end);
+        Ok(offset)
+    }
+}
+
+/// Allocator used for everything owned by a [`crate::WasmModule`].
+#[derive(Clone, Copy)]
+pub(crate) enum ModuleAlloc<'a> {
+    Global,
+    Arena(&'a Arena<'a>),
+}
+
+unsafe impl Allocator for ModuleAlloc<'_> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        match self {
+            ModuleAlloc::Global => Global.allocate(layout),
+            ModuleAlloc::Arena(arena) => {
+                let offset = arena.bump(layout)?;
+                match arena.start {
+                    // SAFETY: `bump` checked that the allocation fits in the buffer
+                    Some(start) => Ok(NonNull::slice_from_raw_parts(unsafe { start.add(offset) }, layout.size())),
+                    None => Global.allocate(layout),
+                }
+            }
+        }
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        match self {
+            ModuleAlloc::Global | ModuleAlloc::Arena(Arena { start: None, .. }) => Global.deallocate(ptr, layout),
+            ModuleAlloc::Arena(_) => {}
+        }
+    }
+}
+
+pub(crate) type ModuleVec<'a, T> = Vec<T, ModuleAlloc<'a>>;
+
+/// Allocations of the parser that fail instead of aborting, so that a full arena can be reported.
+pub(crate) trait TryPush<T> {
+    fn try_push(&mut self, item: T) -> Result<(), TryReserveError>;
+
+    fn try_extend_from_slice(&mut self, items: &[T]) -> Result<(), TryReserveError> where T: Copy;
+}
+
+impl<T> TryPush<T> for ModuleVec<'_, T> {
+    #[inline]
+    fn try_push(&mut self, item: T) -> Result<(), TryReserveError> {
+        self.try_reserve(1)?;
+        self.push(item);
+        Ok(())
+    }
+
+    #[inline]
+    fn try_extend_from_slice(&mut self, items: &[T]) -> Result<(), TryReserveError> where T: Copy {
+        self.try_reserve(items.len())?;
+        self.extend_from_slice(items);
+        Ok(())
+    }
+}
+
+pub(crate) fn try_with_capacity_in<T>(capacity: usize, alloc: ModuleAlloc<'_>) -> Result<ModuleVec<'_, T>, TryReserveError> {
+    let mut vec = Vec::new_in(alloc);
+    vec.try_reserve_exact(capacity)?;
+    Ok(vec)
+}
+
+/// Items owned by a module or borrowed from a precompiled image, see [`crate::write_image`].
+pub(crate) enum ModuleSlice<'a, T> {
+    Owned(ModuleVec<'a, T>),
+    Borrowed(&'a [T]),
+}
+
+impl<T> Deref for ModuleSlice<'_, T> {
+    type Target = [T];
+
+    #[inline]
+    fn deref(&self) -> &[T] {
+        match self {
+            ModuleSlice::Owned(items) => items,
+            ModuleSlice::Borrowed(items) => items,
+        }
+    }
+}
@@ -0,0 +1,919 @@
+use alloc::vec::Vec;
+
+use crate::arena::{try_with_capacity_in, ModuleAlloc, ModuleVec, TryPush};
+use crate::parser::{ParserError, Reader, TypeKind};
+use crate::log::{Level, Log};
+use crate::numeric;
+use crate::{parse_opcode, Func, FuncSignature, Global, ParserState};
+
+/// Instruction of the internal stream that function bodies are translated into at load time.
+///
+/// Immediates are already decoded, locals are addressed by their index in the frame, globals by their
+/// byte offsets, and branches point directly at the index of the target instruction.
+///
+/// The layout is fixed by `repr(u8)`, so that the code can be stored in a precompiled image, see [`crate::write_image`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub(crate) enum Instr {
+    Unreachable,
+    /// Jumps without touching the stack, ends the `then` arm of an `if`.
+    Jump { target: u32 },
+    /// Pops the condition of an `if` and jumps to its `else` arm or end when it is zero.
+    JumpIfZero { target: u32 },
+    Br(BrTarget),
+    BrIf(BrTarget),
+    /// Branch targets are stored in `FuncBody::br_table[start..][..len]`, the last one is the default.
+    BrTable { start: u32, len: u32 },
+    /// Moves `keep` values from the top of the stack in place of the locals of the returning function.
+    Return { keep: u32 },
+    Call { func_idx: u32 },
+    CallIndirect { type_idx: u32 },
+    Drop,
+    Select,
+    LocalGet { idx: u32 },
+    LocalSet { idx: u32 },
+    LocalTee { idx: u32 },
+    GlobalGet { offset: u32, ty: TypeKind },
+    GlobalSet { offset: u32, ty: TypeKind },
+    Load { op: u8, offset: u32 },
+    Store { op: u8, offset: u32 },
+    MemorySize,
+    /// Memory can't grow, see [`crate::numeric::memory_grow`].
+    MemoryGrow,
+    MemoryCopy,
+    MemoryFill,
+    I32Const(i32),
+    I64Const(i64),
+    F32Const(f32),
+    F64Const(f64),
+    I32Eqz,
+    I32Eq,
+    I32Ne,
+    I32LtS,
+    I32LtU,
+    I32GtS,
+    I32GtU,
+    I32LeS,
+    I32LeU,
+    I32GeS,
+    I32GeU,
+    I64Eqz,
+    I64Ne,
+    I64LtU,
+    I64GtU,
+    I64GeU,
+    F64Lt,
+    I32Add,
+    I32Sub,
+    I32Mul,
+    I32DivS,
+    I32DivU,
+    I32RemU,
+    I32And,
+    I32Or,
+    I32Xor,
+    I32Shl,
+    I32ShrU,
+    I64Add,
+    I64Mul,
+    I64DivU,
+    I64RemU,
+    I64And,
+    I64Or,
+    I64Shl,
+    I64ShrU,
+    F32Add,
+    F64Sub,
+    F64Mul,
+    I32WrapI64,
+    I64ExtendI32U,
+    F32ReinterpretI32,
+    I32Extend8S,
+    /// Numeric instruction with one operand and without a variant of its own.
+    Unary { op: u8 },
+    /// Numeric instruction with two operands and without a variant of its own.
+    Binary { op: u8 },
+    /// Saturating conversion of a float into an integer, `op` follows the `0xfc` prefix.
+    TruncSat { op: u8 },
+    /// `local.get idx; i32.const val; i32.add`, see [`fuse`].
+    LocalI32AddConst { idx: u32, val: i32 },
+    /// `local.get a; local.get b; i32.lt_u; br_if`, the target is taken from the `br_if` that follows.
+    LocalsLtUBrIf { a: u32, b: u32 },
+    /// `local.get idx` followed by a load.
+    /// Has to stay the last variant, images with greater tags are rejected.
+    LocalLoad { idx: u32, op: u8, offset: u32 },
+}
+
+impl Instr {
+    /// Opcode of the wasm instruction this one has been translated from.
+    pub(crate) fn opcode(&self) -> u8 {
+        match *self {
+            Instr::Unreachable => 0x00,
+            Instr::Jump { .. } => 0x05,
+            Instr::JumpIfZero { .. } => 0x04,
+            Instr::Br(_) => 0x0c,
+            Instr::BrIf(_) => 0x0d,
+            Instr::BrTable { .. } => 0x0e,
+            Instr::Return { .. } => 0x0f,
+            Instr::Call { .. } => 0x10,
+            Instr::CallIndirect { .. } => 0x11,
+            Instr::Drop => 0x1a,
+            Instr::Select => 0x1b,
+            Instr::LocalGet { .. } => 0x20,
+            Instr::LocalSet { .. } => 0x21,
+            Instr::LocalTee { .. } => 0x22,
+            Instr::GlobalGet { .. } => 0x23,
+            Instr::GlobalSet { .. } => 0x24,
+            Instr::Load { op, .. } | Instr::Store { op, .. } => op,
+            Instr::MemorySize => 0x3f,
+            Instr::MemoryGrow => 0x40,
+            Instr::MemoryCopy | Instr::MemoryFill | Instr::TruncSat { .. } => 0xfc,
+            Instr::I32Const(_) => 0x41,
+            Instr::I64Const(_) => 0x42,
+            Instr::F32Const(_) => 0x43,
+            Instr::F64Const(_) => 0x44,
+            Instr::I32Eqz => 0x45,
+            Instr::I32Eq => 0x46,
+            Instr::I32Ne => 0x47,
+            Instr::I32LtS => 0x48,
+            Instr::I32LtU => 0x49,
+            Instr::I32GtS => 0x4a,
+            Instr::I32GtU => 0x4b,
+            Instr::I32LeS => 0x4c,
+            Instr::I32LeU => 0x4d,
+            Instr::I32GeS => 0x4e,
+            Instr::I32GeU => 0x4f,
+            Instr::I64Eqz => 0x50,
+            Instr::I64Ne => 0x52,
+            Instr::I64LtU => 0x54,
+            Instr::I64GtU => 0x56,
+            Instr::I64GeU => 0x5a,
+            Instr::F64Lt => 0x63,
+            Instr::I32Add => 0x6a,
+            Instr::I32Sub => 0x6b,
+            Instr::I32Mul => 0x6c,
+            Instr::I32DivS => 0x6d,
+            Instr::I32DivU => 0x6e,
+            Instr::I32RemU => 0x70,
+            Instr::I32And => 0x71,
+            Instr::I32Or => 0x72,
+            Instr::I32Xor => 0x73,
+            Instr::I32Shl => 0x74,
+            Instr::I32ShrU => 0x76,
+            Instr::I64Add => 0x7c,
+            Instr::I64Mul => 0x7e,
+            Instr::I64DivU => 0x80,
+            Instr::I64RemU => 0x82,
+            Instr::I64And => 0x83,
+            Instr::I64Or => 0x84,
+            Instr::I64Shl => 0x86,
+            Instr::I64ShrU => 0x88,
+            Instr::F32Add => 0x92,
+            Instr::F64Sub => 0xa1,
+            Instr::F64Mul => 0xa2,
+            Instr::I32WrapI64 => 0xa7,
+            Instr::I64ExtendI32U => 0xad,
+            Instr::F32ReinterpretI32 => 0xbe,
+            Instr::I32Extend8S => 0xc0,
+            Instr::Unary { op } | Instr::Binary { op } => op,
+            Instr::LocalI32AddConst { .. } | Instr::LocalsLtUBrIf { .. } | Instr::LocalLoad { .. } => 0x20,
+        }
+    }
+
+    /// Number of wasm instructions executed by this one, which is what it costs in fuel.
+    pub(crate) fn fused_len(&self) -> u64 {
+        match self {
+            Instr::LocalI32AddConst { .. } => 3,
+            Instr::LocalsLtUBrIf { .. } => 4,
+            Instr::LocalLoad { .. } => 2,
+            _ => 1,
+        }
+    }
+
+    /// First instruction of the sequence replaced by a superinstruction, the rest of it follows in place.
+    pub(crate) fn unfused(self) -> Instr {
+        match self {
+            Instr::LocalI32AddConst { idx, .. } | Instr::LocalLoad { idx, .. } => Instr::LocalGet { idx },
+            Instr::LocalsLtUBrIf { a, .. } => Instr::LocalGet { idx: a },
+            instr => instr,
+        }
+    }
+}
+
+/// Sequence of instructions executed by the stack interpreter as a single one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Superinstruction {
+    /// `local.get; i32.const; i32.add`
+    LocalI32AddConst,
+    /// `local.get; local.get; i32.lt_u; br_if`
+    LocalsLtUBrIf,
+    /// `local.get; <load> offset`
+    LocalLoad,
+}
+
+impl Superinstruction {
+    pub const ALL: [Superinstruction; 3] = [
+        Superinstruction::LocalI32AddConst,
+        Superinstruction::LocalsLtUBrIf,
+        Superinstruction::LocalLoad,
+    ];
+
+    /// Instructions that have been fused.
+    pub fn pattern(self) -> &'static str {
+        match self {
+            Superinstruction::LocalI32AddConst => "local.get; i32.const; i32.add",
+            Superinstruction::LocalsLtUBrIf => "local.get; local.get; i32.lt_u; br_if",
+            Superinstruction::LocalLoad => "local.get; load",
+        }
+    }
+}
+
+/// Replaces the first instruction of each frequent sequence with a superinstruction that executes the whole
+/// sequence and continues after it.
+///
+/// The rest of the sequence stays in place, so branches into the middle of it keep working
+/// and no branch target has to be changed.
+pub(crate) fn fuse(instrs: &mut [Instr]) {
+    for i in 0..instrs.len() {
+        let fused = match instrs[i..] {
+            [Instr::LocalGet { idx }, Instr::I32Const(val), Instr::I32Add, ..] => Instr::LocalI32AddConst { idx, val },
+            [Instr::LocalGet { idx: a }, Instr::LocalGet { idx: b }, Instr::I32LtU, Instr::BrIf(_), ..] => Instr::LocalsLtUBrIf { a, b },
+            [Instr::LocalGet { idx }, Instr::Load { op, offset }, ..] => Instr::LocalLoad { idx, op, offset },
+            _ => continue,
+        };
+        instrs[i] = fused;
+    }
+}
+
+/// Target of a branch together with the number of values to remove from the stack
+/// right below the `keep` values passed to the target.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub(crate) struct BrTarget {
+    pub(crate) target: u32,
+    pub(crate) drop: u32,
+    pub(crate) keep: u32,
+}
+
+/// Stack layout around an instruction, counted in values instead of bytes.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Slots {
+    /// Number of values on the stack before the instruction.
+    pub(crate) height: u16,
+    /// Height of the stack at the target of a branch.
+    pub(crate) base: u16,
+    /// Number of values passed to the target of a branch.
+    pub(crate) keep: u16,
+}
+
+/// Converts a number of values or locals into the 16 bits used by [`Slots`] and the register engine.
+pub(crate) fn slot_count(count: usize) -> Result<u16, ParserError> {
+    u16::try_from(count).map_err(|_| ParserError::FunctionTooLarge)
+}
+
+/// Parts of the module needed to follow types of values through function bodies.
+pub(crate) struct ModuleTypes<'m, 's, 'code> {
+    pub(crate) signatures: &'s [FuncSignature<'code>],
+    pub(crate) functions: &'m [Func<'code>],
+    pub(crate) globals: &'m [Global<'code>],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ControlKind {
+    Block,
+    Loop,
+    If,
+    Else,
+}
+
+struct Control<'s> {
+    kind: ControlKind,
+    // number of values on the stack below the params of the block
+    height: usize,
+    // index of the first instruction of a loop
+    start: u32,
+    params: &'s [TypeKind],
+    results: &'s [TypeKind],
+    // rest of the block can't be reached, so any values can be popped from the stack
+    unreachable: bool,
+    // `JumpIfZero` of an `if` that still waits for its `else`
+    else_site: Option<usize>,
+}
+
+#[derive(Clone, Copy)]
+enum Site {
+    Instr(usize),
+    Table(usize),
+}
+
+// forward branch waiting for the end of its target block
+struct Pending {
+    depth: usize,
+    site: Site,
+}
+
+/// Translates function bodies into [`Instr`]s, reusing its scratch space between functions.
+pub(crate) struct Translator<'s, 'code> {
+    alloc: ModuleAlloc<'code>,
+    instrs: ModuleVec<'code, Instr>,
+    br_table: ModuleVec<'code, BrTarget>,
+    types: ModuleVec<'code, TypeKind>,
+    controls: ModuleVec<'code, Control<'s>>,
+    pending: ModuleVec<'code, Pending>,
+    // layout of the stack for each instruction and each entry of `br_table`
+    slots: ModuleVec<'code, Slots>,
+    table_slots: ModuleVec<'code, Slots>,
+    // number of values on the stack before the current instruction
+    height: usize,
+    max_height: usize,
+}
+
+/// Function body translated by [`Translator::translate`].
+pub(crate) struct Translated<'code> {
+    pub(crate) instrs: ModuleVec<'code, Instr>,
+    pub(crate) br_table: ModuleVec<'code, BrTarget>,
+}
+
+impl<'s, 'code> Translator<'s, 'code> {
+    pub(crate) fn new(alloc: ModuleAlloc<'code>) -> Self {
+        Self {
+            alloc,
+            instrs: Vec::new_in(alloc),
+            br_table: Vec::new_in(alloc),
+            types: Vec::new_in(alloc),
+            controls: Vec::new_in(alloc),
+            pending: Vec::new_in(alloc),
+            slots: Vec::new_in(alloc),
+            table_slots: Vec::new_in(alloc),
+            height: 0,
+            max_height: 0,
+        }
+    }
+
+    /// Stack layout of each instruction of the last translated function.
+    pub(crate) fn slots(&self) -> &[Slots] {
+        &self.slots
+    }
+
+    /// Stack layout of each branch target in `br_table` of the last translated function.
+    pub(crate) fn table_slots(&self) -> &[Slots] {
+        &self.table_slots
+    }
+
+    /// Largest number of values on the stack in the last translated function.
+    pub(crate) fn max_height(&self) -> usize {
+        self.max_height
+    }
+
+    pub(crate) fn translate(
+        &mut self,
+        reader: &mut Reader,
+        signature: &'s FuncSignature<'code>,
+        locals_types: &[TypeKind],
+        module: &ModuleTypes<'_, 's, 'code>,
+        log: &mut impl Log,
+    ) -> Result<Translated<'code>, ParserError> {
+        self.instrs.clear();
+        self.br_table.clear();
+        self.types.clear();
+        self.controls.clear();
+        self.pending.clear();
+        self.slots.clear();
+        self.table_slots.clear();
+        self.max_height = 0;
+
+        // body of the function behaves like a block, branching to it returns from the function
+        self.controls.try_push(Control {
+            kind: ControlKind::Block,
+            height: 0,
+            start: 0,
+            params: &[],
+            results: &signature.results,
+            unreachable: false,
+            else_site: None,
+        })?;
+
+        while !self.controls.is_empty() {
+            let pos = reader.pos();
+            self.height = self.types.len();
+            if log.enabled(Level::Trace) {
+                _ = parse_opcode::<true>(&mut { *reader }, log, &mut ParserState::default());
+            }
+            let op = reader.read_u8()?;
+            match op {
+                0x00 => {
+                    // unreachable
+                    self.emit(Instr::Unreachable)?;
+                    self.set_unreachable();
+                }
+                0x01 => {
+                    // nop
+                }
+                0x02 | 0x03 => {
+                    // block | loop
+                    let (params, results) = read_block_type(reader, module.signatures)?;
+                    for &ty in params.iter().rev() {
+                        self.pop_expect(ty, pos)?;
+                    }
+                    self.controls.try_push(Control {
+                        kind: if op == 0x02 { ControlKind::Block } else { ControlKind::Loop },
+                        height: self.types.len(),
+                        start: self.instrs.len() as u32,
+                        params,
+                        results,
+                        unreachable: false,
+                        else_site: None,
+                    })?;
+                    self.types.try_extend_from_slice(params)?;
+                }
+                0x04 => {
+                    // if
+                    let (params, results) = read_block_type(reader, module.signatures)?;
+                    self.pop_expect(TypeKind::I32, pos)?;
+                    for &ty in params.iter().rev() {
+                        self.pop_expect(ty, pos)?;
+                    }
+                    self.controls.try_push(Control {
+                        kind: ControlKind::If,
+                        height: self.types.len(),
+                        start: 0,
+                        params,
+                        results,
+                        unreachable: false,
+                        else_site: Some(self.instrs.len()),
+                    })?;
+                    self.types.try_extend_from_slice(params)?;
+                    self.emit(Instr::JumpIfZero { target: 0 })?;
+                }
+                0x05 => {
+                    // else
+                    let depth = self.controls.len() - 1;
+                    let ctrl = &self.controls[depth];
+                    if ctrl.kind != ControlKind::If {
+                        return Err(ParserError::InvalidCode { offset: pos });
+                    }
+                    self.end_of_values(pos)?;
+                    self.pending.try_push(Pending { depth, site: Site::Instr(self.instrs.len()) })?;
+                    self.emit(Instr::Jump { target: 0 })?;
+
+                    let target = self.instrs.len() as u32;
+                    let ctrl = &mut self.controls[depth];
+                    let else_site = ctrl.else_site.take().expect("if without else");
+                    ctrl.kind = ControlKind::Else;
+                    ctrl.unreachable = false;
+                    let (height, params) = (ctrl.height, ctrl.params);
+                    self.patch(Site::Instr(else_site), target);
+                    self.types.truncate(height);
+                    self.types.try_extend_from_slice(params)?;
+                }
+                0x0b => {
+                    // end
+                    self.end_of_values(pos)?;
+                    let depth = self.controls.len() - 1;
+                    let target = self.instrs.len() as u32;
+                    let ctrl = self.controls.pop().expect("checked by the loop");
+                    if let Some(else_site) = ctrl.else_site {
+                        self.patch(Site::Instr(else_site), target);
+                    }
+                    // branches to outer blocks may be interleaved with the ones to this block
+                    let mut i = 0;
+                    while i < self.pending.len() {
+                        if self.pending[i].depth == depth {
+                            let site = self.pending.swap_remove(i).site;
+                            self.patch(site, target);
+                        } else {
+                            i += 1;
+                        }
+                    }
+                    self.types.truncate(ctrl.height);
+                    self.types.try_extend_from_slice(ctrl.results)?;
+                    if self.controls.is_empty() {
+                        let keep = slot_count(ctrl.results.len())?;
+                        self.emit_branch(Instr::Return { keep: keep.into() }, Slots { base: 0, keep, ..Slots::default() })?;
+                    }
+                }
+                0x0c => {
+                    // br
+                    let depth = reader.read_usize()?;
+                    let (target, slots) = self.branch(depth, Site::Instr(self.instrs.len()), pos)?;
+                    self.emit_branch(Instr::Br(target), slots)?;
+                    self.set_unreachable();
+                }
+                0x0d => {
+                    // br_if
+                    let depth = reader.read_usize()?;
+                    self.pop_expect(TypeKind::I32, pos)?;
+                    let (target, slots) = self.branch(depth, Site::Instr(self.instrs.len()), pos)?;
+                    self.emit_branch(Instr::BrIf(target), slots)?;
+                }
+                0x0e => {
+                    // br_table
+                    self.pop_expect(TypeKind::I32, pos)?;
+                    let start = self.br_table.len();
+                    let n = reader.read_usize()?;
+                    for _ in 0..=n {
+                        let depth = reader.read_usize()?;
+                        let (target, slots) = self.branch(depth, Site::Table(self.br_table.len()), pos)?;
+                        self.br_table.try_push(target)?;
+                        self.table_slots.try_push(slots)?;
+                    }
+                    self.emit(Instr::BrTable { start: start as u32, len: n as u32 + 1 })?;
+                    self.set_unreachable();
+                }
+                0x0f => {
+                    // return
+                    let keep = slot_count(self.controls[0].results.len())?;
+                    self.emit_branch(Instr::Return { keep: keep.into() }, Slots { base: 0, keep, ..Slots::default() })?;
+                    self.set_unreachable();
+                }
+                0x10 => {
+                    // call <func_idx>
+                    let func_idx = reader.read_usize()?;
+                    let signature = module.functions.get(func_idx)
+                        .and_then(|f| module.signatures.get(f.signature?))
+                        .ok_or(ParserError::InvalidCode { offset: pos })?;
+                    self.call(signature, pos)?;
+                    self.emit(Instr::Call { func_idx: func_idx as u32 })?;
+                }
+                0x11 => {
+                    // call_indirect <sig_idx> <table_idx>
+                    let sig_idx = reader.read_usize()?;
+                    let _table_idx = reader.read_usize()?;
+                    let signature = module.signatures.get(sig_idx)
+                        .ok_or(ParserError::InvalidCode { offset: pos })?;
+                    self.pop_expect(TypeKind::I32, pos)?;
+                    self.call(signature, pos)?;
+                    self.emit(Instr::CallIndirect { type_idx: sig_idx as u32 })?;
+                }
+                0x1a => {
+                    // drop
+                    self.pop(pos)?;
+                    self.emit(Instr::Drop)?;
+                }
+                0x1b => {
+                    // select
+                    self.pop_expect(TypeKind::I32, pos)?;
+                    let ty = self.pop(pos)?;
+                    self.pop_expect(ty, pos)?;
+                    self.types.try_push(ty)?;
+                    self.emit(Instr::Select)?;
+                }
+                0x20..=0x22 => {
+                    // local.get | local.set | local.tee
+                    let local_idx = reader.read_usize()?;
+                    let Some(&ty) = locals_types.get(local_idx) else {
+                        return Err(ParserError::InvalidCode { offset: pos });
+                    };
+                    let idx = local_idx as u32;
+                    match op {
+                        0x20 => {
+                            self.types.try_push(ty)?;
+                            self.emit(Instr::LocalGet { idx })?;
+                        }
+                        0x21 => {
+                            self.pop_expect(ty, pos)?;
+                            self.emit(Instr::LocalSet { idx })?;
+                        }
+                        _ => {
+                            self.pop_expect(ty, pos)?;
+                            self.types.try_push(ty)?;
+                            self.emit(Instr::LocalTee { idx })?;
+                        }
+                    }
+                }
+                0x23 | 0x24 => {
+                    // global.get | global.set
+                    let global_idx = reader.read_usize()?;
+                    let Some(global) = module.globals.get(global_idx) else {
+                        return Err(ParserError::InvalidCode { offset: pos });
+                    };
+                    let ty = global.kind;
+                    let offset = module.globals[..global_idx].iter().map(|g| g.kind.len_bytes()).sum::<usize>() as u32;
+                    if op == 0x23 {
+                        self.types.try_push(ty)?;
+                        self.emit(Instr::GlobalGet { offset, ty })?;
+                    } else {
+                        self.pop_expect(ty, pos)?;
+                        self.emit(Instr::GlobalSet { offset, ty })?;
+                    }
+                }
+                0x28..=0x35 => {
+                    // loads
+                    let _align = reader.read_usize()?;
+                    let offset = reader.read_usize()? as u32;
+                    self.pop_expect(TypeKind::I32, pos)?;
+                    self.types.try_push(match op {
+                        0x28 | 0x2c..=0x2f => TypeKind::I32,
+                        0x29 | 0x30..=0x35 => TypeKind::I64,
+                        0x2a => TypeKind::F32,
+                        _ => TypeKind::F64,
+                    })?;
+                    self.emit(Instr::Load { op, offset })?;
+                }
+                0x36..=0x3e => {
+                    // stores
+                    let _align = reader.read_usize()?;
+                    let offset = reader.read_usize()? as u32;
+                    self.pop_expect(match op {
+                        0x36 | 0x3a | 0x3b => TypeKind::I32,
+                        0x37 | 0x3c..=0x3e => TypeKind::I64,
+                        0x38 => TypeKind::F32,
+                        _ => TypeKind::F64,
+                    }, pos)?;
+                    self.pop_expect(TypeKind::I32, pos)?;
+                    self.emit(Instr::Store { op, offset })?;
+                }
+                0x3f => {
+                    // memory.size
+                    let _mem_idx = reader.read_u8()?;
+                    self.types.try_push(TypeKind::I32)?;
+                    self.emit(Instr::MemorySize)?;
+                }
+                0x40 => {
+                    // memory.grow
+                    let _mem_idx = reader.read_u8()?;
+                    self.pop_expect(TypeKind::I32, pos)?;
+                    self.types.try_push(TypeKind::I32)?;
+                    self.emit(Instr::MemoryGrow)?;
+                }
+                0x41 => {
+                    // i32.const <literal>
+                    let val = reader.read_signed()?;
+                    self.types.try_push(TypeKind::I32)?;
+                    self.emit(Instr::I32Const(val as i32))?;
+                }
+                0x42 => {
+                    // i64.const <literal>
+                    let val = reader.read_signed()?;
+                    self.types.try_push(TypeKind::I64)?;
+                    self.emit(Instr::I64Const(val))?;
+                }
+                0x43 => {
+                    // f32.const <literal>
+                    let val = reader.read_f32()?;
+                    self.types.try_push(TypeKind::F32)?;
+                    self.emit(Instr::F32Const(val))?;
+                }
+                0x44 => {
+                    // f64.const <literal>
+                    let val = reader.read_f64()?;
+                    self.types.try_push(TypeKind::F64)?;
+                    self.emit(Instr::F64Const(val))?;
+                }
+                0xfc => {
+                    let sub = reader.read_usize()?;
+                    match sub {
+                        0..=7 => {
+                            // i32.trunc_sat_* | i64.trunc_sat_*
+                            self.pop_expect(if sub & 2 == 0 { TypeKind::F32 } else { TypeKind::F64 }, pos)?;
+                            self.types.try_push(if sub < 4 { TypeKind::I32 } else { TypeKind::I64 })?;
+                            self.emit(Instr::TruncSat { op: sub as u8 })?;
+                        }
+                        10 => {
+                            // memory.copy <dst_mem> <src_mem>
+                            let _dst_mem = reader.read_u8()?;
+                            let _src_mem = reader.read_u8()?;
+                            for _ in 0..3 {
+                                self.pop_expect(TypeKind::I32, pos)?;
+                            }
+                            self.emit(Instr::MemoryCopy)?;
+                        }
+                        11 => {
+                            // memory.fill <mem>
+                            let _mem_idx = reader.read_u8()?;
+                            for _ in 0..3 {
+                                self.pop_expect(TypeKind::I32, pos)?;
+                            }
+                            self.emit(Instr::MemoryFill)?;
+                        }
+                        // memory.init, data.drop and the table instructions
+                        _ => return Err(ParserError::UnsupportedOpcode { offset: pos, opcode: op }),
+                    }
+                }
+                _ => {
+                    // valid instructions that can't be executed are rejected here, instead of when they are reached
+                    let (Some((params, result)), Some(instr)) = (numeric_type(op), numeric_instr(op)) else {
+                        return Err(ParserError::UnsupportedOpcode { offset: pos, opcode: op });
+                    };
+                    for &ty in params.iter().rev() {
+                        self.pop_expect(ty, pos)?;
+                    }
+                    self.types.try_push(result)?;
+                    self.emit(instr)?;
+                }
+            }
+        }
+
+        // copy into exactly sized buffers, so that the scratch space can grow without wasting memory of the module
+        let mut instrs = try_with_capacity_in(self.instrs.len(), self.alloc)?;
+        instrs.extend_from_slice(&self.instrs);
+        let mut br_table = try_with_capacity_in(self.br_table.len(), self.alloc)?;
+        br_table.extend_from_slice(&self.br_table);
+        Ok(Translated { instrs, br_table })
+    }
+
+    fn emit(&mut self, instr: Instr) -> Result<(), ParserError> {
+        self.emit_branch(instr, Slots::default())
+    }
+
+    fn emit_branch(&mut self, instr: Instr, slots: Slots) -> Result<(), ParserError> {
+        self.instrs.try_push(instr)?;
+        self.slots.try_push(Slots { height: slot_count(self.height)?, ..slots })?;
+        self.max_height = self.max_height.max(self.height).max(self.types.len());
+        Ok(())
+    }
+
+    fn set_unreachable(&mut self) {
+        let ctrl = self.controls.last_mut().expect("inside of a block");
+        ctrl.unreachable = true;
+        self.types.truncate(ctrl.height);
+    }
+
+    fn pop(&mut self, offset: usize) -> Result<TypeKind, ParserError> {
+        let ctrl = self.controls.last().expect("inside of a block");
+        if self.types.len() > ctrl.height {
+            Ok(self.types.pop().expect("checked above"))
+        } else if ctrl.unreachable {
+            // code that is never executed may pop any value
+            Ok(TypeKind::Void)
+        } else {
+            Err(ParserError::InvalidCode { offset })
+        }
+    }
+
+    fn pop_expect(&mut self, expected: TypeKind, offset: usize) -> Result<(), ParserError> {
+        match self.pop(offset)? {
+            TypeKind::Void => Ok(()),
+            ty if ty == expected => Ok(()),
+            _ => Err(ParserError::InvalidCode { offset }),
+        }
+    }
+
+    // checks that the current block leaves exactly its results on the stack
+    fn end_of_values(&mut self, offset: usize) -> Result<(), ParserError> {
+        let results = self.controls.last().expect("inside of a block").results;
+        for &ty in results.iter().rev() {
+            self.pop_expect(ty, offset)?;
+        }
+        let ctrl = self.controls.last().expect("inside of a block");
+        if self.types.len() != ctrl.height && !ctrl.unreachable {
+            return Err(ParserError::InvalidCode { offset });
+        }
+        self.types.try_extend_from_slice(results)?;
+        Ok(())
+    }
+
+    fn call(&mut self, signature: &FuncSignature, offset: usize) -> Result<(), ParserError> {
+        for &ty in signature.params.iter().rev() {
+            self.pop_expect(ty, offset)?;
+        }
+        self.types.try_extend_from_slice(&signature.results)?;
+        Ok(())
+    }
+
+    fn branch(&mut self, depth: usize, site: Site, offset: usize) -> Result<(BrTarget, Slots), ParserError> {
+        let idx = self.controls.len().checked_sub(depth + 1)
+            .ok_or(ParserError::InvalidCode { offset })?;
+        let ctrl = &self.controls[idx];
+        let (arity, target) = match ctrl.kind {
+            ControlKind::Loop => (ctrl.params, ctrl.start),
+            _ => {
+                self.pending.try_push(Pending { depth: idx, site })?;
+                (ctrl.results, 0)
+            }
+        };
+        let kept_from = self.types.len().saturating_sub(arity.len()).max(ctrl.height);
+        let slots = Slots {
+            height: slot_count(self.height)?,
+            base: slot_count(ctrl.height)?,
+            keep: slot_count(arity.len())?,
+        };
+        Ok((BrTarget {
+            target,
+            drop: (kept_from - ctrl.height) as u32,
+            keep: arity.len() as u32,
+        }, slots))
+    }
+
+    fn patch(&mut self, site: Site, target: u32) {
+        match site {
+            Site::Instr(idx) => match &mut self.instrs[idx] {
+                Instr::Jump { target: t } | Instr::JumpIfZero { target: t } => *t = target,
+                Instr::Br(br) | Instr::BrIf(br) => br.target = target,
+                other => unreachable!("{:?} is not a branch", other),
+            },
+            Site::Table(idx) => self.br_table[idx].target = target,
+        }
+    }
+}
+
+fn read_block_type<'s>(
+    reader: &mut Reader,
+    signatures: &'s [FuncSignature],
+) -> Result<(&'s [TypeKind], &'s [TypeKind]), ParserError> {
+    let offset = reader.pos();
+    Ok(match reader.read_signed()? {
+        -0x40 => (&[], &[]),
+        -0x01 => (&[], &[TypeKind::I32]),
+        -0x02 => (&[], &[TypeKind::I64]),
+        -0x03 => (&[], &[TypeKind::F32]),
+        -0x04 => (&[], &[TypeKind::F64]),
+        idx => {
+            let signature = usize::try_from(idx).ok()
+                .and_then(|idx| signatures.get(idx))
+                .ok_or(ParserError::InvalidCode { offset })?;
+            (&signature.params, &signature.results)
+        }
+    })
+}
+
+/// Types of operands and of the result of a numeric instruction.
+pub(crate) fn numeric_type(op: u8) -> Option<(&'static [TypeKind], TypeKind)> {
+    use TypeKind::{F32, F64, I32, I64};
+
+    Some(match op {
+        0x45 => (&[I32], I32),
+        0x46..=0x4f => (&[I32, I32], I32),
+        0x50 => (&[I64], I32),
+        0x51..=0x5a => (&[I64, I64], I32),
+        0x5b..=0x60 => (&[F32, F32], I32),
+        0x61..=0x66 => (&[F64, F64], I32),
+        0x67..=0x69 => (&[I32], I32),
+        0x6a..=0x78 => (&[I32, I32], I32),
+        0x79..=0x7b => (&[I64], I64),
+        0x7c..=0x8a => (&[I64, I64], I64),
+        0x8b..=0x91 => (&[F32], F32),
+        0x92..=0x98 => (&[F32, F32], F32),
+        0x99..=0x9f => (&[F64], F64),
+        0xa0..=0xa6 => (&[F64, F64], F64),
+        0xa7 => (&[I64], I32),
+        0xa8 | 0xa9 | 0xbc => (&[F32], I32),
+        0xaa | 0xab => (&[F64], I32),
+        0xac | 0xad => (&[I32], I64),
+        0xae | 0xaf => (&[F32], I64),
+        0xb0 | 0xb1 | 0xbd => (&[F64], I64),
+        0xb2 | 0xb3 | 0xbe => (&[I32], F32),
+        0xb4 | 0xb5 => (&[I64], F32),
+        0xb6 => (&[F64], F32),
+        0xb7 | 0xb8 => (&[I32], F64),
+        0xb9 | 0xba | 0xbf => (&[I64], F64),
+        0xbb => (&[F32], F64),
+        0xc0 | 0xc1 => (&[I32], I32),
+        0xc2..=0xc4 => (&[I64], I64),
+        _ => return None,
+    })
+}
+
+// `None` for the instructions that the engines can't execute
+fn numeric_instr(op: u8) -> Option<Instr> {
+    Some(match op {
+        0x45 => Instr::I32Eqz,
+        0x46 => Instr::I32Eq,
+        0x47 => Instr::I32Ne,
+        0x48 => Instr::I32LtS,
+        0x49 => Instr::I32LtU,
+        0x4a => Instr::I32GtS,
+        0x4b => Instr::I32GtU,
+        0x4c => Instr::I32LeS,
+        0x4d => Instr::I32LeU,
+        0x4e => Instr::I32GeS,
+        0x4f => Instr::I32GeU,
+        0x50 => Instr::I64Eqz,
+        0x52 => Instr::I64Ne,
+        0x54 => Instr::I64LtU,
+        0x56 => Instr::I64GtU,
+        0x5a => Instr::I64GeU,
+        0x63 => Instr::F64Lt,
+        0x6a => Instr::I32Add,
+        0x6b => Instr::I32Sub,
+        0x6c => Instr::I32Mul,
+        0x6d => Instr::I32DivS,
+        0x6e => Instr::I32DivU,
+        0x70 => Instr::I32RemU,
+        0x71 => Instr::I32And,
+        0x72 => Instr::I32Or,
+        0x73 => Instr::I32Xor,
+        0x74 => Instr::I32Shl,
+        0x76 => Instr::I32ShrU,
+        0x7c => Instr::I64Add,
+        0x7e => Instr::I64Mul,
+        0x80 => Instr::I64DivU,
+        0x82 => Instr::I64RemU,
+        0x83 => Instr::I64And,
+        0x84 => Instr::I64Or,
+        0x86 => Instr::I64Shl,
+        0x88 => Instr::I64ShrU,
+        0x92 => Instr::F32Add,
+        0xa1 => Instr::F64Sub,
+        0xa2 => Instr::F64Mul,
+        0xa7 => Instr::I32WrapI64,
+        0xad => Instr::I64ExtendI32U,
+        0xbe => Instr::F32ReinterpretI32,
+        0xc0 => Instr::I32Extend8S,
+        _ if !numeric::is_supported(op) => return None,
+        _ => match numeric_type(op)?.0.len() {
+            1 => Instr::Unary { op },
+            _ => Instr::Binary { op },
+        },
+    })
+}
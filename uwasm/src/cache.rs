@@ -0,0 +1,162 @@
+use alloc::vec::Vec;
+
+use crate::arena::{ModuleAlloc, ModuleSlice, ModuleVec};
+use crate::bytecode::{ModuleTypes, Translator};
+use crate::interpreter::{InterpreterError, StackFrame};
+use crate::log::NoLog;
+use crate::storage::Storage;
+use crate::{PreparedBody, WasmModule};
+
+/// Bodies of the functions of a lazily parsed module, prepared on their first call.
+pub(crate) struct CodeCache<'code> {
+    // module that `entries` have been prepared for
+    module: Option<&'code WasmModule<'code>>,
+    // indexed by the function index, `None` for functions that aren't prepared
+    entries: Vec<Option<Entry>>,
+    // bytes taken by all prepared bodies
+    size: usize,
+    // max value of `size`, bodies called least recently are evicted when it's exceeded
+    limit: Option<usize>,
+    // incremented on each call to a prepared function
+    clock: u64,
+}
+
+struct Entry {
+    // kept apart from the lifetime of the module, so that the cache can be dropped after it
+    body: PreparedBody<'static>,
+    last_call: u64,
+}
+
+impl<'code> CodeCache<'code> {
+    pub(crate) fn new() -> Self {
+        Self {
+            module: None,
+            entries: Vec::new(),
+            size: 0,
+            limit: None,
+            clock: 0,
+        }
+    }
+
+    pub(crate) fn set_limit(&mut self, limit: Option<usize>, call_stack: &[StackFrame]) {
+        self.limit = limit;
+        self.evict(None, call_stack);
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.iter().filter(|it| it.is_some()).count()
+    }
+
+    /// Drops all prepared bodies except the ones of the functions on `call_stack`, like [`CodeCache::evict`].
+    pub(crate) fn clear(&mut self, call_stack: &[StackFrame]) {
+        for (idx, entry) in self.entries.iter_mut().enumerate() {
+            if call_stack.iter().any(|frame| frame.func_idx == idx) {
+                continue;
+            }
+            if let Some(entry) = entry.take() {
+                self.size -= entry.body.size();
+            }
+        }
+        if self.entries.iter().all(Option::is_none) {
+            self.reset();
+        }
+    }
+
+    fn reset(&mut self) {
+        self.module = None;
+        self.entries.clear();
+        self.size = 0;
+    }
+
+    /// Makes sure that the body of the function is prepared before it's called.
+    ///
+    /// Functions of `call_stack` are never evicted, as the interpreter still has to return to them.
+    /// Bodies are prepared on the heap, so it fails when `call_stack` is kept in a fixed buffer.
+    pub(crate) fn prepare(
+        &mut self,
+        module: &'code WasmModule<'code>,
+        func_idx: usize,
+        call_stack: &Storage<'_, StackFrame>,
+    ) -> Result<(), InterpreterError> {
+        let Some(body) = module.get_function_by_index(func_idx) else {
+            return Err(InterpreterError::FunctionNotFound);
+        };
+        if body.prepared().is_some() {
+            return Ok(());
+        }
+        if call_stack.capacity().is_some() {
+            return Err(InterpreterError::LazyBodyNeedsHeap);
+        }
+
+        if !self.module.is_some_and(|it| core::ptr::eq(it, module)) {
+            self.reset();
+            self.entries.resize_with(module.functions.len(), || None);
+            self.module = Some(module);
+        }
+
+        self.clock += 1;
+        if let Some(entry) = &mut self.entries[func_idx] {
+            entry.last_call = self.clock;
+            return Ok(());
+        }
+
+        let types = ModuleTypes {
+            signatures: &module.signatures,
+            functions: &module.functions,
+            globals: &module.globals,
+        };
+        let mut translator = Translator::new(ModuleAlloc::Global);
+        let prepared = body.prepare(&body.signature, &mut translator, &types, ModuleAlloc::Global, &mut NoLog)?;
+        let prepared = PreparedBody {
+            instrs: ModuleSlice::Owned(to_global(&prepared.instrs)),
+            br_table: ModuleSlice::Owned(to_global(&prepared.br_table)),
+            locals_len: prepared.locals_len,
+        };
+        self.size += prepared.size();
+        self.entries[func_idx] = Some(Entry { body: prepared, last_call: self.clock });
+        self.evict(Some(func_idx), call_stack);
+        Ok(())
+    }
+
+    // Drops the least recently called bodies until the cache fits in its limit.
+    fn evict(&mut self, keep: Option<usize>, call_stack: &[StackFrame]) {
+        let Some(limit) = self.limit else {
+            return;
+        };
+        while self.size > limit {
+            let victim = self.entries
+                .iter()
+                .enumerate()
+                .filter(|(idx, _)| keep != Some(*idx) && !call_stack.iter().any(|frame| frame.func_idx == *idx))
+                .filter_map(|(idx, entry)| Some((idx, entry.as_ref()?.last_call)))
+                .min_by_key(|(_, last_call)| *last_call);
+            let Some((idx, _)) = victim else {
+                break;
+            };
+            if let Some(entry) = self.entries[idx].take() {
+                self.size -= entry.body.size();
+            }
+        }
+    }
+
+    /// Body of a function that has been prepared by [`CodeCache::prepare`].
+    #[inline]
+    pub(crate) fn get<'a>(&'a self, module: &'a WasmModule<'code>, func_idx: usize) -> &'a PreparedBody<'code> {
+        let body = module.get_function_by_index(func_idx)
+            .expect("function existed at time of the call");
+        match body.prepared() {
+            Some(prepared) => prepared,
+            None => &self.entries[func_idx].as_ref().expect("function prepared at time of the call").body,
+        }
+    }
+}
+
+fn to_global<T: Copy>(items: &[T]) -> ModuleVec<'static, T> {
+    let mut vec = Vec::with_capacity_in(items.len(), ModuleAlloc::Global);
+    vec.extend_from_slice(items);
+    vec
+}
@@ -0,0 +1,243 @@
+use core::any::Any;
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::size_of;
+
+use crate::interpreter::{HostError, InterpreterError, Serializer, VmStack};
+use crate::operand::Operand;
+use crate::parser::TypeKind;
+
+/// Environment and memory of the guest that has called a host function defined with [`crate::Linker::func`].
+pub struct Caller<'a, TEnv> {
+    pub env: &'a mut TEnv,
+    pub memory: MemoryView<'a>,
+    pub(crate) data: Option<&'a mut dyn Any>,
+}
+
+impl<TEnv> Caller<'_, TEnv> {
+    /// Host data of the instance, see [`crate::Instance::set_data`]. `None` if there is none
+    /// or it isn't of type `T`.
+    pub fn data<T: Any>(&mut self) -> Option<&mut T> {
+        self.data.as_deref_mut()?.downcast_mut()
+    }
+}
+
+/// Linear memory of a guest with bounds-checked access for host functions.
+///
+/// Values are stored in the byte order of the guest, i.e. little-endian.
+pub struct MemoryView<'a> {
+    data: &'a mut [u8],
+}
+
+impl<'a> MemoryView<'a> {
+    pub fn new(data: &'a mut [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn read<T: Pod>(&self, ptr: WasmPtr<T>) -> Result<T, MemoryError> {
+        let bytes = self.bytes_at(ptr.offset, size_of::<T>())?;
+        // SAFETY: `T` is valid for any bytes and `bytes` is exactly as long as `T`
+        Ok(unsafe { bytes.as_ptr().cast::<T>().read_unaligned() }.to_le())
+    }
+
+    pub fn write<T: Pod>(&mut self, ptr: WasmPtr<T>, value: T) -> Result<(), MemoryError> {
+        let bytes = self.bytes_at_mut(ptr.offset, size_of::<T>())?;
+        // SAFETY: `bytes` is exactly as long as `T`
+        unsafe { bytes.as_mut_ptr().cast::<T>().write_unaligned(value.to_le()) };
+        Ok(())
+    }
+
+    pub fn bytes(&self, slice: WasmSlice<u8>) -> Result<&[u8], MemoryError> {
+        self.bytes_at(slice.ptr.offset, slice.len as usize)
+    }
+
+    pub fn bytes_mut(&mut self, slice: WasmSlice<u8>) -> Result<&mut [u8], MemoryError> {
+        self.bytes_at_mut(slice.ptr.offset, slice.len as usize)
+    }
+
+    pub fn str(&self, slice: WasmSlice<u8>) -> Result<&str, MemoryError> {
+        core::str::from_utf8(self.bytes(slice)?).map_err(|e| MemoryError::InvalidUtf8 {
+            offset: slice.ptr.offset + e.valid_up_to() as u32,
+        })
+    }
+
+    fn bytes_at(&self, offset: u32, len: usize) -> Result<&[u8], MemoryError> {
+        self.data
+            .get(offset as usize..)
+            .and_then(|it| it.get(..len))
+            .ok_or(MemoryError::OutOfBounds { offset, len })
+    }
+
+    fn bytes_at_mut(&mut self, offset: u32, len: usize) -> Result<&mut [u8], MemoryError> {
+        self.data
+            .get_mut(offset as usize..)
+            .and_then(|it| it.get_mut(..len))
+            .ok_or(MemoryError::OutOfBounds { offset, len })
+    }
+}
+
+/// Type that can be copied from and to the memory of a guest.
+///
+/// # Safety
+///
+/// Every bit pattern must be a valid value of the type and it must have no padding. Structs have to be
+/// `#[repr(C)]` with the layout the guest uses, e.g. pointers as [`WasmPtr`] and `usize` as `u32`.
+pub unsafe trait Pod: Copy {
+    /// Converts the value between the native byte order and little-endian, the order of the guest.
+    /// It does nothing on little-endian targets, structs convert each of their fields.
+    fn to_le(self) -> Self;
+}
+
+macro_rules! pod_impls {
+    ( $( $ty:ty )* ) => {
+        $(
+            unsafe impl Pod for $ty {
+                #[inline]
+                fn to_le(self) -> Self {
+                    <$ty>::from_le_bytes(self.to_ne_bytes())
+                }
+            }
+        )*
+    };
+}
+
+pod_impls! { u8 i8 u16 i16 u32 i32 u64 i64 f32 f64 }
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {
+    fn to_le(self) -> Self {
+        self.map(T::to_le)
+    }
+}
+
+unsafe impl<T> Pod for WasmPtr<T> {
+    fn to_le(self) -> Self {
+        Self::new(self.offset.to_le())
+    }
+}
+
+unsafe impl<T> Pod for WasmSlice<T> {
+    fn to_le(self) -> Self {
+        Self { ptr: self.ptr.to_le(), len: self.len.to_le() }
+    }
+}
+
+/// Address of a `T` in the memory of a guest.
+#[repr(transparent)]
+pub struct WasmPtr<T> {
+    offset: u32,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T> WasmPtr<T> {
+    pub const fn new(offset: u32) -> Self {
+        Self { offset, _type: PhantomData }
+    }
+
+    pub const fn offset(self) -> u32 {
+        self.offset
+    }
+}
+
+impl<T> Clone for WasmPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for WasmPtr<T> {}
+
+impl<T> PartialEq for WasmPtr<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.offset == other.offset
+    }
+}
+
+impl<T> fmt::Debug for WasmPtr<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WasmPtr({:#x})", self.offset)
+    }
+}
+
+impl<T> Operand for WasmPtr<T> {
+    const TYPE: TypeKind = TypeKind::I32;
+
+    fn pop(stack: &mut VmStack) -> Result<Self, InterpreterError> {
+        stack.pop_u32().map(Self::new)
+    }
+
+    fn push(stack: &mut VmStack, value: Self) {
+        stack.push_i32(value.offset as i32)
+    }
+
+    fn write_to(&self, serializer: &mut Serializer) {
+        serializer.write(self.offset);
+    }
+}
+
+/// `len` consecutive values of `T` in the memory of a guest, laid out like `&[T]` and `&str` of wasm32.
+#[repr(C)]
+pub struct WasmSlice<T> {
+    pub ptr: WasmPtr<T>,
+    pub len: u32,
+}
+
+impl<T: Pod> WasmSlice<T> {
+    pub const fn new(ptr: WasmPtr<T>, len: u32) -> Self {
+        Self { ptr, len }
+    }
+
+    /// Address of the element at `idx`.
+    pub fn at(self, idx: u32) -> Result<WasmPtr<T>, MemoryError> {
+        let offset = (idx < self.len)
+            .then(|| self.ptr.offset.checked_add(idx.checked_mul(size_of::<T>() as u32)?))
+            .flatten()
+            .ok_or(MemoryError::OutOfBounds { offset: self.ptr.offset, len: self.len as usize * size_of::<T>() })?;
+        Ok(WasmPtr::new(offset))
+    }
+}
+
+impl<T> Clone for WasmSlice<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for WasmSlice<T> {}
+
+impl<T> fmt::Debug for WasmSlice<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WasmSlice").field("ptr", &self.ptr).field("len", &self.len).finish()
+    }
+}
+
+/// Invalid access to the memory of a guest, it traps the guest when returned from a host function.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryError {
+    OutOfBounds { offset: u32, len: usize },
+    InvalidUtf8 { offset: u32 },
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfBounds { offset, len } => write!(f, "{len} bytes at {offset:#x} are out of bounds of memory"),
+            Self::InvalidUtf8 { offset } => write!(f, "invalid UTF-8 at {offset:#x}"),
+        }
+    }
+}
+
+impl core::error::Error for MemoryError {}
+
+impl From<MemoryError> for HostError {
+    fn from(value: MemoryError) -> Self {
+        HostError::new(value)
+    }
+}
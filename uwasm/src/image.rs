@@ -0,0 +1,290 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::{align_of, offset_of, size_of};
+
+use crate::arena::ModuleSlice;
+use crate::bytecode::{BrTarget, Instr};
+use crate::log::NoLog;
+use crate::parser::{ParserError, Reader, TypeKind};
+use crate::{parse, parse_lazy, FuncBody, FuncSignature, PreparedBody};
+
+/// Version of the image format written by [`write_image`].
+pub const IMAGE_VERSION: u32 = 1;
+
+/// Alignment of an image with the code translated ahead of time, given to [`crate::parse_image`].
+pub const IMAGE_ALIGN: usize = 8;
+
+const MAGIC: &[u8; 4] = b"uwim";
+const FLAG_BYTECODE: u32 = 1;
+
+// magic, version, flags, layout, number of functions, hash of the crate version, hash of the wasm
+// and hash of everything after the header
+const HEADER_LEN: usize = 40;
+// offset and length of the locals and the length of the code in the wasm, number of locals,
+// offset and length of the instructions and of the branch targets in the image
+const RECORD_LEN: usize = 32;
+
+const _: () = assert!(align_of::<Instr>() <= IMAGE_ALIGN);
+
+// layout of `Instr::GlobalGet` and `Instr::GlobalSet` given by `repr(u8)`
+#[allow(dead_code)]
+#[repr(C)]
+struct GlobalAccess {
+    tag: u8,
+    offset: u32,
+    ty: TypeKind,
+}
+
+/// Translates a module ahead of time into an image that lets [`crate::parse_image`] skip going through
+/// the function bodies.
+///
+/// The image holds the location and number of locals of each body and, when `with_bytecode` is set,
+/// the translated instructions with their branch tables, which are then used straight from the image.
+/// Translated code can be loaded only by the same version of `uwasm` on a target with the same layout
+/// of the instructions, e.g. it can be written on x86-64 for RV32IMC.
+pub fn write_image(code: &[u8], with_bytecode: bool) -> Result<Vec<u8>, ParserError> {
+    let module = if with_bytecode {
+        parse(code, &mut NoLog)?
+    } else {
+        parse_lazy(code, &mut NoLog)?
+    };
+    let bodies: Vec<&FuncBody> = module.functions.iter().filter_map(|f| f.body.as_ref()).collect();
+
+    let mut image = vec![0u8; HEADER_LEN + bodies.len() * RECORD_LEN];
+    for (idx, body) in bodies.iter().enumerate() {
+        let mut record = [0u32; RECORD_LEN / 4];
+        record[0] = (body.locals.as_ptr() as usize - code.as_ptr() as usize) as u32;
+        record[1] = body.locals.len() as u32;
+        record[2] = body.code.len() as u32;
+        if let Some(prepared) = body.prepared() {
+            record[3] = prepared.locals_len as u32;
+
+            image.resize(image.len().next_multiple_of(IMAGE_ALIGN), 0);
+            record[4] = image.len() as u32;
+            record[5] = prepared.instrs.len() as u32;
+            for instr in prepared.instrs.iter() {
+                let bytes = encode(instr);
+                assert_eq!(encode(&decode(&bytes)), bytes, "layout of {instr:?} doesn't match");
+                image.extend_from_slice(&bytes);
+            }
+
+            image.resize(image.len().next_multiple_of(align_of::<BrTarget>()), 0);
+            record[6] = image.len() as u32;
+            record[7] = prepared.br_table.len() as u32;
+            for br in prepared.br_table.iter() {
+                for field in [br.target, br.drop, br.keep] {
+                    image.extend_from_slice(&field.to_ne_bytes());
+                }
+            }
+        }
+        for (field, value) in record.iter().enumerate() {
+            image[HEADER_LEN + idx * RECORD_LEN + field * 4..][..4].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    let flags = if with_bytecode { FLAG_BYTECODE } else { 0 };
+    let content_hash = hash(&image[HEADER_LEN..]);
+    let header = &mut image[..HEADER_LEN];
+    header[0..4].copy_from_slice(MAGIC);
+    header[4..8].copy_from_slice(&IMAGE_VERSION.to_le_bytes());
+    header[8..12].copy_from_slice(&flags.to_le_bytes());
+    header[12..16].copy_from_slice(&layout().to_le_bytes());
+    header[16..20].copy_from_slice(&(bodies.len() as u32).to_le_bytes());
+    header[20..24].copy_from_slice(&crate_version().to_le_bytes());
+    header[24..32].copy_from_slice(&hash(code).to_le_bytes());
+    header[32..40].copy_from_slice(&content_hash.to_le_bytes());
+    Ok(image)
+}
+
+/// Image that has been checked against the wasm it was written for.
+#[derive(Clone, Copy)]
+pub(crate) struct Image<'code> {
+    bytes: &'code [u8],
+    num_funcs: usize,
+    bytecode: bool,
+}
+
+impl<'code> Image<'code> {
+    pub(crate) fn new(bytes: &'code [u8], code: &[u8]) -> Result<Self, ParserError> {
+        if bytes.len() < HEADER_LEN
+            || &bytes[0..4] != MAGIC
+            || u32_at(bytes, 4) != IMAGE_VERSION
+            || u32_at(bytes, 20) != crate_version()
+            || u64_at(bytes, 32) != hash(&bytes[HEADER_LEN..]) {
+            return Err(ParserError::InvalidImage);
+        }
+        if u64_at(bytes, 24) != hash(code) {
+            return Err(ParserError::StaleImage);
+        }
+
+        let bytecode = u32_at(bytes, 8) & FLAG_BYTECODE != 0;
+        // translated code is used in place
+        if bytecode && (u32_at(bytes, 12) != layout() || bytes.as_ptr() as usize % align_of::<Instr>() != 0) {
+            return Err(ParserError::InvalidImage);
+        }
+        let num_funcs = u32_at(bytes, 16) as usize;
+        if bytes.len() < HEADER_LEN + num_funcs * RECORD_LEN {
+            return Err(ParserError::InvalidImage);
+        }
+        Ok(Self { bytes, num_funcs, bytecode })
+    }
+
+    /// Number of functions with a body.
+    pub(crate) fn len(&self) -> usize {
+        self.num_funcs
+    }
+
+    /// Body of `idx`-th function defined in the module, `code` is the wasm that the image has been checked against.
+    pub(crate) fn body(
+        &self,
+        idx: usize,
+        code: &'code [u8],
+        signature: &FuncSignature<'code>,
+    ) -> Result<FuncBody<'code>, ParserError> {
+        let record = |field: usize| u32_at(self.bytes, HEADER_LEN + idx * RECORD_LEN + field * 4) as usize;
+        let locals = code.get(record(0)..).and_then(|it| it.get(..record(1))).ok_or(ParserError::InvalidImage)?;
+        let body_code = code.get(record(0) + record(1)..).and_then(|it| it.get(..record(2))).ok_or(ParserError::InvalidImage)?;
+
+        let prepared = if self.bytecode {
+            let locals_len = record(3);
+            if locals_len < signature.params.len() {
+                return Err(ParserError::InvalidImage);
+            }
+            Some(PreparedBody {
+                instrs: ModuleSlice::Borrowed(self.instrs(record(4), record(5))?),
+                br_table: ModuleSlice::Borrowed(self.br_table(record(6), record(7))?),
+                locals_len,
+            })
+        } else {
+            None
+        };
+        Ok(FuncBody { signature: signature.try_clone()?, code: body_code, locals, prepared })
+    }
+
+    fn instrs(&self, offset: usize, len: usize) -> Result<&'code [Instr], ParserError> {
+        let bytes = self.slice(offset, len, size_of::<Instr>(), align_of::<Instr>())?;
+        let max_tag = tag(&Instr::LocalLoad { idx: 0, op: 0, offset: 0 });
+        let global_tags = [
+            tag(&Instr::GlobalGet { offset: 0, ty: TypeKind::I32 }),
+            tag(&Instr::GlobalSet { offset: 0, ty: TypeKind::I32 }),
+        ];
+        for instr in bytes.chunks_exact(size_of::<Instr>()) {
+            let valid = instr[0] <= max_tag
+                && (!global_tags.contains(&instr[0])
+                    || Reader::new(&instr[offset_of!(GlobalAccess, ty)..]).read::<TypeKind>().is_ok());
+            if !valid {
+                return Err(ParserError::InvalidImage);
+            }
+        }
+        // SAFETY: the bytes are aligned and each instruction has a valid tag and fields of a valid variant
+        Ok(unsafe { core::slice::from_raw_parts(bytes.as_ptr().cast::<Instr>(), len) })
+    }
+
+    fn br_table(&self, offset: usize, len: usize) -> Result<&'code [BrTarget], ParserError> {
+        let bytes = self.slice(offset, len, size_of::<BrTarget>(), align_of::<BrTarget>())?;
+        // SAFETY: the bytes are aligned and any bits make a valid `BrTarget`
+        Ok(unsafe { core::slice::from_raw_parts(bytes.as_ptr().cast::<BrTarget>(), len) })
+    }
+
+    fn slice(&self, offset: usize, len: usize, size: usize, align: usize) -> Result<&'code [u8], ParserError> {
+        if offset % align != 0 {
+            return Err(ParserError::InvalidImage);
+        }
+        let bytes = self.bytes;
+        bytes.get(offset..)
+            .and_then(|it| it.get(..len.checked_mul(size)?))
+            .ok_or(ParserError::InvalidImage)
+    }
+}
+
+// Layout of the translated code, images with a different one can be used only without it.
+fn layout() -> u32 {
+    size_of::<Instr>() as u32
+        | (align_of::<Instr>() as u32) << 8
+        | (size_of::<BrTarget>() as u32) << 16
+        | u32::from(cfg!(target_endian = "big")) << 24
+}
+
+// Translated code isn't stable between versions of the crate.
+fn crate_version() -> u32 {
+    hash(env!("CARGO_PKG_VERSION").as_bytes()) as u32
+}
+
+fn tag(instr: &Instr) -> u8 {
+    // SAFETY: the first byte of a `repr(u8)` enum is its tag
+    unsafe { *(instr as *const Instr).cast::<u8>() }
+}
+
+// Bytes of the instruction with zeroed padding, fields are placed as in `repr(C)` structs following the tag.
+fn encode(instr: &Instr) -> [u8; size_of::<Instr>()] {
+    let mut bytes = [0u8; size_of::<Instr>()];
+    bytes[0] = tag(instr);
+    let mut put = |offset: usize, field: &[u8]| bytes[offset..][..field.len()].copy_from_slice(field);
+    match *instr {
+        Instr::Jump { target: a }
+        | Instr::JumpIfZero { target: a }
+        | Instr::Return { keep: a }
+        | Instr::Call { func_idx: a }
+        | Instr::CallIndirect { type_idx: a }
+        | Instr::LocalGet { idx: a }
+        | Instr::LocalSet { idx: a }
+        | Instr::LocalTee { idx: a } => put(4, &a.to_ne_bytes()),
+        Instr::Br(br) | Instr::BrIf(br) => {
+            put(4, &br.target.to_ne_bytes());
+            put(8, &br.drop.to_ne_bytes());
+            put(12, &br.keep.to_ne_bytes());
+        }
+        Instr::BrTable { start: a, len: b } | Instr::LocalsLtUBrIf { a, b } => {
+            put(4, &a.to_ne_bytes());
+            put(8, &b.to_ne_bytes());
+        }
+        Instr::GlobalGet { offset, ty } | Instr::GlobalSet { offset, ty } => {
+            put(4, &offset.to_ne_bytes());
+            put(offset_of!(GlobalAccess, ty), &[ty as u8]);
+        }
+        Instr::Unary { op } | Instr::Binary { op } | Instr::TruncSat { op } => put(1, &[op]),
+        Instr::Load { op, offset } | Instr::Store { op, offset } => {
+            put(1, &[op]);
+            put(4, &offset.to_ne_bytes());
+        }
+        Instr::I32Const(val) => put(4, &val.to_ne_bytes()),
+        Instr::I64Const(val) => put(8, &val.to_ne_bytes()),
+        Instr::F32Const(val) => put(4, &val.to_ne_bytes()),
+        Instr::F64Const(val) => put(8, &val.to_ne_bytes()),
+        Instr::LocalI32AddConst { idx, val } => {
+            put(4, &idx.to_ne_bytes());
+            put(8, &val.to_ne_bytes());
+        }
+        Instr::LocalLoad { idx, op, offset } => {
+            put(4, &idx.to_ne_bytes());
+            put(8, &[op]);
+            put(12, &offset.to_ne_bytes());
+        }
+        _ => {}
+    }
+    bytes
+}
+
+// Reads back an instruction written by `encode`.
+fn decode(bytes: &[u8; size_of::<Instr>()]) -> Instr {
+    // SAFETY: `bytes` come from `encode` of a valid instruction, which keeps its tag
+    unsafe { core::ptr::read_unaligned(bytes.as_ptr().cast::<Instr>()) }
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..][..4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..][..8].try_into().unwrap())
+}
+
+// FNV-1a, enough to tell apart different modules
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x100_0000_01b3);
+    }
+    hash
+}
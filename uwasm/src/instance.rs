@@ -0,0 +1,291 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+
+use crate::allocator::{GuestAllocator, GuestBuffer};
+use crate::caller::{MemoryView, WasmPtr, WasmSlice};
+use crate::interpreter::{call_dynamic, evaluate, execute_function, init_defined_globals, init_memory_with_globals, read_global, resume, segment_offset, write_global, Execution, FunctionArgs, HostFunc, InterpreterError, TypedFunc, VmContext};
+use crate::operand::Operand;
+use crate::value::Value;
+use crate::{ByteStr, Environment, WasmModule};
+
+/// Size of a page of linear memory.
+pub const PAGE_SIZE: usize = 64 * 1024;
+
+/// Module instantiated with its own memory, globals and tables, ready to be called.
+pub struct Instance<'code, 'host, TEnv> {
+    module: &'code WasmModule<'code>,
+    ctx: VmContext<'code>,
+    memory: Vec<u8>,
+    globals: Vec<u8>,
+    imports: Vec<HostFunc<'host, 'host, TEnv>>,
+    // buffer of a `call_with_bytes` that has been paused, freed once the call is finished or abandoned
+    pending_buffer: Option<GuestBuffer>,
+}
+
+impl<'code, 'host, TEnv: Environment> Instance<'code, 'host, TEnv> {
+    /// Instantiates the module with its imports, e.g. resolved by [`crate::Linker::link`].
+    ///
+    /// Globals are initialized first, then tables and memory from the segments of the module.
+    pub fn new(module: &'code WasmModule<'code>, imports: Vec<HostFunc<'host, 'host, TEnv>>) -> Result<Self, InterpreterError> {
+        Self::with_globals(module, imports, &[])
+    }
+
+    /// Like [`Instance::new`], for modules that also import globals, e.g. resolved by [`crate::Linker::link_globals`].
+    ///
+    /// Imported globals are kept by the instance like all the others, the host shares them with the guest
+    /// through [`Instance::get_global`] and [`Instance::set_global`] by their field names. They are set before
+    /// the initializers of the other globals and the offsets of the segments are evaluated, so those may read them.
+    pub fn with_globals(
+        module: &'code WasmModule<'code>,
+        imports: Vec<HostFunc<'host, 'host, TEnv>>,
+        imported_globals: &[Value],
+    ) -> Result<Self, InterpreterError> {
+        let expected = module.functions.iter().filter(|it| it.body.is_none()).count();
+        if imports.len() != expected {
+            return Err(InterpreterError::ImportsMismatch { expected, found: imports.len() });
+        }
+        let expected = module.globals.iter().filter(|it| it.import.is_some()).count();
+        if imported_globals.len() != expected {
+            return Err(InterpreterError::ImportsMismatch { expected, found: imported_globals.len() });
+        }
+
+        let mut globals = vec![0; module.globals_len_in_bytes()];
+        // imported globals come before the ones defined by the module, whose initializers may read them
+        for (idx, value) in imported_globals.iter().enumerate() {
+            write_global(module, &mut globals, idx, value.ty(), |stack| stack.push_slot(value.to_slot()))?;
+        }
+        init_defined_globals(&mut globals, module)?;
+
+        let mut tables: Vec<_> = module.tables
+            .iter()
+            .map(|table| vec![None; table.limits.initial as usize])
+            .collect();
+        for segment in &module.elem_segments {
+            let offset = segment_offset(segment.offset.code, module, &globals)?;
+            tables
+                .first_mut()
+                .and_then(|table| table.get_mut(offset..)?.get_mut(..segment.functions.len()))
+                .ok_or(InterpreterError::TableOutOfBounds)?
+                .iter_mut()
+                .zip(segment.functions.iter())
+                .for_each(|(element, &func_idx)| *element = Some(func_idx));
+        }
+
+        let pages = module.memory.map_or(0, |it| it.initial as usize);
+        let mut memory = vec![0; pages * PAGE_SIZE];
+        init_memory_with_globals(&mut memory, module, &globals)?;
+
+        // `call_indirect` resolves functions through the tables of the context
+        let mut ctx = VmContext::new();
+        ctx.tables = tables;
+        Ok(Self {
+            module,
+            ctx,
+            memory,
+            globals,
+            imports,
+            pending_buffer: None,
+        })
+    }
+
+    /// Calls an exported function and waits until it returns.
+    pub fn call<TArgs: FunctionArgs, TResult: Operand>(
+        &mut self,
+        name: &ByteStr,
+        args: TArgs,
+        env: &mut TEnv,
+    ) -> Result<TResult, InterpreterError> {
+        self.abandon_paused_call(env)?;
+        execute_function(&mut self.ctx, self.module, name, args, &mut self.memory, &mut self.globals, &mut self.imports, env)
+    }
+
+    /// Calls an exported function that takes a buffer as its pointer and length, e.g. `fn(&[u8])` or `fn(&str)`.
+    ///
+    /// The buffer is allocated by the allocator of the guest and freed once the function returns.
+    /// If the function is paused, the buffer is kept until [`Instance::resume`] finishes it.
+    pub fn call_with_bytes<TResult: Operand>(
+        &mut self,
+        name: &ByteStr,
+        data: &[u8],
+        env: &mut TEnv,
+    ) -> Result<TResult, InterpreterError> {
+        self.abandon_paused_call(env)?;
+        let buffer = self.alloc_bytes(data, env)?;
+        let slice = buffer.slice();
+        let result = self.call(name, (slice.ptr, slice.len), env);
+        if self.ctx.is_paused() {
+            self.pending_buffer = Some(buffer);
+            return result;
+        }
+        let freed = self.free_bytes(buffer, env);
+        let result = result?;
+        freed?;
+        Ok(result)
+    }
+
+    /// Allocator exported by the module, if there is one.
+    pub fn allocator(&self) -> Option<GuestAllocator> {
+        GuestAllocator::detect(self.module)
+    }
+
+    /// Allocates memory with the allocator of the guest and copies `data` into it.
+    ///
+    /// Fails with [`InterpreterError::CallInProgress`] while a paused call hasn't finished,
+    /// as calling the allocator would abort it.
+    pub fn alloc_bytes(&mut self, data: &[u8], env: &mut TEnv) -> Result<GuestBuffer, InterpreterError> {
+        if self.ctx.is_paused() {
+            return Err(InterpreterError::CallInProgress);
+        }
+        let allocator = self.allocator().ok_or(InterpreterError::NoGuestAllocator)?;
+        let len = u32::try_from(data.len()).map_err(|_| InterpreterError::GuestAllocationFailed)?;
+        let ptr = match allocator {
+            GuestAllocator::Malloc { malloc, .. } => self.call_func::<(u32, ), u32>(malloc, (len, ), env)?,
+            GuestAllocator::CabiRealloc { realloc } => self.call_func(realloc, (0u32, 0u32, 1u32, len), env)?,
+        };
+        let buffer = GuestBuffer { slice: WasmSlice::new(WasmPtr::new(ptr), len), allocator };
+        if ptr == 0 && len != 0 {
+            return Err(InterpreterError::GuestAllocationFailed);
+        }
+        match self.memory().bytes_mut(buffer.slice) {
+            Ok(bytes) => bytes.copy_from_slice(data),
+            Err(_) => {
+                self.free_bytes(buffer, env)?;
+                return Err(InterpreterError::GuestAllocationFailed);
+            }
+        }
+        Ok(buffer)
+    }
+
+    /// Gives memory allocated by [`Instance::alloc_bytes`] back to the guest. Memory allocated through
+    /// `cabi_realloc` is owned by the guest once it has been passed to it, so it is left alone.
+    ///
+    /// Like [`Instance::alloc_bytes`], fails while a paused call hasn't finished.
+    pub fn free_bytes(&mut self, buffer: GuestBuffer, env: &mut TEnv) -> Result<(), InterpreterError> {
+        if self.ctx.is_paused() {
+            return Err(InterpreterError::CallInProgress);
+        }
+        match buffer.allocator {
+            GuestAllocator::Malloc { free, .. } => self.call_func(free, (buffer.slice.ptr, ), env),
+            GuestAllocator::CabiRealloc { .. } => Ok(()),
+        }
+    }
+
+    fn call_func<TArgs: FunctionArgs, TResult: Operand>(
+        &mut self,
+        func_idx: usize,
+        args: TArgs,
+        env: &mut TEnv,
+    ) -> Result<TResult, InterpreterError> {
+        TypedFunc::new(self.module, func_idx)?.call(&mut self.ctx, args, &mut self.memory, &mut self.globals, &mut self.imports, env)
+    }
+
+    // a new call aborts the paused one, so its buffer isn't needed anymore
+    fn abandon_paused_call(&mut self, env: &mut TEnv) -> Result<(), InterpreterError> {
+        if self.pending_buffer.is_some() {
+            self.ctx.abort();
+            self.free_pending_buffer(env)?;
+        }
+        Ok(())
+    }
+
+    fn free_pending_buffer(&mut self, env: &mut TEnv) -> Result<(), InterpreterError> {
+        let Some(buffer) = self.pending_buffer.take() else {
+            return Ok(());
+        };
+        // results of the finished call stay on the stack for the host
+        let mut results = vec![0; self.ctx.stack.len()];
+        self.ctx.stack.pop_slots(&mut results)?;
+        let freed = self.free_bytes(buffer, env);
+        self.ctx.stack.push_slots(&results);
+        freed
+    }
+
+    /// Calls an exported function with types of arguments checked at runtime, see [`crate::call_dynamic`].
+    pub fn call_dynamic(&mut self, name: &ByteStr, args: &[Value], env: &mut TEnv) -> Result<Vec<Value>, InterpreterError> {
+        self.abandon_paused_call(env)?;
+        call_dynamic(&mut self.ctx, self.module, name, args, &mut self.memory, &mut self.globals, &mut self.imports, env)
+    }
+
+    /// Calls a function with arguments given as their native-endian bytes, see [`crate::evaluate`].
+    pub fn evaluate(&mut self, func_idx: usize, args: &[u8], env: &mut TEnv) -> Result<Execution, InterpreterError> {
+        self.abandon_paused_call(env)?;
+        evaluate(&mut self.ctx, self.module, func_idx, args, &mut self.memory, &mut self.globals, &mut self.imports, env)
+    }
+
+    /// Continues a call started e.g. by [`crate::TypedFunc::start`] on [`Instance::ctx`].
+    ///
+    /// Once a call paused in [`Instance::call_with_bytes`] is finished, its buffer is freed
+    /// and the results are left on the stack.
+    pub fn resume(&mut self, env: &mut TEnv) -> Result<Execution, InterpreterError> {
+        let result = resume(&mut self.ctx, self.module, &mut self.memory, &mut self.globals, &mut self.imports, env);
+        if !self.ctx.is_paused() {
+            self.free_pending_buffer(env)?;
+        }
+        result
+    }
+
+    pub fn module(&self) -> &'code WasmModule<'code> {
+        self.module
+    }
+
+    /// Context used for calls, e.g. to choose the engine or set limits.
+    pub fn ctx(&mut self) -> &mut VmContext<'code> {
+        &mut self.ctx
+    }
+
+    /// Sets the host data of this instance, host functions defined with [`crate::Linker::func`]
+    /// get it through [`crate::Caller::data`], so that instances linked by the same linker keep
+    /// state of their own.
+    pub fn set_data<T: Any>(&mut self, data: T) {
+        self.ctx.set_data(data);
+    }
+
+    /// Host data set by [`Instance::set_data`], `None` if there is none or it isn't of type `T`.
+    pub fn data<T: Any>(&mut self) -> Option<&mut T> {
+        self.ctx.data()
+    }
+
+    pub fn memory(&mut self) -> MemoryView<'_> {
+        MemoryView::new(&mut self.memory)
+    }
+
+    /// Value of the global at `idx`, `None` if there is no such global or it isn't of type `T`.
+    pub fn global<T: Operand>(&self, idx: usize) -> Option<T> {
+        read_global(self.module, &self.globals, idx)
+    }
+
+    /// Value of the exported or imported global with given name.
+    pub fn get_global<T: Operand>(&self, name: &ByteStr) -> Result<T, InterpreterError> {
+        let idx = self.module.get_global_index_by_name(name).ok_or(InterpreterError::GlobalNotFound)?;
+        let kind = self.module.globals[idx].kind;
+        if kind != T::TYPE {
+            return Err(InterpreterError::GlobalTypeMismatch { expected: kind, found: T::TYPE });
+        }
+        read_global(self.module, &self.globals, idx).ok_or(InterpreterError::GlobalNotFound)
+    }
+
+    /// Sets the exported or imported global with given name, if it is mutable.
+    pub fn set_global<T: Operand>(&mut self, name: &ByteStr, value: T) -> Result<(), InterpreterError> {
+        let idx = self.module.get_global_index_by_name(name).ok_or(InterpreterError::GlobalNotFound)?;
+        if self.module.globals[idx].mutability == 0 {
+            return Err(InterpreterError::ImmutableGlobal);
+        }
+        write_global(self.module, &mut self.globals, idx, T::TYPE, |stack| T::push(stack, value))
+    }
+
+    /// Function indices in the table at `idx`, `None` for elements that haven't been initialized.
+    pub fn table(&self, idx: usize) -> Option<&[Option<u32>]> {
+        self.ctx.tables.get(idx).map(|it| &it[..])
+    }
+
+    /// Contents of a passive data segment, `None` for active ones, which are dropped once they are copied into memory.
+    ///
+    /// Instances don't track whether a passive segment was dropped, the bulk memory instructions
+    /// (`memory.init`, `data.drop`) aren't supported, so guests have no way to drop one and its
+    /// contents are always returned.
+    pub fn data_segment(&self, idx: usize) -> Option<&'code [u8]> {
+        let segment = self.module.data_segments.get(idx)?;
+        segment.offset.is_none().then_some(segment.data)
+    }
+}
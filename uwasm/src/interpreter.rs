@@ -1,30 +1,296 @@
 use alloc::fmt;
+use alloc::boxed::Box;
+#[cfg(target_has_atomic = "ptr")]
+use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
+use core::any::Any;
 use core::fmt::Formatter;
 use core::iter;
-
-use crate::{ByteStr, Environment, FuncBody, ParserError, WasmModule};
-#[cfg(debug_assertions)]
-use crate::{parse_opcode, ParserState};
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use crate::{ByteStr, Environment, FuncSignature, ParserError, WasmModule};
+use crate::bytecode::{BrTarget, Instr, Superinstruction};
+use crate::cache::CodeCache;
+use crate::log::{debug, trace, Log};
+use crate::numeric;
 use crate::operand::Operand;
 use crate::parser::{Reader, TypeKind};
+use crate::register::{self, Registers};
+use crate::storage::Storage;
+use crate::value::Value;
 
 pub struct VmContext<'code> {
-    pub stack: VmStack,
-    call_stack: Vec<StackFrame<'code>>,
-    // temporary store for locals - TODO: maybe reuse values from the stack
-    locals: Vec<u8>,
-    profile: ExecutionProfile,
+    pub stack: VmStack<'code>,
+    pub(crate) call_stack: Storage<'code, StackFrame>,
+    pub(crate) profile: ExecutionProfile,
+    // max number of instructions executed by a single `evaluate`/`resume` call
+    pub(crate) instruction_limit: Option<u64>,
+    // instructions left until the guest runs out of fuel, unlimited if `None`
+    pub(crate) fuel: Option<u64>,
+    // value of `Environment::ticks` after which execution gets paused
+    pub(crate) time_slice_end: Option<u64>,
+    // imported function that has been called but hasn't provided its results yet
+    pub(crate) pending_import: Option<usize>,
+    // types of the results of `pending_import`
+    pub(crate) pending_results: &'code [TypeKind],
+    // created on demand by `interrupt_handle`
+    pub(crate) interrupt: Option<InterruptHandle>,
+    // value of `Environment::ticks` after which execution is stopped with an error
+    pub(crate) deadline: Option<u64>,
+    // engine used by the next `evaluate`
+    pub(crate) engine: Engine,
+    pub(crate) registers: Registers<'code>,
+    // bodies of the functions of a lazily parsed module
+    pub(crate) code_cache: CodeCache<'code>,
+    // receives the execution trace in debug builds
+    pub(crate) log: Option<Box<dyn Log>>,
+    // state of the host passed to the imports that take it, see `VmContext::set_data`
+    pub(crate) data: Option<Box<dyn Any>>,
+    // function indices in each table of an instance, empty for contexts that aren't owned by one
+    pub(crate) tables: Vec<Vec<Option<u32>>>,
+}
+
+/// Way in which [`VmContext`] executes the code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Engine {
+    /// Interprets the code translated at load time, values are kept on an operand stack.
+    #[default]
+    Stack,
+    /// Translates the module on its first call into instructions that operate on registers
+    /// of the call frames. It takes more memory, but needs fewer instructions to do the same work.
+    Register,
+    /// Register engine that compiles hot functions into native code. Instructions that can't be compiled,
+    /// calls and loops still go through the interpreter. Native code is used only when neither
+    /// an instruction limit nor fuel is set, as it doesn't count the executed instructions.
+    #[cfg(feature = "jit")]
+    Jit,
+}
+
+/// Fixed-size buffers for [`VmContext::with_buffers`].
+///
+/// Parts of the buffers that are not properly aligned for the values they hold are left unused.
+pub struct VmBuffers<'buf> {
+    /// Values on the operand stack together with the locals of the functions on the call stack,
+    /// each one takes 8 bytes.
+    pub stack: &'buf mut [u8],
+    /// Call frames, each one takes [`VmBuffers::FRAME_SIZE`] bytes.
+    pub frames: &'buf mut [u8],
+}
+
+impl VmBuffers<'_> {
+    pub const FRAME_SIZE: usize = size_of::<StackFrame>();
 }
 
-impl VmContext<'_> {
+impl<'code> VmContext<'code> {
     pub fn new() -> Self {
+        Self::with_storage(VmStack::new(), Storage::new())
+    }
+
+    /// Creates a context that never allocates on the heap while executing code, running out of space
+    /// in any of the buffers is reported as [`InterpreterError::StackOverflow`]. Only [`Engine::Stack`]
+    /// can be used with it.
+    ///
+    /// Modules have to be parsed eagerly, with [`crate::parse`] or [`crate::parse_in`], or loaded
+    /// from an image that contains prepared bodies. Bodies of lazily parsed modules would be prepared
+    /// on the heap, so calling one fails with [`InterpreterError::LazyBodyNeedsHeap`] instead.
+    pub fn with_buffers(buffers: VmBuffers<'code>) -> Self {
+        Self::with_storage(
+            VmStack::with_storage(Storage::from_bytes(buffers.stack)),
+            Storage::from_bytes(buffers.frames),
+        )
+    }
+
+    fn with_storage(stack: VmStack<'code>, call_stack: Storage<'code, StackFrame>) -> Self {
         Self {
-            stack: VmStack::new(),
-            call_stack: Vec::new(),
-            locals: Vec::new(),
+            stack,
+            call_stack,
             profile: ExecutionProfile::new(),
+            instruction_limit: None,
+            fuel: None,
+            time_slice_end: None,
+            pending_import: None,
+            pending_results: &[],
+            interrupt: None,
+            deadline: None,
+            engine: Engine::Stack,
+            registers: Registers::new(),
+            code_cache: CodeCache::new(),
+            log: None,
+            data: None,
+            tables: Vec::new(),
+        }
+    }
+
+    /// Sets the log that receives every executed instruction at [`crate::Level::Trace`] in debug builds,
+    /// and functions entered by the guest. Nothing is traced without a log.
+    pub fn set_log(&mut self, log: impl Log + 'static) {
+        self.log = Some(Box::new(log));
+    }
+
+    /// Sets the host data of the guest running in this context, host functions defined with
+    /// [`crate::Linker::func`] get it through [`crate::Caller::data`]. Unlike state captured
+    /// by the functions, it is not shared with other contexts linked by the same linker.
+    pub fn set_data<T: Any>(&mut self, data: T) {
+        self.data = Some(Box::new(data));
+    }
+
+    /// Host data set by [`VmContext::set_data`], `None` if there is none or it isn't of type `T`.
+    pub fn data<T: Any>(&mut self) -> Option<&mut T> {
+        self.data.as_deref_mut()?.downcast_mut()
+    }
+
+    /// Selects the engine used by calls started after this one. The register engine keeps its code
+    /// and registers on the heap, so calls in a context created with [`VmContext::with_buffers`]
+    /// fail with [`InterpreterError::EngineNeedsHeap`] unless the stack engine is used.
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = engine;
+    }
+
+    pub fn engine(&self) -> Engine {
+        self.engine
+    }
+
+    /// Sets the memory for the code compiled by [`Engine::Jit`], dropping all the code compiled so far.
+    /// On x86-64 Linux the memory is mapped on demand when none is given, on other targets
+    /// the functions are only interpreted until it's set.
+    #[cfg(feature = "jit")]
+    pub fn set_code_memory(&mut self, memory: crate::CodeMemory) {
+        self.registers.jit.set_memory(memory);
+    }
+
+    /// Limits the number of bytes taken by the function bodies prepared for a module parsed
+    /// with [`crate::parse_lazy`]. Bodies called least recently are dropped to make room for new ones
+    /// and prepared again on their next call. Functions that are being executed are always kept,
+    /// so the limit may be exceeded by a deep enough call stack. There is no limit by default.
+    pub fn set_code_cache_limit(&mut self, limit: Option<usize>) {
+        self.code_cache.set_limit(limit, &self.call_stack);
+    }
+
+    /// Number of bytes taken by the function bodies prepared for a lazily parsed module.
+    pub fn code_cache_size(&self) -> usize {
+        self.code_cache.size()
+    }
+
+    /// Number of functions of a lazily parsed module whose bodies are prepared.
+    pub fn prepared_functions(&self) -> usize {
+        self.code_cache.len()
+    }
+
+    /// Drops the function bodies prepared for a lazily parsed module, except the ones of a paused call
+    /// that are still needed to resume it.
+    pub fn clear_code_cache(&mut self) {
+        self.code_cache.clear(&self.call_stack);
+    }
+
+    /// Stops execution with [`InterpreterError::DeadlineExceeded`] once [`Environment::ticks`]
+    /// reaches `deadline`. Deadline is checked at loop back-edges and calls. It applies to the call
+    /// that is paused or started next, including its resumptions, and is cleared when that call
+    /// finishes or fails.
+    pub fn set_deadline(&mut self, deadline: Option<u64>) {
+        self.deadline = deadline;
+    }
+
+    /// Sets the deadline to `timeout` from now, fails if [`Environment::ticks_per_second`] is unknown.
+    pub fn set_timeout(&mut self, env: &impl Environment, timeout: Duration) -> Result<(), InterpreterError> {
+        let ticks_per_second = env.ticks_per_second();
+        if ticks_per_second == 0 {
+            return Err(InterpreterError::UnknownTickRate);
+        }
+        let ticks = timeout.as_nanos() * u128::from(ticks_per_second) / 1_000_000_000;
+        let ticks = u64::try_from(ticks).unwrap_or(u64::MAX);
+        self.deadline = Some(env.ticks().saturating_add(ticks));
+        Ok(())
+    }
+
+    /// Returns a handle that can be used to interrupt the guest from an ISR or another thread.
+    ///
+    /// Interrupt is checked at loop back-edges and calls, running guest is then stopped with
+    /// [`InterpreterError::Interrupted`]. An interrupt requested while no guest is running stops the next call.
+    ///
+    /// Not available on targets without atomic read-modify-write operations (like riscv32imc),
+    /// use [`VmContext::set_interrupt_flag`] there.
+    #[cfg(target_has_atomic = "ptr")]
+    pub fn interrupt_handle(&mut self) -> InterruptHandle {
+        self.interrupt.get_or_insert_with(InterruptHandle::new).clone()
+    }
+
+    /// Uses an external flag to interrupt the guest, e.g. a `static` that is set directly by an ISR.
+    ///
+    /// The flag is cleared once the guest has been stopped, so each context needs a flag of its own.
+    /// Returns a handle setting the flag, later calls to [`VmContext::interrupt_handle`] return the same one.
+    pub fn set_interrupt_flag(&mut self, flag: &'static AtomicBool) -> InterruptHandle {
+        self.interrupt.insert(InterruptHandle { flag: InterruptFlag::Static(flag) }).clone()
+    }
+
+    /// Pauses execution after `limit` instructions executed within a single call
+    /// to [`evaluate`] or [`resume`].
+    pub fn set_instruction_limit(&mut self, limit: Option<u64>) {
+        self.instruction_limit = limit;
+    }
+
+    /// Sets how many instructions the guest may execute in total before it is paused
+    /// with [`PauseReason::OutOfFuel`]. `None` disables fuel metering.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    pub fn add_fuel(&mut self, fuel: u64) {
+        self.fuel = Some(self.fuel.unwrap_or(0).saturating_add(fuel));
+    }
+
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Pauses execution with [`PauseReason::TimeSliceElapsed`] once [`Environment::ticks`]
+    /// reaches `end`.
+    pub fn set_time_slice_end(&mut self, end: Option<u64>) {
+        self.time_slice_end = end;
+    }
+
+    /// Returns `true` if there is a paused call that can be continued with [`resume`].
+    pub fn is_paused(&self) -> bool {
+        !self.call_stack.is_empty() || self.registers.is_running()
+    }
+
+    /// Index of the imported function that has returned [`ImportOutcome::Pending`]
+    /// and is still waiting for [`VmContext::complete_import`].
+    pub fn pending_import(&self) -> Option<usize> {
+        self.pending_import
+    }
+
+    /// Finishes the pending call of an imported function. `push_results` is expected to push
+    /// the results of the call, then the guest can be continued with [`resume`].
+    ///
+    /// Fails with [`InterpreterError::InvalidImportResults`] if the values don't match the results
+    /// of the function, the call is then still pending.
+    pub fn complete_import(&mut self, push_results: impl FnOnce(&mut ImportResults)) -> Result<(), InterpreterError> {
+        if self.pending_import.is_none() {
+            return Err(InterpreterError::NoPendingImport);
         }
+        let height = self.stack.len();
+        let mut results = ImportResults { stack: &mut self.stack, types: self.pending_results, pushed: 0, mismatch: false };
+        push_results(&mut results);
+        if results.mismatch || results.pushed != results.types.len() {
+            self.stack.data.truncate(height);
+            return Err(InterpreterError::InvalidImportResults);
+        }
+        self.pending_import = None;
+        Ok(())
+    }
+
+    /// Drops the state of a paused call.
+    pub fn abort(&mut self) {
+        self.pending_import = None;
+        self.pending_results = &[];
+        self.call_stack.clear();
+        self.registers.abort();
+        self.stack.data.clear();
+        self.stack.overflowed = false;
     }
 
     pub fn reset_profile(&mut self) {
@@ -36,9 +302,108 @@ impl VmContext<'_> {
     }
 }
 
+/// Results of a pending imported function, see [`VmContext::complete_import`].
+pub struct ImportResults<'a, 'buf> {
+    stack: &'a mut VmStack<'buf>,
+    types: &'a [TypeKind],
+    pushed: usize,
+    mismatch: bool,
+}
+
+impl ImportResults<'_, '_> {
+    pub fn push_i32(&mut self, val: i32) {
+        self.push(TypeKind::I32, u64::from(val as u32));
+    }
+
+    pub fn push_i64(&mut self, val: i64) {
+        self.push(TypeKind::I64, val as u64);
+    }
+
+    pub fn push_f32(&mut self, val: f32) {
+        self.push(TypeKind::F32, u64::from(val.to_bits()));
+    }
+
+    pub fn push_f64(&mut self, val: f64) {
+        self.push(TypeKind::F64, val.to_bits());
+    }
+
+    // values of a wrong type are not pushed and make the whole call fail
+    fn push(&mut self, ty: TypeKind, slot: u64) {
+        if self.types.get(self.pushed) == Some(&ty) {
+            self.stack.push_slot(slot);
+        } else {
+            self.mismatch = true;
+        }
+        self.pushed += 1;
+    }
+}
+
+#[derive(Clone)]
+enum InterruptFlag {
+    #[cfg(target_has_atomic = "ptr")]
+    Shared(Arc<AtomicBool>),
+    Static(&'static AtomicBool),
+}
+
+/// Cheap `Sync` handle to interrupt a guest running in a [`VmContext`].
+#[derive(Clone)]
+pub struct InterruptHandle {
+    flag: InterruptFlag,
+}
+
+impl InterruptHandle {
+    // `Arc` is not available on targets without atomic read-modify-write operations (like riscv32imc),
+    // the flag has to be given through `VmContext::set_interrupt_flag` there
+    #[cfg(target_has_atomic = "ptr")]
+    fn new() -> Self {
+        Self { flag: InterruptFlag::Shared(Arc::new(AtomicBool::new(false))) }
+    }
+
+    fn flag(&self) -> &AtomicBool {
+        match &self.flag {
+            #[cfg(target_has_atomic = "ptr")]
+            InterruptFlag::Shared(flag) => flag,
+            InterruptFlag::Static(flag) => flag,
+        }
+    }
+
+    /// Requests the guest to stop as soon as possible.
+    pub fn interrupt(&self) {
+        self.flag().store(true, Ordering::Relaxed);
+    }
+
+    fn clear(&self) {
+        self.flag().store(false, Ordering::Relaxed);
+    }
+}
+
+// Called at loop back-edges and calls, so a guest can't run away without checking these.
+#[inline]
+pub(crate) fn poll_stop_requests(
+    interrupt: &Option<InterruptHandle>,
+    deadline: Option<u64>,
+    env: &impl Environment,
+) -> Result<(), InterpreterError> {
+    if let Some(handle) = interrupt {
+        if handle.flag().load(Ordering::Relaxed) {
+            // acknowledged, so the next call can run again
+            handle.clear();
+            return Err(InterpreterError::Interrupted);
+        }
+    }
+
+    if deadline.is_some_and(|deadline| env.ticks() >= deadline) {
+        return Err(InterpreterError::DeadlineExceeded);
+    }
+
+    Ok(())
+}
+
 pub struct ExecutionProfile {
-    executed_instr_count: [u32; 0xFF],
-    executed_instr_time: [u64; 0xFF],
+    pub(crate) executed_instr_count: [u32; 0xFF],
+    pub(crate) executed_instr_time: [u64; 0xFF],
+    // executions of each superinstruction, they are also counted as their first instruction above
+    pub(crate) superinstruction_hits: [u64; Superinstruction::ALL.len()],
 }
 
 impl ExecutionProfile {
@@ -46,8 +411,14 @@ impl ExecutionProfile {
         Self {
             executed_instr_count: core::array::from_fn(|_| 0),
             executed_instr_time: core::array::from_fn(|_| 0),
+            superinstruction_hits: [0; Superinstruction::ALL.len()],
         }
     }
+
+    /// Number of times the stack interpreter has executed given superinstruction.
+    pub fn superinstruction_hits(&self, kind: Superinstruction) -> u64 {
+        self.superinstruction_hits[kind as usize]
+    }
 }
 
 impl fmt::Debug for ExecutionProfile {
@@ -64,203 +435,178 @@ impl fmt::Debug for ExecutionProfile {
                 )?;
             }
         }
+        for kind in Superinstruction::ALL {
+            let hits = self.superinstruction_hits(kind);
+            if hits > 0 {
+                writeln!(f, "{:<40} {:>12}", kind.pattern(), hits)?;
+            }
+        }
         Ok(())
     }
 }
 
-#[derive(Debug)]
-enum BlockType {
-    Block,
-    Loop,
-}
-
-pub struct StackFrame<'code> {
-    func_idx: usize,
-    reader: Reader<'code>,
-    locals_offset: usize,
-    curr_loop_start: Option<usize>,
-    blocks: Vec<BlockMeta>,
+#[derive(Clone, Copy)]
+pub struct StackFrame {
+    pub(crate) func_idx: usize,
+    // index of the next instruction to execute
+    pc: usize,
+    // index of the stack slot with the first local, operands of the function are right above its locals
+    locals_base: usize,
 }
 
-impl<'code> StackFrame<'code> {
-    pub fn new(module: &'code WasmModule, idx: usize, locals_offset: usize) -> Result<Self, InterpreterError> {
-        let Some(func) = module.get_function_by_index(idx) else {
+impl StackFrame {
+    pub fn new(module: &WasmModule, idx: usize, locals_base: usize) -> Result<Self, InterpreterError> {
+        if module.get_function_by_index(idx).is_none() {
             return Err(InterpreterError::FunctionNotFound);
-        };
+        }
 
         Ok(Self {
             func_idx: idx,
-            reader: Reader::new(func.code),
-            locals_offset,
-            curr_loop_start: None,
-            blocks: Vec::new(),
+            pc: 0,
+            locals_base,
         })
     }
 }
 
-pub struct VmStack {
-    data: Vec<u8>,
-    #[cfg(debug_assertions)]
-    types: Vec<TypeKind>,
+/// Operand stack of the guest, every value takes a single 64-bit slot.
+///
+/// Call frames of the stack interpreter keep their locals in the slots right below their operands,
+/// so the arguments of a call become the first locals of the callee without being copied.
+pub struct VmStack<'buf> {
+    data: Storage<'buf, u64>,
+    // set when a value didn't fit into the stack, checked by the interpreter after each instruction
+    pub(crate) overflowed: bool,
 }
 
-impl VmStack {
+impl<'buf> VmStack<'buf> {
     #[inline]
     fn new() -> Self {
+        Self::with_storage(Storage::new())
+    }
+
+    #[inline]
+    fn with_storage(data: Storage<'buf, u64>) -> Self {
         Self {
-            data: Vec::new(),
-            #[cfg(debug_assertions)]
-            types: Vec::new(),
+            data,
+            overflowed: false,
         }
     }
 
+    /// Number of values on the stack.
     #[inline]
-    pub(self) fn push_bytes<const N: usize>(&mut self, ty: TypeKind, data: [u8; N]) {
-        self.data.extend(data);
-        #[cfg(debug_assertions)]
-        self.types.push(ty);
-        #[cfg(not(debug_assertions))]
-        let _ = ty;
+    pub fn len(&self) -> usize {
+        self.data.len()
     }
 
     #[inline]
-    pub fn push_f32(&mut self, val: f32) {
-        self.push_bytes(TypeKind::F32, val.to_le_bytes());
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
     }
 
     #[inline]
-    pub fn push_f64(&mut self, val: f64) {
-        self.push_bytes(TypeKind::F64, val.to_le_bytes());
+    pub(crate) fn push_slot(&mut self, val: u64) {
+        if self.data.push(val).is_err() {
+            self.overflowed = true;
+        }
     }
 
     #[inline]
-    pub fn push_i32(&mut self, val: i32) {
-        self.push_bytes(TypeKind::I32, val.to_le_bytes());
+    pub(crate) fn pop_slot(&mut self) -> Result<u64, InterpreterError> {
+        self.data.pop().ok_or(InterpreterError::StackTooSmall)
+    }
+
+    pub(crate) fn push_slots(&mut self, vals: &[u64]) {
+        if self.data.extend_from_slice(vals).is_err() {
+            self.overflowed = true;
+        }
+    }
+
+    /// Moves the values from the top of the stack into `dst`, keeping their order.
+    pub(crate) fn pop_slots(&mut self, dst: &mut [u64]) -> Result<(), InterpreterError> {
+        let from = self.data.len().checked_sub(dst.len()).ok_or(InterpreterError::StackTooSmall)?;
+        dst.copy_from_slice(&self.data[from..]);
+        self.data.truncate(from);
+        Ok(())
     }
 
     #[inline]
-    pub fn push_i64(&mut self, val: i64) {
-        self.push_bytes(TypeKind::I64, val.to_le_bytes());
+    pub fn push_f32(&mut self, val: f32) {
+        self.push_slot(u64::from(val.to_bits()));
     }
 
-    fn pop_bytes<const N: usize>(&mut self) -> Result<[u8; N], InterpreterError> {
-        let (rest, &bytes) = self.data.split_last_chunk::<N>()
-            .ok_or_else(|| InterpreterError::StackTooSmall)?;
-        self.data.drain(rest.len()..);
-        Ok(bytes)
+    #[inline]
+    pub fn push_f64(&mut self, val: f64) {
+        self.push_slot(val.to_bits());
     }
 
-    pub fn peek_bytes<const N: usize>(&self) -> Result<[u8; N], InterpreterError> {
-        self.data.split_last_chunk::<N>().map(|(_, bytes)| *bytes)
-            .ok_or_else(|| InterpreterError::StackTooSmall)
+    #[inline]
+    pub fn push_i32(&mut self, val: i32) {
+        self.push_slot(u64::from(val as u32));
+    }
+
+    #[inline]
+    pub fn push_i64(&mut self, val: i64) {
+        self.push_slot(val as u64);
     }
 
     #[inline]
     pub fn pop_i32(&mut self) -> Result<i32, InterpreterError> {
-        #[cfg(debug_assertions)]
-        self.types.pop();
-        self.pop_bytes().map(i32::from_le_bytes)
+        self.pop_slot().map(|val| val as u32 as i32)
     }
 
     #[inline]
     pub fn pop_i64(&mut self) -> Result<i64, InterpreterError> {
-        #[cfg(debug_assertions)]
-        self.types.pop();
-        self.pop_bytes().map(i64::from_le_bytes)
+        self.pop_slot().map(|val| val as i64)
     }
 
     #[inline]
     pub fn pop_u32(&mut self) -> Result<u32, InterpreterError> {
-        #[cfg(debug_assertions)]
-        self.types.pop();
-        self.pop_bytes().map(u32::from_le_bytes)
+        self.pop_slot().map(|val| val as u32)
     }
 
     #[inline]
     pub fn pop_f32(&mut self) -> Result<f32, InterpreterError> {
-        #[cfg(debug_assertions)]
-        self.types.pop();
-        self.pop_bytes().map(f32::from_le_bytes)
+        self.pop_slot().map(|val| f32::from_bits(val as u32))
     }
 
     #[inline]
     pub fn pop_f64(&mut self) -> Result<f64, InterpreterError> {
-        #[cfg(debug_assertions)]
-        self.types.pop();
-        self.pop_bytes().map(f64::from_le_bytes)
+        self.pop_slot().map(f64::from_bits)
     }
 
-    #[inline]
-    fn pop_many(&mut self, n_bytes: usize) {
-        #[cfg(debug_assertions)]
-        {
-            let mut remaining_bytes = n_bytes;
-            while remaining_bytes > 0 {
-                let ty = self.types.pop().expect("enough types");
-                remaining_bytes -= ty.len_bytes();
-            }
-        }
-        self.data.drain(self.data.len() - n_bytes..);
+    /// Little-endian bytes of the value on top of the stack, e.g. `peek_bytes::<4>()` for an `i32`.
+    /// Each value takes a single slot, so at most 8 bytes can be taken.
+    pub fn peek_bytes<const N: usize>(&self) -> Result<[u8; N], InterpreterError> {
+        const { assert!(N <= 8, "values take at most 8 bytes") };
+        let val = self.data.last().ok_or(InterpreterError::StackTooSmall)?;
+        let mut bytes = [0; N];
+        bytes.copy_from_slice(&val.to_le_bytes()[..N]);
+        Ok(bytes)
     }
 
+    /// Removes `drop` values from below the top `keep` values.
     #[inline]
-    #[track_caller]
-    fn inplace_bin_op<T: Operand, U: Operand, R: Operand>(&mut self, op: impl FnOnce(T, U) -> R) -> Result<R, InterpreterError> {
-        let b = U::pop(self)?;
-        let a = T::pop(self)?;
-        let result = op(a, b);
-        R::push(self, result);
-        Ok(result)
+    fn unwind(&mut self, drop: usize, keep: usize) {
+        if drop == 0 {
+            return;
+        }
+        let len = self.data.len();
+        self.data.copy_within(len - keep..len, len - keep - drop);
+        self.data.truncate(len - drop);
     }
 
     #[inline]
-    #[track_caller]
-    fn inplace_unary_op<T: Operand, R: Operand>(&mut self, op: impl FnOnce(T) -> R) -> Result<R, InterpreterError> {
-        let a = T::pop(self)?;
-        let result = op(a);
-        R::push(self, result);
-        Ok(result)
+    fn select(&mut self, cond: i32) -> Result<(), InterpreterError> {
+        let b = self.pop_slot()?;
+        let a = self.pop_slot()?;
+        self.push_slot(if cond != 0 { a } else { b });
+        Ok(())
     }
 }
 
-impl fmt::Debug for VmStack {
+impl fmt::Debug for VmStack<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        #[cfg(debug_assertions)]
-        {
-            let mut reader = Reader::new(&self.data);
-            let mut fmt = f.debug_list();
-            for tk in &self.types {
-                match tk {
-                    TypeKind::Void => todo!(),
-                    TypeKind::Func => todo!(),
-                    TypeKind::FuncRef => todo!(),
-                    TypeKind::F32 => {
-                        if let Ok(val) = reader.read_f32() {
-                            fmt.entry(&val);
-                        }
-                    }
-                    TypeKind::F64 => {
-                        if let Ok(val) = reader.read_f64() {
-                            fmt.entry(&val);
-                        }
-                    }
-                    TypeKind::I32 => {
-                        if let Ok(val) = reader.read_i32() {
-                            fmt.entry(&val);
-                        }
-                    }
-                    TypeKind::I64 => {
-                        if let Ok(val) = reader.read_i64() {
-                            fmt.entry(&val);
-                        }
-                    }
-                }
-            }
-            fmt.finish()?;
-        }
-        #[cfg(not(debug_assertions))]
-        write!(f, "{:02X?}", &self.data)?;
-        Ok(())
+        write!(f, "{:X?}", &*self.data)
     }
 }
 
@@ -278,14 +624,6 @@ impl UntypedMemorySpan {
         unsafe { core::mem::transmute(data) }
     }
 
-    #[inline]
-    fn variable_to_offset(offsets: &[usize], var_idx: usize) -> Result<usize, MemoryAccessError> {
-        offsets
-            .get(var_idx)
-            .copied()
-            .ok_or_else(|| MemoryAccessError::InvalidVariable { idx: var_idx })
-    }
-
     #[inline]
     fn read_param_raw<const N: usize>(&self, offset: usize) -> Result<&[u8; N], MemoryAccessError> {
         self.data.get(offset..)
@@ -308,176 +646,49 @@ impl UntypedMemorySpan {
     }
 
     #[inline]
-    fn push_into(&self, stack: &mut VmStack, var_idx: usize, var_type: TypeKind, offsets: &[usize]) -> Result<(), InterpreterError> {
-        let offset = Self::variable_to_offset(offsets, var_idx)?;
-
-        match var_type {
-            TypeKind::Void => todo!(),
-            TypeKind::Func => todo!(),
-            TypeKind::FuncRef => todo!(),
-            TypeKind::F32 => stack.push_bytes(TypeKind::F32, *self.read_param_raw::<4>(offset)?),
-            TypeKind::F64 => stack.push_bytes(TypeKind::F64, *self.read_param_raw::<8>(offset)?),
-            TypeKind::I32 => stack.push_bytes(TypeKind::I32, *self.read_param_raw::<4>(offset)?),
-            TypeKind::I64 => stack.push_bytes(TypeKind::I64, *self.read_param_raw::<8>(offset)?),
-        }
-        Ok(())
-    }
-
-    #[inline]
-    fn pop_from(&mut self, stack: &mut VmStack, var_idx: usize, var_type: TypeKind, offsets: &[usize]) -> Result<(), InterpreterError> {
-        let offset = Self::variable_to_offset(offsets, var_idx)?;
+    fn push_into(&self, stack: &mut VmStack, offset: usize, var_type: TypeKind) -> Result<(), InterpreterError> {
         match var_type {
             TypeKind::Void => todo!(),
             TypeKind::Func => todo!(),
-            TypeKind::FuncRef => todo!(),
-            TypeKind::F32 => self.write_param_raw::<4>(offset, &stack.pop_f32()?.to_ne_bytes())?,
-            TypeKind::F64 => self.write_param_raw::<8>(offset, &stack.pop_f64()?.to_ne_bytes())?,
-            TypeKind::I32 => self.write_param_raw::<4>(offset, &stack.pop_i32()?.to_ne_bytes())?,
-            TypeKind::I64 => self.write_param_raw::<8>(offset, &stack.pop_i64()?.to_ne_bytes())?,
+            TypeKind::F32 | TypeKind::I32 => stack.push_slot(u64::from(u32::from_ne_bytes(*self.read_param_raw(offset)?))),
+            TypeKind::F64 | TypeKind::I64 | TypeKind::FuncRef | TypeKind::ExternRef => stack.push_slot(u64::from_ne_bytes(*self.read_param_raw(offset)?)),
         }
         Ok(())
     }
 
     #[inline]
-    fn copy_from(&mut self, stack: &mut VmStack, var_idx: usize, var_type: TypeKind, offsets: &[usize]) -> Result<(), InterpreterError> {
-        let offset = Self::variable_to_offset(offsets, var_idx)?;
+    fn pop_from(&mut self, stack: &mut VmStack, offset: usize, var_type: TypeKind) -> Result<(), InterpreterError> {
         match var_type {
             TypeKind::Void => todo!(),
             TypeKind::Func => todo!(),
-            TypeKind::FuncRef => todo!(),
-            TypeKind::F32 | TypeKind::I32 => self.write_param_raw::<4>(offset, &stack.peek_bytes()?)?,
-            TypeKind::F64 | TypeKind::I64 => self.write_param_raw::<8>(offset, &stack.peek_bytes()?)?,
+            TypeKind::F32 | TypeKind::I32 => self.write_param_raw(offset, &stack.pop_u32()?.to_ne_bytes())?,
+            TypeKind::F64 | TypeKind::I64 | TypeKind::FuncRef | TypeKind::ExternRef => self.write_param_raw(offset, &stack.pop_slot()?.to_ne_bytes())?,
         }
         Ok(())
     }
 }
 
-#[inline]
-fn copy_params_and_locals(locals: &mut Vec<u8>, params_data: &[u8], func_body: &FuncBody) {
-    locals.extend_from_slice(params_data);
-    locals.resize(locals.len() + func_body.non_param_locals_len_in_bytes, 0);
-}
-
-#[derive(Debug)]
-struct BlockMeta {
-    offset: usize,
-    body_offset: usize,
-    kind: BlockType,
-}
-
-#[repr(transparent)]
-pub struct Memory {
-    data: [u8],
+pub struct Serializer<'a, 'buf> {
+    stack: &'a mut VmStack<'buf>,
 }
 
-impl Memory {
-    pub fn from_slice(data: &[u8]) -> &Self {
-        unsafe { core::mem::transmute(data) }
-    }
-
-    pub fn from_slice_mut(data: &mut [u8]) -> &mut Self {
-        unsafe { core::mem::transmute(data) }
-    }
-
-    #[inline]
-    fn read_bytes_at<const N: usize>(&self, offset: usize) -> Option<[u8; N]> {
-        let (_, data) = self.data.split_at_checked(offset)?;
-        let (&raw, _) = data.split_first_chunk()?;
-        Some(raw)
-    }
-
-    #[inline]
-    #[track_caller]
-    fn write_bytes_at<const N: usize>(&mut self, offset: usize, bytes: &[u8; N]) {
-        assert!(offset + N <= self.data.len(), "out of bounds write: offset={offset} data={n}", n = self.data.len());
-        let (_, data) = self.data.split_at_mut_checked(offset).unwrap();
-        let (raw, _) = data.split_first_chunk_mut::<N>().unwrap();
-        raw.copy_from_slice(bytes);
-    }
-
-    #[inline]
-    fn read_i8(&self, offset: usize) -> Option<i8> {
-        self.read_bytes_at(offset).map(i8::from_ne_bytes)
-    }
-
-    #[inline]
-    fn read_u8(&self, offset: usize) -> Option<u8> {
-        self.read_bytes_at(offset).map(u8::from_ne_bytes)
-    }
-
-    #[inline]
-    fn read_i16(&self, offset: usize) -> Option<i16> {
-        self.read_bytes_at(offset).map(i16::from_ne_bytes)
-    }
-
-    #[inline]
-    fn read_u16(&self, offset: usize) -> Option<u16> {
-        self.read_bytes_at(offset).map(u16::from_ne_bytes)
-    }
-
-    #[inline]
-    fn read_i32(&self, offset: usize) -> Option<i32> {
-        self.read_bytes_at(offset).map(i32::from_ne_bytes)
-    }
-
-    #[inline]
-    fn read_u32(&self, offset: usize) -> Option<u32> {
-        self.read_bytes_at(offset).map(u32::from_ne_bytes)
-    }
-
-    #[inline]
-    fn read_i64(&self, offset: usize) -> Option<i64> {
-        self.read_bytes_at(offset).map(i64::from_ne_bytes)
-    }
-
-    #[inline]
-    fn read_f32(&self, offset: usize) -> Option<f32> {
-        self.read_bytes_at(offset).map(f32::from_ne_bytes)
-    }
-
-    #[inline]
-    fn read_f64(&self, offset: usize) -> Option<f64> {
-        self.read_bytes_at(offset).map(f64::from_ne_bytes)
-    }
-
-    #[inline]
-    fn write_i8(&mut self, offset: usize, value: i8) {
-        self.write_bytes_at(offset, &value.to_ne_bytes());
-    }
-
-    #[inline]
-    fn write_i16(&mut self, offset: usize, value: i16) {
-        self.write_bytes_at(offset, &value.to_ne_bytes());
-    }
-
-    #[inline]
-    fn write_i32(&mut self, offset: usize, value: i32) {
-        self.write_bytes_at(offset, &value.to_ne_bytes());
-    }
-
-    #[inline]
-    fn write_i64(&mut self, offset: usize, value: i64) {
-        self.write_bytes_at(offset, &value.to_ne_bytes());
-    }
-
-    #[inline]
-    fn write_f32(&mut self, offset: usize, value: f32) {
-        self.write_bytes_at(offset, &value.to_ne_bytes());
-    }
-
-    #[inline]
-    fn write_f64(&mut self, offset: usize, value: f64) {
-        self.write_bytes_at(offset, &value.to_ne_bytes());
+impl Serializer<'_, '_> {
+    pub(crate) fn write<T: Operand>(&mut self, value: T) {
+        T::push(self.stack, value);
     }
-}
-
-pub struct Serializer {
-    buf: Vec<u8>,
-}
 
-impl Serializer {
-    pub(crate) fn write_bytes(&mut self, bytes: &[u8]) {
-        self.buf.extend_from_slice(bytes);
+    /// Writes a value of type `ty` given as its native-endian bytes.
+    fn write_bytes(&mut self, ty: TypeKind, bytes: &[u8]) -> Result<(), InterpreterError> {
+        match (ty, bytes) {
+            (TypeKind::I32 | TypeKind::F32, &[a, b, c, d]) => {
+                self.stack.push_slot(u64::from(u32::from_ne_bytes([a, b, c, d])));
+            }
+            (TypeKind::I64 | TypeKind::F64, &[a, b, c, d, e, f, g, h]) => {
+                self.stack.push_slot(u64::from_ne_bytes([a, b, c, d, e, f, g, h]));
+            }
+            _ => return Err(InterpreterError::InvalidSignature),
+        }
+        Ok(())
     }
 }
 
@@ -487,6 +698,12 @@ pub trait FunctionArgs {
     fn write_to(&self, serializer: &mut Serializer);
 }
 
+impl FunctionArgs for () {
+    const TYPE: &'static [TypeKind] = &[];
+
+    fn write_to(&self, _serializer: &mut Serializer) {}
+}
+
 macro_rules! tuple_impls {
     ( $( $name:ident )+ ) => {
         impl<$($name: Operand),+> FunctionArgs for ($($name,)+) {
@@ -529,71 +746,330 @@ pub enum InterpreterError {
     StackTooSmall,
     Unreachable,
     MemoryAccessError(MemoryAccessError),
+    /// Execution has been paused before the function returned, see [`resume`].
+    Paused(PauseReason),
+    /// [`VmContext::complete_import`] has been called while no imported function was pending.
+    NoPendingImport,
+    /// [`crate::Scheduler`] has no app with given id.
+    InvalidApp,
+    /// Values given to [`VmContext::complete_import`] don't match the results of the pending function.
+    InvalidImportResults,
+    /// Execution has been stopped through an [`InterruptHandle`].
+    Interrupted,
+    /// Deadline set by [`VmContext::set_deadline`] has passed.
+    DeadlineExceeded,
+    /// [`VmContext::set_timeout`] has been used with an [`Environment`] whose ticks have no known length.
+    UnknownTickRate,
+    /// One of the stacks used by the VM has run out of space.
+    StackOverflow,
+    /// Engine other than [`Engine::Stack`] has been selected in a context created with [`VmContext::with_buffers`].
+    EngineNeedsHeap,
+    /// Function of a lazily parsed module, or of an image without prepared bodies, has been called
+    /// for the first time in a context created with [`VmContext::with_buffers`].
+    LazyBodyNeedsHeap,
+    /// Integer division or remainder with a zero divisor.
+    DivisionByZero,
+    /// Result of an integer division or of a conversion of a float doesn't fit into its type.
+    IntegerOverflow,
+    /// NaN has been converted into an integer.
+    InvalidConversion,
+    /// Imported function has failed.
+    Host(HostError),
+    /// Element segment doesn't fit into its table.
+    TableOutOfBounds,
+    /// `call_indirect` has used an element out of the bounds of the table or one that hasn't been initialized.
+    UndefinedElement,
+    /// Function called by `call_indirect` doesn't have the expected signature.
+    IndirectCallTypeMismatch,
+    /// Number of imported functions or globals given to [`crate::Instance::new`] doesn't match the module.
+    ImportsMismatch { expected: usize, found: usize },
+    /// Module has no global with given name.
+    GlobalNotFound,
+    /// Global has been accessed as a value of another type.
+    GlobalTypeMismatch { expected: TypeKind, found: TypeKind },
+    /// Host has tried to set a global that isn't mutable.
+    ImmutableGlobal,
+    /// Module exports neither `malloc` and `free` nor `cabi_realloc`.
+    NoGuestAllocator,
+    /// Allocator of the guest has returned null or memory out of its bounds.
+    GuestAllocationFailed,
+    /// Allocator of the guest can't be called while a paused call hasn't finished.
+    CallInProgress,
 }
 
-#[derive(Debug)]
-pub enum MemoryAccessError {
-    InvalidVariable { idx: usize },
-    InvalidOffset { offset: usize },
-    InvalidLength { offset: usize, length: usize },
+/// State of the VM after returning from [`evaluate`] or [`resume`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Execution {
+    /// Called function has returned and its results are on the stack.
+    Finished,
+    /// Execution has stopped before the function returned, all its state is kept in [`VmContext`].
+    Paused(PauseReason),
 }
 
-impl From<ParserError> for InterpreterError {
-    fn from(value: ParserError) -> Self {
-        Self::ParserError(value)
-    }
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PauseReason {
+    /// Limit set by [`VmContext::set_instruction_limit`] has been reached.
+    InstructionLimit,
+    /// Fuel set by [`VmContext::set_fuel`] has been used up.
+    OutOfFuel,
+    /// Imported function has asked to give the control back to the host.
+    Yield,
+    /// End of the time slice set by [`VmContext::set_time_slice_end`] has been reached.
+    TimeSliceElapsed,
+    /// Imported function has asked not to be resumed before `Environment::ticks` reaches `until`.
+    Sleep { until: u64 },
+    /// Imported function has not finished yet, its results have to be supplied
+    /// with [`VmContext::complete_import`] before the guest can continue.
+    ImportPending { func_idx: usize },
 }
 
-impl From<MemoryAccessError> for InterpreterError {
+/// Tells the VM what to do after an imported function returns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportOutcome {
+    /// Continue executing the guest.
+    Return,
+    /// Pause the guest right after the call, see [`PauseReason::Yield`].
+    Yield,
+    /// Pause the guest right after the call, see [`PauseReason::Sleep`].
+    Sleep { until: u64 },
+    /// Results are not available yet, see [`PauseReason::ImportPending`].
+    Pending,
+}
+
+/// Error of an imported function, it traps the guest and is returned as [`InterpreterError::Host`].
+pub struct HostError {
+    payload: Box<dyn HostErrorPayload>,
+}
+
+trait HostErrorPayload: Any + fmt::Debug {
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: Any + fmt::Debug> HostErrorPayload for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl HostError {
+    /// Creates an error carrying a value defined by the host, e.g. a message or an error code.
+    pub fn new(payload: impl Any + fmt::Debug) -> Self {
+        Self { payload: Box::new(payload) }
+    }
+
+    /// Payload of the error, if it's of type `T`.
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        (*self.payload).as_any().downcast_ref()
+    }
+}
+
+impl fmt::Debug for HostError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("HostError").field(&self.payload).finish()
+    }
+}
+
+#[derive(Debug)]
+pub enum MemoryAccessError {
+    InvalidVariable { idx: usize },
+    InvalidOffset { offset: usize },
+    InvalidLength { offset: usize, length: usize },
+}
+
+impl From<ParserError> for InterpreterError {
+    fn from(value: ParserError) -> Self {
+        Self::ParserError(value)
+    }
+}
+
+impl From<HostError> for InterpreterError {
+    fn from(value: HostError) -> Self {
+        Self::Host(value)
+    }
+}
+
+impl From<MemoryAccessError> for InterpreterError {
     fn from(value: MemoryAccessError) -> Self {
         Self::MemoryAccessError(value)
     }
 }
 
-pub type ImportedFunc<TEnv> = fn(&mut TEnv, &mut VmStack, &mut [u8]);
+pub type ImportedFunc<TEnv> = fn(&mut TEnv, &mut VmStack, &mut [u8]) -> Result<ImportOutcome, HostError>;
+
+/// Closure called for an import, it can keep state of its own apart from the environment.
+pub type HostClosure<'a, TEnv> = dyn FnMut(&mut TEnv, &mut VmStack, &mut [u8]) -> Result<ImportOutcome, HostError> + 'a;
+
+/// Closure called for an import that also takes the host data of the context, see [`VmContext::set_data`].
+pub type DataClosure<'a, TEnv> = dyn FnMut(&mut TEnv, &mut VmStack, &mut [u8], Option<&mut dyn Any>) -> Result<ImportOutcome, HostError> + 'a;
+
+/// Host function an import resolves to, imports of a module are passed to [`evaluate`] and others
+/// as a slice of them, indexed by the function index.
+///
+/// Closures live for `'f`, while `'a` is only the lifetime of the borrow of a [`HostFunc::Borrowed`] closure.
+pub enum HostFunc<'a, 'f, TEnv> {
+    Fn(ImportedFunc<TEnv>),
+    Boxed(Box<HostClosure<'f, TEnv>>),
+    /// Closure owned by the caller, e.g. when there is no heap.
+    Borrowed(&'a mut HostClosure<'f, TEnv>),
+    WithData(Box<DataClosure<'f, TEnv>>),
+}
+
+impl<'f, TEnv> HostFunc<'_, 'f, TEnv> {
+    pub fn boxed(func: impl FnMut(&mut TEnv, &mut VmStack, &mut [u8]) -> Result<ImportOutcome, HostError> + 'f) -> Self {
+        Self::Boxed(Box::new(func))
+    }
+
+    #[inline]
+    pub(crate) fn call(
+        &mut self,
+        env: &mut TEnv,
+        stack: &mut VmStack,
+        memory: &mut [u8],
+        data: Option<&mut dyn Any>,
+    ) -> Result<ImportOutcome, HostError> {
+        match self {
+            Self::Fn(func) => func(env, stack, memory),
+            Self::Boxed(func) => func(env, stack, memory),
+            Self::Borrowed(func) => func(env, stack, memory),
+            Self::WithData(func) => func(env, stack, memory, data),
+        }
+    }
+}
+
+impl<TEnv> fmt::Debug for HostFunc<'_, '_, TEnv> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fn(func) => f.debug_tuple("Fn").field(func).finish(),
+            Self::Boxed(_) => f.write_str("Boxed"),
+            Self::Borrowed(_) => f.write_str("Borrowed"),
+            Self::WithData(_) => f.write_str("WithData"),
+        }
+    }
+}
+
+impl<TEnv> From<ImportedFunc<TEnv>> for HostFunc<'_, '_, TEnv> {
+    fn from(func: ImportedFunc<TEnv>) -> Self {
+        Self::Fn(func)
+    }
+}
 
 pub fn init_globals(globals: &mut Vec<u8>, module: &WasmModule) -> Result<(), InterpreterError> {
-    for global in &module.globals {
-        match execute_initializer(global.initializer.code)?.expect("initializer returned nothing useful") {
-            ExprValue::I32(value) => {
-                globals.extend_from_slice(&value.to_ne_bytes());
-            }
-            ExprValue::I64(value) => {
-                globals.extend_from_slice(&value.to_ne_bytes());
-            }
-            ExprValue::F32(value) => {
-                globals.extend_from_slice(&value.to_ne_bytes());
-            }
-            ExprValue::F64(value) => {
-                globals.extend_from_slice(&value.to_ne_bytes());
-            }
+    let start = globals.len();
+    globals.resize(start + module.globals_len_in_bytes(), 0);
+    init_globals_into(&mut globals[start..], module)
+}
+
+/// Initializes globals in a buffer of at least [`WasmModule::globals_len_in_bytes`] bytes.
+pub fn init_globals_into(globals: &mut [u8], module: &WasmModule) -> Result<(), InterpreterError> {
+    // imported globals are zero until the host sets them
+    for (global, &offset) in iter::zip(&module.globals, &module.globals_offsets) {
+        if global.initializer.is_some() {
+            continue;
+        }
+        let length = global.kind.len_bytes();
+        globals
+            .get_mut(offset..)
+            .and_then(|it| it.get_mut(..length))
+            .ok_or(MemoryAccessError::InvalidLength { offset, length })?
+            .fill(0);
+    }
+    init_defined_globals(globals, module)
+}
+
+/// Evaluates the initializers of the globals defined by the module, they may read the imported globals,
+/// which have to be set before.
+pub(crate) fn init_defined_globals(globals: &mut [u8], module: &WasmModule) -> Result<(), InterpreterError> {
+    for (global, &offset) in iter::zip(&module.globals, &module.globals_offsets) {
+        let Some(initializer) = &global.initializer else {
+            continue;
+        };
+        let value = execute_initializer(initializer.code, module, globals)?.expect("initializer returned nothing useful");
+        let globals = UntypedMemorySpan::from_slice_mut(globals);
+        match value {
+            ExprValue::I32(value) => globals.write_param_raw(offset, &value.to_ne_bytes())?,
+            ExprValue::I64(value) => globals.write_param_raw(offset, &value.to_ne_bytes())?,
+            ExprValue::F32(value) => globals.write_param_raw(offset, &value.to_ne_bytes())?,
+            ExprValue::F64(value) => globals.write_param_raw(offset, &value.to_ne_bytes())?,
+            ExprValue::Ref(value) => globals.write_param_raw(offset, &Value::FuncRef(value).to_slot().to_ne_bytes())?,
         }
     }
 
     Ok(())
 }
 
+/// Copies active data segments of the module into memory. Their offsets can't read globals,
+/// [`crate::Instance`] supports those.
 pub fn init_memory(memory: &mut [u8], module: &WasmModule) -> Result<(), InterpreterError> {
+    init_memory_with_globals(memory, module, &[])
+}
+
+/// Like [`init_memory`], with offsets that may read `globals`.
+pub(crate) fn init_memory_with_globals(memory: &mut [u8], module: &WasmModule, globals: &[u8]) -> Result<(), InterpreterError> {
     for segment in &module.data_segments {
-        let offset = match execute_initializer(segment.offset.code)?.expect("initializer returned nothing useful") {
-            ExprValue::I32(value) => value.try_into().unwrap(),
-            other => todo!("{:?}", other),
+        let Some(offset) = &segment.offset else {
+            continue;
         };
-        memory[offset..][..segment.data.len()].copy_from_slice(&segment.data);
+        let offset = segment_offset(offset.code, module, globals)?;
+        let length = segment.data.len();
+        memory
+            .get_mut(offset..)
+            .and_then(|it| it.get_mut(..length))
+            .ok_or(MemoryAccessError::InvalidLength { offset, length })?
+            .copy_from_slice(segment.data);
     }
 
     Ok(())
 }
 
+/// Offset of an active data or element segment, its initializer may read `globals`.
+pub(crate) fn segment_offset(code: &[u8], module: &WasmModule, globals: &[u8]) -> Result<usize, InterpreterError> {
+    match execute_initializer(code, module, globals)?.expect("initializer returned nothing useful") {
+        ExprValue::I32(value) => Ok(value as u32 as usize),
+        other => todo!("{:?}", other),
+    }
+}
+
+/// Value of a global of the module stored in `globals`, if it has type `T`.
+pub(crate) fn read_global<T: Operand>(module: &WasmModule, globals: &[u8], idx: usize) -> Option<T> {
+    let global = module.globals.get(idx)?;
+    if global.kind != T::TYPE {
+        return None;
+    }
+    let mut stack = VmStack::new();
+    UntypedMemorySpan::from_slice(globals)
+        .push_into(&mut stack, module.globals_offsets[idx], global.kind)
+        .ok()?;
+    T::pop(&mut stack).ok()
+}
+
+/// Stores the value pushed by `push` into the global at `idx`, which has to be of type `ty`.
+pub(crate) fn write_global(
+    module: &WasmModule,
+    globals: &mut [u8],
+    idx: usize,
+    ty: TypeKind,
+    push: impl FnOnce(&mut VmStack),
+) -> Result<(), InterpreterError> {
+    let global = module.globals.get(idx).ok_or(InterpreterError::GlobalNotFound)?;
+    if global.kind != ty {
+        return Err(InterpreterError::GlobalTypeMismatch { expected: global.kind, found: ty });
+    }
+    let mut stack = VmStack::new();
+    push(&mut stack);
+    UntypedMemorySpan::from_slice_mut(globals).pop_from(&mut stack, module.globals_offsets[idx], global.kind)
+}
+
 #[derive(Debug)]
 enum ExprValue {
     I32(i32),
     I64(i64),
     F32(f32),
     F64(f64),
+    // index of a function, `None` for a null reference
+    Ref(Option<u32>),
 }
 
-fn execute_initializer(code: &[u8]) -> Result<Option<ExprValue>, InterpreterError> {
+fn execute_initializer(code: &[u8], module: &WasmModule, globals: &[u8]) -> Result<Option<ExprValue>, InterpreterError> {
     let mut reader = Reader::new(code);
     let mut value = None;
 
@@ -633,6 +1109,31 @@ fn execute_initializer(code: &[u8]) -> Result<Option<ExprValue>, InterpreterErro
                 let val = reader.read_f64()?;
                 value = Some(ExprValue::F64(val));
             }
+            0x23 => {
+                // global.get <global_idx>
+                let global_idx = reader.read_usize()?;
+                let global = module.globals.get(global_idx).ok_or(InterpreterError::GlobalNotFound)?;
+                let mut stack = VmStack::new();
+                UntypedMemorySpan::from_slice(globals).push_into(&mut stack, module.globals_offsets[global_idx], global.kind)?;
+                value = Some(match Value::from_slot(global.kind, stack.pop_slot()?) {
+                    Some(Value::I32(val)) => ExprValue::I32(val),
+                    Some(Value::I64(val)) => ExprValue::I64(val),
+                    Some(Value::F32(val)) => ExprValue::F32(val),
+                    Some(Value::F64(val)) => ExprValue::F64(val),
+                    Some(Value::FuncRef(val) | Value::ExternRef(val)) => ExprValue::Ref(val),
+                    None => return Err(InterpreterError::InvalidSignature),
+                });
+            }
+            0xd0 => {
+                // ref.null <reftype>
+                reader.read::<TypeKind>()?;
+                value = Some(ExprValue::Ref(None));
+            }
+            0xd2 => {
+                // ref.func <func_idx>
+                let func_idx = reader.read_usize()? as u32;
+                value = Some(ExprValue::Ref(Some(func_idx)));
+            }
             _ => todo!("opcode {:02x?}", op),
         }
     }
@@ -647,43 +1148,137 @@ pub fn execute_function<'code, TEnv: Environment, TArgs: FunctionArgs, TResult:
     args: TArgs,
     memory: &mut [u8],
     globals: &mut [u8],
-    imports: &[ImportedFunc<TEnv>],
+    imports: &mut [HostFunc<'_, '_, TEnv>],
     env: &mut TEnv,
 ) -> Result<TResult, InterpreterError> {
     let Some(func_idx) = module.get_function_index_by_name(func_name) else {
         return Err(InterpreterError::FunctionNotFound);
     };
+    TypedFunc::new(module, func_idx)?.call(ctx, args, memory, globals, imports, env)
+}
 
-    let Some(func) = &module.functions[func_idx].body else {
+/// Like [`execute_function`], but with the types of arguments checked at runtime. Returns all results.
+#[allow(clippy::too_many_arguments)]
+pub fn call_dynamic<'code, TEnv: Environment>(
+    ctx: &mut VmContext<'code>,
+    module: &'code WasmModule<'code>,
+    func_name: &ByteStr,
+    args: &[Value],
+    memory: &mut [u8],
+    globals: &mut [u8],
+    imports: &mut [HostFunc<'_, '_, TEnv>],
+    env: &mut TEnv,
+) -> Result<Vec<Value>, InterpreterError> {
+    let Some(func_idx) = module.get_function_index_by_name(func_name) else {
+        return Err(InterpreterError::FunctionNotFound);
+    };
+    let Some(func) = module.get_function_by_index(func_idx) else {
         return Err(InterpreterError::FunctionWithoutBody);
     };
-
-    if func.signature.params.len() != TArgs::TYPE.len() {
+    let signature = &func.signature;
+    if args.len() != signature.params.len() || iter::zip(args, &signature.params[..]).any(|(arg, &ty)| arg.ty() != ty) {
         return Err(InterpreterError::InvalidSignature);
     }
 
-    for (expected, actual) in iter::zip(&func.signature.params, TArgs::TYPE) {
-        if expected != actual {
+    enter_function_with(ctx, module, func_idx, |serializer| {
+        for arg in args {
+            serializer.stack.push_slot(arg.to_slot());
+        }
+        Ok(())
+    })?;
+    match resume(ctx, module, memory, globals, imports, env)? {
+        Execution::Finished => {}
+        Execution::Paused(reason) => return Err(InterpreterError::Paused(reason)),
+    }
+
+    let mut slots = vec![0; signature.results.len()];
+    ctx.stack.pop_slots(&mut slots)?;
+    iter::zip(&signature.results[..], slots)
+        .map(|(&ty, slot)| Value::from_slot(ty, slot).ok_or(InterpreterError::InvalidSignature))
+        .collect()
+}
+
+/// Function of a module whose signature has been checked against `TArgs` and `TResult`,
+/// see [`WasmModule::get_typed_func`].
+pub struct TypedFunc<'code, TArgs, TResult> {
+    module: &'code WasmModule<'code>,
+    func_idx: usize,
+    _signature: PhantomData<fn(TArgs) -> TResult>,
+}
+
+impl<TArgs, TResult> Clone for TypedFunc<'_, TArgs, TResult> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<TArgs, TResult> Copy for TypedFunc<'_, TArgs, TResult> {}
+
+impl<'code, TArgs: FunctionArgs, TResult: Operand> TypedFunc<'code, TArgs, TResult> {
+    pub(crate) fn new(module: &'code WasmModule<'code>, func_idx: usize) -> Result<Self, InterpreterError> {
+        let Some(func) = module.functions.get(func_idx) else {
+            return Err(InterpreterError::FunctionNotFound);
+        };
+        let Some(func) = &func.body else {
+            return Err(InterpreterError::FunctionWithoutBody);
+        };
+
+        if func.signature.params[..] != *TArgs::TYPE {
+            return Err(InterpreterError::InvalidSignature);
+        }
+
+        // results are returned as a single value, so functions with more of them can't be typed
+        let valid_return = match &func.signature.results[..] {
+            [] => TResult::TYPE == TypeKind::Void,
+            [ty] => *ty == TResult::TYPE,
+            _ => false,
+        };
+
+        if !valid_return {
             return Err(InterpreterError::InvalidSignature);
         }
+
+        Ok(Self {
+            module,
+            func_idx,
+            _signature: PhantomData,
+        })
     }
 
-    let valid_return = match &func.signature.results[..] {
-        [] => TResult::TYPE == TypeKind::Void,
-        [ty] => *ty == TResult::TYPE,
-        _ => todo!("check multi-value return types"),
-    };
+    /// Index of the function in the module.
+    pub fn func_idx(&self) -> usize {
+        self.func_idx
+    }
 
-    if !valid_return {
-        return Err(InterpreterError::InvalidSignature);
+    /// Executes the function until it returns.
+    pub fn call<TEnv: Environment>(
+        &self,
+        ctx: &mut VmContext<'code>,
+        args: TArgs,
+        memory: &mut [u8],
+        globals: &mut [u8],
+        imports: &mut [HostFunc<'_, '_, TEnv>],
+        env: &mut TEnv,
+    ) -> Result<TResult, InterpreterError> {
+        self.start(ctx, args)?;
+        match resume(ctx, self.module, memory, globals, imports, env)? {
+            Execution::Finished => self.result(ctx),
+            Execution::Paused(reason) => Err(InterpreterError::Paused(reason)),
+        }
     }
 
-    let mut args_mem = Serializer {
-        buf: Vec::new(),
-    };
-    args.write_to(&mut args_mem);
-    evaluate(ctx, module, func_idx, &args_mem.buf, globals, memory, imports, env)?;
-    TResult::pop(&mut ctx.stack)
+    /// Prepares `ctx` to execute the function on the next [`resume`], for guests that may be paused.
+    pub fn start(&self, ctx: &mut VmContext<'code>, args: TArgs) -> Result<(), InterpreterError> {
+        enter_function_with(ctx, self.module, self.func_idx, |serializer| {
+            args.write_to(serializer);
+            Ok(())
+        })
+    }
+
+    /// Takes the result of the function from the stack after [`resume`] has returned [`Execution::Finished`].
+    pub fn result(&self, ctx: &mut VmContext<'code>) -> Result<TResult, InterpreterError> {
+        TResult::pop(&mut ctx.stack)
+    }
 }
 
 pub fn evaluate<'code, TEnv: Environment>(
@@ -691,583 +1286,352 @@ pub fn evaluate<'code, TEnv: Environment>(
     module: &'code WasmModule<'code>,
     func_idx: usize,
     args: &[u8],
-    globals: &mut [u8],
     memory: &mut [u8],
-    imports: &[ImportedFunc<TEnv>],
+    globals: &mut [u8],
+    imports: &mut [HostFunc<'_, '_, TEnv>],
     env: &mut TEnv,
+) -> Result<Execution, InterpreterError> {
+    enter_function(ctx, module, func_idx, args)?;
+    resume(ctx, module, memory, globals, imports, env)
+}
+
+/// Prepares `ctx` to execute given function from its first instruction on the next [`resume`].
+pub(crate) fn enter_function<'code>(
+    ctx: &mut VmContext<'code>,
+    module: &'code WasmModule<'code>,
+    func_idx: usize,
+    args: &[u8],
+) -> Result<(), InterpreterError> {
+    let Some(func) = module.get_function_by_index(func_idx) else {
+        return Err(InterpreterError::FunctionNotFound);
+    };
+    enter_function_with(ctx, module, func_idx, |serializer| {
+        let mut args = args;
+        for &ty in &func.signature.params {
+            let (bytes, rest) = args.split_at_checked(ty.len_bytes()).ok_or(InterpreterError::InvalidSignature)?;
+            serializer.write_bytes(ty, bytes)?;
+            args = rest;
+        }
+        Ok(())
+    })
+}
+
+fn enter_function_with<'code>(
+    ctx: &mut VmContext<'code>,
+    module: &'code WasmModule<'code>,
+    func_idx: usize,
+    write_args: impl FnOnce(&mut Serializer) -> Result<(), InterpreterError>,
 ) -> Result<(), InterpreterError> {
-    ctx.stack.data.clear();
+    ctx.abort();
 
     let Some(func) = module.get_function_by_index(func_idx) else {
         return Err(InterpreterError::FunctionNotFound);
     };
 
-    copy_params_and_locals(&mut ctx.locals, args, func);
-    ctx.call_stack.clear();
-    ctx.call_stack.push(StackFrame::new(
-        module,
-        func_idx,
-        0,
-    )?);
+    write_args(&mut Serializer { stack: &mut ctx.stack })?;
+    if ctx.stack.overflowed {
+        return Err(InterpreterError::StackOverflow);
+    }
+    if ctx.stack.len() != func.signature.params.len() {
+        return Err(InterpreterError::InvalidSignature);
+    }
+    if ctx.engine != Engine::Stack {
+        // arguments only pass through the stack on their way to the registers
+        return register::enter(ctx, module, func_idx);
+    }
+    push_frame(ctx, module, func_idx)
+}
+
+/// Continues execution of a call paused by [`evaluate`] or a previous `resume`.
+pub fn resume<'code, TEnv: Environment>(
+    ctx: &mut VmContext<'code>,
+    module: &'code WasmModule<'code>,
+    memory: &mut [u8],
+    globals: &mut [u8],
+    imports: &mut [HostFunc<'_, '_, TEnv>],
+    env: &mut TEnv,
+) -> Result<Execution, InterpreterError> {
+    if let Some(func_idx) = ctx.pending_import {
+        return Ok(Execution::Paused(PauseReason::ImportPending { func_idx }));
+    }
+
+    let result = if ctx.registers.is_running() {
+        register::run(ctx, module, memory, globals, imports, env)
+    } else {
+        run(ctx, module, memory, globals, imports, env)
+    };
+    if result.is_err() {
+        // trapped guest can't be resumed
+        ctx.abort();
+    }
+    if !matches!(result, Ok(Execution::Paused(_))) {
+        ctx.deadline = None;
+    }
+    result
+}
 
+fn run<'code, TEnv: Environment>(
+    ctx: &mut VmContext<'code>,
+    module: &'code WasmModule<'code>,
+    memory: &mut [u8],
+    globals: &mut [u8],
+    imports: &mut [HostFunc<'_, '_, TEnv>],
+    env: &mut TEnv,
+) -> Result<Execution, InterpreterError> {
+    let mut executed_instr_count = 0u64;
     while let Some(frame) = ctx.call_stack.last_mut() {
-        let current_func = module.get_function_by_index(frame.func_idx)
-            .expect("function existed at time of the call");
-        let reader = &mut frame.reader;
-        let pos = current_func.offset + reader.pos();
+        if ctx.instruction_limit.is_some_and(|limit| executed_instr_count >= limit) {
+            return Ok(Execution::Paused(PauseReason::InstructionLimit));
+        }
+        if ctx.fuel == Some(0) {
+            return Ok(Execution::Paused(PauseReason::OutOfFuel));
+        }
 
-        #[cfg(debug_assertions)]
-        let opcode_reader = reader.clone();
-        let op = match reader.read_u8() {
-            Ok(op) => op,
-            Err(ParserError::EndOfStream { .. }) => {
-                if let Some(frame) = ctx.call_stack.pop() {
-                    ctx.locals.drain(frame.locals_offset..);
-                }
-                // don't care if this is the last call - it will be taken care of before next iteration
-                continue;
-            }
-            Err(e) => panic!("other error: {e:?}"),
-        };
+        let start = env.ticks();
+        if ctx.time_slice_end.is_some_and(|end| start >= end) {
+            return Ok(Execution::Paused(PauseReason::TimeSliceElapsed));
+        }
 
-        #[cfg(debug_assertions)]
-        {
-            let mut reader = opcode_reader;
-            let pos = reader.pos();
-            write!(env, "{:02x?} @ {pos:02X} ({func_idx}) :: {:?} :: ", op, &ctx.stack);
-            _ = parse_opcode::<true>(&mut reader, pos, env, &mut ParserState::default());
-            drop(reader);
+        let func_idx = frame.func_idx;
+        let current_func = ctx.code_cache.get(module, func_idx);
+        let pc = frame.pc;
+        let mut instr = current_func.instrs[pc];
+        frame.pc += 1;
+
+        // superinstructions cost as much as the instructions they replace, when there is not enough left
+        // only the first of them is executed, so fusing doesn't change where the guest is paused
+        let left = ctx.instruction_limit
+            .map_or(u64::MAX, |limit| limit - executed_instr_count)
+            .min(ctx.fuel.unwrap_or(u64::MAX));
+        if instr.fused_len() > left {
+            instr = instr.unfused();
+        }
+        if let Some(fuel) = &mut ctx.fuel {
+            *fuel -= instr.fused_len();
         }
+        executed_instr_count += instr.fused_len();
+        let op = instr.opcode();
+
+        #[cfg(debug_assertions)]
+        trace!(ctx.log, "{:02x?} @ {pc:04} ({func_idx}) :: {:?} :: {:?}", op, &ctx.stack, instr);
 
         ctx.profile.executed_instr_count[op as usize] += 1;
 
-        let start = env.ticks();
-        match op {
-            0x00 => {
-                writeln!(env, "entered unreachable");
+        let mut outcome = ImportOutcome::Return;
+        match instr {
+            Instr::Unreachable => {
+                trace!(ctx.log, "entered unreachable");
                 return Err(InterpreterError::Unreachable);
             }
-            0x02 => {
-                // block
-                let _ty = reader.read_usize()?;
-                frame.curr_loop_start = Some(pos);
-                frame.blocks.push(BlockMeta {
-                    offset: pos,
-                    body_offset: reader.pos(),
-                    kind: BlockType::Block,
-                });
-            }
-            0x03 => {
-                // loop
-                let _ty = reader.read_usize()?;
-                frame.curr_loop_start = Some(pos);
-                frame.blocks.push(BlockMeta {
-                    offset: pos,
-                    body_offset: reader.pos(),
-                    kind: BlockType::Loop,
-                });
+            Instr::Jump { target } => {
+                frame.pc = target as usize;
             }
-            0x04 => {
-                // if
-                let cond = match reader.read::<TypeKind>()? {
-                    TypeKind::Void => todo!(),
-                    TypeKind::Func => todo!(),
-                    TypeKind::FuncRef => todo!(),
-                    TypeKind::F32 => ctx.stack.pop_f32()? != 0.0,
-                    TypeKind::F64 => ctx.stack.pop_f64()? != 0.0,
-                    TypeKind::I32 => ctx.stack.pop_i32()? != 0,
-                    TypeKind::I64 => ctx.stack.pop_i64()? != 0,
-                };
-
-                if !cond {
-                    reader.skip_to(current_func.jump_targets[&pos]);
+            Instr::JumpIfZero { target } => {
+                if ctx.stack.pop_i32()? == 0 {
+                    frame.pc = target as usize;
                 }
             }
-            0x05 => {
-                // else
-                reader.skip_to(current_func.jump_targets[&pos] + 1);
-            }
-            0x0b => {
-                // end
-                if let Some(block) = frame.blocks.pop() {
-                    #[cfg(debug_assertions)]
-                    writeln!(env, "end of {:?}", block.kind);
-                    _ = block;
-                } else {
-                    #[cfg(debug_assertions)]
-                    writeln!(env, "exit function");
-                }
-                continue;
-            }
-            0x0c => {
-                // br
-                let depth = reader.read_usize()?;
-                let target = do_branch(frame, current_func, depth);
-                frame.reader.skip_to(target);
+            Instr::Br(target) => {
+                do_branch(frame, &mut ctx.stack, target, &ctx.interrupt, ctx.deadline, env)?;
                 #[cfg(debug_assertions)]
-                writeln!(env, "taken");
+                trace!(ctx.log, "taken");
             }
-            0x0d => {
-                // br_if
-                let depth = reader.read_usize()?;
+            Instr::BrIf(target) => {
                 if ctx.stack.pop_i32()? != 0 {
-                    let target = do_branch(frame, current_func, depth);
-                    frame.reader.skip_to(target);
+                    do_branch(frame, &mut ctx.stack, target, &ctx.interrupt, ctx.deadline, env)?;
                     #[cfg(debug_assertions)]
-                    writeln!(env, "taken");
+                    trace!(ctx.log, "taken");
                 } else {
                     #[cfg(debug_assertions)]
-                    writeln!(env, "not taken");
+                    trace!(ctx.log, "not taken");
                 }
             }
-            0x0e => {
-                // br_table
-                let val = ctx.stack.pop_i32()?;
-
-                let n = reader.read_usize()?;
-                let mut selected_depth = None;
-                for idx in 0..n {
-                    let depth = reader.read_usize()?;
-                    if idx as i32 == val {
-                        selected_depth = Some(depth);
-                    }
-                }
-                let default_depth = reader.read_usize()?;
-                let depth = selected_depth.unwrap_or(default_depth);
-                let target = do_branch(frame, current_func, depth);
-                frame.reader.skip_to(target);
+            Instr::BrTable { start, len } => {
+                let val = ctx.stack.pop_i32()? as u32;
+                let idx = val.min(len - 1);
+                let target = current_func.br_table[(start + idx) as usize];
+                do_branch(frame, &mut ctx.stack, target, &ctx.interrupt, ctx.deadline, env)?;
                 #[cfg(debug_assertions)]
-                writeln!(env, "taken");
-            }
-            0x0f => {
-                // return
-                reader.skip_to_end();
+                trace!(ctx.log, "taken");
+            }
+            Instr::Return { keep } => {
+                // results take the place of the locals
+                let keep = keep as usize;
+                let results_from = ctx.stack.len() - keep;
+                ctx.stack.unwind(results_from - frame.locals_base, keep);
+                ctx.call_stack.pop();
+                #[cfg(debug_assertions)]
+                trace!(ctx.log, "exit function");
+                // don't care if this is the last call - it will be taken care of before next iteration
             }
-            0x10 => {
-                // call <func_idx>
-                let func_idx = reader.read_usize()?;
-                do_call(ctx, module, func_idx, memory, imports, env);
+            Instr::Call { func_idx } => {
+                outcome = do_call(ctx, module, func_idx as usize, memory, imports, env)?;
             }
-            0x11 => {
-                // call_indirect <func_idx>
-                let _sig_idx = reader.read_usize()?;
-                let _table_idx = reader.read_usize()?;
-
-                let func_idx = ctx.stack.pop_i32()? as usize;
-                do_call(ctx, module, func_idx, memory, imports, env);
+            Instr::CallIndirect { type_idx } => {
+                let idx = ctx.stack.pop_i32()? as u32;
+                let func_idx = indirect_callee(&ctx.tables, module, idx, type_idx)?;
+                outcome = do_call(ctx, module, func_idx, memory, imports, env)?;
             }
-            0x1a => {
-                // drop
-                ctx.stack.pop_i32()?;
+            Instr::Drop => {
+                ctx.stack.pop_slot()?;
             }
-            0x1b => {
-                // select
+            Instr::Select => {
                 let cond = ctx.stack.pop_i32()?;
-                let a = ctx.stack.pop_i32()?;
-                let b = ctx.stack.pop_i32()?;
-                let res = match cond {
-                    0 => a,
-                    _ => b,
-                };
-                ctx.stack.push_i32(res);
+                ctx.stack.select(cond)?;
             }
-            0x20 => {
-                // local.get <local>
-                let local_idx = reader.read_usize()?;
-
-                UntypedMemorySpan::from_slice(
-                    &ctx.locals[frame.locals_offset..]
-                ).push_into(
-                    &mut ctx.stack,
-                    local_idx,
-                    current_func.locals_types[local_idx],
-                    &current_func.locals_offsets,
-                )?;
+            Instr::LocalGet { idx } => {
+                let val = ctx.stack.data[frame.locals_base + idx as usize];
+                ctx.stack.push_slot(val);
             }
-            0x21 => {
-                // local.set <local>
-                let local_idx = reader.read_usize()?;
-                UntypedMemorySpan::from_slice_mut(
-                    &mut ctx.locals[frame.locals_offset..]
-                ).pop_from(
-                    &mut ctx.stack,
-                    local_idx,
-                    current_func.locals_types[local_idx],
-                    &current_func.locals_offsets,
-                )?;
+            Instr::LocalSet { idx } => {
+                let val = ctx.stack.pop_slot()?;
+                ctx.stack.data[frame.locals_base + idx as usize] = val;
             }
-            0x22 => {
-                // local.tee <local>
-                let local_idx = reader.read_usize()?;
-                UntypedMemorySpan::from_slice_mut(
-                    &mut ctx.locals[frame.locals_offset..]
-                ).copy_from(
-                    &mut ctx.stack,
-                    local_idx,
-                    current_func.locals_types[local_idx],
-                    &current_func.locals_offsets,
-                )?;
+            Instr::LocalTee { idx } => {
+                let val = *ctx.stack.data.last().ok_or(InterpreterError::StackTooSmall)?;
+                ctx.stack.data[frame.locals_base + idx as usize] = val;
             }
-            0x23 => {
-                // global.get <global>
-                let global_idx = reader.read_usize()?;
+            Instr::GlobalGet { offset, ty } => {
                 UntypedMemorySpan::from_slice(globals)
-                    .push_into(
-                        &mut ctx.stack,
-                        global_idx,
-                        module.globals[global_idx].kind,
-                        &module.globals_offsets,
-                    )?;
+                    .push_into(&mut ctx.stack, offset as usize, ty)?;
             }
-            0x24 => {
-                // global.set <global>
-                let global_idx = reader.read_usize()?;
+            Instr::GlobalSet { offset, ty } => {
                 UntypedMemorySpan::from_slice_mut(globals)
-                    .pop_from(
-                        &mut ctx.stack,
-                        global_idx,
-                        module.globals[global_idx].kind,
-                        &module.globals_offsets,
-                    )?;
+                    .pop_from(&mut ctx.stack, offset as usize, ty)?;
             }
-            0x28..=0x35 => {
-                // i32.load     0x28
-                // i64.load     0x29
-                // f32.load     0x2a
-                // f64.load     0x2b
-                // i32.load8_s  0x2c
-                // i32.load8_u  0x2d
-                // i32.load16_s 0x2e
-                // i32.load16_u 0x2f
-                // i64.load8_s 	0x30
-                // i64.load8_u 	0x31
-                // i64.load16_s 0x32
-                // i64.load16_u 0x33
-                // i64.load32_s 0x34
-                // i64.load32_u 0x35
-                let _alignment = reader.read_usize()?;
-                let fixed_offset = reader.read_usize()?;
-                let dyn_offset = ctx.stack.pop_i32()?;
-                let offset = fixed_offset.checked_add_signed(dyn_offset as _).unwrap();
-                let mem = Memory::from_slice(memory);
+            Instr::Load { op, offset } => {
+                let addr = ctx.stack.pop_u32()?;
                 #[cfg(debug_assertions)]
-                writeln!(env, "load: mem[{fixed_offset}{dyn_offset:+}]");
-                match op {
-                    0x28 => ctx.stack.push_i32(mem.read_i32(offset).unwrap()),
-                    0x29 => ctx.stack.push_i64(mem.read_i64(offset).unwrap()),
-                    0x2a => ctx.stack.push_f32(mem.read_f32(offset).unwrap()),
-                    0x2b => ctx.stack.push_f64(mem.read_f64(offset).unwrap()),
-                    0x2c => ctx.stack.push_i32(mem.read_i8(offset).unwrap() as i32),
-                    0x2d => ctx.stack.push_i32(mem.read_u8(offset).unwrap() as i32),
-                    0x2e => ctx.stack.push_i32(mem.read_i16(offset).unwrap() as i32),
-                    0x2f => ctx.stack.push_i32(mem.read_u16(offset).unwrap() as i32),
-                    0x30 => ctx.stack.push_i64(mem.read_i8(offset).unwrap() as i64),
-                    0x31 => ctx.stack.push_i64(mem.read_u8(offset).unwrap() as i64),
-                    0x32 => ctx.stack.push_i64(mem.read_i16(offset).unwrap() as i64),
-                    0x33 => ctx.stack.push_i64(mem.read_u16(offset).unwrap() as i64),
-                    0x34 => ctx.stack.push_i64(mem.read_i32(offset).unwrap() as i64),
-                    0x35 => ctx.stack.push_i64(mem.read_u32(offset).unwrap() as i64),
-                    _ => unreachable!(),
-                }
+                trace!(ctx.log, "load: mem[{addr}+{offset}]");
+                ctx.stack.push_slot(numeric::load(memory, op, addr, offset)?);
             }
-            0x36..=0x3e => {
-                // i32.store 	0x36
-                // i64.store 	0x37
-                // f32.store 	0x38
-                // f64.store 	0x39
-                // i32.store8 	0x3a
-                // i32.store16 	0x3b
-                // i64.store8 	0x3c
-                // i64.store16 	0x3d
-                // i64.store32 	0x3e
-                let mem = Memory::from_slice_mut(memory);
-                let _alignment = reader.read_usize()?;
-                let fixed_offset = reader.read_usize()?;
-
-                match op {
-                    0x36 => {
-                        // i32.store
-                        let val = ctx.stack.pop_i32()?;
-                        let dyn_offset = ctx.stack.pop_i32()? as isize;
-                        #[cfg(debug_assertions)]
-                        writeln!(env, "i32.store: mem[{fixed_offset}{dyn_offset:+}] <- {val}");
-                        let offset = fixed_offset.checked_add_signed(dyn_offset).unwrap();
-                        mem.write_i32(offset, val);
-                    }
-                    0x37 => {
-                        // i64.store
-                        let val = ctx.stack.pop_i64()?;
-                        let dyn_offset = ctx.stack.pop_i32()? as isize;
-                        #[cfg(debug_assertions)]
-                        writeln!(env, "i64.store: mem[{fixed_offset}{dyn_offset:+}] <- {val}");
-                        let offset = fixed_offset.checked_add_signed(dyn_offset).unwrap();
-                        mem.write_i64(offset, val);
-                    }
-                    0x38 => {
-                        // f32.store
-                        let val = ctx.stack.pop_f32()?;
-                        let dyn_offset = ctx.stack.pop_i32()? as isize;
-                        #[cfg(debug_assertions)]
-                        writeln!(env, "f32.store: mem[{fixed_offset}{dyn_offset:+}] <- {val}");
-                        let offset = fixed_offset.checked_add_signed(dyn_offset).unwrap();
-                        mem.write_f32(offset, val);
-                    }
-                    0x39 => {
-                        // f64.store
-                        let val = ctx.stack.pop_f64()?;
-                        let dyn_offset = ctx.stack.pop_i32()? as isize;
-                        #[cfg(debug_assertions)]
-                        writeln!(env, "f64.store: mem[{fixed_offset}{dyn_offset:+}] <- {val}");
-                        let offset = fixed_offset.checked_add_signed(dyn_offset).unwrap();
-                        mem.write_f64(offset, val);
-                    }
-                    0x3a => {
-                        // i32.store8
-                        let val = ctx.stack.pop_i32()? as i8;
-                        let idx = ctx.stack.pop_i32()? as isize;
-                        #[cfg(debug_assertions)]
-                        writeln!(env, "i32.store8: mem[{fixed_offset}{idx:+}] <- {val}");
-                        let offset = fixed_offset.checked_add_signed(idx).unwrap();
-                        mem.write_i8(offset, val);
-                    }
-                    0x3b => {
-                        // i32.store16
-                        let val = ctx.stack.pop_i32()? as i16;
-                        let idx = ctx.stack.pop_i32()? as isize;
-                        #[cfg(debug_assertions)]
-                        writeln!(env, "i32.store16: mem[{fixed_offset}{idx:+}] <- {val}");
-                        let offset = fixed_offset.checked_add_signed(idx).unwrap();
-                        mem.write_i16(offset, val);
-                    }
-                    0x3c => {
-                        // i64.store8
-                        let val = ctx.stack.pop_i64()? as i8;
-                        let idx = ctx.stack.pop_i32()? as isize;
-                        #[cfg(debug_assertions)]
-                        writeln!(env, "i64.store8: mem[{fixed_offset}{idx:+}] <- {val}");
-                        let offset = fixed_offset.checked_add_signed(idx).unwrap();
-                        mem.write_i8(offset, val);
-                    }
-                    0x3d => {
-                        // i64.store16
-                        let val = ctx.stack.pop_i64()? as i16;
-                        let idx = ctx.stack.pop_i32()? as isize;
-                        #[cfg(debug_assertions)]
-                        writeln!(env, "i64.store8: mem[{fixed_offset}{idx:+}] <- {val}");
-                        let offset = fixed_offset.checked_add_signed(idx).unwrap();
-                        mem.write_i16(offset, val);
-                    }
-                    0x3e => {
-                        // i64.store32
-                        let val = ctx.stack.pop_i64()? as i32;
-                        let idx = ctx.stack.pop_i32()? as isize;
-                        #[cfg(debug_assertions)]
-                        writeln!(env, "i64.store8: mem[{fixed_offset}{idx:+}] <- {val}");
-                        let offset = fixed_offset.checked_add_signed(idx).unwrap();
-                        mem.write_i32(offset, val);
-                    }
-                    _ => unreachable!(),
+            Instr::Store { op, offset } => {
+                let val = ctx.stack.pop_slot()?;
+                let addr = ctx.stack.pop_u32()?;
+                #[cfg(debug_assertions)]
+                trace!(ctx.log, "store: mem[{addr}+{offset}] <- {val:#x}");
+                numeric::store(memory, op, addr, offset, val)?;
+            }
+            Instr::MemorySize => ctx.stack.push_slot(numeric::memory_size(memory)),
+            Instr::MemoryGrow => {
+                let delta = ctx.stack.pop_u32()?;
+                ctx.stack.push_slot(numeric::memory_grow(memory, delta));
+            }
+            Instr::MemoryCopy => {
+                let len = ctx.stack.pop_u32()?;
+                let src = ctx.stack.pop_u32()?;
+                let dst = ctx.stack.pop_u32()?;
+                numeric::memory_copy(memory, dst, src, len)?;
+            }
+            Instr::MemoryFill => {
+                let len = ctx.stack.pop_u32()?;
+                let val = ctx.stack.pop_u32()?;
+                let dst = ctx.stack.pop_u32()?;
+                numeric::memory_fill(memory, dst, val, len)?;
+            }
+            Instr::I32Const(val) => ctx.stack.push_i32(val),
+            Instr::I64Const(val) => ctx.stack.push_i64(val),
+            Instr::F32Const(val) => ctx.stack.push_f32(val),
+            Instr::F64Const(val) => ctx.stack.push_f64(val),
+            Instr::I32Eqz
+            | Instr::I64Eqz
+            | Instr::I32WrapI64
+            | Instr::I64ExtendI32U
+            | Instr::F32ReinterpretI32
+            | Instr::I32Extend8S
+            | Instr::Unary { .. } => {
+                let a = ctx.stack.pop_slot()?;
+                ctx.stack.push_slot(numeric::unary(op, a)?);
+            }
+            Instr::TruncSat { op } => {
+                let a = ctx.stack.pop_slot()?;
+                ctx.stack.push_slot(numeric::trunc_sat(op, a));
+            }
+            Instr::I32Eq
+            | Instr::I32Ne
+            | Instr::I32LtS
+            | Instr::I32LtU
+            | Instr::I32GtS
+            | Instr::I32GtU
+            | Instr::I32LeS
+            | Instr::I32LeU
+            | Instr::I32GeS
+            | Instr::I32GeU
+            | Instr::I64Ne
+            | Instr::I64LtU
+            | Instr::I64GtU
+            | Instr::I64GeU
+            | Instr::F64Lt
+            | Instr::I32Add
+            | Instr::I32Sub
+            | Instr::I32Mul
+            | Instr::I32DivS
+            | Instr::I32DivU
+            | Instr::I32RemU
+            | Instr::I32And
+            | Instr::I32Or
+            | Instr::I32Xor
+            | Instr::I32Shl
+            | Instr::I32ShrU
+            | Instr::I64Add
+            | Instr::I64Mul
+            | Instr::I64DivU
+            | Instr::I64RemU
+            | Instr::I64And
+            | Instr::I64Or
+            | Instr::I64Shl
+            | Instr::I64ShrU
+            | Instr::F32Add
+            | Instr::F64Sub
+            | Instr::F64Mul
+            | Instr::Binary { .. } => {
+                let b = ctx.stack.pop_slot()?;
+                let a = ctx.stack.pop_slot()?;
+                ctx.stack.push_slot(numeric::binary(op, a, b)?);
+            }
+            Instr::LocalI32AddConst { idx, val } => {
+                ctx.profile.superinstruction_hits[Superinstruction::LocalI32AddConst as usize] += 1;
+                frame.pc = pc + 3;
+                let local = ctx.stack.data[frame.locals_base + idx as usize] as u32 as i32;
+                ctx.stack.push_i32(local.wrapping_add(val));
+            }
+            Instr::LocalsLtUBrIf { a, b } => {
+                ctx.profile.superinstruction_hits[Superinstruction::LocalsLtUBrIf as usize] += 1;
+                frame.pc = pc + 4;
+                let locals = &ctx.stack.data[frame.locals_base..];
+                if (locals[a as usize] as u32) < (locals[b as usize] as u32) {
+                    let Instr::BrIf(target) = current_func.instrs[pc + 3] else {
+                        unreachable!("fused with a br_if");
+                    };
+                    do_branch(frame, &mut ctx.stack, target, &ctx.interrupt, ctx.deadline, env)?;
                 }
             }
-            0x41 => {
-                // i32.const <literal>
-                let val = reader.read_isize()?;
-                ctx.stack.push_i32(i32::try_from(val).unwrap());
-            }
-            0x42 => {
-                // i64.const <literal>
-                let val = reader.read_usize()?;
-                ctx.stack.push_i64(i64::try_from(val).unwrap());
-            }
-            0x43 => {
-                // f32.const <literal>
-                let val = reader.read_f32()?;
-                ctx.stack.push_f32(val);
+            Instr::LocalLoad { idx, op, offset } => {
+                ctx.profile.superinstruction_hits[Superinstruction::LocalLoad as usize] += 1;
+                frame.pc = pc + 2;
+                let addr = ctx.stack.data[frame.locals_base + idx as usize] as u32;
+                ctx.stack.push_slot(numeric::load(memory, op, addr, offset)?);
             }
-            0x44 => {
-                // f64.const <literal>
-                let val = reader.read_f64()?;
-                ctx.stack.push_f64(val);
-            }
-            0x45 => {
-                // i32.eqz
-                ctx.stack.inplace_unary_op(|a: i32| a == 0)?;
-            }
-            0x46 => {
-                // i32.eq
-                ctx.stack.inplace_bin_op(|a: i32, b: i32| a == b)?;
-            }
-            0x47 => {
-                // i32.ne
-                ctx.stack.inplace_bin_op(|a: i32, b: i32| a != b)?;
-            }
-            0x48 => {
-                // i32.lt_s
-                ctx.stack.inplace_bin_op(|a: i32, b: i32| a < b)?;
-            }
-            0x49 => {
-                // i32.lt_u
-                ctx.stack.inplace_bin_op(|a: u32, b: u32| a < b)?;
-            }
-            0x4a => {
-                // i32.le_s
-                ctx.stack.inplace_bin_op(|a: i32, b: i32| a <= b)?;
-            }
-            0x4b => {
-                // i32.gt_s
-                ctx.stack.inplace_bin_op(|a: i32, b: i32| a > b)?;
-            }
-            0x4c => {
-                // i32.gt_u
-                ctx.stack.inplace_bin_op(|a: u32, b: u32| a > b)?;
-            }
-            0x4d => {
-                // i32.le_u
-                ctx.stack.inplace_bin_op(|a: u32, b: u32| a <= b)?;
-            }
-            0x4e => {
-                // i32.ge_s
-                ctx.stack.inplace_bin_op(|a: i32, b: i32| a >= b)?;
-            }
-            0x4f => {
-                // i32.ge_u
-                ctx.stack.inplace_bin_op(|a: u32, b: u32| a >= b)?;
-            }
-            0x50 => {
-                // i64.eqz
-                ctx.stack.inplace_unary_op(|a: i64| a == 0)?;
-            }
-            0x52 => {
-                // i64.ne
-                ctx.stack.inplace_bin_op(|a: i64, b: i64| a != b)?;
-            }
-            0x54 => {
-                // i64.lt_u
-                ctx.stack.inplace_bin_op(|a: u64, b: u64| a < b)?;
-            }
-            0x56 => {
-                // i64.gt_u
-                ctx.stack.inplace_bin_op(|a: u64, b: u64| a > b)?;
-            }
-            0x5a => {
-                // i64.ge_u
-                ctx.stack.inplace_bin_op(|a: u64, b: u64| a >= b)?;
-            }
-            0x63 => {
-                // f64.lt
-                ctx.stack.inplace_bin_op(|a: f64, b: f64| (a < b) as i32 as f64)?;
-            }
-            0x6a => {
-                // i32.add
-                ctx.stack.inplace_bin_op(|a: i32, b: i32| a + b)?;
-            }
-            0x6b => {
-                // i32.sub
-                ctx.stack.inplace_bin_op(|a: i32, b: i32| a - b)?;
-            }
-            0x6c => {
-                // i32.mul
-                ctx.stack.inplace_bin_op(|a: i32, b: i32| a.wrapping_mul(b))?;
-            }
-            0x6d => {
-                // i32.div_s
-                ctx.stack.inplace_bin_op(|a: i32, b: i32| a / b)?;
-            }
-            0x6e => {
-                // i32.div_u
-                ctx.stack.inplace_bin_op(|a: u32, b: u32| a / b)?;
-            }
-            0x70 => {
-                // i32.rem_u
-                ctx.stack.inplace_bin_op(|a: u32, b: u32| a % b)?;
-            }
-            0x71 => {
-                // i32.and
-                ctx.stack.inplace_bin_op(|a: i32, b: i32| a & b)?;
-            }
-            0x72 => {
-                // i32.or
-                ctx.stack.inplace_bin_op(|a: i32, b: i32| a | b)?;
-            }
-            0x73 => {
-                // i32.xor
-                ctx.stack.inplace_bin_op(|a: i32, b: i32| a ^ b)?;
-            }
-            0x74 => {
-                // i32.shl
-                ctx.stack.inplace_bin_op(|a: i32, b: i32| a << b)?;
-            }
-            0x76 => {
-                // i32.shr_u
-                ctx.stack.inplace_bin_op(|a: i32, b: i32| a >> b)?;
-            }
-            0x7c => {
-                // i64.add
-                ctx.stack.inplace_bin_op(|a: i64, b: i64| a + b)?;
-            }
-            0x7e => {
-                // i64.mul
-                ctx.stack.inplace_bin_op(|a: i64, b: i64| a * b)?;
-            }
-            0x80 => {
-                // i64.div_u
-                ctx.stack.inplace_bin_op(|a: u64, b: u64| a / b)?;
-            }
-            0x82 => {
-                // i64.rem_u
-                ctx.stack.inplace_bin_op(|a: u64, b: u64| a % b)?;
-            }
-            0x83 => {
-                // i64.and
-                ctx.stack.inplace_bin_op(|a: i64, b: i64| a & b)?;
-            }
-            0x84 => {
-                // i64.or
-                ctx.stack.inplace_bin_op(|a: i64, b: i64| a | b)?;
-            }
-            0x86 => {
-                // i64.shl
-                ctx.stack.inplace_bin_op(|a: i64, b: i64| a << b)?;
-            }
-            0x88 => {
-                // i64.shr_u
-                ctx.stack.inplace_bin_op(|a: u64, b: u64| a >> b)?;
-            }
-            0x92 => {
-                // f32.add
-                ctx.stack.inplace_bin_op(|a: f32, b: f32| a + b)?;
-            }
-            0xa1 => {
-                // f64.sub
-                ctx.stack.inplace_bin_op(|a: f64, b: f64| a - b)?;
-            }
-            0xa2 => {
-                // f64.mul
-                ctx.stack.inplace_bin_op(|a: f64, b: f64| a * b)?;
-            }
-            0xa7 => {
-                // i32.wrap_i64
-                ctx.stack.inplace_unary_op(|a: i64| i32::try_from(a & 0xffffffff).unwrap())?;
-            }
-            0xad => {
-                // i64.extend_i32_u
-                ctx.stack.inplace_unary_op(|a: i32| i64::from(a))?;
-            }
-            0xbe => {
-                // f32.reinterpret_i32
-                ctx.stack.inplace_unary_op(|a: i32| f32::from_ne_bytes(a.to_ne_bytes()))?;
-            }
-            0xc0 => {
-                // i32.extend8_s
-                ctx.stack.inplace_unary_op(|a: i32| a)?;
-            }
-            _ => todo!("opcode {:02x?}", op),
         }
 
         ctx.profile.executed_instr_time[op as usize] += env.ticks() - start;
+
+        if ctx.stack.overflowed {
+            return Err(InterpreterError::StackOverflow);
+        }
+
+        match outcome {
+            ImportOutcome::Return => {}
+            ImportOutcome::Yield => return Ok(Execution::Paused(PauseReason::Yield)),
+            ImportOutcome::Sleep { until } => return Ok(Execution::Paused(PauseReason::Sleep { until })),
+            ImportOutcome::Pending => {
+                let func_idx = ctx.pending_import.expect("set by do_call");
+                return Ok(Execution::Paused(PauseReason::ImportPending { func_idx }));
+            }
+        }
     }
 
-    Ok(())
+    Ok(Execution::Finished)
 }
 
 fn do_call<'code, TEnv: Environment>(
@@ -1275,38 +1639,86 @@ fn do_call<'code, TEnv: Environment>(
     module: &'code WasmModule,
     func_idx: usize,
     memory: &mut [u8],
-    imports: &[ImportedFunc<TEnv>],
+    imports: &mut [HostFunc<'_, '_, TEnv>],
     env: &mut TEnv
-) {
-    if let Some(callee) = module.get_function_by_index(func_idx) {
-        #[cfg(debug_assertions)]
-        writeln!(env, "calling function {}", func_idx);
-        ctx.call_stack.push(StackFrame {
-            func_idx,
-            reader: Reader::new(callee.code),
-            locals_offset: ctx.locals.len(),
-            curr_loop_start: None,
-            blocks: Vec::new(),
-        });
-        let params_mem = &ctx.stack.data[ctx.stack.data.len() - callee.params_len_in_bytes..];
-        copy_params_and_locals(&mut ctx.locals, params_mem, callee);
-        ctx.stack.pop_many(callee.params_len_in_bytes);
+) -> Result<ImportOutcome, InterpreterError> {
+    poll_stop_requests(&ctx.interrupt, ctx.deadline, env)?;
+
+    if module.get_function_by_index(func_idx).is_some() {
+        debug!(ctx.log, "calling function {}", func_idx);
+        push_frame(ctx, module, func_idx)?;
+        Ok(ImportOutcome::Return)
     } else {
-        #[cfg(debug_assertions)]
-        writeln!(env, "calling imported function {}", func_idx);
-        imports[func_idx](env, &mut ctx.stack, memory);
+        debug!(ctx.log, "calling imported function {}", func_idx);
+        let outcome = imports[func_idx].call(env, &mut ctx.stack, memory, ctx.data.as_deref_mut())?;
+        if outcome == ImportOutcome::Pending {
+            ctx.pending_import = Some(func_idx);
+            ctx.pending_results = signature_results(module, func_idx);
+        }
+        Ok(outcome)
     }
 }
 
-fn do_branch(frame: &mut StackFrame, current_func: &FuncBody, depth: usize) -> usize {
-    let block_idx = frame.blocks.len() - 1 - depth;
-    let block = &frame.blocks[block_idx];
-    let target = match block.kind {
-        BlockType::Block => current_func.jump_targets[&block.offset] - 1,
-        BlockType::Loop => block.body_offset
+/// Function in the element at `idx` of the first table, called by `call_indirect` with the signature `type_idx`.
+/// Contexts that aren't owned by an [`crate::Instance`] have no tables, the element is looked up
+/// in the segments of the module instead.
+pub(crate) fn indirect_callee(tables: &[Vec<Option<u32>>], module: &WasmModule, idx: u32, type_idx: u32) -> Result<usize, InterpreterError> {
+    let idx = idx as usize;
+    let element = match tables.first() {
+        Some(table) => table.get(idx).copied().flatten(),
+        // later segments overwrite the earlier ones
+        None => module.elem_segments.iter().rev().find_map(|segment| {
+            let offset = segment_offset(segment.offset.code, module, &[]).ok()?;
+            segment.functions.get(idx.checked_sub(offset)?).copied()
+        }),
     };
-    // skip blocks that we are no longer executing due to the jump
-    // TODO: check if this is correct
-    frame.blocks.drain(block_idx + 1..);
-    target
+    let func_idx = element.ok_or(InterpreterError::UndefinedElement)? as usize;
+    let expected = &module.signatures[type_idx as usize];
+    match module.get_function_signature(func_idx) {
+        Some(found) if found.params() == expected.params() && found.results() == expected.results() => Ok(func_idx),
+        _ => Err(InterpreterError::IndirectCallTypeMismatch),
+    }
+}
+
+pub(crate) fn signature_results<'code>(module: &'code WasmModule<'code>, func_idx: usize) -> &'code [TypeKind] {
+    module.get_function_signature(func_idx).map_or(&[], FuncSignature::results)
+}
+
+/// Pushes a frame of the function whose arguments are on top of the stack, they become its first locals.
+#[inline]
+fn push_frame<'code>(
+    ctx: &mut VmContext<'code>,
+    module: &'code WasmModule<'code>,
+    func_idx: usize,
+) -> Result<(), InterpreterError> {
+    ctx.code_cache.prepare(module, func_idx, &ctx.call_stack)?;
+    let func = ctx.code_cache.get(module, func_idx);
+    let params = module.get_function_by_index(func_idx)
+        .expect("function existed at time of the call")
+        .signature.params.len();
+    let locals_base = ctx.stack.len().checked_sub(params)
+        .ok_or(InterpreterError::StackTooSmall)?;
+    ctx.stack.data.resize(locals_base + func.locals_len, 0)
+        .map_err(|_| InterpreterError::StackOverflow)?;
+    ctx.call_stack.push(StackFrame { func_idx, pc: 0, locals_base })
+        .map_err(|_| InterpreterError::StackOverflow)
+}
+
+#[inline]
+fn do_branch(
+    frame: &mut StackFrame,
+    stack: &mut VmStack,
+    target: BrTarget,
+    interrupt: &Option<InterruptHandle>,
+    deadline: Option<u64>,
+    env: &impl Environment,
+) -> Result<(), InterpreterError> {
+    let target_pc = target.target as usize;
+    // only loops can run forever, so it's enough to look for stop requests on backward branches
+    if target_pc < frame.pc {
+        poll_stop_requests(interrupt, deadline, env)?;
+    }
+    stack.unwind(target.drop as usize, target.keep as usize);
+    frame.pc = target_pc;
+    Ok(())
 }
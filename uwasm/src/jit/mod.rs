@@ -0,0 +1,300 @@
+//! Copy-and-patch compiler for the code of the register engine.
+//!
+//! Every instruction is turned into machine code by copying short precompiled pieces of code (stencils)
+//! and patching registers, immediates and jump targets into their holes. Instructions without a stencil
+//! get an exit that returns their index, so the interpreter executes them and enters the native code
+//! again at the next instruction. Calls, returns and backward branches always go through the interpreter,
+//! which keeps checking for interrupts, deadlines and time slices as usual.
+
+use alloc::vec::Vec;
+
+use crate::register::RegFunc;
+
+// also built for tests on the host, which check the encoding of the stencils
+#[cfg(any(target_arch = "riscv32", test))]
+#[cfg_attr(not(target_arch = "riscv32"), allow(dead_code))]
+mod riscv32;
+#[cfg(target_arch = "x86_64")]
+mod x86_64;
+
+#[cfg(target_arch = "riscv32")]
+use riscv32 as arch;
+#[cfg(target_arch = "x86_64")]
+use x86_64 as arch;
+
+/// Number of instructions of a function executed by the interpreter before it gets compiled.
+const HOT_INSTRUCTIONS: u32 = 1000;
+
+/// Default size of the code memory mapped when none has been given with [`crate::VmContext::set_code_memory`].
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+const DEFAULT_CODE_MEMORY: usize = 1024 * 1024;
+
+/// Memory for the machine code generated by the JIT.
+pub struct CodeMemory {
+    // the code may be written and executed through different addresses of the same memory
+    write: *mut u8,
+    exec: *const u8,
+    len: usize,
+    used: usize,
+    // allocated with `mmap`, so it has to be protected while written and unmapped on drop
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    mapped: bool,
+}
+
+// SAFETY: the memory is owned by `CodeMemory` and never shared with anything else
+unsafe impl Send for CodeMemory {}
+
+impl CodeMemory {
+    /// Maps `len` bytes of memory that is made executable once the code is written.
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    pub fn map(len: usize) -> Option<Self> {
+        // SAFETY: asks for a new mapping, nothing else is affected
+        let ptr = unsafe {
+            linux::mmap(core::ptr::null_mut(), len, linux::PROT_READ, linux::MAP_PRIVATE | linux::MAP_ANONYMOUS, -1, 0)
+        };
+        if ptr == linux::MAP_FAILED {
+            return None;
+        }
+        Some(Self {
+            write: ptr.cast(),
+            exec: ptr.cast(),
+            len,
+            used: 0,
+            mapped: true,
+        })
+    }
+
+    /// Uses memory that is already executable, e.g. SRAM of ESP32-C3 written through the data bus
+    /// at `write` and executed through the instruction bus at `exec`.
+    ///
+    /// # Safety
+    /// Both pointers must refer to the same `len` bytes, which are valid for writes and execution for
+    /// as long as the `CodeMemory` lives, and written code must be visible to instruction fetches
+    /// without any cache maintenance.
+    pub unsafe fn from_raw_parts(write: *mut u8, exec: *const u8, len: usize) -> Self {
+        Self {
+            write,
+            exec,
+            len,
+            used: 0,
+            #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+            mapped: false,
+        }
+    }
+
+    /// Copies the code into the memory and returns the address it can be executed from.
+    fn push(&mut self, code: &[u8]) -> Option<*const u8> {
+        let start = self.used.next_multiple_of(16);
+        let end = start.checked_add(code.len()).filter(|&end| end <= self.len)?;
+        self.set_writable(true)?;
+        // SAFETY: `start..end` is within the memory and not used by any code yet
+        unsafe { core::ptr::copy_nonoverlapping(code.as_ptr(), self.write.add(start), code.len()) };
+        self.set_writable(false)?;
+        self.used = end;
+        // SAFETY: as above
+        Some(unsafe { self.exec.add(start) })
+    }
+
+    /// Frees the space taken by all the code, which must not be executed anymore.
+    fn clear(&mut self) {
+        self.used = 0;
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    fn set_writable(&mut self, writable: bool) -> Option<()> {
+        if !self.mapped {
+            return Some(());
+        }
+        let prot = if writable {
+            linux::PROT_READ | linux::PROT_WRITE
+        } else {
+            linux::PROT_READ | linux::PROT_EXEC
+        };
+        // SAFETY: changes only the protection of the memory mapped by `map`
+        let result = unsafe { linux::mprotect(self.write.cast(), self.len, prot) };
+        (result == 0).then_some(())
+    }
+
+    #[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+    fn set_writable(&mut self, _writable: bool) -> Option<()> {
+        Some(())
+    }
+}
+
+impl Drop for CodeMemory {
+    fn drop(&mut self) {
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        if self.mapped {
+            // SAFETY: the memory has been mapped by `map` and no code can run from it anymore
+            unsafe { linux::munmap(self.write.cast(), self.len) };
+        }
+    }
+}
+
+impl core::fmt::Debug for CodeMemory {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("CodeMemory")
+            .field("len", &self.len)
+            .field("used", &self.used)
+            .finish()
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod linux {
+    use core::ffi::{c_int, c_long, c_void};
+
+    pub(super) const PROT_READ: c_int = 1;
+    pub(super) const PROT_WRITE: c_int = 2;
+    pub(super) const PROT_EXEC: c_int = 4;
+    pub(super) const MAP_PRIVATE: c_int = 2;
+    pub(super) const MAP_ANONYMOUS: c_int = 0x20;
+    pub(super) const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+    extern "C" {
+        pub(super) fn mmap(addr: *mut c_void, len: usize, prot: c_int, flags: c_int, fd: c_int, offset: c_long) -> *mut c_void;
+        pub(super) fn mprotect(addr: *mut c_void, len: usize, prot: c_int) -> c_int;
+        pub(super) fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    }
+}
+
+/// Values patched into the holes of stencils.
+#[derive(Default)]
+struct Operands {
+    a: u16,
+    b: u16,
+    c: u16,
+    dst: u16,
+    imm: u64,
+    // index of the instruction, returned by exits
+    pc: u32,
+    // index of the instruction that a jump goes to
+    target: u32,
+}
+
+#[derive(Default)]
+struct Emitter {
+    code: Vec<u8>,
+    // holes for jumps that are filled in once the code of all instructions is known
+    fixups: Vec<(usize, u32)>,
+}
+
+struct NativeFunc {
+    entry: *const u8,
+    // offset of the code of each instruction, `None` for the ones left to the interpreter
+    offsets: Vec<Option<u32>>,
+}
+
+enum NativeState {
+    Cold { executed: u32 },
+    Compiled(NativeFunc),
+    // has no stencils for the target or the code memory is full
+    Failed,
+}
+
+/// State of the JIT kept by the register engine.
+pub(crate) struct Jit {
+    memory: Option<CodeMemory>,
+    funcs: Vec<NativeState>,
+}
+
+impl Jit {
+    pub(crate) fn new() -> Self {
+        Self {
+            memory: None,
+            funcs: Vec::new(),
+        }
+    }
+
+    pub(crate) fn set_memory(&mut self, memory: CodeMemory) {
+        self.memory = Some(memory);
+        self.reset(self.funcs.len());
+    }
+
+    /// Forgets all the code, called when the functions are translated for another module.
+    pub(crate) fn reset(&mut self, funcs: usize) {
+        self.funcs.clear();
+        self.funcs.resize_with(funcs, || NativeState::Cold { executed: 0 });
+        if let Some(memory) = &mut self.memory {
+            memory.clear();
+        }
+    }
+
+    /// Runs native code of the function starting from instruction `pc`.
+    ///
+    /// Returns index of the instruction that the interpreter has to execute next, or `None`
+    /// when there is no native code for `pc` and the interpreter should execute it.
+    #[inline]
+    pub(crate) fn run(&mut self, func_idx: usize, func: &RegFunc, pc: u32, regs: &mut [u64], memory: &mut [u8]) -> Option<u32> {
+        match &mut self.funcs[func_idx] {
+            NativeState::Compiled(native) => {
+                let offset = native.offsets[pc as usize]?;
+                // SAFETY: the code has been compiled for this function, whose frame is in `regs`
+                return Some(unsafe { run_native(native.entry.add(offset as usize), regs, memory) });
+            }
+            NativeState::Cold { executed } => {
+                *executed += 1;
+                if *executed < HOT_INSTRUCTIONS {
+                    return None;
+                }
+            }
+            NativeState::Failed => return None,
+        }
+        self.funcs[func_idx] = match self.compile(func) {
+            Some(native) => NativeState::Compiled(native),
+            None => NativeState::Failed,
+        };
+        None
+    }
+
+    fn compile(&mut self, func: &RegFunc) -> Option<NativeFunc> {
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        if self.memory.is_none() {
+            self.memory = CodeMemory::map(DEFAULT_CODE_MEMORY);
+        }
+        let memory = self.memory.as_mut()?;
+
+        let mut emitter = Emitter::default();
+        // start of the code of each instruction, including the ones that only exit
+        let mut starts = Vec::with_capacity(func.code.len());
+        let mut offsets = Vec::with_capacity(func.code.len());
+        for (pc, instr) in func.code.iter().enumerate() {
+            let start = emitter.code.len();
+            starts.push(start);
+            if emit(&mut emitter, instr, pc as u32) {
+                offsets.push(Some(start as u32));
+            } else {
+                exit(&mut emitter, pc as u32);
+                offsets.push(None);
+            }
+        }
+        if offsets.iter().all(Option::is_none) {
+            return None;
+        }
+        for &(at, target) in &emitter.fixups {
+            link(&mut emitter.code, at, starts[target as usize]);
+        }
+
+        let entry = memory.push(&emitter.code)?;
+        Some(NativeFunc { entry, offsets })
+    }
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "riscv32"))]
+use arch::{emit, exit, link, run_native};
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "riscv32")))]
+fn emit(_emitter: &mut Emitter, _instr: &crate::register::RegInstr, _pc: u32) -> bool {
+    false
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "riscv32")))]
+fn exit(_emitter: &mut Emitter, _pc: u32) {}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "riscv32")))]
+fn link(_code: &mut [u8], _at: usize, _target: usize) {}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "riscv32")))]
+unsafe fn run_native(_entry: *const u8, _regs: &mut [u64], _memory: &mut [u8]) -> u32 {
+    unreachable!("no code is compiled for this target")
+}
@@ -0,0 +1,519 @@
+//! Stencils for RV32IM, e.g. ESP32-C3.
+//!
+//! Native code is called with the standard calling convention: registers of the frame are in `a0`,
+//! the linear memory in `a1` and its length in `a2`. It returns in `a0` the index of the instruction
+//! that the interpreter continues with. Only the temporary registers `t0`-`t6` are used as scratch.
+//! Every 64-bit register of the frame is accessed as two words, the lower one first.
+
+use super::{Emitter, Operands};
+use crate::register::{RegBranch, RegInstr};
+
+const ZERO: u32 = 0;
+const RA: u32 = 1;
+const T0: u32 = 5;
+const T1: u32 = 6;
+const T2: u32 = 7;
+const A0: u32 = 10;
+const A1: u32 = 11;
+const A2: u32 = 12;
+const T3: u32 = 28;
+const T4: u32 = 29;
+const T5: u32 = 30;
+const T6: u32 = 31;
+
+const fn r_type(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | 0x33
+}
+
+const fn i_type(imm: i32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    ((imm as u32) & 0xfff) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+const fn s_type(imm: i32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    let imm = imm as u32;
+    ((imm >> 5) & 0x7f) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 0x1f) << 7 | 0x23
+}
+
+const fn b_type(imm: i32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    let imm = imm as u32;
+    ((imm >> 12) & 1) << 31 | ((imm >> 5) & 0x3f) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12
+        | ((imm >> 1) & 0xf) << 8 | ((imm >> 11) & 1) << 7 | 0x63
+}
+
+const fn j_type(imm: i32, rd: u32) -> u32 {
+    let imm = imm as u32;
+    ((imm >> 20) & 1) << 31 | ((imm >> 1) & 0x3ff) << 21 | ((imm >> 11) & 1) << 20 | ((imm >> 12) & 0xff) << 12
+        | rd << 7 | 0x6f
+}
+
+const fn lb(rd: u32, rs1: u32, imm: i32) -> u32 { i_type(imm, rs1, 0, rd, 0x03) }
+const fn lh(rd: u32, rs1: u32, imm: i32) -> u32 { i_type(imm, rs1, 1, rd, 0x03) }
+const fn lw(rd: u32, rs1: u32, imm: i32) -> u32 { i_type(imm, rs1, 2, rd, 0x03) }
+const fn lbu(rd: u32, rs1: u32, imm: i32) -> u32 { i_type(imm, rs1, 4, rd, 0x03) }
+const fn lhu(rd: u32, rs1: u32, imm: i32) -> u32 { i_type(imm, rs1, 5, rd, 0x03) }
+const fn sb(rs2: u32, rs1: u32, imm: i32) -> u32 { s_type(imm, rs2, rs1, 0) }
+const fn sh(rs2: u32, rs1: u32, imm: i32) -> u32 { s_type(imm, rs2, rs1, 1) }
+const fn sw(rs2: u32, rs1: u32, imm: i32) -> u32 { s_type(imm, rs2, rs1, 2) }
+const fn addi(rd: u32, rs1: u32, imm: i32) -> u32 { i_type(imm, rs1, 0, rd, 0x13) }
+const fn sltiu(rd: u32, rs1: u32, imm: i32) -> u32 { i_type(imm, rs1, 3, rd, 0x13) }
+const fn xori(rd: u32, rs1: u32, imm: i32) -> u32 { i_type(imm, rs1, 4, rd, 0x13) }
+const fn andi(rd: u32, rs1: u32, imm: i32) -> u32 { i_type(imm, rs1, 7, rd, 0x13) }
+const fn slli(rd: u32, rs1: u32, shamt: i32) -> u32 { i_type(shamt, rs1, 1, rd, 0x13) }
+const fn srai(rd: u32, rs1: u32, shamt: i32) -> u32 { i_type(0x400 | shamt, rs1, 5, rd, 0x13) }
+const fn add(rd: u32, rs1: u32, rs2: u32) -> u32 { r_type(0, rs2, rs1, 0, rd) }
+const fn sub(rd: u32, rs1: u32, rs2: u32) -> u32 { r_type(0x20, rs2, rs1, 0, rd) }
+const fn sll(rd: u32, rs1: u32, rs2: u32) -> u32 { r_type(0, rs2, rs1, 1, rd) }
+const fn slt(rd: u32, rs1: u32, rs2: u32) -> u32 { r_type(0, rs2, rs1, 2, rd) }
+const fn sltu(rd: u32, rs1: u32, rs2: u32) -> u32 { r_type(0, rs2, rs1, 3, rd) }
+const fn xor(rd: u32, rs1: u32, rs2: u32) -> u32 { r_type(0, rs2, rs1, 4, rd) }
+const fn srl(rd: u32, rs1: u32, rs2: u32) -> u32 { r_type(0, rs2, rs1, 5, rd) }
+const fn or(rd: u32, rs1: u32, rs2: u32) -> u32 { r_type(0, rs2, rs1, 6, rd) }
+const fn and(rd: u32, rs1: u32, rs2: u32) -> u32 { r_type(0, rs2, rs1, 7, rd) }
+const fn mul(rd: u32, rs1: u32, rs2: u32) -> u32 { r_type(1, rs2, rs1, 0, rd) }
+const fn mulhu(rd: u32, rs1: u32, rs2: u32) -> u32 { r_type(1, rs2, rs1, 3, rd) }
+const fn lui(rd: u32) -> u32 { rd << 7 | 0x37 }
+const fn beq(rs1: u32, rs2: u32, imm: i32) -> u32 { b_type(imm, rs2, rs1, 0) }
+const fn bne(rs1: u32, rs2: u32, imm: i32) -> u32 { b_type(imm, rs2, rs1, 1) }
+const fn bltu(rs1: u32, rs2: u32, imm: i32) -> u32 { b_type(imm, rs2, rs1, 6) }
+const fn jal(rd: u32, imm: i32) -> u32 { j_type(imm, rd) }
+const fn ret() -> u32 { i_type(0, RA, 0, ZERO, 0x67) }
+
+#[derive(Clone, Copy)]
+enum Value {
+    /// Offset of the lower word of a register in the frame.
+    A,
+    B,
+    C,
+    Dst,
+    /// Offset of the upper word of a register in the frame.
+    AHi,
+    BHi,
+    DstHi,
+    /// Lower word of the immediate.
+    Imm,
+    /// Upper word of the immediate.
+    ImmHi,
+    /// Mask of the address bits that must be zero for an aligned access.
+    Align,
+    Pc,
+    /// Jump to the instruction `target`.
+    Target,
+}
+
+#[derive(Clone, Copy)]
+enum Field {
+    /// 12-bit immediate of I-type instructions, also used for the lower part of `lui`/`addi` pairs.
+    I,
+    /// 12-bit immediate of S-type instructions.
+    S,
+    /// Upper 20 bits of `lui`, rounded so the sign-extended immediate of the following `addi` adds up.
+    U,
+    /// Offset of `jal`.
+    J,
+}
+
+struct Stencil {
+    code: &'static [u32],
+    holes: &'static [(usize, Value, Field)],
+}
+
+const LOAD_A: Stencil = Stencil { code: &[lw(T0, A0, 0)], holes: &[(0, Value::A, Field::I)] };
+const LOAD_AB: Stencil = Stencil {
+    code: &[lw(T0, A0, 0), lw(T1, A0, 0)],
+    holes: &[(0, Value::A, Field::I), (1, Value::B, Field::I)],
+};
+const LOAD64_A: Stencil = Stencil {
+    code: &[lw(T0, A0, 0), lw(T1, A0, 0)],
+    holes: &[(0, Value::A, Field::I), (1, Value::AHi, Field::I)],
+};
+const LOAD64_AB: Stencil = Stencil {
+    code: &[lw(T0, A0, 0), lw(T1, A0, 0), lw(T2, A0, 0), lw(T3, A0, 0)],
+    holes: &[(0, Value::A, Field::I), (1, Value::AHi, Field::I), (2, Value::B, Field::I), (3, Value::BHi, Field::I)],
+};
+// stores `t0` and zero-extends it
+const STORE32: Stencil = Stencil {
+    code: &[sw(T0, A0, 0), sw(ZERO, A0, 0)],
+    holes: &[(0, Value::Dst, Field::S), (1, Value::DstHi, Field::S)],
+};
+// stores `t1:t0`
+const STORE64: Stencil = Stencil {
+    code: &[sw(T0, A0, 0), sw(T1, A0, 0)],
+    holes: &[(0, Value::Dst, Field::S), (1, Value::DstHi, Field::S)],
+};
+const CONST: Stencil = Stencil {
+    code: &[lui(T0), addi(T0, T0, 0), sw(T0, A0, 0), lui(T0), addi(T0, T0, 0), sw(T0, A0, 0)],
+    holes: &[
+        (0, Value::Imm, Field::U), (1, Value::Imm, Field::I), (2, Value::Dst, Field::S),
+        (3, Value::ImmHi, Field::U), (4, Value::ImmHi, Field::I), (5, Value::DstHi, Field::S),
+    ],
+};
+
+const ADD32: Stencil = Stencil { code: &[add(T0, T0, T1)], holes: &[] };
+const SUB32: Stencil = Stencil { code: &[sub(T0, T0, T1)], holes: &[] };
+const MUL32: Stencil = Stencil { code: &[mul(T0, T0, T1)], holes: &[] };
+const AND32: Stencil = Stencil { code: &[and(T0, T0, T1)], holes: &[] };
+const OR32: Stencil = Stencil { code: &[or(T0, T0, T1)], holes: &[] };
+const XOR32: Stencil = Stencil { code: &[xor(T0, T0, T1)], holes: &[] };
+const SHL32: Stencil = Stencil { code: &[sll(T0, T0, T1)], holes: &[] };
+const SHR32: Stencil = Stencil { code: &[srl(T0, T0, T1)], holes: &[] };
+const EQ32: Stencil = Stencil { code: &[sub(T0, T0, T1), sltiu(T0, T0, 1)], holes: &[] };
+const NE32: Stencil = Stencil { code: &[sub(T0, T0, T1), sltu(T0, ZERO, T0)], holes: &[] };
+const LT_S32: Stencil = Stencil { code: &[slt(T0, T0, T1)], holes: &[] };
+const LT_U32: Stencil = Stencil { code: &[sltu(T0, T0, T1)], holes: &[] };
+const GT_S32: Stencil = Stencil { code: &[slt(T0, T1, T0)], holes: &[] };
+const GT_U32: Stencil = Stencil { code: &[sltu(T0, T1, T0)], holes: &[] };
+// flips the result of a comparison, `a <= b` is `!(b < a)`
+const NOT: Stencil = Stencil { code: &[xori(T0, T0, 1)], holes: &[] };
+const EQZ32: Stencil = Stencil { code: &[sltiu(T0, T0, 1)], holes: &[] };
+const EQZ64: Stencil = Stencil { code: &[or(T0, T0, T1), sltiu(T0, T0, 1)], holes: &[] };
+const EXTEND8: Stencil = Stencil { code: &[slli(T0, T0, 24), srai(T0, T0, 24)], holes: &[] };
+
+const ADD64: Stencil = Stencil {
+    code: &[add(T4, T0, T2), sltu(T5, T4, T0), add(T1, T1, T3), add(T1, T1, T5), addi(T0, T4, 0)],
+    holes: &[],
+};
+const MUL64: Stencil = Stencil {
+    code: &[
+        mul(T4, T0, T2), mulhu(T5, T0, T2),
+        mul(T6, T0, T3), add(T5, T5, T6),
+        mul(T6, T1, T2), add(T1, T5, T6),
+        addi(T0, T4, 0),
+    ],
+    holes: &[],
+};
+const AND64: Stencil = Stencil { code: &[and(T0, T0, T2), and(T1, T1, T3)], holes: &[] };
+const OR64: Stencil = Stencil { code: &[or(T0, T0, T2), or(T1, T1, T3)], holes: &[] };
+const NE64: Stencil = Stencil {
+    code: &[xor(T0, T0, T2), xor(T1, T1, T3), or(T0, T0, T1), sltu(T0, ZERO, T0)],
+    holes: &[],
+};
+// upper words decide unless they are equal
+const LT_U64: Stencil = Stencil {
+    code: &[beq(T1, T3, 12), sltu(T0, T1, T3), jal(ZERO, 8), sltu(T0, T0, T2)],
+    holes: &[],
+};
+const GT_U64: Stencil = Stencil {
+    code: &[beq(T1, T3, 12), sltu(T0, T3, T1), jal(ZERO, 8), sltu(T0, T2, T0)],
+    holes: &[],
+};
+
+// picks `a` when `c` isn't zero, otherwise `b`
+const SELECT: Stencil = Stencil {
+    code: &[lw(T0, A0, 0), addi(T1, A0, 0), bne(T0, ZERO, 8), addi(T1, A0, 0), lw(T0, T1, 0), lw(T1, T1, 4)],
+    holes: &[(0, Value::C, Field::I), (1, Value::A, Field::I), (3, Value::B, Field::I)],
+};
+
+const JUMP: Stencil = Stencil { code: &[jal(ZERO, 0)], holes: &[(0, Value::Target, Field::J)] };
+const JUMP_IF_ZERO: Stencil = Stencil {
+    code: &[lw(T0, A0, 0), bne(T0, ZERO, 8), jal(ZERO, 0)],
+    holes: &[(0, Value::C, Field::I), (2, Value::Target, Field::J)],
+};
+const JUMP_IF_NOT_ZERO: Stencil = Stencil {
+    code: &[lw(T0, A0, 0), beq(T0, ZERO, 8), jal(ZERO, 0)],
+    holes: &[(0, Value::C, Field::I), (2, Value::Target, Field::J)],
+};
+const EXIT: Stencil = Stencil {
+    code: &[lui(A0), addi(A0, A0, 0), ret()],
+    holes: &[(0, Value::Pc, Field::U), (1, Value::Pc, Field::I)],
+};
+
+// Leaves the address of the accessed memory in `t2`, or exits when the access could go out of bounds
+// or isn't aligned. To keep it short, the check is done for 8 bytes, so the last few bytes of the memory
+// are left to the interpreter.
+const ADDRESS: Stencil = Stencil {
+    code: &[
+        lw(T0, A0, 0),
+        lui(T1),
+        addi(T1, T1, 0),
+        add(T0, T0, T1),
+        // the address has wrapped around
+        bltu(T0, T1, 32),
+        addi(T2, T0, 8),
+        bltu(T2, T0, 24),
+        bltu(A2, T2, 20),
+        andi(T1, T0, 0),
+        bne(T1, ZERO, 12),
+        add(T2, A1, T0),
+        jal(ZERO, 16),
+        lui(A0),
+        addi(A0, A0, 0),
+        ret(),
+    ],
+    holes: &[
+        (0, Value::A, Field::I),
+        (1, Value::Imm, Field::U),
+        (2, Value::Imm, Field::I),
+        (8, Value::Align, Field::I),
+        (12, Value::Pc, Field::U),
+        (13, Value::Pc, Field::I),
+    ],
+};
+const LOAD_W: Stencil = Stencil { code: &[lw(T0, T2, 0)], holes: &[] };
+const LOAD_D: Stencil = Stencil { code: &[lw(T0, T2, 0), lw(T1, T2, 4)], holes: &[] };
+const LOAD_B: Stencil = Stencil { code: &[lb(T0, T2, 0)], holes: &[] };
+const LOAD_BU: Stencil = Stencil { code: &[lbu(T0, T2, 0)], holes: &[] };
+const LOAD_H: Stencil = Stencil { code: &[lh(T0, T2, 0)], holes: &[] };
+const LOAD_HU: Stencil = Stencil { code: &[lhu(T0, T2, 0)], holes: &[] };
+// sign-extends `t0` into `t1:t0`
+const SIGN: Stencil = Stencil { code: &[srai(T1, T0, 31)], holes: &[] };
+// value to store
+const LOAD_SRC: Stencil = Stencil { code: &[lw(T0, A0, 0)], holes: &[(0, Value::B, Field::I)] };
+const LOAD64_SRC: Stencil = Stencil {
+    code: &[lw(T0, A0, 0), lw(T1, A0, 0)],
+    holes: &[(0, Value::B, Field::I), (1, Value::BHi, Field::I)],
+};
+const STORE_B: Stencil = Stencil { code: &[sb(T0, T2, 0)], holes: &[] };
+const STORE_H: Stencil = Stencil { code: &[sh(T0, T2, 0)], holes: &[] };
+const STORE_W: Stencil = Stencil { code: &[sw(T0, T2, 0)], holes: &[] };
+const STORE_D: Stencil = Stencil { code: &[sw(T0, T2, 0), sw(T1, T2, 4)], holes: &[] };
+
+/// Copies stencils of the instruction, returns `false` when some of them are missing.
+pub(super) fn emit(emitter: &mut Emitter, instr: &RegInstr, pc: u32) -> bool {
+    let mut ops = Operands { pc, ..Operands::default() };
+    // alignment required by the memory access, the lowest bits of the address must be zero
+    let mut align = 0;
+    let stencils: &[&Stencil] = match *instr {
+        RegInstr::Copy { dst, src } => {
+            (ops.a, ops.dst) = (src, dst);
+            &[&LOAD64_A, &STORE64]
+        }
+        RegInstr::Const { dst, val } => {
+            (ops.imm, ops.dst) = (val, dst);
+            &[&CONST]
+        }
+        // backward jumps are left to the interpreter, which looks for stop requests there
+        RegInstr::Jump { target } | RegInstr::Br(RegBranch { target, count: 0, .. }) if target > pc => {
+            ops.target = target;
+            &[&JUMP]
+        }
+        RegInstr::JumpIfZero { cond, target } if target > pc => {
+            (ops.c, ops.target) = (cond, target);
+            &[&JUMP_IF_ZERO]
+        }
+        RegInstr::BrIf { cond, br: RegBranch { target, count: 0, .. } } if target > pc => {
+            (ops.c, ops.target) = (cond, target);
+            &[&JUMP_IF_NOT_ZERO]
+        }
+        RegInstr::Select { dst, a, b, cond } => {
+            (ops.a, ops.b, ops.c, ops.dst) = (a, b, cond, dst);
+            &[&SELECT, &STORE64]
+        }
+        RegInstr::Load { op, dst, addr, offset } => {
+            (ops.a, ops.imm, ops.dst) = (addr, u64::from(offset), dst);
+            align = match op {
+                0x2c | 0x2d | 0x30 | 0x31 => 0,
+                0x2e | 0x2f | 0x32 | 0x33 => 1,
+                _ => 3,
+            };
+            match op {
+                0x28 | 0x2a | 0x35 => &[&ADDRESS, &LOAD_W, &STORE32],
+                0x29 | 0x2b => &[&ADDRESS, &LOAD_D, &STORE64],
+                0x2c => &[&ADDRESS, &LOAD_B, &STORE32],
+                0x2d | 0x31 => &[&ADDRESS, &LOAD_BU, &STORE32],
+                0x2e => &[&ADDRESS, &LOAD_H, &STORE32],
+                0x2f | 0x33 => &[&ADDRESS, &LOAD_HU, &STORE32],
+                0x30 => &[&ADDRESS, &LOAD_B, &SIGN, &STORE64],
+                0x32 => &[&ADDRESS, &LOAD_H, &SIGN, &STORE64],
+                0x34 => &[&ADDRESS, &LOAD_W, &SIGN, &STORE64],
+                _ => return false,
+            }
+        }
+        RegInstr::Store { op, addr, src, offset } => {
+            (ops.a, ops.imm, ops.b) = (addr, u64::from(offset), src);
+            match op {
+                0x3a | 0x3c => &[&ADDRESS, &LOAD_SRC, &STORE_B],
+                0x3b | 0x3d => {
+                    align = 1;
+                    &[&ADDRESS, &LOAD_SRC, &STORE_H]
+                }
+                0x36 | 0x38 | 0x3e => {
+                    align = 3;
+                    &[&ADDRESS, &LOAD_SRC, &STORE_W]
+                }
+                0x37 | 0x39 => {
+                    align = 3;
+                    &[&ADDRESS, &LOAD64_SRC, &STORE_D]
+                }
+                _ => return false,
+            }
+        }
+        RegInstr::Unary { op, dst, src } => {
+            (ops.a, ops.dst) = (src, dst);
+            match op {
+                0x45 => &[&LOAD_A, &EQZ32, &STORE32],
+                0x50 => &[&LOAD64_A, &EQZ64, &STORE32],
+                // i32.wrap_i64 | i64.extend_i32_u | f32.reinterpret_i32
+                0xa7 | 0xad | 0xbe => &[&LOAD_A, &STORE32],
+                0xc0 => &[&LOAD_A, &EXTEND8, &STORE32],
+                _ => return false,
+            }
+        }
+        RegInstr::Binary { op, dst, a, b } => {
+            (ops.a, ops.b, ops.dst) = (a, b, dst);
+            match op {
+                0x46 => &[&LOAD_AB, &EQ32, &STORE32],
+                0x47 => &[&LOAD_AB, &NE32, &STORE32],
+                0x48 => &[&LOAD_AB, &LT_S32, &STORE32],
+                0x49 => &[&LOAD_AB, &LT_U32, &STORE32],
+                0x4a => &[&LOAD_AB, &GT_S32, &STORE32],
+                0x4b => &[&LOAD_AB, &GT_U32, &STORE32],
+                0x4c => &[&LOAD_AB, &GT_S32, &NOT, &STORE32],
+                0x4d => &[&LOAD_AB, &GT_U32, &NOT, &STORE32],
+                0x4e => &[&LOAD_AB, &LT_S32, &NOT, &STORE32],
+                0x4f => &[&LOAD_AB, &LT_U32, &NOT, &STORE32],
+                0x52 => &[&LOAD64_AB, &NE64, &STORE32],
+                0x54 => &[&LOAD64_AB, &LT_U64, &STORE32],
+                0x56 => &[&LOAD64_AB, &GT_U64, &STORE32],
+                0x5a => &[&LOAD64_AB, &LT_U64, &NOT, &STORE32],
+                0x6a => &[&LOAD_AB, &ADD32, &STORE32],
+                0x6b => &[&LOAD_AB, &SUB32, &STORE32],
+                0x6c => &[&LOAD_AB, &MUL32, &STORE32],
+                0x71 => &[&LOAD_AB, &AND32, &STORE32],
+                0x72 => &[&LOAD_AB, &OR32, &STORE32],
+                0x73 => &[&LOAD_AB, &XOR32, &STORE32],
+                0x74 => &[&LOAD_AB, &SHL32, &STORE32],
+                0x76 => &[&LOAD_AB, &SHR32, &STORE32],
+                0x7c => &[&LOAD64_AB, &ADD64, &STORE64],
+                0x7e => &[&LOAD64_AB, &MUL64, &STORE64],
+                0x83 => &[&LOAD64_AB, &AND64, &STORE64],
+                0x84 => &[&LOAD64_AB, &OR64, &STORE64],
+                _ => return false,
+            }
+        }
+        _ => return false,
+    };
+    // registers are addressed with 12-bit signed offsets
+    if [ops.a, ops.b, ops.c, ops.dst].iter().any(|&reg| usize::from(reg) * 8 + 4 > 2047) {
+        return false;
+    }
+    for stencil in stencils {
+        copy(emitter, stencil, &ops, align);
+    }
+    true
+}
+
+/// Returns to the interpreter, which continues with instruction `pc`.
+pub(super) fn exit(emitter: &mut Emitter, pc: u32) {
+    copy(emitter, &EXIT, &Operands { pc, ..Operands::default() }, 0);
+}
+
+/// Fills in the offset of `jal` at `at` that jumps to `target`.
+pub(super) fn link(code: &mut [u8], at: usize, target: usize) {
+    let word = u32::from_le_bytes(code[at..at + 4].try_into().expect("4 bytes"));
+    let offset = target as i32 - at as i32;
+    let word = word & 0xfff | jal(ZERO, offset) & !0xfff;
+    code[at..at + 4].copy_from_slice(&word.to_le_bytes());
+}
+
+fn copy(emitter: &mut Emitter, stencil: &Stencil, ops: &Operands, align: u32) {
+    let start = emitter.code.len();
+    for (idx, &word) in stencil.code.iter().enumerate() {
+        let at = start + idx * 4;
+        let mut word = word;
+        for &(_, value, field) in stencil.holes.iter().filter(|(hole, _, _)| *hole == idx) {
+            let reg = |reg: u16| u32::from(reg) * 8;
+            let value = match value {
+                Value::A => reg(ops.a),
+                Value::B => reg(ops.b),
+                Value::C => reg(ops.c),
+                Value::Dst => reg(ops.dst),
+                Value::AHi => reg(ops.a) + 4,
+                Value::BHi => reg(ops.b) + 4,
+                Value::DstHi => reg(ops.dst) + 4,
+                Value::Imm => ops.imm as u32,
+                Value::ImmHi => (ops.imm >> 32) as u32,
+                Value::Align => align,
+                Value::Pc => ops.pc,
+                Value::Target => {
+                    emitter.fixups.push((at, ops.target));
+                    continue;
+                }
+            };
+            word = match field {
+                Field::I => word & 0x000f_ffff | (value & 0xfff) << 20,
+                Field::S => word & 0x01ff_f07f | s_type(value as i32, 0, 0, 0) & 0xfe00_0f80,
+                Field::U => word & 0xfff | value.wrapping_add(0x800) & 0xffff_f000,
+                Field::J => unreachable!("jumps are linked later"),
+            };
+        }
+        emitter.code.extend_from_slice(&word.to_le_bytes());
+    }
+}
+
+/// Runs the code at `entry` on the frame `regs`.
+///
+/// # Safety
+/// `entry` must point to code compiled for the function that owns the frame.
+pub(super) unsafe fn run_native(entry: *const u8, regs: &mut [u64], memory: &mut [u8]) -> u32 {
+    let entry: unsafe extern "C" fn(*mut u64, *mut u8, usize) -> u32 = core::mem::transmute(entry);
+    entry(regs.as_mut_ptr(), memory.as_mut_ptr(), memory.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jit::{Emitter, Operands};
+
+    // instruction words of the code
+    fn words(code: &[u8]) -> alloc::vec::Vec<u32> {
+        code.chunks_exact(4).map(|it| u32::from_le_bytes(it.try_into().unwrap())).collect()
+    }
+
+    // expected words are the encodings given by `llvm-mc -triple=riscv32 -mattr=+m -show-encoding`
+    #[test]
+    fn encode_instructions() {
+        assert_eq!(lw(T0, A0, 8), 0x0085_2283);
+        assert_eq!(lw(T0, A0, -4), 0xffc5_2283);
+        assert_eq!(sw(T0, A0, 2044), 0x7e55_2e23);
+        assert_eq!(sw(T1, A0, -8), 0xfe65_2c23);
+        assert_eq!(addi(T0, T0, -1), 0xfff2_8293);
+        assert_eq!(ret(), 0x0000_8067);
+
+        assert_eq!(beq(T1, T3, 12), 0x01c3_0663);
+        assert_eq!(bne(T0, ZERO, -8), 0xfe02_9ce3);
+        assert_eq!(bltu(A2, T2, 2046), 0x7e76_6f63);
+        assert_eq!(bltu(A2, T2, -4096), 0x8076_6063);
+
+        assert_eq!(jal(ZERO, 8), 0x0080_006f);
+        assert_eq!(jal(ZERO, -2048), 0x801f_f06f);
+        assert_eq!(jal(ZERO, 1048574), 0x7fff_f06f);
+        assert_eq!(jal(ZERO, -1048576), 0x8000_006f);
+    }
+
+    #[test]
+    fn link_jumps() {
+        let mut code = [jal(ZERO, 0), jal(ZERO, 0), jal(ZERO, 0)].map(u32::to_le_bytes).concat();
+        link(&mut code, 4, 12);
+        link(&mut code, 8, 0);
+        assert_eq!(words(&code), [0x0000_006f, jal(ZERO, 8), jal(ZERO, -8)]);
+
+        // offsets are relative to the jump, so only its own offset matters
+        let mut code = alloc::vec![0; 2052];
+        code[2048..].copy_from_slice(&jal(ZERO, 0).to_le_bytes());
+        link(&mut code, 2048, 0);
+        assert_eq!(words(&code[2048..]), [0x801f_f06f]);
+    }
+
+    #[test]
+    fn copy_patches_holes() {
+        let mut emitter = Emitter::default();
+        let ops = Operands { dst: 3, imm: 0x1234_4fff, ..Operands::default() };
+        copy(&mut emitter, &CONST, &ops, 0);
+        // lower word needs `lui` rounded up, as `addi` sign-extends its immediate
+        assert_eq!(
+            words(&emitter.code),
+            [0x1234_52b7, 0xfff2_8293, 0x0055_2c23, 0x0000_02b7, 0x0002_8293, 0x0055_2e23],
+        );
+
+        let mut emitter = Emitter::default();
+        copy(&mut emitter, &LOAD_AB, &Operands { a: 1, b: 255, ..Operands::default() }, 0);
+        assert_eq!(words(&emitter.code), [0x0085_2283, 0x7f85_2303]);
+
+        // jumps are left for `link` once all the code is known
+        let mut emitter = Emitter::default();
+        copy(&mut emitter, &LOAD_A, &Operands::default(), 0);
+        copy(&mut emitter, &JUMP, &Operands { target: 7, ..Operands::default() }, 0);
+        assert_eq!(words(&emitter.code)[1], 0x0000_006f);
+        assert_eq!(emitter.fixups, [(4, 7)]);
+    }
+}
@@ -0,0 +1,335 @@
+//! Stencils for x86-64.
+//!
+//! Native code is called with the System V calling convention: registers of the frame are in `rdi`,
+//! the linear memory in `rsi` and its length in `rdx`. It returns in `eax` the index of the instruction
+//! that the interpreter continues with. Only `rax` and `rcx` are used as scratch registers.
+
+use super::{Emitter, Operands};
+use crate::register::{RegBranch, RegInstr};
+
+#[derive(Clone, Copy)]
+enum Value {
+    /// Offset of a register in the frame, as a 32-bit displacement.
+    A,
+    B,
+    C,
+    Dst,
+    /// Lower 32 bits of the immediate.
+    Imm32,
+    Imm64,
+    Pc,
+    /// 32-bit displacement of a jump to the instruction `target`.
+    Target,
+}
+
+struct Stencil {
+    code: &'static [u8],
+    holes: &'static [(usize, Value)],
+}
+
+// mov eax, [rdi + A]
+const LOAD32_A: Stencil = Stencil { code: &[0x8b, 0x87, 0, 0, 0, 0], holes: &[(2, Value::A)] };
+// mov rax, [rdi + A]
+const LOAD64_A: Stencil = Stencil { code: &[0x48, 0x8b, 0x87, 0, 0, 0, 0], holes: &[(3, Value::A)] };
+// mov ecx, [rdi + B]
+const LOAD32_B_ECX: Stencil = Stencil { code: &[0x8b, 0x8f, 0, 0, 0, 0], holes: &[(2, Value::B)] };
+// mov rcx, [rdi + B]
+const LOAD64_B_RCX: Stencil = Stencil { code: &[0x48, 0x8b, 0x8f, 0, 0, 0, 0], holes: &[(3, Value::B)] };
+// mov [rdi + D], rax
+const STORE_RAX: Stencil = Stencil { code: &[0x48, 0x89, 0x87, 0, 0, 0, 0], holes: &[(3, Value::Dst)] };
+// mov [rdi + D], rcx
+const STORE_RCX: Stencil = Stencil { code: &[0x48, 0x89, 0x8f, 0, 0, 0, 0], holes: &[(3, Value::Dst)] };
+// mov rax, imm64
+const CONST: Stencil = Stencil { code: &[0x48, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0], holes: &[(2, Value::Imm64)] };
+
+// add eax, [rdi + B]
+const ADD32: Stencil = Stencil { code: &[0x03, 0x87, 0, 0, 0, 0], holes: &[(2, Value::B)] };
+// sub eax, [rdi + B]
+const SUB32: Stencil = Stencil { code: &[0x2b, 0x87, 0, 0, 0, 0], holes: &[(2, Value::B)] };
+// imul eax, [rdi + B]
+const MUL32: Stencil = Stencil { code: &[0x0f, 0xaf, 0x87, 0, 0, 0, 0], holes: &[(3, Value::B)] };
+// and eax, [rdi + B]
+const AND32: Stencil = Stencil { code: &[0x23, 0x87, 0, 0, 0, 0], holes: &[(2, Value::B)] };
+// or eax, [rdi + B]
+const OR32: Stencil = Stencil { code: &[0x0b, 0x87, 0, 0, 0, 0], holes: &[(2, Value::B)] };
+// xor eax, [rdi + B]
+const XOR32: Stencil = Stencil { code: &[0x33, 0x87, 0, 0, 0, 0], holes: &[(2, Value::B)] };
+// shl eax, cl
+const SHL32: Stencil = Stencil { code: &[0xd3, 0xe0], holes: &[] };
+// shr eax, cl
+const SHR32: Stencil = Stencil { code: &[0xd3, 0xe8], holes: &[] };
+// add rax, [rdi + B]
+const ADD64: Stencil = Stencil { code: &[0x48, 0x03, 0x87, 0, 0, 0, 0], holes: &[(3, Value::B)] };
+// imul rax, [rdi + B]
+const MUL64: Stencil = Stencil { code: &[0x48, 0x0f, 0xaf, 0x87, 0, 0, 0, 0], holes: &[(4, Value::B)] };
+// and rax, [rdi + B]
+const AND64: Stencil = Stencil { code: &[0x48, 0x23, 0x87, 0, 0, 0, 0], holes: &[(3, Value::B)] };
+// or rax, [rdi + B]
+const OR64: Stencil = Stencil { code: &[0x48, 0x0b, 0x87, 0, 0, 0, 0], holes: &[(3, Value::B)] };
+// shl rax, cl
+const SHL64: Stencil = Stencil { code: &[0x48, 0xd3, 0xe0], holes: &[] };
+// shr rax, cl
+const SHR64: Stencil = Stencil { code: &[0x48, 0xd3, 0xe8], holes: &[] };
+
+// xor ecx, ecx
+// cmp eax, [rdi + B]
+const CMP32: Stencil = Stencil { code: &[0x31, 0xc9, 0x3b, 0x87, 0, 0, 0, 0], holes: &[(4, Value::B)] };
+// xor ecx, ecx
+// cmp rax, [rdi + B]
+const CMP64: Stencil = Stencil { code: &[0x31, 0xc9, 0x48, 0x3b, 0x87, 0, 0, 0, 0], holes: &[(5, Value::B)] };
+// xor ecx, ecx
+// cmp dword [rdi + A], 0
+const TEST32: Stencil = Stencil { code: &[0x31, 0xc9, 0x83, 0xbf, 0, 0, 0, 0, 0], holes: &[(4, Value::A)] };
+// xor ecx, ecx
+// cmp qword [rdi + A], 0
+const TEST64: Stencil = Stencil { code: &[0x31, 0xc9, 0x48, 0x83, 0xbf, 0, 0, 0, 0, 0], holes: &[(5, Value::A)] };
+// sete cl
+const SETE: Stencil = Stencil { code: &[0x0f, 0x94, 0xc1], holes: &[] };
+// setne cl
+const SETNE: Stencil = Stencil { code: &[0x0f, 0x95, 0xc1], holes: &[] };
+// setl cl
+const SETL: Stencil = Stencil { code: &[0x0f, 0x9c, 0xc1], holes: &[] };
+// setb cl
+const SETB: Stencil = Stencil { code: &[0x0f, 0x92, 0xc1], holes: &[] };
+// setle cl
+const SETLE: Stencil = Stencil { code: &[0x0f, 0x9e, 0xc1], holes: &[] };
+// setbe cl
+const SETBE: Stencil = Stencil { code: &[0x0f, 0x96, 0xc1], holes: &[] };
+// setg cl
+const SETG: Stencil = Stencil { code: &[0x0f, 0x9f, 0xc1], holes: &[] };
+// seta cl
+const SETA: Stencil = Stencil { code: &[0x0f, 0x97, 0xc1], holes: &[] };
+// setge cl
+const SETGE: Stencil = Stencil { code: &[0x0f, 0x9d, 0xc1], holes: &[] };
+// setae cl
+const SETAE: Stencil = Stencil { code: &[0x0f, 0x93, 0xc1], holes: &[] };
+// movsx eax, byte [rdi + A]
+const EXTEND8_A: Stencil = Stencil { code: &[0x0f, 0xbe, 0x87, 0, 0, 0, 0], holes: &[(3, Value::A)] };
+
+// mov rax, [rdi + A]
+// cmp dword [rdi + C], 0
+// cmove rax, [rdi + B]
+const SELECT: Stencil = Stencil {
+    code: &[0x48, 0x8b, 0x87, 0, 0, 0, 0, 0x83, 0xbf, 0, 0, 0, 0, 0, 0x48, 0x0f, 0x44, 0x87, 0, 0, 0, 0],
+    holes: &[(3, Value::A), (9, Value::C), (18, Value::B)],
+};
+
+// jmp target
+const JUMP: Stencil = Stencil { code: &[0xe9, 0, 0, 0, 0], holes: &[(1, Value::Target)] };
+// cmp dword [rdi + C], 0
+// je target
+const JUMP_IF_ZERO: Stencil = Stencil {
+    code: &[0x83, 0xbf, 0, 0, 0, 0, 0, 0x0f, 0x84, 0, 0, 0, 0],
+    holes: &[(2, Value::C), (9, Value::Target)],
+};
+// cmp dword [rdi + C], 0
+// jne target
+const JUMP_IF_NOT_ZERO: Stencil = Stencil {
+    code: &[0x83, 0xbf, 0, 0, 0, 0, 0, 0x0f, 0x85, 0, 0, 0, 0],
+    holes: &[(2, Value::C), (9, Value::Target)],
+};
+// mov eax, pc
+// ret
+const EXIT: Stencil = Stencil { code: &[0xb8, 0, 0, 0, 0, 0xc3], holes: &[(1, Value::Pc)] };
+
+// Leaves the address of the accessed memory in `rax`, or exits when the access could go out of bounds.
+// To keep it short, the check is done for 8 bytes, so the last few bytes of the memory are left to the interpreter.
+//     mov eax, [rdi + A]
+//     add rax, imm32
+//     lea rcx, [rax + 8]
+//     cmp rcx, rdx
+//     jbe ok
+//     mov eax, pc
+//     ret
+// ok:
+const ADDRESS: Stencil = Stencil {
+    code: &[
+        0x8b, 0x87, 0, 0, 0, 0,
+        0x48, 0x05, 0, 0, 0, 0,
+        0x48, 0x8d, 0x48, 0x08,
+        0x48, 0x39, 0xd1,
+        0x76, 0x06,
+        0xb8, 0, 0, 0, 0,
+        0xc3,
+    ],
+    holes: &[(2, Value::A), (8, Value::Imm32), (22, Value::Pc)],
+};
+// mov eax, [rsi + rax]
+const LOAD_U32: Stencil = Stencil { code: &[0x8b, 0x04, 0x06], holes: &[] };
+// mov rax, [rsi + rax]
+const LOAD_U64: Stencil = Stencil { code: &[0x48, 0x8b, 0x04, 0x06], holes: &[] };
+// movsx eax, byte [rsi + rax]
+const LOAD_S8_32: Stencil = Stencil { code: &[0x0f, 0xbe, 0x04, 0x06], holes: &[] };
+// movzx eax, byte [rsi + rax]
+const LOAD_U8: Stencil = Stencil { code: &[0x0f, 0xb6, 0x04, 0x06], holes: &[] };
+// movsx eax, word [rsi + rax]
+const LOAD_S16_32: Stencil = Stencil { code: &[0x0f, 0xbf, 0x04, 0x06], holes: &[] };
+// movzx eax, word [rsi + rax]
+const LOAD_U16: Stencil = Stencil { code: &[0x0f, 0xb7, 0x04, 0x06], holes: &[] };
+// movsx rax, byte [rsi + rax]
+const LOAD_S8_64: Stencil = Stencil { code: &[0x48, 0x0f, 0xbe, 0x04, 0x06], holes: &[] };
+// movsx rax, word [rsi + rax]
+const LOAD_S16_64: Stencil = Stencil { code: &[0x48, 0x0f, 0xbf, 0x04, 0x06], holes: &[] };
+// movsxd rax, dword [rsi + rax]
+const LOAD_S32_64: Stencil = Stencil { code: &[0x48, 0x63, 0x04, 0x06], holes: &[] };
+// mov [rsi + rax], cl
+const STORE8: Stencil = Stencil { code: &[0x88, 0x0c, 0x06], holes: &[] };
+// mov [rsi + rax], cx
+const STORE16: Stencil = Stencil { code: &[0x66, 0x89, 0x0c, 0x06], holes: &[] };
+// mov [rsi + rax], ecx
+const STORE32: Stencil = Stencil { code: &[0x89, 0x0c, 0x06], holes: &[] };
+// mov [rsi + rax], rcx
+const STORE64: Stencil = Stencil { code: &[0x48, 0x89, 0x0c, 0x06], holes: &[] };
+
+/// Copies stencils of the instruction, returns `false` when some of them are missing.
+pub(super) fn emit(emitter: &mut Emitter, instr: &RegInstr, pc: u32) -> bool {
+    let mut ops = Operands { pc, ..Operands::default() };
+    let stencils: &[&Stencil] = match *instr {
+        RegInstr::Copy { dst, src } => {
+            (ops.a, ops.dst) = (src, dst);
+            &[&LOAD64_A, &STORE_RAX]
+        }
+        RegInstr::Const { dst, val } => {
+            (ops.imm, ops.dst) = (val, dst);
+            &[&CONST, &STORE_RAX]
+        }
+        // backward jumps are left to the interpreter, which looks for stop requests there
+        RegInstr::Jump { target } | RegInstr::Br(RegBranch { target, count: 0, .. }) if target > pc => {
+            ops.target = target;
+            &[&JUMP]
+        }
+        RegInstr::JumpIfZero { cond, target } if target > pc => {
+            (ops.c, ops.target) = (cond, target);
+            &[&JUMP_IF_ZERO]
+        }
+        RegInstr::BrIf { cond, br: RegBranch { target, count: 0, .. } } if target > pc => {
+            (ops.c, ops.target) = (cond, target);
+            &[&JUMP_IF_NOT_ZERO]
+        }
+        RegInstr::Select { dst, a, b, cond } => {
+            (ops.a, ops.b, ops.c, ops.dst) = (a, b, cond, dst);
+            &[&SELECT, &STORE_RAX]
+        }
+        RegInstr::Load { op, dst, addr, offset } if offset < 0x8000_0000 => {
+            (ops.a, ops.imm, ops.dst) = (addr, u64::from(offset), dst);
+            match op {
+                0x28 | 0x2a | 0x35 => &[&ADDRESS, &LOAD_U32, &STORE_RAX],
+                0x29 | 0x2b => &[&ADDRESS, &LOAD_U64, &STORE_RAX],
+                0x2c => &[&ADDRESS, &LOAD_S8_32, &STORE_RAX],
+                0x2d | 0x31 => &[&ADDRESS, &LOAD_U8, &STORE_RAX],
+                0x2e => &[&ADDRESS, &LOAD_S16_32, &STORE_RAX],
+                0x2f | 0x33 => &[&ADDRESS, &LOAD_U16, &STORE_RAX],
+                0x30 => &[&ADDRESS, &LOAD_S8_64, &STORE_RAX],
+                0x32 => &[&ADDRESS, &LOAD_S16_64, &STORE_RAX],
+                0x34 => &[&ADDRESS, &LOAD_S32_64, &STORE_RAX],
+                _ => return false,
+            }
+        }
+        RegInstr::Store { op, addr, src, offset } if offset < 0x8000_0000 => {
+            (ops.a, ops.imm, ops.b) = (addr, u64::from(offset), src);
+            match op {
+                0x3a | 0x3c => &[&ADDRESS, &LOAD64_B_RCX, &STORE8],
+                0x3b | 0x3d => &[&ADDRESS, &LOAD64_B_RCX, &STORE16],
+                0x36 | 0x38 | 0x3e => &[&ADDRESS, &LOAD64_B_RCX, &STORE32],
+                0x37 | 0x39 => &[&ADDRESS, &LOAD64_B_RCX, &STORE64],
+                _ => return false,
+            }
+        }
+        RegInstr::Unary { op, dst, src } => {
+            (ops.a, ops.dst) = (src, dst);
+            match op {
+                0x45 => &[&TEST32, &SETE, &STORE_RCX],
+                0x50 => &[&TEST64, &SETE, &STORE_RCX],
+                // i32.wrap_i64 | i64.extend_i32_u | f32.reinterpret_i32
+                0xa7 | 0xad | 0xbe => &[&LOAD32_A, &STORE_RAX],
+                0xc0 => &[&EXTEND8_A, &STORE_RAX],
+                _ => return false,
+            }
+        }
+        RegInstr::Binary { op, dst, a, b } => {
+            (ops.a, ops.b, ops.dst) = (a, b, dst);
+            match op {
+                0x46 => &[&LOAD32_A, &CMP32, &SETE, &STORE_RCX],
+                0x47 => &[&LOAD32_A, &CMP32, &SETNE, &STORE_RCX],
+                0x48 => &[&LOAD32_A, &CMP32, &SETL, &STORE_RCX],
+                0x49 => &[&LOAD32_A, &CMP32, &SETB, &STORE_RCX],
+                0x4a => &[&LOAD32_A, &CMP32, &SETG, &STORE_RCX],
+                0x4b => &[&LOAD32_A, &CMP32, &SETA, &STORE_RCX],
+                0x4c => &[&LOAD32_A, &CMP32, &SETLE, &STORE_RCX],
+                0x4d => &[&LOAD32_A, &CMP32, &SETBE, &STORE_RCX],
+                0x4e => &[&LOAD32_A, &CMP32, &SETGE, &STORE_RCX],
+                0x4f => &[&LOAD32_A, &CMP32, &SETAE, &STORE_RCX],
+                0x52 => &[&LOAD64_A, &CMP64, &SETNE, &STORE_RCX],
+                0x54 => &[&LOAD64_A, &CMP64, &SETB, &STORE_RCX],
+                0x56 => &[&LOAD64_A, &CMP64, &SETA, &STORE_RCX],
+                0x5a => &[&LOAD64_A, &CMP64, &SETAE, &STORE_RCX],
+                0x6a => &[&LOAD32_A, &ADD32, &STORE_RAX],
+                0x6b => &[&LOAD32_A, &SUB32, &STORE_RAX],
+                0x6c => &[&LOAD32_A, &MUL32, &STORE_RAX],
+                0x71 => &[&LOAD32_A, &AND32, &STORE_RAX],
+                0x72 => &[&LOAD32_A, &OR32, &STORE_RAX],
+                0x73 => &[&LOAD32_A, &XOR32, &STORE_RAX],
+                0x74 => &[&LOAD32_A, &LOAD32_B_ECX, &SHL32, &STORE_RAX],
+                0x76 => &[&LOAD32_A, &LOAD32_B_ECX, &SHR32, &STORE_RAX],
+                0x7c => &[&LOAD64_A, &ADD64, &STORE_RAX],
+                0x7e => &[&LOAD64_A, &MUL64, &STORE_RAX],
+                0x83 => &[&LOAD64_A, &AND64, &STORE_RAX],
+                0x84 => &[&LOAD64_A, &OR64, &STORE_RAX],
+                0x86 => &[&LOAD64_A, &LOAD32_B_ECX, &SHL64, &STORE_RAX],
+                0x88 => &[&LOAD64_A, &LOAD32_B_ECX, &SHR64, &STORE_RAX],
+                _ => return false,
+            }
+        }
+        _ => return false,
+    };
+    for stencil in stencils {
+        copy(emitter, stencil, &ops);
+    }
+    true
+}
+
+/// Returns to the interpreter, which continues with instruction `pc`.
+pub(super) fn exit(emitter: &mut Emitter, pc: u32) {
+    copy(emitter, &EXIT, &Operands { pc, ..Operands::default() });
+}
+
+/// Fills in the displacement at `at` of a jump to `target`.
+pub(super) fn link(code: &mut [u8], at: usize, target: usize) {
+    let displacement = target as i64 - (at + 4) as i64;
+    code[at..at + 4].copy_from_slice(&(displacement as i32).to_le_bytes());
+}
+
+fn copy(emitter: &mut Emitter, stencil: &Stencil, ops: &Operands) {
+    let start = emitter.code.len();
+    emitter.code.extend_from_slice(stencil.code);
+    for &(at, value) in stencil.holes {
+        let at = start + at;
+        let reg = |reg: u16| u32::from(reg) * 8;
+        let value = match value {
+            Value::A => reg(ops.a),
+            Value::B => reg(ops.b),
+            Value::C => reg(ops.c),
+            Value::Dst => reg(ops.dst),
+            Value::Imm32 => ops.imm as u32,
+            Value::Imm64 => {
+                emitter.code[at..at + 8].copy_from_slice(&ops.imm.to_le_bytes());
+                continue;
+            }
+            Value::Pc => ops.pc,
+            Value::Target => {
+                emitter.fixups.push((at, ops.target));
+                continue;
+            }
+        };
+        emitter.code[at..at + 4].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+/// Runs the code at `entry` on the frame `regs`.
+///
+/// # Safety
+/// `entry` must point to code compiled for the function that owns the frame.
+pub(super) unsafe fn run_native(entry: *const u8, regs: &mut [u64], memory: &mut [u8]) -> u32 {
+    let entry: unsafe extern "sysv64" fn(*mut u64, *mut u8, usize) -> u32 = core::mem::transmute(entry);
+    entry(regs.as_mut_ptr(), memory.as_mut_ptr(), memory.len())
+}
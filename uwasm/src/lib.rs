@@ -1,60 +1,132 @@
+#![feature(allocator_api)]
 #![feature(debug_closure_helpers)]
 #![feature(error_in_core)]
 #![no_std]
 
 extern crate alloc;
 
-use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 use core::fmt;
+use core::mem::size_of;
 use core::ops::ControlFlow;
 
-pub use crate::interpreter::{init_globals, init_memory, evaluate, execute_function, StackFrame, UntypedMemorySpan, VmContext, VmStack, ImportedFunc};
-use crate::parser::{Item, Reader, SectionKind, TypeKind};
-pub use crate::parser::ParserError;
+pub use crate::interpreter::{init_globals, init_globals_into, init_memory, evaluate, execute_function, call_dynamic, resume, Engine, Execution, ExecutionProfile, PauseReason, ImportOutcome, ImportResults, InterpreterError, InterruptHandle, StackFrame, TypedFunc, UntypedMemorySpan, VmBuffers, VmContext, VmStack, DataClosure, HostClosure, HostError, HostFunc, ImportedFunc};
+use crate::interpreter::FunctionArgs;
+use crate::operand::Operand;
+use crate::arena::{try_with_capacity_in, ModuleAlloc, ModuleSlice, ModuleVec, TryPush};
+use crate::bytecode::{fuse, BrTarget, Instr, ModuleTypes, Translated, Translator};
+use crate::image::Image;
+use crate::log::{log, logln};
+use crate::parser::{Reader, SectionKind};
+pub use crate::allocator::{GuestAllocator, GuestBuffer};
+pub use crate::arena::Arena;
+pub use crate::bytecode::Superinstruction;
+pub use crate::caller::{Caller, MemoryError, MemoryView, Pod, WasmPtr, WasmSlice};
+pub use crate::image::{write_image, IMAGE_ALIGN, IMAGE_VERSION};
+pub use crate::instance::{Instance, PAGE_SIZE};
+#[cfg(feature = "jit")]
+pub use crate::jit::CodeMemory;
+pub use crate::linker::{HostFn, HostResult, ImportName, LinkError, Linker};
+pub use crate::log::{Level, Log, NoLog};
+pub use crate::parser::{ParserError, TypeKind};
+pub use crate::scheduler::{App, AppConfig, AppId, AppState, Scheduler, SchedulerStep};
 pub use crate::str::ByteStr;
-
+pub use crate::value::Value;
+#[cfg(feature = "wasi")]
+pub use crate::wasi::{ProcExit, RandomFill, Wasi};
+
+mod allocator;
+mod arena;
+mod bytecode;
+mod cache;
+mod caller;
+mod image;
+mod instance;
 mod interpreter;
+#[cfg(feature = "jit")]
+mod jit;
+mod linker;
+mod log;
+mod numeric;
 mod parser;
+mod register;
+mod scheduler;
+mod storage;
 mod str;
 mod operand;
+mod value;
+#[cfg(feature = "wasi")]
+mod wasi;
 
+/// Types of parameters and results of a function.
 #[derive(Debug, Clone)]
-struct FuncSignature {
-    params: Vec<TypeKind>,
-    results: Vec<TypeKind>,
+pub struct FuncSignature<'code> {
+    params: ModuleVec<'code, TypeKind>,
+    results: ModuleVec<'code, TypeKind>,
 }
 
-impl Item for FuncSignature {
-    fn read(reader: &mut Reader, _offset: usize) -> Result<Self, ParserError> {
+impl<'code> FuncSignature<'code> {
+    pub fn params(&self) -> &[TypeKind] {
+        &self.params
+    }
+
+    pub fn results(&self) -> &[TypeKind] {
+        &self.results
+    }
+
+    fn read_in(reader: &mut Reader, alloc: ModuleAlloc<'code>) -> Result<Self, ParserError> {
         let num_params = reader.read_usize()?;
-        let mut params = Vec::new();
+        let mut params = try_with_capacity_in(num_params, alloc)?;
         for _ in 0..num_params {
             params.push(reader.read::<TypeKind>()?);
         }
 
         let num_results = reader.read_usize()?;
-        let mut results = Vec::new();
+        let mut results = try_with_capacity_in(num_results, alloc)?;
         for _ in 0..num_results {
             results.push(reader.read::<TypeKind>()?);
         }
 
         Ok(FuncSignature { params, results })
     }
+
+    // like `clone`, but fails when there is no memory left
+    pub(crate) fn try_clone(&self) -> Result<Self, ParserError> {
+        let mut params = try_with_capacity_in(self.params.len(), *self.params.allocator())?;
+        params.extend_from_slice(&self.params);
+        let mut results = try_with_capacity_in(self.results.len(), *self.results.allocator())?;
+        results.extend_from_slice(&self.results);
+        Ok(FuncSignature { params, results })
+    }
 }
 
+/// Services of the host for guests. Diagnostics of the runtime itself go to a [`Log`] instead.
 pub trait Environment {
+    /// Console of guests.
     fn write_fmt(&mut self, args: fmt::Arguments);
     fn ticks(&self) -> u64;
+    /// Number of [`Environment::ticks`] that make up one second, 0 when ticks are not related
+    /// to the wall-clock time.
+    fn ticks_per_second(&self) -> u64 {
+        0
+    }
 }
 
 #[derive(Debug)]
 pub struct WasmModule<'code> {
-    functions: Vec<Func<'code>>,
-    globals: Vec<Global<'code>>,
-    data_segments: Vec<DataSegment<'code>>,
-    globals_offsets: Vec<usize>,
-    tables: Vec<Table>,
+    signatures: ModuleVec<'code, FuncSignature<'code>>,
+    functions: ModuleVec<'code, Func<'code>>,
+    globals: ModuleVec<'code, Global<'code>>,
+    data_segments: ModuleVec<'code, DataSegment<'code>>,
+    elem_segments: ModuleVec<'code, ElemSegment<'code>>,
+    globals_offsets: ModuleVec<'code, usize>,
+    tables: ModuleVec<'code, Table>,
+    // size of the linear memory in pages, if the module defines one
+    memory: Option<Limits>,
+    // exported functions sorted by their names
+    exports: ModuleVec<'code, (&'code ByteStr, usize)>,
+    // exported globals sorted by their names
+    global_exports: ModuleVec<'code, (&'code ByteStr, usize)>,
 }
 
 impl<'code> WasmModule<'code> {
@@ -62,32 +134,147 @@ impl<'code> WasmModule<'code> {
         self.functions.get(index)?.body.as_ref()
     }
 
+    /// Signature of the function at `func_idx`, including imported ones.
+    pub fn get_function_signature(&self, func_idx: usize) -> Option<&FuncSignature<'code>> {
+        self.signatures.get(self.functions.get(func_idx)?.signature?)
+    }
+
+    /// Index of the exported or imported function with given name.
     pub fn get_function_index_by_name(&self, name: &ByteStr) -> Option<usize> {
-        self.functions
-            .iter()
-            .position(|f| f.name.is_some_and(|b| b.as_bytes() == name.as_bytes()))
+        self.get_export_index(name).or_else(|| {
+            self.functions
+                .iter()
+                .position(|f| f.body.is_none() && f.name.is_some_and(|b| b.as_bytes() == name.as_bytes()))
+        })
+    }
+
+    /// Index of the exported function with given name, found without going through all the functions.
+    pub fn get_export_index(&self, name: &ByteStr) -> Option<usize> {
+        self.exports
+            .binary_search_by(|(export, _)| export.as_bytes().cmp(name.as_bytes()))
+            .ok()
+            .map(|idx| self.exports[idx].1)
+    }
+
+    /// Index of the exported or imported global with given name.
+    pub fn get_global_index_by_name(&self, name: &ByteStr) -> Option<usize> {
+        self.global_exports
+            .binary_search_by(|(export, _)| export.as_bytes().cmp(name.as_bytes()))
+            .ok()
+            .map(|idx| self.global_exports[idx].1)
+            .or_else(|| {
+                self.globals
+                    .iter()
+                    .position(|g| g.import.is_some_and(|import| import.name.as_bytes() == name.as_bytes()))
+            })
+    }
+
+    /// Looks up an exported function and checks its signature, so that it can be called many times
+    /// without doing either again.
+    pub fn get_typed_func<TArgs: FunctionArgs, TResult: Operand>(
+        &'code self,
+        name: &ByteStr,
+    ) -> Result<TypedFunc<'code, TArgs, TResult>, InterpreterError> {
+        let func_idx = self.get_export_index(name).ok_or(InterpreterError::FunctionNotFound)?;
+        TypedFunc::new(self, func_idx)
     }
 
     pub fn get_imports(&self) -> impl Iterator<Item=&ByteStr> {
         self.functions.iter().filter_map(|f| f.body.is_none().then(|| f.name.as_deref().unwrap()))
     }
+
+    /// Number of bytes needed to hold all globals of the module.
+    pub fn globals_len_in_bytes(&self) -> usize {
+        self.globals.iter().map(|g| g.kind.len_bytes()).sum()
+    }
 }
 
 pub struct FuncBody<'code> {
-    signature: FuncSignature,
-    offset: usize,
+    signature: FuncSignature<'code>,
     pub code: &'code [u8],
+    // declarations of the locals that precede the code
+    locals: &'code [u8],
+
+    // `None` when the module has been parsed lazily, the body is then prepared on its first call
+    prepared: Option<PreparedBody<'code>>,
+}
+
+/// Function body made ready for the stack interpreter.
+pub(crate) struct PreparedBody<'code> {
+    // code translated for the interpreter
+    pub(crate) instrs: ModuleSlice<'code, Instr>,
+    // targets of all `br_table`s in the function
+    pub(crate) br_table: ModuleSlice<'code, BrTarget>,
+
+    // number of the params and the other locals
+    pub(crate) locals_len: usize,
+}
+
+impl PreparedBody<'_> {
+    /// Number of bytes taken by the prepared body on top of the module.
+    pub(crate) fn size(&self) -> usize {
+        self.instrs.len() * size_of::<Instr>()
+            + self.br_table.len() * size_of::<BrTarget>()
+    }
+}
+
+impl<'code> FuncBody<'code> {
+    pub(crate) fn prepared(&self) -> Option<&PreparedBody<'code>> {
+        self.prepared.as_ref()
+    }
+
+    /// Types of the params followed by the types of the other locals.
+    pub(crate) fn locals_types<'a>(&self, alloc: ModuleAlloc<'a>) -> Result<ModuleVec<'a, TypeKind>, ParserError> {
+        let mut reader = Reader::new(self.locals);
+        let locals_num = reader.read_usize()?;
 
-    // if location => else location
-    jump_targets: BTreeMap<usize, usize>,
+        // count the locals first, so that their types can be stored without reallocating
+        let mut non_param_locals_num = 0;
+        let mut counting_reader = reader;
+        for _ in 0..locals_num {
+            non_param_locals_num += counting_reader.read_usize()?;
+            let _ty = counting_reader.read::<TypeKind>()?;
+        }
+
+        let mut locals_types = try_with_capacity_in(self.signature.params.len().saturating_add(non_param_locals_num), alloc)?;
+        // Copy params into params
+        locals_types.extend(self.signature.params.iter().copied());
+
+        // Copy actual function locals
+        for _ in 0..locals_num {
+            let n = reader.read_usize()?;
+            let ty = reader.read::<TypeKind>()?;
+            for _ in 0..n {
+                locals_types.push(ty);
+            }
+        }
+        Ok(locals_types)
+    }
 
-    locals_types: Vec<TypeKind>,
-    // params + locals
-    locals_offsets: Vec<usize>,
-    // total length of parameters that this function accepts
-    params_len_in_bytes: usize,
-    // total length of internal function locals
-    non_param_locals_len_in_bytes: usize,
+    /// Translates the body for the stack interpreter, `signature` is the same as the one of the body.
+    pub(crate) fn prepare<'s>(
+        &self,
+        signature: &'s FuncSignature<'code>,
+        translator: &mut Translator<'s, 'code>,
+        types: &ModuleTypes<'_, 's, 'code>,
+        alloc: ModuleAlloc<'code>,
+        log: &mut impl Log,
+    ) -> Result<PreparedBody<'code>, ParserError> {
+        let locals_types = self.locals_types(alloc)?;
+        let Translated { mut instrs, br_table } = translator.translate(
+            &mut Reader::new(self.code),
+            signature,
+            &locals_types,
+            types,
+            log,
+        )?;
+        fuse(&mut instrs);
+        Ok(PreparedBody {
+            instrs: ModuleSlice::Owned(instrs),
+            br_table: ModuleSlice::Owned(br_table),
+            locals_len: locals_types.len(),
+        })
+    }
 }
 
 impl fmt::Debug for FuncBody<'_> {
@@ -98,22 +285,22 @@ impl fmt::Debug for FuncBody<'_> {
             .debug_struct("FuncBody")
             .field("signature", &self.signature)
             .field_with("code", |f| {
-                struct DummyEnv<'a, 'b>(&'a mut fmt::Formatter<'b>);
-                impl Environment for DummyEnv<'_, '_> {
-                    fn write_fmt(&mut self, args: fmt::Arguments) {
+                struct DummyLog<'a, 'b>(&'a mut fmt::Formatter<'b>);
+                impl Log for DummyLog<'_, '_> {
+                    fn log(&mut self, _level: Level, args: fmt::Arguments) {
                         self.0.write_fmt(args).unwrap();
                     }
 
-                    fn ticks(&self) -> u64 {
-                        0
+                    fn enabled(&self, _level: Level) -> bool {
+                        true
                     }
                 }
 
                 let mut reader = Reader::new(self.code);
-                _ = parse_code(&mut reader, &mut DummyEnv(f));
+                _ = parse_code(&mut reader, ModuleAlloc::Global, &mut DummyLog(f));
                 Ok(())
             })
-            .field("locals_types", &self.locals_types)
+            .field("locals_len", &self.prepared.as_ref().map(|it| it.locals_len))
             .finish()
     }
 }
@@ -122,13 +309,17 @@ impl fmt::Debug for FuncBody<'_> {
 pub struct Func<'code> {
     body: Option<FuncBody<'code>>,
     pub name: Option<&'code ByteStr>,
+    // module that an imported function comes from
+    module: Option<&'code ByteStr>,
     signature: Option<usize>,
 }
 
 struct Global<'c> {
     kind: TypeKind,
     mutability: u8,
-    initializer: CodeInfo<'c>,
+    // `None` for imported globals, whose values come from the host
+    initializer: Option<CodeInfo<'c>>,
+    import: Option<ImportName<'c>>,
 }
 
 impl fmt::Debug for Global<'_> {
@@ -136,26 +327,42 @@ impl fmt::Debug for Global<'_> {
         f.debug_struct("Global")
             .field("kind", &self.kind)
             .field("mutability", &self.mutability)
+            .field("import", &self.import)
             .finish()
     }
 }
 
-fn offsets_of_types(types: impl ExactSizeIterator<Item=TypeKind>) -> Vec<usize> {
-    let mut offsets = Vec::with_capacity(types.len());
+fn offsets_of_types(types: impl ExactSizeIterator<Item=TypeKind>, alloc: ModuleAlloc<'_>) -> Result<ModuleVec<'_, usize>, ParserError> {
+    let mut offsets = try_with_capacity_in(types.len(), alloc)?;
     let mut offset = 0;
     for param in types {
         offsets.push(offset);
         offset += param.len_bytes();
     }
-    offsets
+    Ok(offsets)
 }
 
 struct DataSegment<'code> {
     flags: u8,
-    offset: CodeInfo<'code>,
+    // `None` for passive segments, which aren't copied into memory on instantiation
+    offset: Option<CodeInfo<'code>>,
     data: &'code [u8],
 }
 
+// Only active segments of function indices are supported.
+struct ElemSegment<'code> {
+    offset: CodeInfo<'code>,
+    functions: ModuleVec<'code, u32>,
+}
+
+impl fmt::Debug for ElemSegment<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ElemSegment")
+            .field("functions", &self.functions.len())
+            .finish()
+    }
+}
+
 impl fmt::Debug for DataSegment<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("DataSegment")
@@ -167,33 +374,169 @@ impl fmt::Debug for DataSegment<'_> {
 #[derive(Debug)]
 struct Table {
     kind: TypeKind,
-    limits_flags: u8,
-    limits_initial: u8,
-    limits_max: u8
+    limits: Limits,
+}
+
+/// Initial and max size of a table or memory.
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    initial: u32,
+    max: Option<u32>,
+}
+
+fn read_limits(reader: &mut Reader) -> Result<Limits, ParserError> {
+    let flags = reader.read_u8()?;
+    let initial = reader.read_usize()? as u32;
+    let max = if flags & 1 != 0 { Some(reader.read_usize()? as u32) } else { None };
+    Ok(Limits { initial, max })
 }
 
 pub fn parse<'code>(
     code: &'code [u8],
-    env: &mut impl Environment,
+    log: &mut impl Log,
+) -> Result<WasmModule<'code>, ParserError> {
+    parse_with(code, ModuleAlloc::Global, Bodies::Eager, log)
+}
+
+/// Parses a module without preparing the bodies of its functions. Each body is prepared by the [`VmContext`]
+/// that calls it for the first time and kept in its cache, see [`VmContext::set_code_cache_limit`].
+///
+/// Errors in the code of a function are then reported only when it is called.
+pub fn parse_lazy<'code>(
+    code: &'code [u8],
+    log: &mut impl Log,
+) -> Result<WasmModule<'code>, ParserError> {
+    parse_with(code, ModuleAlloc::Global, Bodies::Lazy, log)
+}
+
+/// Parses a module keeping all of its data in the `arena` instead of the heap.
+///
+/// Use [`arena_size`] to find out how big the arena has to be. An arena that is too small is reported
+/// as [`ParserError::ArenaTooSmall`] instead of running out of memory, the required size is then measured
+/// with the global allocator. Parsing that fits into the arena doesn't touch the heap.
+pub fn parse_in<'code>(
+    code: &'code [u8],
+    arena: &'code Arena<'code>,
+    log: &mut impl Log,
+) -> Result<WasmModule<'code>, ParserError> {
+    parse_in_with(code, arena, Bodies::Eager, log)
+}
+
+/// Same as [`parse_lazy`], but keeps the module in the `arena` which needs [`lazy_arena_size`] bytes.
+pub fn parse_lazy_in<'code>(
+    code: &'code [u8],
+    arena: &'code Arena<'code>,
+    log: &mut impl Log,
+) -> Result<WasmModule<'code>, ParserError> {
+    parse_in_with(code, arena, Bodies::Lazy, log)
+}
+
+/// Parses a module using an image written by [`write_image`] for the same wasm, which holds everything
+/// needed to locate and run the function bodies, so that they don't have to be gone through.
+///
+/// Translated code in the image is used in place, the image then has to be aligned to [`IMAGE_ALIGN`].
+/// Functions of an image without it are prepared on their first call, as with [`parse_lazy`].
+/// Images written for another module are rejected with [`ParserError::StaleImage`].
+pub fn parse_image<'code>(
+    code: &'code [u8],
+    image: &'code [u8],
+    log: &mut impl Log,
+) -> Result<WasmModule<'code>, ParserError> {
+    parse_with(code, ModuleAlloc::Global, Bodies::Image(Image::new(image, code)?), log)
+}
+
+/// Same as [`parse_image`], but keeps the module in the `arena` which needs [`image_arena_size`] bytes.
+pub fn parse_image_in<'code>(
+    code: &'code [u8],
+    image: &'code [u8],
+    arena: &'code Arena<'code>,
+    log: &mut impl Log,
+) -> Result<WasmModule<'code>, ParserError> {
+    parse_in_with(code, arena, Bodies::Image(Image::new(image, code)?), log)
+}
+
+fn parse_in_with<'code>(
+    code: &'code [u8],
+    arena: &'code Arena<'code>,
+    bodies: Bodies<'code>,
+    log: &mut impl Log,
+) -> Result<WasmModule<'code>, ParserError> {
+    let used = arena.used();
+    let result = parse_with(code, ModuleAlloc::Arena(arena), bodies, log);
+    if result.is_err() {
+        // everything allocated by the failed parse has been dropped already
+        arena.rewind(used);
+    }
+    match result {
+        Err(ParserError::OutOfMemory) => {
+            Err(ParserError::ArenaTooSmall { required: Arena::required_len(measure(code, 0, bodies)?) })
+        }
+        result => result,
+    }
+}
+
+/// Returns the number of bytes that a buffer given to [`Arena::new`] needs to hold the module parsed by [`parse_in`].
+pub fn arena_size(code: &[u8]) -> Result<usize, ParserError> {
+    Ok(Arena::required_len(measure(code, 0, Bodies::Eager)?))
+}
+
+/// Returns the number of bytes that a buffer given to [`Arena::new`] needs to hold the module parsed by [`parse_lazy_in`].
+pub fn lazy_arena_size(code: &[u8]) -> Result<usize, ParserError> {
+    Ok(Arena::required_len(measure(code, 0, Bodies::Lazy)?))
+}
+
+/// Returns the number of bytes that a buffer given to [`Arena::new`] needs to hold the module parsed by [`parse_image_in`].
+pub fn image_arena_size(code: &[u8], image: &[u8]) -> Result<usize, ParserError> {
+    Ok(Arena::required_len(measure(code, 0, Bodies::Image(Image::new(image, code)?))?))
+}
+
+// Way in which the function bodies are prepared for the stack interpreter.
+#[derive(Clone, Copy)]
+enum Bodies<'code> {
+    // while parsing
+    Eager,
+    // on their first call
+    Lazy,
+    // ahead of time, or on their first call if the image has no translated code
+    Image(Image<'code>),
+}
+
+// Returns the offset in an arena after parsing the module into it, when `used` bytes were already taken.
+fn measure<'code>(code: &'code [u8], used: usize, bodies: Bodies<'code>) -> Result<usize, ParserError> {
+    let arena = Arena::measuring(used);
+    drop(parse_with(code, ModuleAlloc::Arena(&arena), bodies, &mut NoLog)?);
+    Ok(arena.used())
+}
+
+fn parse_with<'code>(
+    code: &'code [u8],
+    alloc: ModuleAlloc<'code>,
+    bodies: Bodies<'code>,
+    log: &mut impl Log,
 ) -> Result<WasmModule<'code>, ParserError> {
     let mut reader = Reader::new(code);
     reader.expect_bytes(b"\x00asm")?;
 
-    let mut functions: Vec<_> = Vec::new();
-    let mut signatures = Vec::new();
+    let mut functions = Vec::new_in(alloc);
+    let mut signatures = Vec::new_in(alloc);
     let mut imports = 0;
-    let mut globals = Vec::new();
-    let mut data_segments = Vec::new();
-    let mut tables = Vec::new();
-
-    writeln!(env, "Version: {:?}", reader.read_u32()?);
+    let mut globals = Vec::new_in(alloc);
+    let mut data_segments = Vec::new_in(alloc);
+    let mut elem_segments = Vec::new_in(alloc);
+    let mut tables = Vec::new_in(alloc);
+    let mut memory = None;
+    let mut exports = Vec::new_in(alloc);
+    let mut global_exports = Vec::new_in(alloc);
+
+    let version = reader.read_u32()?;
+    logln!(log, Level::Debug, "Version: {:?}", version);
     while let Ok(section_type) = reader.read::<SectionKind>() {
-        let _section_size = reader.read_usize()?;
+        let section_size = reader.read_usize()?;
         match section_type {
             #[allow(unused)]
             SectionKind::Custom => {
                 let name = reader.read_str()?;
-                writeln!(env, "Found custom section: {}", name);
+                logln!(log, Level::Debug, "Found custom section: {}", name);
 
                 break; // FIXME
 
@@ -210,239 +553,249 @@ pub fn parse<'code>(
                 }
             }
             SectionKind::Type => {
-                writeln!(env, "Found type section");
+                logln!(log, Level::Debug, "Found type section");
 
                 let num_types = reader.read_usize()?;
+                signatures.try_reserve_exact(num_types)?;
                 for _ in 0..num_types {
                     let kind = reader.read::<TypeKind>()?;
                     match kind {
                         TypeKind::Func => {
-                            let sig = reader.read::<FuncSignature>()?;
-                            writeln!(env, "Signature: {:?}", sig);
-                            signatures.push(sig);
+                            let sig = FuncSignature::read_in(&mut reader, alloc)?;
+                            logln!(log, Level::Debug, "Signature: {:?}", sig);
+                            signatures.try_push(sig)?;
                         }
                         other => todo!("{:?}", other),
                     }
                 }
             }
             SectionKind::Import => {
-                writeln!(env, "Found import section");
+                logln!(log, Level::Debug, "Found import section");
                 let num_imports = reader.read_usize()?;
-                writeln!(env, "{num_imports}");
+                logln!(log, Level::Debug, "{num_imports}");
                 for _ in 0..num_imports {
                     let module_name = reader.read_str()?;
                     let field_name = reader.read_str()?;
                     let import_kind = reader.read_u8()?;
+                    if import_kind == 3 {
+                        // global
+                        let kind = reader.read::<TypeKind>()?;
+                        let global_mut = reader.read_u8()?;
+                        logln!(log, Level::Debug, "Found imported global: {module_name}.{field_name} | {:?} mut={}", kind, global_mut);
+                        globals.try_push(Global {
+                            kind,
+                            mutability: global_mut,
+                            initializer: None,
+                            import: Some(ImportName { module: module_name, name: field_name }),
+                        })?;
+                        continue;
+                    }
                     let import_sig_idx = reader.read_usize()?;
-                    writeln!(
-                        env,
+                    logln!(
+                        log,
+                        Level::Debug,
                         "Found imported: {module_name}.{field_name} | signature index: {import_sig_idx} | kind: {import_kind}"
                     );
                     if import_kind == 0 {
                         // function
-                        functions.push(Func {
+                        functions.try_push(Func {
                             body: None,
                             name: Some(field_name),
+                            module: Some(module_name),
                             signature: Some(import_sig_idx),
-                        });
+                        })?;
                         imports += 1;
                     }
                 }
             }
             SectionKind::Function => {
-                writeln!(env, "Found function section");
+                logln!(log, Level::Debug, "Found function section");
 
                 let num_funcs = reader.read_usize()?;
-                writeln!(env, "{:?}", num_funcs);
+                logln!(log, Level::Debug, "{:?}", num_funcs);
+                functions.try_reserve_exact(num_funcs)?;
                 for func_idx in 0..num_funcs {
                     let sig_index = reader.read_usize()?;
-                    writeln!(env, "Function #{func_idx} | signature #{sig_index}: {:?}", &signatures[sig_index]);
-                    functions.push(Func {
+                    logln!(log, Level::Debug, "Function #{func_idx} | signature #{sig_index}: {:?}", &signatures[sig_index]);
+                    functions.try_push(Func {
                         body: None,
                         name: None,
+                        module: None,
                         signature: Some(sig_index),
-                    });
+                    })?;
                 }
             }
             SectionKind::Table => {
-                writeln!(env, "Found table section");
+                logln!(log, Level::Debug, "Found table section");
 
                 let num_tables = reader.read_usize()?;
+                tables.try_reserve_exact(num_tables)?;
                 for _ in 0..num_tables {
                     let kind = reader.read::<TypeKind>()?;
-                    let limits_flags = reader.read_u8()?;
-                    let limits_initial = reader.read_u8()?;
-                    let limits_max = reader.read_u8()?;
-
-                    tables.push(Table {
-                        kind,
-                        limits_flags,
-                        limits_initial,
-                        limits_max,
-                    });
+                    let limits = read_limits(&mut reader)?;
+                    tables.try_push(Table { kind, limits })?;
                 }
             }
             SectionKind::Memory => {
-                writeln!(env, "Found memory section");
+                logln!(log, Level::Debug, "Found memory section");
                 let num_memories = reader.read_usize()?;
                 for _ in 0..num_memories {
-                    let _limits_flags = reader.read_u8()?;
-                    let _limits_initial = reader.read_u8()?;
+                    memory = Some(read_limits(&mut reader)?);
                 }
             }
             SectionKind::Global => {
-                writeln!(env, "Found global section");
+                logln!(log, Level::Debug, "Found global section");
                 let num_globals = reader.read_usize()?;
+                globals.try_reserve_exact(num_globals)?;
                 for i in 0..num_globals {
                     let kind = reader.read::<TypeKind>()?;
                     let global_mut = reader.read_u8()?;
-                    writeln!(env, "global #{i}: {:?} mut={}", kind, global_mut);
-                    let code = parse_code(&mut reader, env)?;
+                    logln!(log, Level::Debug, "global #{i}: {:?} mut={}", kind, global_mut);
+                    let code = parse_code(&mut reader, alloc, log)?;
 
-                    globals.push(Global {
+                    globals.try_push(Global {
                         kind,
                         mutability: global_mut,
-                        initializer: code,
-                    });
+                        initializer: Some(code),
+                        import: None,
+                    })?;
                 }
             }
             SectionKind::Export => {
-                writeln!(env, "Found export section");
+                logln!(log, Level::Debug, "Found export section");
                 let num_exports = reader.read_usize()?;
-                writeln!(env, "{num_exports}");
+                logln!(log, Level::Debug, "{num_exports}");
+                exports.try_reserve_exact(num_exports)?;
                 for _ in 0..num_exports {
                     let name = reader.read_str()?;
                     let export_kind = reader.read_u8()?;
                     let export_func_idx = reader.read_usize()?;
-                    writeln!(
-                        env,
+                    logln!(
+                        log,
+                        Level::Debug,
                         "Found exported: {name} | index: {export_func_idx} | kind: {export_kind}"
                     );
                     if export_kind == 0 {
                         // function
                         functions[export_func_idx].name = Some(name);
+                        exports.try_push((name, export_func_idx))?;
+                    } else if export_kind == 3 {
+                        // global
+                        global_exports.try_push((name, export_func_idx))?;
                     }
                 }
+                exports.sort_unstable_by(|(a, _), (b, _)| a.as_bytes().cmp(b.as_bytes()));
+                global_exports.sort_unstable_by(|(a, _), (b, _)| a.as_bytes().cmp(b.as_bytes()));
             }
             SectionKind::Elem => {
-                writeln!(env, "Found elem section");
+                logln!(log, Level::Debug, "Found elem section");
                 let num_elem_segments = reader.read_usize()?;
+                elem_segments.try_reserve_exact(num_elem_segments)?;
                 for _ in 0..num_elem_segments {
-                    let _segment_flags = reader.read_u8()?;
-                    loop {
-                        let opcode = reader.read_u8()?;
-                        match opcode {
-                            0x41 => {
-                                // i32.const
-                                _ = reader.read_usize()?;
-                            }
-                            0x0b => {
-                                // end
-                                break;
-                            }
-                            _ => todo!(),
-                        };
+                    let offset = reader.pos();
+                    let segment_flags = reader.read_u8()?;
+                    if segment_flags != 0 {
+                        return Err(ParserError::InvalidValue { offset, found: segment_flags });
                     }
+                    let code = parse_code(&mut reader, alloc, log)?;
                     let num_elements = reader.read_usize()?;
+                    let mut functions = try_with_capacity_in(num_elements, alloc)?;
                     for _ in 0..num_elements {
-                        _ = reader.read_usize()?;
+                        functions.try_push(reader.read_usize()? as u32)?;
                     }
-                    // FIXME
+                    elem_segments.try_push(ElemSegment { offset: code, functions })?;
                 }
             }
             SectionKind::Code => {
-                writeln!(env, "Found code section");
+                logln!(log, Level::Debug, "Found code section");
+
+                if let Bodies::Image(image) = bodies {
+                    // bodies are located through the image instead
+                    _ = reader.read_slice(section_size)?;
+                    if image.len() != functions.len() - imports {
+                        return Err(ParserError::InvalidImage);
+                    }
+                    for func_idx in 0..image.len() {
+                        let signature = &signatures[functions[imports + func_idx].signature.unwrap()];
+                        functions[imports + func_idx].body = Some(image.body(func_idx, code, signature)?);
+                    }
+                    continue;
+                }
 
                 let num_funcs = reader.read_usize()?;
+                let mut translator = Translator::new(alloc);
                 for func_idx in 0..num_funcs {
-                    let signature = signatures[functions[imports + func_idx].signature.unwrap()].clone();
-
-                    let _body_len = reader.read_usize()?;
-                    let locals_num = reader.read_usize()?;
-
-                    let mut locals_types = Vec::new();
-                    // Copy params into params
-                    locals_types.extend(signature.params.iter().copied());
+                    let signature = &signatures[functions[imports + func_idx].signature.unwrap()];
 
-                    // Copy actual function locals
+                    let body_len = reader.read_usize()?;
+                    let mut body_reader = Reader::new(reader.read_slice(body_len)?);
+                    let marker = body_reader.marker();
+                    let locals_num = body_reader.read_usize()?;
                     for _ in 0..locals_num {
-                        let n = reader.read_usize()?;
-                        let ty = reader.read::<TypeKind>()?;
-                        for _ in 0..n {
-                            locals_types.push(ty);
-                        }
+                        let _n = body_reader.read_usize()?;
+                        let _ty = body_reader.read::<TypeKind>()?;
                     }
-
-                    let offsets = offsets_of_types(locals_types.iter().copied());
-                    writeln!(env, "offsets={:?}", offsets);
-
-                    let CodeInfo { offset, code, jump_targets } = parse_code(&mut reader, env)?;
-                    let params_len_in_bytes = signature
-                        .params
-                        .iter()
-                        .map(|t| t.len_bytes())
-                        .sum();
-                    let non_param_locals_len_in_bytes = locals_types[signature.params.len()..]
-                        .iter()
-                        .map(|ty| ty.len_bytes())
-                        .sum();
-                    functions[imports + func_idx].body = Some(FuncBody {
-                        signature,
-                        locals_offsets: offsets,
-                        locals_types,
-                        offset,
-                        code,
-                        jump_targets,
-                        params_len_in_bytes,
-                        non_param_locals_len_in_bytes,
-                    });
+                    let locals = marker.into_slice(&mut body_reader);
+                    let body = FuncBody {
+                        signature: signature.try_clone()?,
+                        code: body_reader.read_slice(body_len - locals.len())?,
+                        locals,
+                        prepared: None,
+                    };
+                    let prepared = if matches!(bodies, Bodies::Lazy) {
+                        None
+                    } else {
+                        let types = ModuleTypes { signatures: &signatures, functions: &functions, globals: &globals };
+                        Some(body.prepare(signature, &mut translator, &types, alloc, log)?)
+                    };
+                    functions[imports + func_idx].body = Some(FuncBody { prepared, ..body });
                 }
             }
             SectionKind::Data => {
-                writeln!(env, "Found data section");
+                logln!(log, Level::Debug, "Found data section");
 
                 let num_segments = reader.read_usize()?;
+                data_segments.try_reserve_exact(num_segments)?;
                 for _ in 0..num_segments {
                     let segment_flags = reader.read_u8()?;
-                    let code = parse_code(&mut reader, env)?;
+                    let code = match segment_flags {
+                        0 => Some(parse_code(&mut reader, alloc, log)?),
+                        1 => None,
+                        _ => {
+                            // active segment of an explicit memory, there is only one
+                            _ = reader.read_usize()?;
+                            Some(parse_code(&mut reader, alloc, log)?)
+                        }
+                    };
                     let data_len = reader.read_usize()?;
                     let data = reader.read_slice(data_len)?;
 
-                    data_segments.push(DataSegment {
+                    data_segments.try_push(DataSegment {
                         flags: segment_flags,
                         offset: code,
                         data
-                    });
+                    })?;
                 }
             }
         }
     }
 
-    let globals_offsets = offsets_of_types(globals.iter().map(|it| it.kind));
-    Ok(WasmModule { functions, globals, globals_offsets, data_segments, tables })
+    let globals_offsets = offsets_of_types(globals.iter().map(|it| it.kind), alloc)?;
+    Ok(WasmModule { signatures, functions, globals, globals_offsets, data_segments, elem_segments, tables, memory, exports, global_exports })
 }
 
 struct CodeInfo<'code> {
-    offset: usize,
     code: &'code [u8],
-    jump_targets: BTreeMap<usize, usize>,
 }
 
-fn parse_code<'c>(reader: &mut Reader<'c>, env: &mut impl Environment) -> Result<CodeInfo<'c>, ParserError> {
+fn parse_code<'c>(reader: &mut Reader<'c>, alloc: ModuleAlloc<'c>, log: &mut impl Log) -> Result<CodeInfo<'c>, ParserError> {
     let marker = reader.marker();
-    let mut state = ParserState::default();
+    let mut state = ParserState::new_in(alloc);
 
-    loop {
-        match parse_opcode::<false>(reader, marker.pos(), env, &mut state)? {
-            ControlFlow::Continue(_) => continue,
-            ControlFlow::Break(_) => break,
-        }
-    }
+    while let ControlFlow::Continue(_) = parse_opcode::<false>(reader, log, &mut state)? {}
 
     Ok(CodeInfo {
-        offset: marker.pos(),
         code: marker.into_slice(&mut *reader),
-        jump_targets: state.jump_targets,
     })
 }
 
@@ -454,21 +807,27 @@ enum BlockType {
     Else,
 }
 
-struct BlockMeta {
-    kind: BlockType,
-    offset: usize,
+struct ParserState<'a> {
+    blocks: ModuleVec<'a, BlockType>,
+}
+
+impl<'a> ParserState<'a> {
+    fn new_in(alloc: ModuleAlloc<'a>) -> Self {
+        Self {
+            blocks: Vec::new_in(alloc),
+        }
+    }
 }
 
-#[derive(Default)]
-struct ParserState {
-    blocks: Vec<BlockMeta>,
-    jump_targets: BTreeMap<usize, usize>,
+impl Default for ParserState<'_> {
+    fn default() -> Self {
+        Self::new_in(ModuleAlloc::Global)
+    }
 }
 
 fn parse_opcode<const ONLY_PRINT: bool>(
     reader: &mut Reader,
-    func_offset: usize,
-    env: &mut impl Environment,
+    log: &mut impl Log,
     state: &mut ParserState
 ) -> Result<ControlFlow<(), ()>, ParserError> {
     let pos = reader.pos();
@@ -476,131 +835,129 @@ fn parse_opcode<const ONLY_PRINT: bool>(
     match op {
         0x00 => {
             // unreachable
-            writeln!(env, "unreachable");
+            logln!(log, Level::Trace, "unreachable");
         }
         0x01 => {
             // nop
-            writeln!(env, "nop");
+            logln!(log, Level::Trace, "nop");
         }
         0x02 => {
             // block
             let block_type = reader.read_u8()?;
-            writeln!(env, "block {:02x}", block_type);
+            logln!(log, Level::Trace, "block {:02x}", block_type);
             if !ONLY_PRINT {
-                state.blocks.push(BlockMeta { kind: BlockType::Block, offset: pos });
+                state.blocks.try_push(BlockType::Block)?;
             }
         }
         0x03 => {
             // loop
-            writeln!(env, "loop");
+            logln!(log, Level::Trace, "loop");
             let _loop_type = reader.read_u8()?;
             if !ONLY_PRINT {
-                state.blocks.push(BlockMeta { kind: BlockType::Loop, offset: pos });
+                state.blocks.try_push(BlockType::Loop)?;
             }
         }
         0x04 => {
             // if
-            writeln!(env, "if");
+            logln!(log, Level::Trace, "if");
             let _ty = reader.read::<TypeKind>()?;
             if !ONLY_PRINT {
-                state.blocks.push(BlockMeta { kind: BlockType::If, offset: pos });
+                state.blocks.try_push(BlockType::If)?;
             }
         }
         0x05 => {
             // else
-            writeln!(env, "else");
+            logln!(log, Level::Trace, "else");
             if !ONLY_PRINT {
-                let BlockMeta { kind, offset } = state.blocks.pop().unwrap();
+                let kind = state.blocks.pop().unwrap();
                 assert_eq!(kind, BlockType::If);
-                state.jump_targets.insert(offset, pos + 1 - func_offset);
-                state.blocks.push(BlockMeta { kind: BlockType::Else, offset: pos });
+                state.blocks.try_push(BlockType::Else)?;
             }
         }
         0x0b => {
             // end
             if !ONLY_PRINT {
-                write!(env, "end");
-                if let Some(BlockMeta { kind, offset: start_offset }) = state.blocks.pop() {
-                    writeln!(env, " // {:?} @ {:02X}", kind, pos);
-                    state.jump_targets.insert(start_offset, pos + 1 - func_offset);
+                log!(log, Level::Trace, "end");
+                if let Some(kind) = state.blocks.pop() {
+                    logln!(log, Level::Trace, " // {:?} @ {:02X}", kind, pos);
                 } else {
                     // end of function
-                    writeln!(env, " // code");
+                    logln!(log, Level::Trace, " // code");
                     return Ok(ControlFlow::Break(()));
                 }
             } else {
-                writeln!(env, "end");
+                logln!(log, Level::Trace, "end");
             }
         }
         0x0c => {
             // br
             let break_depth = reader.read_usize()?;
-            writeln!(env, "br {}", break_depth);
+            logln!(log, Level::Trace, "br {}", break_depth);
         }
         0x0d => {
             // br_if
             let break_depth = reader.read_usize()?;
-            writeln!(env, "br_if {}", break_depth);
+            logln!(log, Level::Trace, "br_if {}", break_depth);
         }
         0x0e => {
             // br_table
             // FIXME
             let n = reader.read_usize()?;
-            write!(env, "br_table");
+            log!(log, Level::Trace, "br_table");
             for _ in 0..n {
                 let n = reader.read_usize()?;
-                write!(env, " {}", n);
+                log!(log, Level::Trace, " {}", n);
             }
             let else_c = reader.read_usize()?;
-            writeln!(env, " {} ", else_c);
+            logln!(log, Level::Trace, " {} ", else_c);
         }
         0x0f => {
             // return
-            writeln!(env, "return");
+            logln!(log, Level::Trace, "return");
         }
         0x10 => {
             // call <func_idx>
             let func_idx = reader.read_usize()?;
-            writeln!(env, "call {}", func_idx);
+            logln!(log, Level::Trace, "call {}", func_idx);
         }
         0x11 => {
             // call_indirect <func_idx>
             let sig_idx = reader.read_usize()?;
             let table_idx = reader.read_usize()?;
-            writeln!(env, "call_indirect {} {}", sig_idx, table_idx);
+            logln!(log, Level::Trace, "call_indirect {} {}", sig_idx, table_idx);
         }
         0x1a => {
             // drop
-            writeln!(env, "drop");
+            logln!(log, Level::Trace, "drop");
         }
         0x1b => {
             // select
-            writeln!(env, "select");
+            logln!(log, Level::Trace, "select");
         }
         0x20 => {
             // local.get <local>
             let local_idx = reader.read_usize()?;
-            writeln!(env, "local.get {}", local_idx);
+            logln!(log, Level::Trace, "local.get {}", local_idx);
         }
         0x21 => {
             // local.set <local>
             let local_idx = reader.read_usize()?;
-            writeln!(env, "local.set {}", local_idx);
+            logln!(log, Level::Trace, "local.set {}", local_idx);
         }
         0x22 => {
             // local.tee <local>
             let local_idx = reader.read_usize()?;
-            writeln!(env, "local.tee {}", local_idx);
+            logln!(log, Level::Trace, "local.tee {}", local_idx);
         }
         0x23 => {
             // global.get <global>
             let global_idx = reader.read_usize()?;
-            writeln!(env, "global.get {}", global_idx);
+            logln!(log, Level::Trace, "global.get {}", global_idx);
         }
         0x24 => {
             // global.set <global>
             let global_idx = reader.read_usize()?;
-            writeln!(env, "global.set {}", global_idx);
+            logln!(log, Level::Trace, "global.set {}", global_idx);
         }
         0x28..=0x35 => {
             // i32.load     0x28
@@ -636,303 +993,355 @@ fn parse_opcode<const ONLY_PRINT: bool>(
                 0x35 => "i64.load32_u",
                 _ => unreachable!(),
             };
-            writeln!(env, "{name} align={align} offset={offset}");
+            logln!(log, Level::Trace, "{name} align={align} offset={offset}");
         }
         0x36 => {
             // i32.store
             let align = reader.read_usize()?;
             let offset = reader.read_usize()?;
-            writeln!(env, "i32.store align={align} offset={offset}");
+            logln!(log, Level::Trace, "i32.store align={align} offset={offset}");
         }
         0x37 => {
             // i64.store
             let align = reader.read_usize()?;
             let offset = reader.read_usize()?;
-            writeln!(env, "i64.store align={align} offset={offset}");
+            logln!(log, Level::Trace, "i64.store align={align} offset={offset}");
+        }
+        0x38 => {
+            // f32.store
+            let align = reader.read_usize()?;
+            let offset = reader.read_usize()?;
+            logln!(log, Level::Trace, "f32.store align={align} offset={offset}");
         }
         0x39 => {
             // f64.store
             let align = reader.read_usize()?;
             let offset = reader.read_usize()?;
-            writeln!(env, "f64.store align={align} offset={offset}");
+            logln!(log, Level::Trace, "f64.store align={align} offset={offset}");
         }
         0x3a => {
             // i32.store8
             let align = reader.read_usize()?;
             let offset = reader.read_usize()?;
-            writeln!(env, "i32.store8 align={align} offset={offset}");
+            logln!(log, Level::Trace, "i32.store8 align={align} offset={offset}");
         }
         0x3b => {
             // i32.store16
             let align = reader.read_usize()?;
             let offset = reader.read_usize()?;
-            writeln!(env, "i32.store16 align={align} offset={offset}");
+            logln!(log, Level::Trace, "i32.store16 align={align} offset={offset}");
+        }
+        0x3c => {
+            // i64.store8
+            let align = reader.read_usize()?;
+            let offset = reader.read_usize()?;
+            logln!(log, Level::Trace, "i64.store8 align={align} offset={offset}");
         }
         0x3d => {
             // i64.store16
             let align = reader.read_usize()?;
             let offset = reader.read_usize()?;
-            writeln!(env, "i64.store16 align={align} offset={offset}");
+            logln!(log, Level::Trace, "i64.store16 align={align} offset={offset}");
+        }
+        0x3e => {
+            // i64.store32
+            let align = reader.read_usize()?;
+            let offset = reader.read_usize()?;
+            logln!(log, Level::Trace, "i64.store32 align={align} offset={offset}");
+        }
+        0x3f => {
+            // memory.size
+            let mem_idx = reader.read_usize()?;
+            logln!(log, Level::Trace, "memory.size {}", mem_idx);
         }
         0x40 => {
             // memory.grow
             let mem_idx = reader.read_usize()?;
-            writeln!(env, "memory.grow {}", mem_idx);
+            logln!(log, Level::Trace, "memory.grow {}", mem_idx);
         }
         0x41 => {
             // i32.const <literal>
             let val = reader.read_signed()?;
-            writeln!(env, "i32.const {}", val);
+            logln!(log, Level::Trace, "i32.const {}", val);
         }
         0x42 => {
             // i64.const <literal>
             let val = reader.read_signed()?;
-            writeln!(env, "i64.const {}", val);
+            logln!(log, Level::Trace, "i64.const {}", val);
         }
         0x43 => {
             // f32.const <literal>
             let val = reader.read_f32()?;
-            writeln!(env, "f32.const {}", val);
+            logln!(log, Level::Trace, "f32.const {}", val);
         }
         0x44 => {
             // f64.const <literal>
             let val = reader.read_f64()?;
-            writeln!(env, "f64.const {}", val);
+            logln!(log, Level::Trace, "f64.const {}", val);
         }
         0x45 => {
             // i32.eqz
-            writeln!(env, "i32.eqz");
+            logln!(log, Level::Trace, "i32.eqz");
         }
         0x46 => {
             // i32.eq
-            writeln!(env, "i32.eq");
+            logln!(log, Level::Trace, "i32.eq");
         }
         0x47 => {
             // i32.ne
-            writeln!(env, "i32.ne");
+            logln!(log, Level::Trace, "i32.ne");
         }
         0x48 => {
             // i32.lt_s
-            writeln!(env, "i32.lt_s");
+            logln!(log, Level::Trace, "i32.lt_s");
         }
         0x49 => {
             // i32.lt_u
-            writeln!(env, "i32.lt_u");
+            logln!(log, Level::Trace, "i32.lt_u");
         }
         0x4a => {
-            // i32.le_s
-            writeln!(env, "i32.le_s");
+            // i32.gt_s
+            logln!(log, Level::Trace, "i32.gt_s");
         }
         0x4b => {
-            // i32.gt_s
-            writeln!(env, "i32.gt_s");
+            // i32.gt_u
+            logln!(log, Level::Trace, "i32.gt_u");
         }
         0x4c => {
-            // i32.gt_u
-            writeln!(env, "i32.gt_u");
+            // i32.le_s
+            logln!(log, Level::Trace, "i32.le_s");
         }
         0x4d => {
             // i32.le_u
-            writeln!(env, "i32.le_u");
+            logln!(log, Level::Trace, "i32.le_u");
         }
         0x4e => {
             // i32.ge_s
-            writeln!(env, "i32.ge_s");
+            logln!(log, Level::Trace, "i32.ge_s");
         }
         0x4f => {
             // i32.ge_u
-            writeln!(env, "i32.ge_u");
+            logln!(log, Level::Trace, "i32.ge_u");
         }
         0x50 => {
             // i64.eqz
-            writeln!(env, "i64.eqz");
+            logln!(log, Level::Trace, "i64.eqz");
         }
         0x52 => {
             // i64.ne
-            writeln!(env, "i64.ne");
+            logln!(log, Level::Trace, "i64.ne");
         }
         0x54 => {
             // i64.lt_u
-            writeln!(env, "i64.lt_u");
+            logln!(log, Level::Trace, "i64.lt_u");
         }
         0x56 => {
             // i64.gt_u
-            writeln!(env, "i64.gt_u");
+            logln!(log, Level::Trace, "i64.gt_u");
         }
         0x5a => {
             // i64.ge_u
-            writeln!(env, "i64.ge_u");
+            logln!(log, Level::Trace, "i64.ge_u");
         }
         0x5c => {
             // f32.ne
-            writeln!(env, "f32.ne");
+            logln!(log, Level::Trace, "f32.ne");
         }
         0x63 => {
             // f64.lt
-            writeln!(env, "f64.lt");
+            logln!(log, Level::Trace, "f64.lt");
         }
         0x65 => {
             // f64.le
-            writeln!(env, "f64.le");
+            logln!(log, Level::Trace, "f64.le");
         }
         0x67 => {
             // i32.clz
-            writeln!(env, "i32.clz");
+            logln!(log, Level::Trace, "i32.clz");
         }
         0x68 => {
             // i32.ctz
-            writeln!(env, "i32.ctz");
+            logln!(log, Level::Trace, "i32.ctz");
         }
         0x69 => {
             // i32.popcnt
-            writeln!(env, "i32.popcnt");
+            logln!(log, Level::Trace, "i32.popcnt");
         }
         0x6a => {
             // i32.add
-            writeln!(env, "i32.add");
+            logln!(log, Level::Trace, "i32.add");
         }
         0x6b => {
             // i32.sub
-            writeln!(env, "i32.sub");
+            logln!(log, Level::Trace, "i32.sub");
         }
         0x6c => {
             // i32.mul
-            writeln!(env, "i32.mul");
+            logln!(log, Level::Trace, "i32.mul");
         }
         0x6d => {
             // i32.div_s
-            writeln!(env, "i32.div_s");
+            logln!(log, Level::Trace, "i32.div_s");
         }
         0x6e => {
             // i32.div_u
-            writeln!(env, "i32.div_u");
+            logln!(log, Level::Trace, "i32.div_u");
         }
         0x6f => {
             // i32.rem_s
-            writeln!(env, "i32.rem_s");
+            logln!(log, Level::Trace, "i32.rem_s");
         }
         0x70 => {
             // i32.rem_u
-            writeln!(env, "i32.rem_u");
+            logln!(log, Level::Trace, "i32.rem_u");
         }
         0x71 => {
             // i32.and
-            writeln!(env, "i32.and");
+            logln!(log, Level::Trace, "i32.and");
         }
         0x72 => {
             // i32.or
-            writeln!(env, "i32.or");
+            logln!(log, Level::Trace, "i32.or");
         }
         0x73 => {
             // i32.xor
-            writeln!(env, "i32.xor");
+            logln!(log, Level::Trace, "i32.xor");
         }
         0x74 => {
             // i32.shl
-            writeln!(env, "i32.shl");
+            logln!(log, Level::Trace, "i32.shl");
         }
         0x75 => {
             // i32.shr_s
-            writeln!(env, "i32.shr_s");
+            logln!(log, Level::Trace, "i32.shr_s");
         }
         0x76 => {
             // i32.shr_u
-            writeln!(env, "i32.shr_u");
+            logln!(log, Level::Trace, "i32.shr_u");
         }
         0x77 => {
             // i32.rotl
-            writeln!(env, "i32.rotl");
+            logln!(log, Level::Trace, "i32.rotl");
         }
         0x78 => {
             // i32.rotr
-            writeln!(env, "i32.rotr");
+            logln!(log, Level::Trace, "i32.rotr");
         }
         0x7a => {
             // i64.ctz
-            writeln!(env, "i64.ctz");
+            logln!(log, Level::Trace, "i64.ctz");
         }
         0x7c => {
             // i64.add
-            writeln!(env, "i64.add");
+            logln!(log, Level::Trace, "i64.add");
         }
         0x7d => {
             // i64.sub
-            writeln!(env, "i64.sub");
+            logln!(log, Level::Trace, "i64.sub");
         }
         0x7e => {
             // i64.mul
-            writeln!(env, "i64.mul");
+            logln!(log, Level::Trace, "i64.mul");
         }
         0x80 => {
             // i64.div_u
-            writeln!(env, "i64.div_u");
+            logln!(log, Level::Trace, "i64.div_u");
         }
         0x82 => {
             // i64.rem_u
-            writeln!(env, "i64.rem_u");
+            logln!(log, Level::Trace, "i64.rem_u");
         }
         0x83 => {
             // i64.and
-            writeln!(env, "i64.and");
+            logln!(log, Level::Trace, "i64.and");
         }
         0x84 => {
             // i64.or
-            writeln!(env, "i64.or");
+            logln!(log, Level::Trace, "i64.or");
         }
         0x86 => {
             // i64.shl
-            writeln!(env, "i64.shl");
+            logln!(log, Level::Trace, "i64.shl");
         }
         0x88 => {
             // i64.shr_u
-            writeln!(env, "i64.shr_u");
+            logln!(log, Level::Trace, "i64.shr_u");
         }
         0x8c => {
             // f32.neg
-            writeln!(env, "f32.neg");
+            logln!(log, Level::Trace, "f32.neg");
         }
         0x92 => {
             // f32.add
-            writeln!(env, "f32.add");
+            logln!(log, Level::Trace, "f32.add");
         }
         0x9a => {
             // f64.neg
-            writeln!(env, "f64.neg");
+            logln!(log, Level::Trace, "f64.neg");
         }
         0xa0 => {
             // f64.add
-            writeln!(env, "f64.add");
+            logln!(log, Level::Trace, "f64.add");
         }
         0xa1 => {
             // f64.sub
-            writeln!(env, "f64.sub");
+            logln!(log, Level::Trace, "f64.sub");
         }
         0xa2 => {
             // f64.mul
-            writeln!(env, "f64.mul");
+            logln!(log, Level::Trace, "f64.mul");
         }
         0xa7 => {
             // i32.wrap_i64
-            writeln!(env, "i32.wrap_i64");
+            logln!(log, Level::Trace, "i32.wrap_i64");
         }
         0xad => {
             // i64.extend_i32_u
-            writeln!(env, "i64.extend_i32_u");
+            logln!(log, Level::Trace, "i64.extend_i32_u");
         }
         0xbe => {
             // f32.reinterpret_i32
-            writeln!(env, "f32.reinterpret_i32");
+            logln!(log, Level::Trace, "f32.reinterpret_i32");
         }
         0xc0 => {
             // i32.extend8_s
-            writeln!(env, "i32.extend8_s");
+            logln!(log, Level::Trace, "i32.extend8_s");
         }
         0xc1 => {
             // i32.extend16_s
-            writeln!(env, "i32.extend16_s");
+            logln!(log, Level::Trace, "i32.extend16_s");
+        }
+        0xd0 => {
+            // ref.null <reftype>
+            let kind = reader.read::<TypeKind>()?;
+            logln!(log, Level::Trace, "ref.null {:?}", kind);
+        }
+        0xd1 => {
+            // ref.is_null
+            logln!(log, Level::Trace, "ref.is_null");
+        }
+        0xd2 => {
+            // ref.func <func_idx>
+            let func_idx = reader.read_usize()?;
+            logln!(log, Level::Trace, "ref.func {}", func_idx);
+        }
+        0xfc => {
+            // prefixed instructions, only their immediates are skipped
+            let sub = reader.read_usize()?;
+            match sub {
+                // memory.init | memory.copy | table.init | table.copy
+                8 | 10 | 12 | 14 => {
+                    _ = reader.read_usize()?;
+                    _ = reader.read_usize()?;
+                }
+                // data.drop | memory.fill | elem.drop | table.grow | table.size | table.fill
+                9 | 11 | 13 | 15..=17 => _ = reader.read_usize()?,
+                _ => {}
+            }
+            logln!(log, Level::Trace, "opcode fc {sub:02x?} @ {pos:02x}");
         }
         _ => {
-            writeln!(env, "opcode {op:02x?} @ {pos:02x}")
+            logln!(log, Level::Trace, "opcode {op:02x?} @ {pos:02x}")
         }
     }
 
@@ -941,9 +1350,19 @@ fn parse_opcode<const ONLY_PRINT: bool>(
 
 #[cfg(test)]
 mod tests {
+    use alloc::rc::Rc;
+    use alloc::string::String;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use core::cell::{Cell, RefCell};
     use core::fmt::Arguments;
+    use core::iter;
+    use core::sync::atomic::AtomicBool;
+    use core::time::Duration;
 
-    use crate::{Environment, execute_function, parse, VmContext};
+    use crate::{AppConfig, AppState, arena_size, lazy_arena_size, Arena, ByteStr, call_dynamic, Caller, Engine, Environment, evaluate, execute_function, Execution, GuestAllocator, HostError, HostFunc, ImportName, ImportOutcome, init_globals, init_memory, Instance, Level, Linker, Log, MemoryError, MemoryView, NoLog, parse, parse_in, parse_image, parse_image_in, image_arena_size, parse_lazy, parse_lazy_in, PAGE_SIZE, ParserError, PauseReason, Pod, resume, Scheduler, SchedulerStep, Superinstruction, TypedFunc, TypeKind, Value, VmBuffers, VmContext, VmStack, WasmPtr, WasmSlice, write_image};
+    use crate::arena::ModuleSlice;
+    use crate::interpreter::InterpreterError;
 
     struct MyEnv;
 
@@ -955,6 +1374,42 @@ mod tests {
         }
     }
 
+    // every read of the clock moves it forward by one millisecond
+    struct ClockEnv {
+        now: Cell<u64>,
+        // ids reported by guests through `report` and when they did it
+        reports: Vec<(u32, u64)>,
+    }
+
+    impl ClockEnv {
+        fn new() -> Self {
+            Self { now: Cell::new(0), reports: Vec::new() }
+        }
+    }
+
+    impl Environment for ClockEnv {
+        fn write_fmt(&mut self, _args: Arguments) {}
+
+        fn ticks(&self) -> u64 {
+            let now = self.now.get();
+            self.now.set(now + 1);
+            now
+        }
+
+        fn ticks_per_second(&self) -> u64 {
+            1000
+        }
+    }
+
+    // every guest has to behave the same in all of them
+    fn engines() -> Vec<Engine> {
+        #[allow(unused_mut)]
+        let mut engines = vec![Engine::Stack, Engine::Register];
+        #[cfg(feature = "jit")]
+        engines.push(Engine::Jit);
+        engines
+    }
+
     fn native_factorial(n: u32) -> u32 {
         (1..=n).product()
     }
@@ -962,10 +1417,10 @@ mod tests {
     #[test]
     fn factorial() {
         let module =
-            parse(include_bytes!("../../tests/factorial.wasm"), &mut MyEnv).expect("parse module");
+            parse(include_bytes!("../../tests/factorial.wasm"), &mut NoLog).expect("parse module");
         let mut ctx = VmContext::new();
         for i in 0..10 {
-            let result = execute_function::<MyEnv, (f64, ), f64>(&mut ctx, &module, b"fac".into(), (i as f64, ), &mut [], &mut [], &[], &mut MyEnv).unwrap();
+            let result = execute_function::<MyEnv, (f64, ), f64>(&mut ctx, &module, b"fac".into(), (i as f64, ), &mut [], &mut [], &mut [], &mut MyEnv).unwrap();
             assert_eq!(result, native_factorial(i) as f64);
         }
     }
@@ -973,35 +1428,1478 @@ mod tests {
     #[test]
     fn multivalue_sub() {
         let module =
-            parse(include_bytes!("../../tests/multivalue.wasm"), &mut MyEnv).expect("parse module");
+            parse(include_bytes!("../../tests/multivalue.wasm"), &mut NoLog).expect("parse module");
         let mut ctx = VmContext::new();
         for i in 0..10i32 {
             for j in 10..20i32 {
-                let result = execute_function::<MyEnv, (i32, i32), i32>(&mut ctx, &module, b"reverseSub".into(), (i, j), &mut [], &mut [], &[], &mut MyEnv).unwrap();
+                let result = execute_function::<MyEnv, (i32, i32), i32>(&mut ctx, &module, b"reverseSub".into(), (i, j), &mut [], &mut [], &mut [], &mut MyEnv).unwrap();
                 assert_eq!(result, j - i);
             }
         }
+
+        // `swap` returns two values, which are only available through `call_dynamic`
+        let swap = TypedFunc::<(i32, i32), i32>::new(&module, 0);
+        assert!(matches!(swap, Err(InterpreterError::InvalidSignature)));
+    }
+
+    #[test]
+    fn call_with_dynamic_values() {
+        let module =
+            parse(include_bytes!("../../tests/factorial.wasm"), &mut NoLog).expect("parse module");
+        let mut ctx = VmContext::new();
+        let signature = module.get_function_signature(module.get_export_index(b"fac".into()).unwrap()).unwrap();
+        assert_eq!(signature.params(), [TypeKind::F64]);
+        assert_eq!(signature.results(), [TypeKind::F64]);
+
+        let results = call_dynamic::<MyEnv>(&mut ctx, &module, b"fac".into(), &[Value::F64(5.0)], &mut [], &mut [], &mut [], &mut MyEnv).unwrap();
+        assert_eq!(results, [Value::F64(120.0)]);
+        let result = call_dynamic::<MyEnv>(&mut ctx, &module, b"fac".into(), &[Value::I32(5)], &mut [], &mut [], &mut [], &mut MyEnv);
+        assert!(matches!(result, Err(InterpreterError::InvalidSignature)));
+        let result = call_dynamic::<MyEnv>(&mut ctx, &module, b"fac".into(), &[], &mut [], &mut [], &mut [], &mut MyEnv);
+        assert!(matches!(result, Err(InterpreterError::InvalidSignature)));
+
+        let module =
+            parse(include_bytes!("../../tests/multivalue.wasm"), &mut NoLog).expect("parse module");
+        let mut instance = Instance::<MyEnv>::new(&module, Vec::new()).unwrap();
+        let results = instance.call_dynamic(b"reverseSub".into(), &[Value::I32(3), Value::I32(10)], &mut MyEnv).unwrap();
+        assert_eq!(results, [Value::I32(7)]);
+    }
+
+    #[test]
+    fn reference_globals() {
+        let module =
+            parse(include_bytes!("../../tests/refs.wasm"), &mut NoLog).expect("parse module");
+        assert_eq!(module.globals_len_in_bytes(), 8 + 8 + 4);
+        for engine in engines() {
+            let mut instance = Instance::<MyEnv>::new(&module, Vec::new()).unwrap();
+            instance.ctx().set_engine(engine);
+            assert_eq!(instance.call_dynamic(b"get_func".into(), &[], &mut MyEnv).unwrap(), [Value::FuncRef(Some(0))]);
+            assert_eq!(instance.call_dynamic(b"get_null".into(), &[], &mut MyEnv).unwrap(), [Value::ExternRef(None)]);
+            instance.call_dynamic(b"set_func".into(), &[Value::FuncRef(None)], &mut MyEnv).unwrap();
+            assert_eq!(instance.call_dynamic(b"get_func".into(), &[], &mut MyEnv).unwrap(), [Value::FuncRef(None)]);
+            instance.call_dynamic(b"set_func".into(), &[Value::FuncRef(Some(3))], &mut MyEnv).unwrap();
+            assert_eq!(instance.call_dynamic(b"get_func".into(), &[], &mut MyEnv).unwrap(), [Value::FuncRef(Some(3))]);
+            assert_eq!(instance.call_dynamic(b"get_after".into(), &[], &mut MyEnv).unwrap(), [Value::I32(7)]);
+        }
     }
 
     #[test]
     fn sum_array_of_f32() {
         let module =
-            parse(include_bytes!("../../tests/sum_array.wasm"), &mut MyEnv).expect("parse module");
+            parse(include_bytes!("../../tests/sum_array.wasm"), &mut NoLog).expect("parse module");
         let mut ctx = VmContext::new();
         let mut numbers = [1.23f32, 4.56];
         let data = unsafe { core::slice::from_raw_parts_mut(numbers.as_mut_ptr().cast(), numbers.len() * 4) };
-        let result = execute_function::<MyEnv, (u32, u32), f32>(&mut ctx, &module, b"sum_slice".into(), (0u32, numbers.len() as u32), data, &mut [], &[], &mut MyEnv).unwrap();
+        let result = execute_function::<MyEnv, (u32, u32), f32>(&mut ctx, &module, b"sum_slice".into(), (0u32, numbers.len() as u32), data, &mut [], &mut [], &mut MyEnv).unwrap();
         assert_eq!(result, 5.79);
     }
 
     #[test]
     fn sum_array_of_f32_recurrent() {
         let module =
-            parse(include_bytes!("../../tests/sum_array_rec.wasm"), &mut MyEnv).expect("parse module");
+            parse(include_bytes!("../../tests/sum_array_rec.wasm"), &mut NoLog).expect("parse module");
+        let mut ctx = VmContext::new();
+        let mut numbers = [1.23f32, 4.56, -10.0];
+        let data = unsafe { core::slice::from_raw_parts_mut(numbers.as_mut_ptr().cast(), numbers.len() * 4) };
+        let result = execute_function::<MyEnv, (u32, u32), f32>(&mut ctx, &module, b"sum_slice".into(), (0u32, numbers.len() as u32), data, &mut [], &mut [], &mut MyEnv).unwrap();
+        assert_eq!(result, -4.21);
+    }
+
+    #[test]
+    fn parse_into_arena() {
+        let code = include_bytes!("../../tests/sum_array_rec.wasm");
+        let mut buf = vec![0u8; arena_size(code).unwrap()];
+        let arena = Arena::new(&mut buf);
+        let module = parse_in(code, &arena, &mut NoLog).expect("parse module");
+        let mut ctx = VmContext::new();
+        let mut numbers = [1.23f32, 4.56, -10.0];
+        let data = unsafe { core::slice::from_raw_parts_mut(numbers.as_mut_ptr().cast(), numbers.len() * 4) };
+        let result = execute_function::<MyEnv, (u32, u32), f32>(&mut ctx, &module, b"sum_slice".into(), (0u32, numbers.len() as u32), data, &mut [], &mut [], &mut MyEnv).unwrap();
+        assert_eq!(result, -4.21);
+    }
+
+    #[test]
+    fn parse_lazily_into_arena() {
+        let code = include_bytes!("../../tests/sum_array_rec.wasm");
+        assert!(lazy_arena_size(code).unwrap() < arena_size(code).unwrap());
+        let mut buf = vec![0u8; lazy_arena_size(code).unwrap()];
+        let arena = Arena::new(&mut buf);
+        let module = parse_lazy_in(code, &arena, &mut NoLog).expect("parse module");
+        let mut ctx = VmContext::new();
+        ctx.set_engine(Engine::Stack);
+        let mut numbers = [1.23f32, 4.56, -10.0];
+        let data = unsafe { core::slice::from_raw_parts_mut(numbers.as_mut_ptr().cast(), numbers.len() * 4) };
+        let result = execute_function::<MyEnv, (u32, u32), f32>(&mut ctx, &module, b"sum_slice".into(), (0u32, numbers.len() as u32), data, &mut [], &mut [], &mut MyEnv).unwrap();
+        assert_eq!(result, -4.21);
+        assert!(ctx.prepared_functions() > 0);
+    }
+
+    // Copies an image into memory aligned as required by `parse_image`.
+    fn aligned_image(image: &[u8]) -> Vec<u64> {
+        let mut words = vec![0u64; image.len().div_ceil(8)];
+        for (word, chunk) in words.iter_mut().zip(image.chunks(8)) {
+            let mut bytes = [0u8; 8];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            *word = u64::from_ne_bytes(bytes);
+        }
+        words
+    }
+
+    #[test]
+    fn parse_from_image() {
+        let code = include_bytes!("../../tests/sum_array_rec.wasm");
+        for with_bytecode in [true, false] {
+            let image = write_image(code, with_bytecode).unwrap();
+            let words = aligned_image(&image);
+            let image = unsafe { core::slice::from_raw_parts(words.as_ptr().cast::<u8>(), image.len()) };
+
+            let mut buf = vec![0u8; image_arena_size(code, image).unwrap()];
+            let arena = Arena::new(&mut buf);
+            let module = parse_image_in(code, image, &arena, &mut NoLog).expect("parse module");
+            let mut ctx = VmContext::new();
+            ctx.set_engine(Engine::Stack);
+            let mut numbers = [1.23f32, 4.56, -10.0];
+            let data = unsafe { core::slice::from_raw_parts_mut(numbers.as_mut_ptr().cast(), numbers.len() * 4) };
+            let result = execute_function::<MyEnv, (u32, u32), f32>(&mut ctx, &module, b"sum_slice".into(), (0u32, numbers.len() as u32), data, &mut [], &mut [], &mut MyEnv).unwrap();
+            assert_eq!(result, -4.21);
+            // translated code is taken from the image when it has one
+            assert_eq!(ctx.prepared_functions() == 0, with_bytecode);
+        }
+    }
+
+    #[test]
+    fn reject_stale_or_damaged_image() {
+        let code = include_bytes!("../../tests/fused.wasm");
+        let image = write_image(code, true).unwrap();
+        let words = aligned_image(&image);
+        let aligned = unsafe { core::slice::from_raw_parts(words.as_ptr().cast::<u8>(), image.len()) };
+        assert!(parse_image(code, aligned, &mut NoLog).is_ok());
+
+        let other = include_bytes!("../../tests/factorial.wasm");
+        assert_eq!(parse_image(other, aligned, &mut NoLog).err(), Some(ParserError::StaleImage));
+
+        let mut damaged = image.clone();
+        *damaged.last_mut().unwrap() ^= 1;
+        let words = aligned_image(&damaged);
+        let damaged = unsafe { core::slice::from_raw_parts(words.as_ptr().cast::<u8>(), damaged.len()) };
+        assert_eq!(parse_image(code, damaged, &mut NoLog).err(), Some(ParserError::InvalidImage));
+
+        let words = aligned_image(&[&[0u8][..], &image].concat());
+        let misaligned = unsafe { core::slice::from_raw_parts(words.as_ptr().cast::<u8>().add(1), image.len()) };
+        assert_eq!(parse_image(code, misaligned, &mut NoLog).err(), Some(ParserError::InvalidImage));
+    }
+
+    #[test]
+    fn evict_prepared_bodies() {
+        let module =
+            parse_lazy(include_bytes!("../../tests/fused.wasm"), &mut NoLog).expect("parse module");
+        let mut ctx = VmContext::new();
+        ctx.set_engine(Engine::Stack);
+        let mut memory = vec![0u8; 64];
+        memory[12..16].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+        assert_eq!(ctx.prepared_functions(), 0);
+
+        let count_up = module.get_typed_func::<(u32, ), u32>(b"count_up".into()).unwrap();
+        let load_at = module.get_typed_func::<(u32, ), u32>(b"load_at".into()).unwrap();
+        assert_eq!(count_up.call(&mut ctx, (5, ), &mut memory, &mut [], &mut [], &mut MyEnv).unwrap(), 5);
+        assert_eq!(ctx.prepared_functions(), 1);
+        assert_eq!(load_at.call(&mut ctx, (8, ), &mut memory, &mut [], &mut [], &mut MyEnv).unwrap(), 0x1234_5678);
+        assert_eq!(ctx.prepared_functions(), 2);
+
+        // only the function that is being called fits
+        ctx.set_code_cache_limit(Some(1));
+        for _ in 0..3 {
+            assert_eq!(count_up.call(&mut ctx, (5, ), &mut memory, &mut [], &mut [], &mut MyEnv).unwrap(), 5);
+            assert_eq!(load_at.call(&mut ctx, (8, ), &mut memory, &mut [], &mut [], &mut MyEnv).unwrap(), 0x1234_5678);
+            assert_eq!(ctx.prepared_functions(), 1);
+        }
+
+        ctx.clear_code_cache();
+        assert_eq!(ctx.prepared_functions(), 0);
+        assert_eq!(ctx.code_cache_size(), 0);
+        assert_eq!(count_up.call(&mut ctx, (5, ), &mut memory, &mut [], &mut [], &mut MyEnv).unwrap(), 5);
+
+        // body of a paused call is kept
+        ctx.set_code_cache_limit(None);
+        ctx.set_instruction_limit(Some(10));
+        assert_eq!(load_at.call(&mut ctx, (8, ), &mut memory, &mut [], &mut [], &mut MyEnv).unwrap(), 0x1234_5678);
+        count_up.start(&mut ctx, (100, )).unwrap();
+        assert_eq!(resume(&mut ctx, &module, &mut memory, &mut [], &mut [], &mut MyEnv).unwrap(), Execution::Paused(PauseReason::InstructionLimit));
+        ctx.clear_code_cache();
+        assert_eq!(ctx.prepared_functions(), 1);
+        ctx.set_instruction_limit(None);
+        assert_eq!(resume(&mut ctx, &module, &mut memory, &mut [], &mut [], &mut MyEnv).unwrap(), Execution::Finished);
+        assert_eq!(count_up.result(&mut ctx).unwrap(), 100);
+    }
+
+    #[test]
+    fn parse_into_too_small_arena() {
+        let code = include_bytes!("../../tests/factorial.wasm");
+        let required = arena_size(code).unwrap();
+        let mut buf = vec![0u8; required / 2];
+        let arena = Arena::new(&mut buf);
+        let result = parse_in(code, &arena, &mut NoLog);
+        assert_eq!(result.err(), Some(ParserError::ArenaTooSmall { required }));
+        assert_eq!(arena.used(), 0);
+    }
+
+    #[test]
+    fn factorial_in_fixed_buffers() {
+        let module =
+            parse(include_bytes!("../../tests/factorial.wasm"), &mut NoLog).expect("parse module");
+        let mut stack = [0u8; 512];
+        let mut frames = [0u8; 16 * VmBuffers::FRAME_SIZE];
+        let mut ctx = VmContext::with_buffers(VmBuffers {
+            stack: &mut stack,
+            frames: &mut frames,
+        });
+        for i in 0..10 {
+            let result = execute_function::<MyEnv, (f64, ), f64>(&mut ctx, &module, b"fac".into(), (i as f64, ), &mut [], &mut [], &mut [], &mut MyEnv).unwrap();
+            assert_eq!(result, native_factorial(i) as f64);
+        }
+
+        // registers would have to be allocated on the heap
+        ctx.set_engine(Engine::Register);
+        let result = execute_function::<MyEnv, (f64, ), f64>(&mut ctx, &module, b"fac".into(), (3.0, ), &mut [], &mut [], &mut [], &mut MyEnv);
+        assert!(matches!(result, Err(InterpreterError::EngineNeedsHeap)));
+    }
+
+    #[test]
+    fn module_kinds_in_fixed_buffers() {
+        let code = include_bytes!("../../tests/factorial.wasm");
+        let images = [false, true].map(|with_bytecode| {
+            let image = write_image(code, with_bytecode).unwrap();
+            (aligned_image(&image), image.len())
+        });
+        let image = |idx: usize| {
+            let (words, len) = &images[idx];
+            unsafe { core::slice::from_raw_parts(words.as_ptr().cast::<u8>(), *len) }
+        };
+        let modules = [
+            // bodies prepared on the first call would be allocated on the heap
+            (parse_lazy(code, &mut NoLog).unwrap(), false),
+            (parse_image(code, image(0), &mut NoLog).unwrap(), false),
+            (parse_image(code, image(1), &mut NoLog).unwrap(), true),
+        ];
+        for (module, supported) in &modules {
+            let mut stack = [0u8; 512];
+            let mut frames = [0u8; 16 * VmBuffers::FRAME_SIZE];
+            let mut ctx = VmContext::with_buffers(VmBuffers {
+                stack: &mut stack,
+                frames: &mut frames,
+            });
+            let result = execute_function::<MyEnv, (f64, ), f64>(&mut ctx, module, b"fac".into(), (5.0, ), &mut [], &mut [], &mut [], &mut MyEnv);
+            if *supported {
+                assert_eq!(result.unwrap(), native_factorial(5) as f64);
+            } else {
+                assert!(matches!(result, Err(InterpreterError::LazyBodyNeedsHeap)));
+                assert_eq!(ctx.prepared_functions(), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn call_typed_func_repeatedly() {
+        let module =
+            parse(include_bytes!("../../tests/factorial.wasm"), &mut NoLog).expect("parse module");
+        assert!(matches!(module.get_typed_func::<(f64, ), f64>(b"missing".into()), Err(InterpreterError::FunctionNotFound)));
+        assert!(matches!(module.get_typed_func::<(u32, ), f64>(b"fac".into()), Err(InterpreterError::InvalidSignature)));
+        assert!(matches!(module.get_typed_func::<(f64, ), u32>(b"fac".into()), Err(InterpreterError::InvalidSignature)));
+
+        let fac = module.get_typed_func::<(f64, ), f64>(b"fac".into()).unwrap();
+        assert_eq!(Some(fac.func_idx()), module.get_function_index_by_name(b"fac".into()));
+        let mut ctx = VmContext::new();
+        for i in 0..10 {
+            let result = fac.call(&mut ctx, (i as f64, ), &mut [], &mut [], &mut [], &mut MyEnv).unwrap();
+            assert_eq!(result, native_factorial(i) as f64);
+        }
+
+        // imports are found by name, but they aren't exports
+        let module =
+            parse(include_bytes!("../../tests/async_import.wasm"), &mut NoLog).expect("parse module");
+        assert_eq!(module.get_function_index_by_name(b"read_sensor".into()), Some(0));
+        assert_eq!(module.get_export_index(b"read_sensor".into()), None);
+        assert!(module.get_export_index(b"sum_sensors".into()).is_some());
+    }
+
+    #[test]
+    fn trap_on_call_stack_overflow() {
+        let module =
+            parse(include_bytes!("../../tests/sum_array_rec.wasm"), &mut NoLog).expect("parse module");
+        let mut stack = [0u8; 512];
+        let mut frames = [0u8; 4 * VmBuffers::FRAME_SIZE];
+        let mut ctx = VmContext::with_buffers(VmBuffers {
+            stack: &mut stack,
+            frames: &mut frames,
+        });
+        let mut numbers = [1.0f32; 16];
+        let data = unsafe { core::slice::from_raw_parts_mut(numbers.as_mut_ptr().cast(), numbers.len() * 4) };
+        let result = execute_function::<MyEnv, (u32, u32), f32>(&mut ctx, &module, b"sum_slice".into(), (0u32, numbers.len() as u32), data, &mut [], &mut [], &mut MyEnv);
+        assert!(matches!(result, Err(InterpreterError::StackOverflow)));
+
+        // the context stays usable for calls that fit
+        let result = execute_function::<MyEnv, (u32, u32), f32>(&mut ctx, &module, b"sum_slice".into(), (0u32, 2), data, &mut [], &mut [], &mut MyEnv).unwrap();
+        assert_eq!(result, 2.0);
+    }
+
+    #[test]
+    fn pause_on_instruction_limit() {
+        let module =
+            parse(include_bytes!("../../tests/factorial.wasm"), &mut NoLog).expect("parse module");
+        let func_idx = module.get_function_index_by_name(b"fac".into()).unwrap();
+        let mut ctx = VmContext::new();
+        ctx.set_instruction_limit(Some(3));
+
+        let mut state = evaluate(&mut ctx, &module, func_idx, &5f64.to_ne_bytes(), &mut [], &mut [], &mut [], &mut MyEnv).unwrap();
+        let mut slices = 1;
+        while let Execution::Paused(reason) = state {
+            assert_eq!(reason, PauseReason::InstructionLimit);
+            assert!(ctx.is_paused());
+            state = resume(&mut ctx, &module, &mut [], &mut [], &mut [], &mut MyEnv).unwrap();
+            slices += 1;
+        }
+        assert!(slices > 1);
+        assert!(!ctx.is_paused());
+        assert_eq!(ctx.stack.pop_f64().unwrap(), 120.0);
+    }
+
+    #[test]
+    fn pause_on_fuel_exhaustion() {
+        let module =
+            parse(include_bytes!("../../tests/factorial.wasm"), &mut NoLog).expect("parse module");
+        let mut ctx = VmContext::new();
+        ctx.set_fuel(Some(10));
+
+        let result = execute_function::<MyEnv, (f64, ), f64>(&mut ctx, &module, b"fac".into(), (5.0, ), &mut [], &mut [], &mut [], &mut MyEnv);
+        assert!(matches!(result, Err(InterpreterError::Paused(PauseReason::OutOfFuel))));
+        assert_eq!(ctx.fuel(), Some(0));
+
+        ctx.add_fuel(1000);
+        let state = resume(&mut ctx, &module, &mut [], &mut [], &mut [], &mut MyEnv).unwrap();
+        assert_eq!(state, Execution::Finished);
+        assert_eq!(ctx.stack.pop_f64().unwrap(), 120.0);
+    }
+
+    #[test]
+    fn pause_on_yield_from_import() {
+        fn yield_now(_env: &mut MyEnv, _stack: &mut VmStack, _memory: &mut [u8]) -> Result<ImportOutcome, HostError> {
+            Ok(ImportOutcome::Yield)
+        }
+
+        let module =
+            parse(include_bytes!("../../tests/yield.wasm"), &mut NoLog).expect("parse module");
+        let func_idx = module.get_function_index_by_name(b"count".into()).unwrap();
+        let mut imports = [HostFunc::Fn(yield_now)];
+        let mut globals = Vec::new();
+        init_globals(&mut globals, &module).unwrap();
+        let mut ctx = VmContext::new();
+
+        let mut state = evaluate(&mut ctx, &module, func_idx, &4u32.to_ne_bytes(), &mut [], &mut globals, &mut imports, &mut MyEnv).unwrap();
+        let mut yields = 0;
+        while state == Execution::Paused(PauseReason::Yield) {
+            yields += 1;
+            state = resume(&mut ctx, &module, &mut [], &mut globals, &mut imports, &mut MyEnv).unwrap();
+        }
+        assert_eq!(state, Execution::Finished);
+        assert_eq!(yields, 4);
+        assert_eq!(ctx.stack.pop_u32().unwrap(), 6);
+    }
+
+    #[test]
+    fn link_typed_host_function() {
+        let module =
+            parse(include_bytes!("../../tests/async_import.wasm"), &mut NoLog).expect("parse module");
+        let mut globals = Vec::new();
+        init_globals(&mut globals, &module).unwrap();
+
+        let mut linker = Linker::new();
+        let error = linker.link(&module).unwrap_err();
+        let import = ImportName { module: b"env".into(), name: b"read_sensor".into() };
+        assert_eq!(error.unresolved, [import]);
+
+        linker.func("env", "read_sensor", |_env: &mut MyEnv, channel: u64| channel as u32);
+        assert_eq!(linker.link(&module).unwrap_err().mismatched, [import]);
+
+        linker.func("env", "read_sensor", |_env: &mut MyEnv, channel: u32| channel * 10);
+        let mut imports = linker.link(&module).unwrap();
+        let mut ctx = VmContext::new();
+        let result = execute_function::<MyEnv, (u32, ), u32>(&mut ctx, &module, b"sum_sensors".into(), (4, ), &mut [], &mut globals, &mut imports, &mut MyEnv).unwrap();
+        assert_eq!(result, 60);
+    }
+
+    #[test]
+    fn stateful_host_functions() {
+        let module =
+            parse(include_bytes!("../../tests/async_import.wasm"), &mut NoLog).expect("parse module");
+        let mut globals = Vec::new();
+        init_globals(&mut globals, &module).unwrap();
+
+        // state captured by the linker's closure outlives the linker
+        let mut requested = Vec::new();
+        {
+            let mut linker = Linker::new();
+            linker.func("env", "read_sensor", |_env: &mut MyEnv, channel: u32| {
+                requested.push(channel);
+                channel + 1
+            });
+            let mut imports = linker.link(&module).unwrap();
+            let mut ctx = VmContext::new();
+            let result = execute_function::<MyEnv, (u32, ), u32>(&mut ctx, &module, b"sum_sensors".into(), (3, ), &mut [], &mut globals, &mut imports, &mut MyEnv).unwrap();
+            assert_eq!(result, 6);
+        }
+        assert_eq!(requested, [0, 1, 2]);
+
+        // closure borrowed without a heap
+        let mut calls = 0;
+        let mut read_sensor = |_env: &mut MyEnv, stack: &mut VmStack, _memory: &mut [u8]| {
+            calls += 1;
+            let channel = stack.pop_u32().unwrap();
+            stack.push_i32((channel * calls) as i32);
+            Ok(ImportOutcome::Return)
+        };
+        let mut imports = [HostFunc::Borrowed(&mut read_sensor)];
+        let mut ctx = VmContext::new();
+        let result = execute_function::<MyEnv, (u32, ), u32>(&mut ctx, &module, b"sum_sensors".into(), (3, ), &mut [], &mut globals, &mut imports, &mut MyEnv).unwrap();
+        // channels multiplied by the number of calls so far: 0 * 1 + 1 * 2 + 2 * 3
+        assert_eq!(result, 8);
+        drop(imports);
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn host_data_of_instances() {
+        let module =
+            parse(include_bytes!("../../tests/async_import.wasm"), &mut NoLog).expect("parse module");
+
+        // each reading is offset by the data of the instance, which counts the readings
+        let mut linker = Linker::new();
+        linker.func("env", "read_sensor", |mut caller: Caller<MyEnv>, channel: u32| match caller.data::<u32>() {
+            Some(offset) => {
+                *offset += 1;
+                channel + *offset
+            }
+            None => channel,
+        });
+        let mut first = Instance::new(&module, linker.link(&module).unwrap()).unwrap();
+        first.set_data(10u32);
+        let mut second = Instance::new(&module, linker.link(&module).unwrap()).unwrap();
+        second.set_data(100u32);
+        let mut without_data = Instance::new(&module, linker.link(&module).unwrap()).unwrap();
+
+        assert_eq!(first.call::<(u32, ), u32>(b"sum_sensors".into(), (3, ), &mut MyEnv).unwrap(), 39);
+        second.ctx().set_engine(Engine::Register);
+        assert_eq!(second.call::<(u32, ), u32>(b"sum_sensors".into(), (3, ), &mut MyEnv).unwrap(), 309);
+        assert_eq!(without_data.call::<(u32, ), u32>(b"sum_sensors".into(), (3, ), &mut MyEnv).unwrap(), 3);
+        assert_eq!(first.data::<u32>(), Some(&mut 13));
+        assert_eq!(second.data::<u32>(), Some(&mut 103));
+        assert_eq!(second.data::<u64>(), None);
+        assert_eq!(without_data.data::<u32>(), None);
+    }
+
+    #[test]
+    fn host_function_traps() {
+        #[derive(Debug, PartialEq)]
+        struct NoSensor(u32);
+
+        let module =
+            parse(include_bytes!("../../tests/async_import.wasm"), &mut NoLog).expect("parse module");
+        let mut globals = Vec::new();
+        init_globals(&mut globals, &module).unwrap();
+
+        let mut linker = Linker::new();
+        linker.func("env", "read_sensor", |_env: &mut MyEnv, channel: u32| match channel {
+            0..=1 => Ok(channel + 1),
+            _ => Err(HostError::new(NoSensor(channel))),
+        });
+        let mut imports = linker.link(&module).unwrap();
+        for engine in [Engine::Stack, Engine::Register] {
+            let mut ctx = VmContext::new();
+            ctx.set_engine(engine);
+            let result = execute_function::<MyEnv, (u32, ), u32>(&mut ctx, &module, b"sum_sensors".into(), (4, ), &mut [], &mut globals, &mut imports, &mut MyEnv);
+            let Err(InterpreterError::Host(error)) = result else {
+                panic!("expected a trap, got {result:?}");
+            };
+            assert_eq!(error.downcast_ref::<NoSensor>(), Some(&NoSensor(2)));
+            assert_eq!(error.downcast_ref::<u32>(), None);
+
+            // trapped guest is unwound, so the context can be used again
+            let result = execute_function::<MyEnv, (u32, ), u32>(&mut ctx, &module, b"sum_sensors".into(), (2, ), &mut [], &mut globals, &mut imports, &mut MyEnv).unwrap();
+            assert_eq!(result, 3);
+        }
+    }
+
+    #[test]
+    fn access_guest_memory() {
+        // `Str` of the guest
+        #[derive(Clone, Copy)]
+        #[repr(C)]
+        struct GuestStr {
+            data: WasmPtr<u8>,
+            len: u32,
+        }
+
+        unsafe impl Pod for GuestStr {
+            fn to_le(self) -> Self {
+                Self { data: self.data.to_le(), len: self.len.to_le() }
+            }
+        }
+
+        let module =
+            parse(include_bytes!("../../tests/print_str.wasm"), &mut NoLog).expect("parse module");
+        let mut globals = Vec::new();
+        init_globals(&mut globals, &module).unwrap();
+        let mut memory = vec![0; 17 * 64 * 1024];
+        init_memory(&mut memory, &module).unwrap();
+
+        let printed = RefCell::new(Vec::new());
+        let mut linker = Linker::new();
+        linker
+            .func("env", "print", |caller: Caller<MyEnv>, ptr: WasmPtr<u8>, len: u32| -> Result<(), HostError> {
+                printed.borrow_mut().push(String::from(caller.memory.str(WasmSlice::new(ptr, len))?));
+                Ok(())
+            })
+            .func("env", "print_ref", |caller: Caller<MyEnv>, s: WasmPtr<GuestStr>| -> Result<(), HostError> {
+                let s = caller.memory.read(s)?;
+                printed.borrow_mut().push(String::from(caller.memory.str(WasmSlice::new(s.data, s.len))?));
+                Ok(())
+            });
+        let mut imports = linker.link(&module).unwrap();
+        let mut ctx = VmContext::new();
+        execute_function::<MyEnv, (), ()>(&mut ctx, &module, b"greet".into(), (), &mut memory, &mut globals, &mut imports, &mut MyEnv).unwrap();
+        assert_eq!(*printed.borrow(), ["hello", "world"]);
+
+        let result = execute_function::<MyEnv, (u32, ), ()>(&mut ctx, &module, b"print_bad".into(), (16, ), &mut memory, &mut globals, &mut imports, &mut MyEnv);
+        let Err(InterpreterError::Host(error)) = result else {
+            panic!("expected a trap, got {result:?}");
+        };
+        assert_eq!(error.downcast_ref(), Some(&MemoryError::OutOfBounds { offset: 0xffff_fff0, len: 16 }));
+
+        let mut bytes = [0xff; 8];
+        let mut view = MemoryView::new(&mut bytes);
+        let words = WasmSlice::new(WasmPtr::<u32>::new(0), 2);
+        view.write(words.at(1).unwrap(), 0x1234_5678).unwrap();
+        assert_eq!(view.read(words.at(1).unwrap()), Ok(0x1234_5678));
+        assert_eq!(words.at(2), Err(MemoryError::OutOfBounds { offset: 0, len: 8 }));
+        assert_eq!(view.write(WasmPtr::<u32>::new(6), 0), Err(MemoryError::OutOfBounds { offset: 6, len: 4 }));
+        assert_eq!(view.str(WasmSlice::new(WasmPtr::new(2), 2)), Err(MemoryError::InvalidUtf8 { offset: 2 }));
+        assert_eq!(bytes[4..], [0x78, 0x56, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn instantiate_module() {
+        let module = parse(include_bytes!("../../tests/br.wasm"), &mut NoLog).expect("parse module");
+        let instance = Instance::<MyEnv>::new(&module, Vec::new()).unwrap();
+        assert_eq!(instance.table(0), Some(&[Some(37)][..]));
+        assert_eq!(instance.table(1), None);
+
+        let module =
+            parse(include_bytes!("../../tests/print_str.wasm"), &mut NoLog).expect("parse module");
+        let result = Instance::<MyEnv>::new(&module, Vec::new());
+        assert!(matches!(result, Err(InterpreterError::ImportsMismatch { expected: 2, found: 0 })));
+
+        let printed = RefCell::new(Vec::new());
+        let mut linker = Linker::new();
+        linker
+            .func("env", "print", |caller: Caller<MyEnv>, ptr: WasmPtr<u8>, len: u32| -> Result<(), HostError> {
+                printed.borrow_mut().push(String::from(caller.memory.str(WasmSlice::new(ptr, len))?));
+                Ok(())
+            })
+            .func("env", "print_ref", |_: &mut MyEnv, _: u32| {});
+        let mut instance = Instance::new(&module, linker.link(&module).unwrap()).unwrap();
+        // initial size of the memory and the stack pointer set up by rustc
+        assert_eq!(instance.memory().len(), 17 * PAGE_SIZE);
+        assert_eq!(instance.global::<u32>(0), Some(0x10_0000));
+        assert_eq!(instance.global::<u64>(0), None);
+        // active segments are dropped once they are copied into memory
+        assert_eq!(instance.data_segment(0), None);
+
+        instance.call::<(), ()>(b"greet".into(), (), &mut MyEnv).unwrap();
+        assert_eq!(*printed.borrow(), ["hello"]);
+        assert_eq!(instance.global::<u32>(0), Some(0x10_0000));
+    }
+
+    #[test]
+    fn call_through_table() {
+        let module =
+            parse(include_bytes!("../../tests/call_indirect.wasm"), &mut NoLog).expect("parse module");
+        let mut instance = Instance::<MyEnv>::new(&module, Vec::new()).unwrap();
+        assert_eq!(instance.table(0), Some(&[None, Some(0), Some(1), Some(2)][..]));
+        for engine in engines() {
+            instance.ctx().set_engine(engine);
+            assert_eq!(instance.call::<(u32, i32), i32>(b"apply".into(), (1, 5), &mut MyEnv).unwrap(), 10);
+            assert_eq!(instance.call::<(u32, i32), i32>(b"apply".into(), (3, 5), &mut MyEnv).unwrap(), -5);
+            let result = instance.call::<(u32, i32), i32>(b"apply".into(), (2, 5), &mut MyEnv);
+            assert!(matches!(result, Err(InterpreterError::IndirectCallTypeMismatch)), "{result:?}");
+            for idx in [0, 4] {
+                let result = instance.call::<(u32, i32), i32>(b"apply".into(), (idx, 5), &mut MyEnv);
+                assert!(matches!(result, Err(InterpreterError::UndefinedElement)), "{result:?}");
+            }
+
+            // without an instance the elements are taken from the segments of the module
+            let mut ctx = VmContext::new();
+            ctx.set_engine(engine);
+            let result = execute_function::<MyEnv, (u32, i32), i32>(&mut ctx, &module, b"apply".into(), (3, 7), &mut [], &mut [], &mut [], &mut MyEnv);
+            assert_eq!(result.unwrap(), -7);
+            let result = execute_function::<MyEnv, (u32, i32), i32>(&mut ctx, &module, b"apply".into(), (0, 7), &mut [], &mut [], &mut [], &mut MyEnv);
+            assert!(matches!(result, Err(InterpreterError::UndefinedElement)), "{result:?}");
+        }
+    }
+
+    #[test]
+    fn access_globals_by_name() {
+        let module =
+            parse(include_bytes!("../../tests/print_str.wasm"), &mut NoLog).expect("parse module");
+        let mut linker = Linker::new();
+        linker
+            .func("env", "print", |_: &mut MyEnv, _: u32, _: u32| {})
+            .func("env", "print_ref", |_: &mut MyEnv, _: u32| {});
+        let mut instance = Instance::new(&module, linker.link(&module).unwrap()).unwrap();
+        // exported by rustc, the heap starts after the data aligned to 16 bytes
+        assert_eq!(instance.get_global::<u32>(b"__data_end".into()).unwrap(), 0x10_000a);
+        assert_eq!(instance.get_global::<u32>(b"__heap_base".into()).unwrap(), 0x10_0010);
+        assert!(matches!(
+            instance.get_global::<u64>(b"__data_end".into()),
+            Err(InterpreterError::GlobalTypeMismatch { expected: TypeKind::I32, found: TypeKind::I64 })
+        ));
+        assert!(matches!(instance.set_global(b"__heap_base".into(), 0u32), Err(InterpreterError::ImmutableGlobal)));
+        assert!(matches!(instance.get_global::<u32>(b"missing".into()), Err(InterpreterError::GlobalNotFound)));
+
+        let module =
+            parse(include_bytes!("../../tests/globals.wasm"), &mut NoLog).expect("parse module");
+        let result = Instance::<MyEnv>::new(&module, Vec::new());
+        assert!(matches!(result, Err(InterpreterError::ImportsMismatch { expected: 1, found: 0 })));
+
+        let mut linker = Linker::<MyEnv>::new();
+        linker.global("env", "scale", Value::I64(3));
+        let error = linker.link_globals(&module).unwrap_err();
+        assert_eq!(error.mismatched, [ImportName { module: b"env".into(), name: b"scale".into() }]);
+
+        linker.global("env", "scale", Value::I32(3));
+        let globals = linker.link_globals(&module).unwrap();
+        let mut instance = Instance::with_globals(&module, Vec::new(), &globals).unwrap();
+        assert_eq!(instance.get_global::<f32>(b"limit".into()).unwrap(), 1.5);
+        assert_eq!(instance.call::<(), i32>(b"tick".into(), (), &mut MyEnv).unwrap(), 3);
+        assert_eq!(instance.get_global::<i64>(b"counter".into()).unwrap(), 1);
+        // the imported global is shared, both the guest and the host can change it
+        assert_eq!(instance.get_global::<i32>(b"scale".into()).unwrap(), 4);
+        instance.set_global(b"scale".into(), 10i32).unwrap();
+        instance.set_global(b"counter".into(), 41i64).unwrap();
+        assert_eq!(instance.call::<(), i32>(b"tick".into(), (), &mut MyEnv).unwrap(), 10);
+        assert_eq!(instance.get_global::<i64>(b"counter".into()).unwrap(), 42);
+    }
+
+    #[test]
+    fn initialize_from_imported_globals() {
+        let module =
+            parse(include_bytes!("../../tests/global_init.wasm"), &mut NoLog).expect("parse module");
+        let mut linker = Linker::<MyEnv>::new();
+        linker.global("env", "base", Value::I32(100));
+        let globals = linker.link_globals(&module).unwrap();
+        let mut instance = Instance::with_globals(&module, Vec::new(), &globals).unwrap();
+        assert_eq!(instance.get_global::<i32>(b"end".into()).unwrap(), 100);
+        assert_eq!(instance.get_global::<i64>(b"limit".into()).unwrap(), 5);
+        assert_eq!(instance.memory().bytes(WasmSlice::new(WasmPtr::new(100), 2)).unwrap(), b"hi");
+        assert_eq!(instance.call::<(), i32>(b"load".into(), (), &mut MyEnv).unwrap(), i32::from(b'h'));
+
+        // without an instance the imported global stays zero and the offset of the segment can't be read
+        let mut globals = Vec::new();
+        init_globals(&mut globals, &module).unwrap();
+        assert_eq!(globals[4..8], [0; 4]);
+        assert!(init_memory(&mut vec![0; PAGE_SIZE], &module).is_err());
+    }
+
+    #[test]
+    fn pass_buffers_through_guest_allocator() {
+        let module =
+            parse(include_bytes!("../../tests/guest_alloc.wasm"), &mut NoLog).expect("parse module");
+        let mut instance = Instance::<MyEnv>::new(&module, Vec::new()).unwrap();
+        assert!(matches!(instance.allocator(), Some(GuestAllocator::Malloc { .. })));
+
+        let sum = instance.call_with_bytes::<u32>(b"checksum".into(), &[1, 2, 3, 250], &mut MyEnv).unwrap();
+        assert_eq!(sum, 256);
+        let words = instance.call_with_bytes::<u32>(b"count_words".into(), b"hello from the host", &mut MyEnv).unwrap();
+        assert_eq!(words, 4);
+        assert_eq!(instance.call::<(), u32>(b"live_allocations".into(), (), &mut MyEnv).unwrap(), 0);
+
+        let buffer = instance.alloc_bytes(b"kept", &mut MyEnv).unwrap();
+        assert_eq!(instance.memory().bytes(buffer.slice()).unwrap(), b"kept");
+        assert_eq!(instance.call::<(), u32>(b"live_allocations".into(), (), &mut MyEnv).unwrap(), 1);
+        instance.free_bytes(buffer, &mut MyEnv).unwrap();
+        assert_eq!(instance.call::<(), u32>(b"live_allocations".into(), (), &mut MyEnv).unwrap(), 0);
+
+        // the guest has a heap of 1 KiB
+        let result = instance.call_with_bytes::<u32>(b"checksum".into(), &[0; 2048], &mut MyEnv);
+        assert!(matches!(result, Err(InterpreterError::GuestAllocationFailed)));
+
+        let module =
+            parse(include_bytes!("../../tests/factorial.wasm"), &mut NoLog).expect("parse module");
+        let mut instance = Instance::<MyEnv>::new(&module, Vec::new()).unwrap();
+        assert_eq!(instance.allocator(), None);
+        assert!(matches!(instance.alloc_bytes(b"", &mut MyEnv), Err(InterpreterError::NoGuestAllocator)));
+    }
+
+    #[test]
+    fn resume_call_with_bytes() {
+        let module =
+            parse(include_bytes!("../../tests/guest_alloc.wasm"), &mut NoLog).expect("parse module");
+        let mut instance = Instance::<MyEnv>::new(&module, Vec::new()).unwrap();
+        let data = [7; 256];
+
+        instance.ctx().set_instruction_limit(Some(100));
+        let result = instance.call_with_bytes::<u32>(b"checksum".into(), &data, &mut MyEnv);
+        assert!(matches!(result, Err(InterpreterError::Paused(PauseReason::InstructionLimit))));
+        // allocator would abort the paused call
+        assert!(matches!(instance.alloc_bytes(b"", &mut MyEnv), Err(InterpreterError::CallInProgress)));
+
+        while instance.resume(&mut MyEnv).unwrap() != Execution::Finished {}
+        assert_eq!(instance.ctx().stack.pop_u32().unwrap(), 7 * 256);
+        instance.ctx().set_instruction_limit(None);
+        assert_eq!(instance.call::<(), u32>(b"live_allocations".into(), (), &mut MyEnv).unwrap(), 0);
+
+        // buffer of a paused call is freed when another call is made instead
+        instance.ctx().set_instruction_limit(Some(100));
+        let result = instance.call_with_bytes::<u32>(b"checksum".into(), &data, &mut MyEnv);
+        assert!(matches!(result, Err(InterpreterError::Paused(_))));
+        instance.ctx().set_instruction_limit(None);
+        assert_eq!(instance.call::<(), u32>(b"live_allocations".into(), (), &mut MyEnv).unwrap(), 0);
+    }
+
+    #[test]
+    fn complete_pending_import() {
+        let requested_channel = Cell::new(None);
+        let read_sensor = |_: &mut MyEnv, stack: &mut VmStack, _memory: &mut [u8]| {
+            requested_channel.set(Some(stack.pop_u32().unwrap()));
+            Ok(ImportOutcome::Pending)
+        };
+
+        let mut env = MyEnv;
+        let module =
+            parse(include_bytes!("../../tests/async_import.wasm"), &mut NoLog).expect("parse module");
+        let func_idx = module.get_function_index_by_name(b"sum_sensors".into()).unwrap();
+        let mut imports = [HostFunc::boxed(read_sensor)];
+        let mut globals = Vec::new();
+        init_globals(&mut globals, &module).unwrap();
+        let mut ctx = VmContext::new();
+
+        let mut state = evaluate(&mut ctx, &module, func_idx, &3u32.to_ne_bytes(), &mut [], &mut globals, &mut imports, &mut env).unwrap();
+        let mut requests = 0;
+        while state == Execution::Paused(PauseReason::ImportPending { func_idx: 0 }) {
+            // guest stays suspended until the result is supplied
+            assert_eq!(resume(&mut ctx, &module, &mut [], &mut globals, &mut imports, &mut env).unwrap(), state);
+
+            let channel = requested_channel.take().unwrap();
+            assert_eq!(ctx.pending_import(), Some(0));
+            // results that don't match the signature of the import are rejected
+            assert!(matches!(ctx.complete_import(|stack| stack.push_i64(1)), Err(InterpreterError::InvalidImportResults)));
+            assert!(matches!(ctx.complete_import(|_| {}), Err(InterpreterError::InvalidImportResults)));
+            assert_eq!(ctx.pending_import(), Some(0));
+            ctx.complete_import(|stack| stack.push_i32(channel as i32 * 10)).unwrap();
+            requests += 1;
+            state = resume(&mut ctx, &module, &mut [], &mut globals, &mut imports, &mut env).unwrap();
+        }
+        assert_eq!(state, Execution::Finished);
+        assert_eq!(requests, 3);
+        assert_eq!(ctx.stack.pop_u32().unwrap(), 30);
+        assert!(matches!(ctx.complete_import(|_| {}), Err(InterpreterError::NoPendingImport)));
+    }
+
+    #[test]
+    fn interrupt_guest_stuck_in_loop() {
+        let mut env = MyEnv;
+        let module =
+            parse(include_bytes!("../../tests/hang.wasm"), &mut NoLog).expect("parse module");
+        let mut globals = Vec::new();
+        init_globals(&mut globals, &module).unwrap();
+        let mut ctx = VmContext::new();
+
+        // acts like a timer ISR firing while the guest keeps running
+        let ticks = Cell::new(0);
+        let interrupt = ctx.interrupt_handle();
+        let tick = |_: &mut MyEnv, _stack: &mut VmStack, _memory: &mut [u8]| {
+            ticks.set(ticks.get() + 1);
+            if ticks.get() == 100 {
+                interrupt.interrupt();
+            }
+            Ok(ImportOutcome::Return)
+        };
+        let mut imports = [HostFunc::boxed(tick)];
+
+        let poll = module.get_function_index_by_name(b"poll".into()).unwrap();
+        let result = evaluate(&mut ctx, &module, poll, &[], &mut [], &mut globals, &mut imports, &mut env);
+        assert!(matches!(result, Err(InterpreterError::Interrupted)));
+        assert_eq!(ticks.get(), 100);
+        assert!(!ctx.is_paused());
+
+        // loop without any calls is interrupted at its back-edge
+        let spin = module.get_function_index_by_name(b"spin".into()).unwrap();
+        ctx.set_instruction_limit(Some(1000));
+        let state = evaluate(&mut ctx, &module, spin, &[], &mut [], &mut globals, &mut imports, &mut env).unwrap();
+        assert_eq!(state, Execution::Paused(PauseReason::InstructionLimit));
+        ctx.interrupt_handle().interrupt();
+        let result = resume(&mut ctx, &module, &mut [], &mut globals, &mut imports, &mut env);
+        assert!(matches!(result, Err(InterpreterError::Interrupted)));
+
+        // context is still usable afterwards
+        let state = evaluate(&mut ctx, &module, spin, &[], &mut [], &mut globals, &mut imports, &mut env).unwrap();
+        assert_eq!(state, Execution::Paused(PauseReason::InstructionLimit));
+
+        // interrupt requested before the call is not lost
+        ctx.interrupt_handle().interrupt();
+        let result = evaluate(&mut ctx, &module, spin, &[], &mut [], &mut globals, &mut imports, &mut env);
+        assert!(matches!(result, Err(InterpreterError::Interrupted)));
+    }
+
+    #[test]
+    fn interrupt_only_one_context() {
+        static FLAG_A: AtomicBool = AtomicBool::new(false);
+        static FLAG_B: AtomicBool = AtomicBool::new(false);
+
+        fn tick(_env: &mut MyEnv, _stack: &mut VmStack, _memory: &mut [u8]) -> Result<ImportOutcome, HostError> {
+            Ok(ImportOutcome::Return)
+        }
+
+        let module =
+            parse(include_bytes!("../../tests/hang.wasm"), &mut NoLog).expect("parse module");
+        let spin = module.get_function_index_by_name(b"spin".into()).unwrap();
+        let mut imports = [HostFunc::Fn(tick)];
+        let mut globals = Vec::new();
+        init_globals(&mut globals, &module).unwrap();
+
+        // flags given by the caller, as on targets without `Arc`
+        let mut a = VmContext::new();
+        let mut b = VmContext::new();
+        let handle_a = a.set_interrupt_flag(&FLAG_A);
+        b.set_interrupt_flag(&FLAG_B);
+        a.set_instruction_limit(Some(1000));
+        b.set_instruction_limit(Some(1000));
+
+        handle_a.interrupt();
+        let result = evaluate(&mut b, &module, spin, &[], &mut [], &mut globals, &mut imports, &mut MyEnv);
+        assert_eq!(result.unwrap(), Execution::Paused(PauseReason::InstructionLimit));
+        let result = evaluate(&mut a, &module, spin, &[], &mut [], &mut globals, &mut imports, &mut MyEnv);
+        assert!(matches!(result, Err(InterpreterError::Interrupted)));
+        let result = resume(&mut b, &module, &mut [], &mut globals, &mut imports, &mut MyEnv);
+        assert_eq!(result.unwrap(), Execution::Paused(PauseReason::InstructionLimit));
+
+        // handles created by the contexts themselves
+        let mut a = VmContext::new();
+        let mut b = VmContext::new();
+        a.set_instruction_limit(Some(1000));
+        b.set_instruction_limit(Some(1000));
+        a.interrupt_handle().interrupt();
+        let result = evaluate(&mut b, &module, spin, &[], &mut [], &mut globals, &mut imports, &mut MyEnv);
+        assert_eq!(result.unwrap(), Execution::Paused(PauseReason::InstructionLimit));
+        let result = evaluate(&mut a, &module, spin, &[], &mut [], &mut globals, &mut imports, &mut MyEnv);
+        assert!(matches!(result, Err(InterpreterError::Interrupted)));
+    }
+
+    #[test]
+    fn stop_guest_after_deadline() {
+        let mut env = ClockEnv::new();
+        let module =
+            parse(include_bytes!("../../tests/hang.wasm"), &mut NoLog).expect("parse module");
+        let spin = module.get_function_index_by_name(b"spin".into()).unwrap();
+        let mut globals = Vec::new();
+        init_globals(&mut globals, &module).unwrap();
+        let mut ctx = VmContext::new();
+
+        ctx.set_timeout(&env, Duration::from_millis(50)).unwrap();
+        let result = evaluate::<ClockEnv>(&mut ctx, &module, spin, &[], &mut [], &mut globals, &mut [], &mut env);
+        assert!(matches!(result, Err(InterpreterError::DeadlineExceeded)));
+        let now = env.now.get();
+        assert!((50..60).contains(&now), "stopped at {now}");
+
+        // deadline applies only to the call started after it has been set, also across pauses
+        ctx.set_instruction_limit(Some(1000));
+        let state = evaluate::<ClockEnv>(&mut ctx, &module, spin, &[], &mut [], &mut globals, &mut [], &mut env).unwrap();
+        assert_eq!(state, Execution::Paused(PauseReason::InstructionLimit));
+
+        ctx.set_timeout(&env, Duration::from_millis(50)).unwrap();
+        let mut state = evaluate::<ClockEnv>(&mut ctx, &module, spin, &[], &mut [], &mut globals, &mut [], &mut env);
+        while let Ok(Execution::Paused(PauseReason::InstructionLimit)) = state {
+            state = resume::<ClockEnv>(&mut ctx, &module, &mut [], &mut globals, &mut [], &mut env);
+        }
+        assert!(matches!(state, Err(InterpreterError::DeadlineExceeded)));
+
+        // timeout needs to know the length of a tick
+        assert!(matches!(ctx.set_timeout(&MyEnv, Duration::from_millis(50)), Err(InterpreterError::UnknownTickRate)));
+    }
+
+    fn report(env: &mut ClockEnv, stack: &mut VmStack, _memory: &mut [u8]) -> Result<ImportOutcome, HostError> {
+        let id = stack.pop_u32().unwrap();
+        env.reports.push((id, env.now.get()));
+        Ok(ImportOutcome::Return)
+    }
+
+    fn sleep_ms(env: &mut ClockEnv, stack: &mut VmStack, _memory: &mut [u8]) -> Result<ImportOutcome, HostError> {
+        let ms = u64::from(stack.pop_u32().unwrap());
+        Ok(ImportOutcome::Sleep { until: env.ticks() + ms })
+    }
+
+    fn sleep_ms_async(_env: &mut ClockEnv, stack: &mut VmStack, _memory: &mut [u8]) -> Result<ImportOutcome, HostError> {
+        _ = stack.pop_u32().unwrap();
+        Ok(ImportOutcome::Pending)
+    }
+
+    #[test]
+    fn sleeping_app_does_not_block_busy_app() {
+        let mut env = ClockEnv::new();
+        let module = parse(include_bytes!("../../tests/sleep.wasm"), &mut NoLog).expect("parse module");
+        let config = AppConfig { priority: 0, quantum: 50, memory_size: 0 };
+
+        let mut scheduler = Scheduler::new();
+        let args: Vec<u8> = [1u32, 5].iter().flat_map(|it| it.to_ne_bytes()).collect();
+        let blinker = scheduler.spawn(&module, vec![HostFunc::Fn(report), HostFunc::Fn(sleep_ms)], b"blink".into(), &args, config).unwrap();
+        let args: Vec<u8> = [2u32, 1000].iter().flat_map(|it| it.to_ne_bytes()).collect();
+        let spinner = scheduler.spawn(&module, vec![HostFunc::Fn(report), HostFunc::Fn(sleep_ms)], b"spin".into(), &args, config).unwrap();
+
+        assert_eq!(scheduler.run(&mut env, |env, until| env.now.set(until)), SchedulerStep::Done);
+
+        assert_eq!(scheduler.app(blinker).unwrap().state(), AppState::Finished);
+        assert_eq!(scheduler.app(spinner).unwrap().state(), AppState::Finished);
+
+        let blinks: Vec<u64> = env.reports.iter().filter(|(id, _)| *id == 1).map(|(_, t)| *t).collect();
+        assert_eq!(blinks.len(), 5);
+        assert!(blinks.windows(2).all(|w| w[1] - w[0] >= 10));
+
+        // the busy app has been preempted in the meantime instead of running to completion first
+        let spin_end = env.reports.iter().position(|(id, _)| *id == 2).unwrap();
+        assert_eq!(spin_end, env.reports.len() - 1);
+    }
+
+    #[test]
+    fn higher_priority_app_runs_first() {
+        let mut env = ClockEnv::new();
+        let module = parse(include_bytes!("../../tests/sleep.wasm"), &mut NoLog).expect("parse module");
+
+        let mut scheduler = Scheduler::new();
+        for (id, priority) in [(1u32, 0), (2, 5), (3, 1)] {
+            let args: Vec<u8> = [id, 100].iter().flat_map(|it| it.to_ne_bytes()).collect();
+            let config = AppConfig { priority, quantum: 10, memory_size: 0 };
+            scheduler.spawn(&module, vec![HostFunc::Fn(report), HostFunc::Fn(sleep_ms)], b"spin".into(), &args, config).unwrap();
+        }
+
+        assert_eq!(scheduler.run(&mut env, |_, _| unreachable!("nothing sleeps")), SchedulerStep::Done);
+
+        let order: Vec<u32> = env.reports.iter().map(|(id, _)| *id).collect();
+        assert_eq!(order, [2, 3, 1]);
+    }
+
+    #[test]
+    fn starved_app_runs_after_limit() {
+        let module = parse(include_bytes!("../../tests/sleep.wasm"), &mut NoLog).expect("parse module");
+        // strict priorities starve the app with lower priority until the busy one has finished
+        for (limit, expected) in [(None, [2, 1]), (Some(3), [1, 2])] {
+            let mut env = ClockEnv::new();
+            let mut scheduler = Scheduler::new();
+            scheduler.set_starvation_limit(limit);
+            // the busy app needs many quanta, the other one just a single one
+            for (id, priority, n) in [(1u32, 0, 5u32), (2, 5, 1000)] {
+                let args: Vec<u8> = [id, n].iter().flat_map(|it| it.to_ne_bytes()).collect();
+                let config = AppConfig { priority, quantum: 10, memory_size: 0 };
+                scheduler.spawn(&module, vec![HostFunc::Fn(report), HostFunc::Fn(sleep_ms)], b"spin".into(), &args, config).unwrap();
+            }
+
+            if limit.is_some() {
+                for _ in 0..3 {
+                    assert_eq!(scheduler.step(&mut env), SchedulerStep::Ran(1));
+                }
+                assert_eq!(scheduler.step(&mut env), SchedulerStep::Ran(0));
+            }
+            assert_eq!(scheduler.run(&mut env, |_, _| unreachable!("nothing sleeps")), SchedulerStep::Done);
+            let order: Vec<u32> = env.reports.iter().map(|(id, _)| *id).collect();
+            assert_eq!(order, expected);
+        }
+    }
+
+    #[test]
+    fn waiting_app_is_resumed_after_import_completes() {
+        let mut env = ClockEnv::new();
+        let module = parse(include_bytes!("../../tests/sleep.wasm"), &mut NoLog).expect("parse module");
+        let config = AppConfig { priority: 0, quantum: 50, memory_size: 0 };
+
+        let mut scheduler = Scheduler::new();
+        let args: Vec<u8> = [1u32, 2].iter().flat_map(|it| it.to_ne_bytes()).collect();
+        let id = scheduler.spawn(&module, vec![HostFunc::Fn(report), HostFunc::Fn(sleep_ms_async)], b"blink".into(), &args, config).unwrap();
+
+        for expected_reports in 1..=2 {
+            assert_eq!(scheduler.run(&mut env, |_, _| unreachable!("nothing sleeps")), SchedulerStep::Blocked);
+            assert_eq!(scheduler.app(id).unwrap().state(), AppState::Waiting { func_idx: 1 });
+            assert_eq!(env.reports.len(), expected_reports);
+            scheduler.complete_import(id, |_| {}).unwrap();
+        }
+        assert!(matches!(scheduler.complete_import(id + 1, |_| {}), Err(InterpreterError::InvalidApp)));
+
+        assert_eq!(scheduler.run(&mut env, |_, _| unreachable!("nothing sleeps")), SchedulerStep::Done);
+        assert_eq!(scheduler.app(id).unwrap().state(), AppState::Finished);
+    }
+
+    #[test]
+    fn log_parser_and_interpreter() {
+        #[derive(Clone)]
+        struct LinesLog {
+            max_level: Level,
+            lines: Rc<RefCell<Vec<(Level, String)>>>,
+        }
+
+        impl Log for LinesLog {
+            fn log(&mut self, level: Level, args: Arguments) {
+                let mut lines = self.lines.borrow_mut();
+                match lines.last_mut() {
+                    Some((last_level, line)) if *last_level == level && !line.ends_with('\n') => {
+                        core::fmt::Write::write_fmt(line, args).unwrap();
+                    }
+                    _ => lines.push((level, alloc::format!("{args}"))),
+                }
+            }
+
+            fn enabled(&self, level: Level) -> bool {
+                level <= self.max_level
+            }
+        }
+
+        let code = include_bytes!("../../tests/factorial.wasm");
+        let mut log = LinesLog { max_level: Level::Info, lines: Rc::default() };
+        parse(code, &mut log).expect("parse module");
+        assert!(log.lines.borrow().is_empty());
+
+        log.max_level = Level::Debug;
+        let module = parse(code, &mut log).expect("parse module");
+        assert!(log.lines.borrow().iter().all(|(level, _)| *level == Level::Debug));
+        assert!(log.lines.borrow().iter().any(|(_, line)| line == "Found type section\n"));
+
+        log.lines.borrow_mut().clear();
+        log.max_level = Level::Trace;
+        let mut ctx = VmContext::new();
+        ctx.set_log(log.clone());
+        let result = execute_function::<MyEnv, (f64, ), f64>(&mut ctx, &module, b"fac".into(), (3.0, ), &mut [], &mut [], &mut [], &mut MyEnv).unwrap();
+        assert_eq!(result, 6.0);
+        assert!(log.lines.borrow().iter().any(|(level, line)| *level == Level::Debug && line.starts_with("calling function")));
+
+        // calls are seen without tracing every instruction
+        log.lines.borrow_mut().clear();
+        log.max_level = Level::Debug;
+        ctx.set_log(log.clone());
+        for engine in engines() {
+            ctx.set_engine(engine);
+            execute_function::<MyEnv, (f64, ), f64>(&mut ctx, &module, b"fac".into(), (3.0, ), &mut [], &mut [], &mut [], &mut MyEnv).unwrap();
+        }
+        assert!(log.lines.borrow().iter().all(|(level, _)| *level == Level::Debug));
+        assert!(log.lines.borrow().iter().filter(|(_, line)| line.starts_with("calling function")).count() >= engines().len());
+    }
+
+    #[test]
+    fn stack_values_take_one_slot_each() {
+        let mut stack = [0u8; 4 * 8];
+        let mut frames = [0u8; VmBuffers::FRAME_SIZE];
+        let mut ctx = VmContext::with_buffers(VmBuffers {
+            stack: &mut stack,
+            frames: &mut frames,
+        });
+        ctx.stack.push_i32(-1);
+        ctx.stack.push_i64(i64::MIN);
+        ctx.stack.push_f32(1.5);
+        ctx.stack.push_f64(-2.25);
+        assert_eq!(ctx.stack.len(), 4);
+        assert!(!ctx.stack.overflowed);
+        ctx.stack.push_i32(0);
+        assert!(ctx.stack.overflowed);
+
+        assert_eq!(ctx.stack.peek_bytes::<8>().unwrap(), (-2.25f64).to_le_bytes());
+        assert_eq!(ctx.stack.pop_f64().unwrap(), -2.25);
+        assert_eq!(ctx.stack.peek_bytes::<4>().unwrap(), 1.5f32.to_le_bytes());
+        assert_eq!(ctx.stack.pop_f32().unwrap(), 1.5);
+        assert_eq!(ctx.stack.pop_i64().unwrap(), i64::MIN);
+        assert_eq!(ctx.stack.pop_u32().unwrap(), u32::MAX);
+        assert!(matches!(ctx.stack.pop_i32(), Err(InterpreterError::StackTooSmall)));
+    }
+
+    #[test]
+    fn count_superinstruction_hits() {
+        let module =
+            parse(include_bytes!("../../tests/fused.wasm"), &mut NoLog).expect("parse module");
         let mut ctx = VmContext::new();
+        // superinstructions exist only in the code of the stack interpreter
+        ctx.set_engine(Engine::Stack);
+        for (n, expected) in [(10u32, 10u32), (0, 1), (3, 3)] {
+            let result = execute_function::<MyEnv, (u32, ), u32>(&mut ctx, &module, b"count_up".into(), (n, ), &mut [], &mut [], &mut [], &mut MyEnv).unwrap();
+            assert_eq!(result, expected);
+        }
+        assert_eq!(ctx.profile().superinstruction_hits(Superinstruction::LocalI32AddConst), 14);
+        assert_eq!(ctx.profile().superinstruction_hits(Superinstruction::LocalsLtUBrIf), 14);
+
+        let mut memory = vec![0u8; 64];
+        memory[12..16].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+        let result = execute_function::<MyEnv, (u32, ), u32>(&mut ctx, &module, b"load_at".into(), (8, ), &mut memory, &mut [], &mut [], &mut MyEnv).unwrap();
+        assert_eq!(result, 0x1234_5678);
+        assert_eq!(ctx.profile().superinstruction_hits(Superinstruction::LocalLoad), 1);
+    }
+
+    #[test]
+    fn fused_code_uses_the_same_fuel() {
+        let fused =
+            parse(include_bytes!("../../tests/fused.wasm"), &mut NoLog).expect("parse module");
+        let mut unfused =
+            parse(include_bytes!("../../tests/fused.wasm"), &mut NoLog).expect("parse module");
+        for func in unfused.functions.iter_mut() {
+            let prepared = func.body.as_mut().and_then(|body| body.prepared.as_mut()).unwrap();
+            let ModuleSlice::Owned(instrs) = &mut prepared.instrs else {
+                unreachable!("parsed without an image");
+            };
+            instrs.iter_mut().for_each(|instr| *instr = instr.unfused());
+        }
+
+        let mut memory = vec![0u8; 64];
+        memory[12..16].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+        for (name, arg) in [(&b"count_up"[..], 3u32), (b"load_at", 8)] {
+            for fuel in 1..60 {
+                let mut states = Vec::new();
+                for module in [&fused, &unfused] {
+                    let mut ctx = VmContext::new();
+                    ctx.set_engine(Engine::Stack);
+                    ctx.set_fuel(Some(fuel));
+                    module.get_typed_func::<(u32, ), u32>(name.into()).unwrap().start(&mut ctx, (arg, )).unwrap();
+                    let state = resume(&mut ctx, module, &mut memory, &mut [], &mut [], &mut MyEnv).unwrap();
+                    // locals and operands of the paused function
+                    let values: Vec<_> = iter::from_fn(|| ctx.stack.pop_i64().ok()).collect();
+                    states.push((state, ctx.fuel(), values));
+                }
+                assert_eq!(states[0], states[1], "{} with {fuel} fuel", ByteStr::from_bytes(name));
+            }
+        }
+    }
+
+    // module exporting `f: () -> i32` with given locals and code
+    fn module_with_body(locals: usize, code: &[u8]) -> Vec<u8> {
+        fn leb(mut val: usize, out: &mut Vec<u8>) {
+            while val >= 0x80 {
+                out.push(val as u8 | 0x80);
+                val >>= 7;
+            }
+            out.push(val as u8);
+        }
+
+        let mut body = Vec::new();
+        body.push(1);
+        leb(locals, &mut body);
+        body.push(0x7f);
+        body.extend_from_slice(code);
+        let mut section = vec![1];
+        leb(body.len(), &mut section);
+        section.extend_from_slice(&body);
+
+        let mut bytes = b"\0asm\x01\0\0\0".to_vec();
+        bytes.extend_from_slice(&[0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f]);
+        bytes.extend_from_slice(&[0x03, 0x02, 0x01, 0x00]);
+        bytes.extend_from_slice(&[0x07, 0x05, 0x01, 0x01, b'f', 0x00, 0x00]);
+        bytes.push(0x0a);
+        leb(section.len(), &mut bytes);
+        bytes.extend_from_slice(&section);
+        bytes
+    }
+
+    #[test]
+    fn reject_functions_too_large_for_slots() {
+        // local.get 69999
+        let bytes = module_with_body(70_000, &[0x20, 0xef, 0xa2, 0x04, 0x0b]);
+        let module = parse(&bytes, &mut NoLog).expect("parse module");
+        let mut ctx = VmContext::new();
+        ctx.set_engine(Engine::Stack);
+        let result = execute_function::<MyEnv, (), i32>(&mut ctx, &module, b"f".into(), (), &mut [], &mut [], &mut [], &mut MyEnv);
+        assert_eq!(result.unwrap(), 0);
+        // registers of the frame can't be addressed anymore
+        ctx.set_engine(Engine::Register);
+        let result = execute_function::<MyEnv, (), i32>(&mut ctx, &module, b"f".into(), (), &mut [], &mut [], &mut [], &mut MyEnv);
+        assert!(matches!(result, Err(InterpreterError::ParserError(ParserError::FunctionTooLarge))));
+
+        // 70000 values on the stack, all but one dropped
+        let mut code = [0x41, 0x00].repeat(70_000);
+        code.extend_from_slice(&[0x1a; 69_999]);
+        code.push(0x0b);
+        let bytes = module_with_body(0, &code);
+        assert!(matches!(parse(&bytes, &mut NoLog), Err(ParserError::FunctionTooLarge)));
+    }
+
+    #[test]
+    fn register_engine_matches_stack() {
+        let module =
+            parse(include_bytes!("../../tests/factorial.wasm"), &mut NoLog).expect("parse module");
+        let mut ctx = VmContext::new();
+        ctx.set_engine(Engine::Register);
+        for i in 0..10 {
+            let result = execute_function::<MyEnv, (f64, ), f64>(&mut ctx, &module, b"fac".into(), (i as f64, ), &mut [], &mut [], &mut [], &mut MyEnv).unwrap();
+            assert_eq!(result, native_factorial(i) as f64);
+        }
+
+        let module =
+            parse(include_bytes!("../../tests/multivalue.wasm"), &mut NoLog).expect("parse module");
+        for (i, j) in [(0, 10), (7, 3), (-5, 12)] {
+            let result = execute_function::<MyEnv, (i32, i32), i32>(&mut ctx, &module, b"reverseSub".into(), (i, j), &mut [], &mut [], &mut [], &mut MyEnv).unwrap();
+            assert_eq!(result, j - i);
+        }
+
+        let module =
+            parse(include_bytes!("../../tests/sum_array_rec.wasm"), &mut NoLog).expect("parse module");
         let mut numbers = [1.23f32, 4.56, -10.0];
         let data = unsafe { core::slice::from_raw_parts_mut(numbers.as_mut_ptr().cast(), numbers.len() * 4) };
-        let result = execute_function::<MyEnv, (u32, u32), f32>(&mut ctx, &module, b"sum_slice".into(), (0u32, numbers.len() as u32), data, &mut [], &[], &mut MyEnv).unwrap();
+        let result = execute_function::<MyEnv, (u32, u32), f32>(&mut ctx, &module, b"sum_slice".into(), (0u32, numbers.len() as u32), data, &mut [], &mut [], &mut MyEnv).unwrap();
         assert_eq!(result, -4.21);
     }
+
+    #[test]
+    fn branch_value_reaches_local() {
+        let module =
+            parse(include_bytes!("../../tests/branch_value.wasm"), &mut NoLog).expect("parse module");
+        for engine in engines() {
+            let mut ctx = VmContext::new();
+            ctx.set_engine(engine);
+            // enough calls for the JIT to compile the function
+            for _ in 0..100 {
+                for (arg, expected) in [(1, 7), (0, 5)] {
+                    let result = execute_function::<MyEnv, (i32, ), i32>(&mut ctx, &module, b"pick".into(), (arg, ), &mut [], &mut [], &mut [], &mut MyEnv).unwrap();
+                    assert_eq!(result, expected, "{engine:?} pick({arg})");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn engines_agree_on_edge_cases() {
+        let module =
+            parse(include_bytes!("../../tests/numeric.wasm"), &mut NoLog).expect("parse module");
+        // exported function, its arguments and either its results or the error it fails with
+        type Case<'a> = (&'a [u8], &'a [Value], Result<&'a [Value], &'a str>);
+        let cases: &[Case] = &[
+            (b"div_u", &[Value::I32(7), Value::I32(0)], Err("DivisionByZero")),
+            (b"div_u", &[Value::I32(-1), Value::I32(2)], Ok(&[Value::I32(i32::MAX)])),
+            (b"div_s", &[Value::I32(i32::MIN), Value::I32(-1)], Err("IntegerOverflow")),
+            (b"div_s", &[Value::I32(-7), Value::I32(2)], Ok(&[Value::I32(-3)])),
+            (b"rem_u", &[Value::I32(7), Value::I32(0)], Err("DivisionByZero")),
+            (b"div_u64", &[Value::I64(1), Value::I64(0)], Err("DivisionByZero")),
+            (b"shl", &[Value::I32(1), Value::I32(33)], Ok(&[Value::I32(2)])),
+            (b"shr_u", &[Value::I32(-8), Value::I32(1)], Ok(&[Value::I32(0x7fff_fffc)])),
+            (b"shl64", &[Value::I64(1), Value::I64(65)], Ok(&[Value::I64(2)])),
+            (b"wrap", &[Value::I64(0x1_8000_0001)], Ok(&[Value::I32(0x8000_0001_u32 as i32)])),
+            (b"extend_u", &[Value::I32(-1)], Ok(&[Value::I64(0xffff_ffff)])),
+            (b"extend8_s", &[Value::I32(0x180)], Ok(&[Value::I32(-128)])),
+            (b"add", &[Value::I32(i32::MAX), Value::I32(1)], Ok(&[Value::I32(i32::MIN)])),
+            (b"mul64", &[Value::I64(i64::MAX), Value::I64(2)], Ok(&[Value::I64(-2)])),
+            (b"load", &[Value::I32(0)], Ok(&[Value::I32(0x8403_0201_u32 as i32)])),
+            (b"load", &[Value::I32(PAGE_SIZE as i32 - 2)], Err("MemoryAccessError(InvalidOffset { offset: 65534 })")),
+            (b"load8_s", &[Value::I32(0)], Ok(&[Value::I32(-124)])),
+            (b"store", &[Value::I32(PAGE_SIZE as i32 - 3), Value::I32(1)], Err("MemoryAccessError(InvalidOffset { offset: 65533 })")),
+            (b"rem_s", &[Value::I32(i32::MIN), Value::I32(-1)], Ok(&[Value::I32(0)])),
+            (b"rem_s", &[Value::I32(-7), Value::I32(2)], Ok(&[Value::I32(-1)])),
+            (b"shr_s64", &[Value::I64(-8), Value::I64(65)], Ok(&[Value::I64(-4)])),
+            (b"rotl", &[Value::I32(0x8000_0001_u32 as i32), Value::I32(33)], Ok(&[Value::I32(3)])),
+            (b"clz64", &[Value::I64(1)], Ok(&[Value::I64(63)])),
+            (b"trunc_s", &[Value::F32(-2.9)], Ok(&[Value::I32(-2)])),
+            (b"trunc_s", &[Value::F32(f32::NAN)], Err("InvalidConversion")),
+            (b"trunc_s", &[Value::F32(2147483648.0)], Err("IntegerOverflow")),
+            (b"trunc_u64", &[Value::F64(-0.9)], Ok(&[Value::I64(0)])),
+            (b"trunc_u64", &[Value::F64(-1.0)], Err("IntegerOverflow")),
+            (b"convert_u64", &[Value::I64(-1)], Ok(&[Value::F32(18446744073709551616.0)])),
+            (b"min", &[Value::F32(2.0), Value::F32(-1.0)], Ok(&[Value::F32(-1.0)])),
+            (b"copysign", &[Value::F64(3.0), Value::F64(-0.0)], Ok(&[Value::F64(-3.0)])),
+            (b"copy", &[Value::I32(16), Value::I32(0), Value::I32(4)], Ok(&[Value::I32(0x8403_0201_u32 as i32)])),
+            (b"copy", &[Value::I32(16), Value::I32(PAGE_SIZE as i32 - 2), Value::I32(4)], Err("MemoryAccessError(InvalidOffset { offset: 65534 })")),
+            (b"fill", &[Value::I32(32), Value::I32(0x1ab), Value::I32(4)], Ok(&[Value::I32(0xabab_abab_u32 as i32)])),
+            (b"fill", &[Value::I32(PAGE_SIZE as i32 - 2), Value::I32(0), Value::I32(4)], Err("MemoryAccessError(InvalidOffset { offset: 65534 })")),
+            (b"gt_u", &[Value::I32(-1), Value::I32(1)], Ok(&[Value::I32(1)])),
+            (b"gt_u", &[Value::I32(1024), Value::I32(-65)], Ok(&[Value::I32(0)])),
+            (b"le_s", &[Value::I32(-1), Value::I32(1)], Ok(&[Value::I32(1)])),
+            (b"le_s", &[Value::I32(2), Value::I32(2)], Ok(&[Value::I32(1)])),
+            (b"trunc_sat", &[Value::F32(-2.9)], Ok(&[Value::I32(-2)])),
+            (b"trunc_sat", &[Value::F32(f32::NAN)], Ok(&[Value::I32(0)])),
+            (b"trunc_sat", &[Value::F32(3e9)], Ok(&[Value::I32(i32::MAX)])),
+            (b"trunc_sat_u64", &[Value::F64(-5.0)], Ok(&[Value::I64(0)])),
+            (b"trunc_sat_u64", &[Value::F64(1e20)], Ok(&[Value::I64(-1)])),
+        ];
+
+        for engine in engines() {
+            let mut ctx = VmContext::new();
+            ctx.set_engine(engine);
+            let mut memory = vec![0u8; PAGE_SIZE];
+            init_memory(&mut memory, &module).unwrap();
+            // enough calls for the JIT to compile every function
+            for _ in 0..400 {
+                for (name, args, expected) in cases {
+                    let result = call_dynamic(&mut ctx, &module, (*name).into(), args, &mut memory, &mut [], &mut [], &mut MyEnv);
+                    match (result, expected) {
+                        (Ok(results), Ok(expected)) => assert_eq!(results, *expected, "{engine:?} {}", ByteStr::from_bytes(name)),
+                        (Err(error), Err(expected)) => assert_eq!(alloc::format!("{error:?}"), *expected, "{engine:?} {}", ByteStr::from_bytes(name)),
+                        (result, _) => panic!("{engine:?} {}: {result:?} instead of {expected:?}", ByteStr::from_bytes(name)),
+                    }
+                }
+            }
+
+            call_dynamic(&mut ctx, &module, b"store".into(), &[Value::I32(4), Value::I32(0x0102_0304)], &mut memory, &mut [], &mut [], &mut MyEnv).unwrap();
+            assert_eq!(memory[4..8], [4, 3, 2, 1], "{engine:?}");
+        }
+    }
+
+    #[test]
+    fn memory_size_and_grow() {
+        let module =
+            parse(include_bytes!("../../tests/numeric.wasm"), &mut NoLog).expect("parse module");
+        for engine in engines() {
+            let mut ctx = VmContext::new();
+            ctx.set_engine(engine);
+            let mut memory = vec![0u8; 2 * PAGE_SIZE];
+            let mut call = |name: &[u8], args: &[Value]| {
+                call_dynamic(&mut ctx, &module, name.into(), args, &mut memory, &mut [], &mut [], &mut MyEnv).unwrap()
+            };
+            assert_eq!(call(b"size", &[]), [Value::I32(2)], "{engine:?}");
+            assert_eq!(call(b"grow", &[Value::I32(0)]), [Value::I32(2)], "{engine:?}");
+            // memory given by the host can't be resized
+            assert_eq!(call(b"grow", &[Value::I32(1)]), [Value::I32(-1)], "{engine:?}");
+            assert_eq!(call(b"size", &[]), [Value::I32(2)], "{engine:?}");
+        }
+    }
+
+    #[test]
+    fn reject_unsupported_opcodes() {
+        // f32.const 4.0; f32.sqrt; i32.reinterpret_f32
+        let bytes = module_with_body(0, &[0x43, 0x00, 0x00, 0x80, 0x40, 0x91, 0xbc, 0x0b]);
+        let result = parse(&bytes, &mut NoLog);
+        assert!(matches!(result, Err(ParserError::UnsupportedOpcode { opcode: 0x91, .. })), "{result:?}");
+
+        // lazily parsed modules find it before the function is run
+        let module = parse_lazy(&bytes, &mut NoLog).expect("parse module");
+        let mut ctx = VmContext::new();
+        let result = execute_function::<MyEnv, (), i32>(&mut ctx, &module, b"f".into(), (), &mut [], &mut [], &mut [], &mut MyEnv);
+        assert!(matches!(result, Err(InterpreterError::ParserError(ParserError::UnsupportedOpcode { opcode: 0x91, .. }))), "{result:?}");
+    }
+
+    #[test]
+    fn register_engine_pauses_on_yield() {
+        fn yield_now(_env: &mut MyEnv, _stack: &mut VmStack, _memory: &mut [u8]) -> Result<ImportOutcome, HostError> {
+            Ok(ImportOutcome::Yield)
+        }
+
+        let module =
+            parse(include_bytes!("../../tests/yield.wasm"), &mut NoLog).expect("parse module");
+        let func_idx = module.get_function_index_by_name(b"count".into()).unwrap();
+        let mut imports = [HostFunc::Fn(yield_now)];
+        let mut globals = Vec::new();
+        init_globals(&mut globals, &module).unwrap();
+        let mut ctx = VmContext::new();
+        ctx.set_engine(Engine::Register);
+
+        let mut state = evaluate(&mut ctx, &module, func_idx, &4u32.to_ne_bytes(), &mut [], &mut globals, &mut imports, &mut MyEnv).unwrap();
+        let mut yields = 0;
+        while state == Execution::Paused(PauseReason::Yield) {
+            assert!(ctx.is_paused());
+            yields += 1;
+            state = resume(&mut ctx, &module, &mut [], &mut globals, &mut imports, &mut MyEnv).unwrap();
+        }
+        assert_eq!(state, Execution::Finished);
+        assert_eq!(yields, 4);
+        assert_eq!(ctx.stack.pop_u32().unwrap(), 6);
+    }
+
+    #[test]
+    #[cfg(feature = "jit")]
+    fn jit_matches_register_engine() {
+        let module =
+            parse(include_bytes!("../../tests/checksum.wasm"), &mut NoLog).expect("parse module");
+        let mut globals = Vec::new();
+        init_globals(&mut globals, &module).unwrap();
+
+        let mut outcomes = Vec::new();
+        for engine in [Engine::Register, Engine::Jit] {
+            let mut ctx = VmContext::new();
+            ctx.set_engine(engine);
+            let mut memory: Vec<u8> = (0..8192).map(|i| i as u8).collect();
+            let mut results = Vec::new();
+            // functions get compiled after they have been interpreted for a while
+            for _ in 0..20 {
+                let hash = execute_function::<MyEnv, (u32, u32), u32>(&mut ctx, &module, b"scramble".into(), (0, 4096), &mut memory, &mut globals, &mut [], &mut MyEnv).unwrap();
+                let counts = execute_function::<MyEnv, (u32, u32, u32), u64>(&mut ctx, &module, b"count_below".into(), (4096, 1024, 0x8000_0000), &mut memory, &mut globals, &mut [], &mut MyEnv).unwrap();
+                results.push((hash, counts));
+            }
+            let interpreted: u64 = ctx.profile.executed_instr_count.iter().map(|&count| u64::from(count)).sum();
+            outcomes.push((results, memory, interpreted));
+        }
+
+        let (jit, register) = (outcomes.pop().unwrap(), outcomes.pop().unwrap());
+        assert_eq!(jit.0, register.0);
+        assert_eq!(jit.1, register.1);
+        assert!(jit.2 < register.2 / 2, "{} instructions interpreted with the JIT, {} without", jit.2, register.2);
+    }
+
+    // collects everything the guest writes to the console
+    #[cfg(feature = "wasi")]
+    struct ConsoleEnv {
+        output: String,
+    }
+
+    #[cfg(feature = "wasi")]
+    impl Environment for ConsoleEnv {
+        fn write_fmt(&mut self, args: Arguments) {
+            core::fmt::Write::write_fmt(&mut self.output, args).unwrap();
+        }
+
+        fn ticks(&self) -> u64 {
+            5
+        }
+
+        fn ticks_per_second(&self) -> u64 {
+            2
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "wasi")]
+    fn wasi_calls() {
+        use crate::{ProcExit, Wasi};
+
+        let module = parse(include_bytes!("../../tests/wasi.wasm"), &mut NoLog).expect("parse module");
+        let mut linker = Linker::new();
+        let mut wasi = Wasi::new();
+        wasi.arg("app").arg("--verbose").random(|bytes| bytes.fill(0x11));
+        wasi.link(&mut linker);
+        let mut instance = Instance::new(&module, linker.link(&module).unwrap()).unwrap();
+        let mut env = ConsoleEnv { output: String::new() };
+
+        assert_eq!(instance.call::<(), u32>(b"hello".into(), (), &mut env).unwrap(), 12);
+        assert_eq!(instance.call::<(), u32>(b"print_args".into(), (), &mut env).unwrap(), 0);
+        assert_eq!(env.output, "hello, wasi\napp --verbose");
+        // EBADF
+        assert_eq!(instance.call::<(), u32>(b"bad_fd".into(), (), &mut env).unwrap(), 8);
+        assert_eq!(instance.call::<(), u64>(b"now".into(), (), &mut env).unwrap(), 2_500_000_000);
+        assert_eq!(instance.call::<(), u32>(b"random".into(), (), &mut env).unwrap(), 0x1111_1111);
+        // ENOSYS from `fd_close`, `sched_yield` succeeds
+        assert_eq!(instance.call::<(), u32>(b"unsupported".into(), (), &mut env).unwrap(), 52);
+        // EBADF, there are no preopened directories
+        assert_eq!(instance.call::<(), u32>(b"preopens".into(), (), &mut env).unwrap(), 8);
+        // EOVERFLOW
+        assert_eq!(instance.call::<(), u32>(b"overflow".into(), (), &mut env).unwrap(), 61);
+        assert_eq!(env.output, "hello, wasi\napp --verbose");
+
+        let Err(InterpreterError::Host(error)) = instance.call::<(u32, ), ()>(b"exit".into(), (3, ), &mut env) else {
+            panic!("guest should have exited");
+        };
+        assert_eq!(error.downcast_ref::<ProcExit>(), Some(&ProcExit(3)));
+    }
+
+    #[test]
+    #[cfg(feature = "wasi")]
+    fn wasi_std_guest() {
+        use crate::Wasi;
+
+        let module = parse(include_bytes!("../../tests/wasi_std.wasm"), &mut NoLog).expect("parse module");
+        for engine in engines() {
+            let mut linker = Linker::new();
+            let mut wasi = Wasi::new();
+            wasi.arg("app").arg("--verbose");
+            wasi.link(&mut linker);
+            let mut instance = Instance::new(&module, linker.link(&module).unwrap()).unwrap();
+            instance.ctx().set_engine(engine);
+            let mut env = ConsoleEnv { output: String::new() };
+
+            instance.call::<(), ()>(b"_start".into(), (), &mut env).unwrap();
+            assert_eq!(env.output, "hello from std, 2 args\n", "{engine:?}");
+        }
+    }
 }
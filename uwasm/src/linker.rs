@@ -0,0 +1,284 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::cell::RefCell;
+use core::fmt;
+use core::marker::PhantomData;
+
+use crate::caller::{Caller, MemoryView};
+use crate::interpreter::{DataClosure, HostError, HostFunc, ImportOutcome, VmStack};
+use crate::operand::Operand;
+use crate::parser::TypeKind;
+use crate::value::Value;
+use crate::{ByteStr, WasmModule};
+
+/// Host functions that resolve imports of modules by their module and field names.
+///
+/// Functions may capture state of their own, so that separate subsystems can define their imports
+/// independently of each other and of the environment. The captured state is shared by all imports
+/// linked by the same linker, state of a single instance is kept as its host data instead,
+/// see [`crate::Instance::set_data`] and [`Caller::data`].
+pub struct Linker<'a, TEnv> {
+    definitions: Vec<Definition<'a, TEnv>>,
+    globals: Vec<GlobalDefinition>,
+}
+
+struct GlobalDefinition {
+    module: &'static str,
+    name: &'static str,
+    value: Value,
+}
+
+struct Definition<'a, TEnv> {
+    module: &'static str,
+    name: &'static str,
+    // params and result, `None` for functions that handle the stack by themselves
+    signature: Option<(&'static [TypeKind], TypeKind)>,
+    // borrowed by every import resolved to it
+    func: RefCell<Box<DataClosure<'a, TEnv>>>,
+}
+
+impl<'a, TEnv> Linker<'a, TEnv> {
+    pub fn new() -> Self {
+        Self { definitions: Vec::new(), globals: Vec::new() }
+    }
+
+    /// Defines a host function that takes its arguments and returns its result as Rust values.
+    /// Its types are checked against the import by [`Linker::link`], `()` stands for no result.
+    ///
+    /// The function may also return `Result<_, HostError>`, an error traps the guest. To access the memory
+    /// of the guest it takes [`Caller`] instead of the environment.
+    pub fn func<TArgs, TResult: HostResult, F: HostFn<TEnv, TArgs, TResult> + 'a>(
+        &mut self,
+        module: &'static str,
+        name: &'static str,
+        mut func: F,
+    ) -> &mut Self {
+        self.define(module, name, Some((F::PARAMS, TResult::Value::TYPE)), Box::new(move |env, stack, memory, data| {
+            func.call(env, stack, memory, data)?;
+            Ok(ImportOutcome::Return)
+        }))
+    }
+
+    /// Defines a host function that pops its arguments and pushes its results by itself,
+    /// e.g. to pause the guest with [`ImportOutcome`]. Its types are not checked.
+    pub fn func_raw(
+        &mut self,
+        module: &'static str,
+        name: &'static str,
+        mut func: impl FnMut(&mut TEnv, &mut VmStack, &mut [u8]) -> Result<ImportOutcome, HostError> + 'a,
+    ) -> &mut Self {
+        self.define(module, name, None, Box::new(move |env, stack, memory, _| func(env, stack, memory)))
+    }
+
+    fn define(
+        &mut self,
+        module: &'static str,
+        name: &'static str,
+        signature: Option<(&'static [TypeKind], TypeKind)>,
+        func: Box<DataClosure<'a, TEnv>>,
+    ) -> &mut Self {
+        let definition = Definition { module, name, signature, func: RefCell::new(func) };
+        match self.definitions.iter_mut().find(|it| it.module == module && it.name == name) {
+            Some(existing) => *existing = definition,
+            None => self.definitions.push(definition),
+        }
+        self
+    }
+
+    /// Defines the initial value of an imported global, its type is checked by [`Linker::link_globals`].
+    pub fn global(&mut self, module: &'static str, name: &'static str, value: Value) -> &mut Self {
+        let definition = GlobalDefinition { module, name, value };
+        match self.globals.iter_mut().find(|it| it.module == module && it.name == name) {
+            Some(existing) => *existing = definition,
+            None => self.globals.push(definition),
+        }
+        self
+    }
+
+    /// Resolves imported globals of the module, the result is meant to be passed to [`crate::Instance::with_globals`].
+    pub fn link_globals<'code>(&self, module: &WasmModule<'code>) -> Result<Vec<Value>, LinkError<'code>> {
+        let mut values = Vec::new();
+        let mut error = LinkError { unresolved: Vec::new(), mismatched: Vec::new() };
+        for global in &module.globals {
+            let Some(import) = global.import else {
+                continue;
+            };
+            let definition = self.globals.iter().find(|it| {
+                it.module.as_bytes() == import.module.as_bytes() && it.name.as_bytes() == import.name.as_bytes()
+            });
+            match definition {
+                Some(definition) if definition.value.ty() == global.kind => values.push(definition.value),
+                Some(_) => error.mismatched.push(import),
+                None => error.unresolved.push(import),
+            }
+        }
+
+        if error.unresolved.is_empty() && error.mismatched.is_empty() {
+            Ok(values)
+        } else {
+            Err(error)
+        }
+    }
+
+    /// Resolves all imported functions of the module, the result is meant to be passed to [`crate::evaluate`] and others.
+    ///
+    /// Imports that are missing or whose types don't match are all reported in one error.
+    pub fn link<'code>(&self, module: &WasmModule<'code>) -> Result<Vec<HostFunc<'_, '_, TEnv>>, LinkError<'code>> {
+        let mut imports = Vec::new();
+        let mut error = LinkError { unresolved: Vec::new(), mismatched: Vec::new() };
+        for func in module.functions.iter().filter(|it| it.body.is_none()) {
+            let import = ImportName {
+                module: func.module.expect("imported function has a module"),
+                name: func.name.expect("imported function has a name"),
+            };
+            let definition = self.definitions.iter().find(|it| {
+                it.module.as_bytes() == import.module.as_bytes() && it.name.as_bytes() == import.name.as_bytes()
+            });
+            let Some(definition) = definition else {
+                error.unresolved.push(import);
+                continue;
+            };
+            if let Some((params, result)) = definition.signature {
+                let signature = &module.signatures[func.signature.unwrap()];
+                let valid_result = match &signature.results[..] {
+                    [] => result == TypeKind::Void,
+                    [ty] => *ty == result,
+                    _ => false,
+                };
+                if signature.params[..] != *params || !valid_result {
+                    error.mismatched.push(import);
+                    continue;
+                }
+            }
+            // host functions never call back into the guest, so the closure is never borrowed twice
+            let func = &definition.func;
+            imports.push(HostFunc::WithData(Box::new(move |env, stack, memory, data| {
+                (func.borrow_mut())(env, stack, memory, data)
+            })));
+        }
+
+        if error.unresolved.is_empty() && error.mismatched.is_empty() {
+            Ok(imports)
+        } else {
+            Err(error)
+        }
+    }
+}
+
+/// Module and field name of an import.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImportName<'code> {
+    pub module: &'code ByteStr,
+    pub name: &'code ByteStr,
+}
+
+impl fmt::Display for ImportName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.module, self.name)
+    }
+}
+
+/// Imports that [`Linker::link`] couldn't resolve.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkError<'code> {
+    /// Imports without a definition.
+    pub unresolved: Vec<ImportName<'code>>,
+    /// Imports defined with other types than the module expects.
+    pub mismatched: Vec<ImportName<'code>>,
+}
+
+impl fmt::Display for LinkError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for import in &self.unresolved {
+            writeln!(f, "unresolved import {import}")?;
+        }
+        for import in &self.mismatched {
+            writeln!(f, "import {import} has mismatched types")?;
+        }
+        Ok(())
+    }
+}
+
+impl core::error::Error for LinkError<'_> {}
+
+/// Result of a function defined with [`Linker::func`], either a value or `Result` of a value.
+pub trait HostResult {
+    type Value: Operand;
+
+    fn into_result(self) -> Result<Self::Value, HostError>;
+}
+
+impl<T: Operand> HostResult for T {
+    type Value = T;
+
+    fn into_result(self) -> Result<T, HostError> {
+        Ok(self)
+    }
+}
+
+impl<T: Operand> HostResult for Result<T, HostError> {
+    type Value = T;
+
+    fn into_result(self) -> Result<T, HostError> {
+        self
+    }
+}
+
+/// Rust function that can be defined with [`Linker::func`], e.g. `|env: &mut TEnv, pin: u32, high: bool| {}`
+/// or `|caller: Caller<TEnv>, s: WasmSlice<u8>| {}`.
+pub trait HostFn<TEnv, TArgs, TResult: HostResult> {
+    const PARAMS: &'static [TypeKind];
+
+    /// Pops the arguments, calls the function and pushes its result.
+    fn call(&mut self, env: &mut TEnv, stack: &mut VmStack, memory: &mut [u8], data: Option<&mut dyn Any>) -> Result<(), HostError>;
+}
+
+/// Arguments of a [`HostFn`] that takes [`Caller`] before them.
+pub struct WithCaller<TArgs>(PhantomData<TArgs>);
+
+// Arguments are popped in the reverse order.
+macro_rules! host_fn_impls {
+    ( $( $arg:ident )* ; $( $popped:ident )* ) => {
+        impl<TEnv, TResult: HostResult, TFunc, $($arg: Operand),*> HostFn<TEnv, ($($arg,)*), TResult> for TFunc
+        where
+            TFunc: FnMut(&mut TEnv, $($arg),*) -> TResult,
+        {
+            const PARAMS: &'static [TypeKind] = &[$($arg::TYPE),*];
+
+            #[allow(nonstandard_style)]
+            fn call(&mut self, env: &mut TEnv, stack: &mut VmStack, _memory: &mut [u8], _data: Option<&mut dyn Any>) -> Result<(), HostError> {
+                $(
+                    let $popped = $popped::pop(stack).expect("arguments checked by the linker");
+                )*
+                TResult::Value::push(stack, self(env, $($arg),*).into_result()?);
+                Ok(())
+            }
+        }
+
+        impl<TEnv, TResult: HostResult, TFunc, $($arg: Operand),*> HostFn<TEnv, WithCaller<($($arg,)*)>, TResult> for TFunc
+        where
+            TFunc: FnMut(Caller<'_, TEnv>, $($arg),*) -> TResult,
+        {
+            const PARAMS: &'static [TypeKind] = &[$($arg::TYPE),*];
+
+            #[allow(nonstandard_style)]
+            fn call(&mut self, env: &mut TEnv, stack: &mut VmStack, memory: &mut [u8], data: Option<&mut dyn Any>) -> Result<(), HostError> {
+                $(
+                    let $popped = $popped::pop(stack).expect("arguments checked by the linker");
+                )*
+                let caller = Caller { env, memory: MemoryView::new(memory), data };
+                TResult::Value::push(stack, self(caller, $($arg),*).into_result()?);
+                Ok(())
+            }
+        }
+    };
+}
+
+host_fn_impls! { ; }
+host_fn_impls! { A ; A }
+host_fn_impls! { A B ; B A }
+host_fn_impls! { A B C ; C B A }
+host_fn_impls! { A B C D ; D C B A }
+host_fn_impls! { A B C D E ; E D C B A }
+host_fn_impls! { A B C D E F ; F E D C B A }
@@ -0,0 +1,75 @@
+use core::fmt;
+
+/// Importance of a diagnostic message, from the most to the least important.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    /// Sections and items of a module found by the parser, functions entered by the interpreter.
+    Debug,
+    /// Every instruction parsed and, in debug builds, executed.
+    Trace,
+}
+
+/// Sink for diagnostics of the parser and the interpreter, kept apart from the console of guests
+/// behind [`crate::Environment::write_fmt`].
+///
+/// Messages may come in parts, each one ends with a new line.
+pub trait Log {
+    fn log(&mut self, level: Level, args: fmt::Arguments);
+
+    /// Whether messages of the level are wanted, the others aren't even formatted.
+    /// [`Level::Debug`] and [`Level::Trace`] are off by default.
+    fn enabled(&self, level: Level) -> bool {
+        level <= Level::Info
+    }
+}
+
+/// Discards all messages.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoLog;
+
+impl Log for NoLog {
+    fn log(&mut self, _level: Level, _args: fmt::Arguments) {}
+
+    fn enabled(&self, _level: Level) -> bool {
+        false
+    }
+}
+
+// Formats and writes a message only if the log wants messages of the level.
+macro_rules! log {
+    ($log:expr, $level:expr, $($arg:tt)*) => {{
+        let level = $level;
+        if $crate::log::Log::enabled(&*$log, level) {
+            $crate::log::Log::log(&mut *$log, level, format_args!($($arg)*));
+        }
+    }};
+}
+
+macro_rules! logln {
+    ($log:expr, $level:expr, $($arg:tt)*) => {
+        $crate::log::log!($log, $level, "{}\n", format_args!($($arg)*))
+    };
+}
+
+// Writes a line of the execution trace to the log of a `VmContext`, if it has one.
+macro_rules! trace {
+    ($log:expr, $($arg:tt)*) => {
+        if let Some(log) = $log.as_deref_mut() {
+            $crate::log::logln!(log, $crate::log::Level::Trace, $($arg)*);
+        }
+    };
+}
+
+// Like `trace!`, for the functions entered by the interpreter.
+macro_rules! debug {
+    ($log:expr, $($arg:tt)*) => {
+        if let Some(log) = $log.as_deref_mut() {
+            $crate::log::logln!(log, $crate::log::Level::Debug, $($arg)*);
+        }
+    };
+}
+
+pub(crate) use {debug, log, logln, trace};
@@ -0,0 +1,360 @@
+use core::ops::Range;
+
+use crate::interpreter::{InterpreterError, MemoryAccessError};
+use crate::PAGE_SIZE;
+
+// Semantics of the numeric and memory instructions shared by all engines. Values are passed in
+// 64-bit slots, 32-bit ones in their lower half with the upper half cleared.
+
+// address of a load or store, `None` when it doesn't fit into the address space of the host
+#[inline]
+fn address(base: u32, offset: u32) -> Option<usize> {
+    usize::try_from(u64::from(base) + u64::from(offset)).ok()
+}
+
+fn out_of_bounds(base: u32, offset: u32) -> InterpreterError {
+    MemoryAccessError::InvalidOffset { offset: (base as usize).saturating_add(offset as usize) }.into()
+}
+
+fn read<const N: usize>(memory: &[u8], addr: usize) -> Option<[u8; N]> {
+    memory.get(addr..)?.first_chunk().copied()
+}
+
+/// Executes a load from `base + offset`, guest memory is little-endian.
+#[inline]
+pub(crate) fn load(memory: &[u8], op: u8, base: u32, offset: u32) -> Result<u64, InterpreterError> {
+    address(base, offset)
+        .and_then(|addr| load_at(memory, op, addr))
+        .ok_or_else(|| out_of_bounds(base, offset))
+}
+
+fn load_at(memory: &[u8], op: u8, addr: usize) -> Option<u64> {
+    Some(match op {
+        // i32.load | f32.load
+        0x28 | 0x2a => u64::from(u32::from_le_bytes(read(memory, addr)?)),
+        // i64.load | f64.load
+        0x29 | 0x2b => u64::from_le_bytes(read(memory, addr)?),
+        0x2c => i8::from_le_bytes(read(memory, addr)?) as i32 as u32 as u64,
+        0x2d | 0x31 => u64::from(u8::from_le_bytes(read(memory, addr)?)),
+        0x2e => i16::from_le_bytes(read(memory, addr)?) as i32 as u32 as u64,
+        0x2f | 0x33 => u64::from(u16::from_le_bytes(read(memory, addr)?)),
+        0x30 => i8::from_le_bytes(read(memory, addr)?) as i64 as u64,
+        0x32 => i16::from_le_bytes(read(memory, addr)?) as i64 as u64,
+        0x34 => i32::from_le_bytes(read(memory, addr)?) as i64 as u64,
+        0x35 => u64::from(u32::from_le_bytes(read(memory, addr)?)),
+        _ => unreachable!("{:02x} is not a load", op),
+    })
+}
+
+/// Executes a store of `val` to `base + offset`.
+#[inline]
+pub(crate) fn store(memory: &mut [u8], op: u8, base: u32, offset: u32, val: u64) -> Result<(), InterpreterError> {
+    let len = match op {
+        // i32.store8 | i64.store8
+        0x3a | 0x3c => 1,
+        // i32.store16 | i64.store16
+        0x3b | 0x3d => 2,
+        // i32.store | f32.store | i64.store32
+        0x36 | 0x38 | 0x3e => 4,
+        // i64.store | f64.store
+        0x37 | 0x39 => 8,
+        _ => unreachable!("{:02x} is not a store", op),
+    };
+    let bytes = address(base, offset)
+        .and_then(|addr| memory.get_mut(addr..addr.checked_add(len)?))
+        .ok_or_else(|| out_of_bounds(base, offset))?;
+    bytes.copy_from_slice(&val.to_le_bytes()[..len]);
+    Ok(())
+}
+
+/// Executes `memory.size`, the memory given by the host is all the memory the guest has.
+pub(crate) fn memory_size(memory: &[u8]) -> u64 {
+    (memory.len() / PAGE_SIZE) as u64
+}
+
+/// Executes `memory.grow` by `delta` pages. Memory is given by the host and can't be resized while
+/// the guest runs, so growing it fails with -1, as it would when the host is out of memory.
+pub(crate) fn memory_grow(memory: &[u8], delta: u32) -> u64 {
+    match delta {
+        0 => memory_size(memory),
+        _ => u64::from(u32::MAX),
+    }
+}
+
+/// Executes `memory.copy` of `len` bytes from `src` to `dst`, the ranges may overlap.
+pub(crate) fn memory_copy(memory: &mut [u8], dst: u32, src: u32, len: u32) -> Result<(), InterpreterError> {
+    let src = range(memory, src, len)?;
+    let dst = range(memory, dst, len)?;
+    memory.copy_within(src, dst.start);
+    Ok(())
+}
+
+/// Executes `memory.fill` of `len` bytes at `dst` with the lowest byte of `val`.
+pub(crate) fn memory_fill(memory: &mut [u8], dst: u32, val: u32, len: u32) -> Result<(), InterpreterError> {
+    let dst = range(memory, dst, len)?;
+    memory[dst].fill(val as u8);
+    Ok(())
+}
+
+// bytes touched by a bulk instruction, it traps without writing anything when any of them is out of bounds
+fn range(memory: &[u8], addr: u32, len: u32) -> Result<Range<usize>, InterpreterError> {
+    address(addr, len)
+        .filter(|&end| end <= memory.len())
+        .map(|end| end - len as usize..end)
+        .ok_or_else(|| out_of_bounds(addr, 0))
+}
+
+/// Executes a saturating conversion of a float into an integer, `op` is the opcode following the `0xfc`
+/// prefix. Casts in Rust already saturate and turn NaNs into zeros, just as these instructions do.
+#[inline]
+pub(crate) fn trunc_sat(op: u8, a: u64) -> u64 {
+    let (af32, af64) = (f32::from_bits(a as u32), f64::from_bits(a));
+    match op {
+        // i32.trunc_sat_f32_s | i32.trunc_sat_f32_u | i32.trunc_sat_f64_s | i32.trunc_sat_f64_u
+        0 => u64::from(af32 as i32 as u32),
+        1 => u64::from(af32 as u32),
+        2 => u64::from(af64 as i32 as u32),
+        3 => u64::from(af64 as u32),
+        // i64.trunc_sat_f32_s | i64.trunc_sat_f32_u | i64.trunc_sat_f64_s | i64.trunc_sat_f64_u
+        4 => af32 as i64 as u64,
+        5 => af32 as u64,
+        6 => af64 as i64 as u64,
+        7 => af64 as u64,
+        _ => unreachable!("fc {:02x} is not a saturating conversion", op),
+    }
+}
+
+/// Whether the engines can execute the numeric instruction. Rounding and square roots of floats
+/// would need a libm, which isn't a part of `core`.
+pub(crate) fn is_supported(op: u8) -> bool {
+    !matches!(op, 0x8d..=0x91 | 0x9b..=0x9f)
+}
+
+/// Executes a unary operation, conversions of floats into integers trap when the result doesn't fit.
+#[inline]
+pub(crate) fn unary(op: u8, a: u64) -> Result<u64, InterpreterError> {
+    let a32 = a as u32;
+    let (af32, af64) = (f32::from_bits(a32), f64::from_bits(a));
+    Ok(match op {
+        // i32.eqz
+        0x45 => u64::from(a32 == 0),
+        // i64.eqz
+        0x50 => u64::from(a == 0),
+        0x67 => u64::from(a32.leading_zeros()),
+        0x68 => u64::from(a32.trailing_zeros()),
+        0x69 => u64::from(a32.count_ones()),
+        0x79 => u64::from(a.leading_zeros()),
+        0x7a => u64::from(a.trailing_zeros()),
+        0x7b => u64::from(a.count_ones()),
+        // f32.abs | f32.neg
+        0x8b => u64::from(a32 & !F32_SIGN),
+        0x8c => u64::from(a32 ^ F32_SIGN),
+        // f64.abs | f64.neg
+        0x99 => a & !F64_SIGN,
+        0x9a => a ^ F64_SIGN,
+        // i32.wrap_i64
+        0xa7 => u64::from(a32),
+        // i32.trunc_f32_s | i32.trunc_f32_u | i32.trunc_f64_s | i32.trunc_f64_u
+        0xa8 => u64::from(truncate(f64::from(af32), -2147483649.0, 2147483648.0)? as i32 as u32),
+        0xa9 => u64::from(truncate(f64::from(af32), -1.0, 4294967296.0)? as u32),
+        0xaa => u64::from(truncate(af64, -2147483649.0, 2147483648.0)? as i32 as u32),
+        0xab => u64::from(truncate(af64, -1.0, 4294967296.0)? as u32),
+        // i64.extend_i32_s | i64.extend_i32_u
+        0xac => a32 as i32 as i64 as u64,
+        0xad => u64::from(a32),
+        // i64.trunc_f32_s | i64.trunc_f32_u | i64.trunc_f64_s | i64.trunc_f64_u
+        0xae => truncate(f64::from(af32), -9223372036854777856.0, 9223372036854775808.0)? as i64 as u64,
+        0xaf => truncate(f64::from(af32), -1.0, 18446744073709551616.0)? as u64,
+        0xb0 => truncate(af64, -9223372036854777856.0, 9223372036854775808.0)? as i64 as u64,
+        0xb1 => truncate(af64, -1.0, 18446744073709551616.0)? as u64,
+        // f32.convert_i32_s | f32.convert_i32_u | f32.convert_i64_s | f32.convert_i64_u | f32.demote_f64
+        0xb2 => u64::from((a32 as i32 as f32).to_bits()),
+        0xb3 => u64::from((a32 as f32).to_bits()),
+        0xb4 => u64::from((a as i64 as f32).to_bits()),
+        0xb5 => u64::from((a as f32).to_bits()),
+        0xb6 => u64::from((af64 as f32).to_bits()),
+        // f64.convert_i32_s | f64.convert_i32_u | f64.convert_i64_s | f64.convert_i64_u | f64.promote_f32
+        0xb7 => f64::from(a32 as i32).to_bits(),
+        0xb8 => f64::from(a32).to_bits(),
+        0xb9 => (a as i64 as f64).to_bits(),
+        0xba => (a as f64).to_bits(),
+        0xbb => f64::from(af32).to_bits(),
+        // reinterpretations keep the bits, which are already in the slot
+        0xbc | 0xbe => u64::from(a32),
+        0xbd | 0xbf => a,
+        // i32.extend8_s | i32.extend16_s
+        0xc0 => a32 as i8 as i32 as u32 as u64,
+        0xc1 => a32 as i16 as i32 as u32 as u64,
+        // i64.extend8_s | i64.extend16_s | i64.extend32_s
+        0xc2 => a as i8 as i64 as u64,
+        0xc3 => a as i16 as i64 as u64,
+        0xc4 => a as i32 as i64 as u64,
+        _ => unreachable!("{:02x} is not a supported unary operation", op),
+    })
+}
+
+const F32_SIGN: u32 = 1 << 31;
+const F64_SIGN: u64 = 1 << 63;
+
+// value of a float that is converted into an integer type whose values are between `above` and `below`
+// (both exclusive) once the fraction is dropped
+fn truncate(val: f64, above: f64, below: f64) -> Result<f64, InterpreterError> {
+    if val.is_nan() {
+        Err(InterpreterError::InvalidConversion)
+    } else if val <= above || val >= below {
+        Err(InterpreterError::IntegerOverflow)
+    } else {
+        Ok(val)
+    }
+}
+
+// `min` and `max` of wasm are NaN when any operand is, and order -0 below +0
+fn min_f32(a: f32, b: f32) -> f32 {
+    if a.is_nan() || b.is_nan() {
+        f32::NAN
+    } else if a == b {
+        f32::from_bits(a.to_bits() | b.to_bits())
+    } else {
+        a.min(b)
+    }
+}
+
+fn max_f32(a: f32, b: f32) -> f32 {
+    if a.is_nan() || b.is_nan() {
+        f32::NAN
+    } else if a == b {
+        f32::from_bits(a.to_bits() & b.to_bits())
+    } else {
+        a.max(b)
+    }
+}
+
+fn min_f64(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == b {
+        f64::from_bits(a.to_bits() | b.to_bits())
+    } else {
+        a.min(b)
+    }
+}
+
+fn max_f64(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == b {
+        f64::from_bits(a.to_bits() & b.to_bits())
+    } else {
+        a.max(b)
+    }
+}
+
+/// Executes a binary operation. Integer arithmetic wraps around, shifts take their count modulo
+/// the width of the type and division traps on zero and overflow.
+#[inline]
+pub(crate) fn binary(op: u8, a: u64, b: u64) -> Result<u64, InterpreterError> {
+    let (a32, b32) = (a as u32, b as u32);
+    let (af32, bf32) = (f32::from_bits(a32), f32::from_bits(b32));
+    let (af64, bf64) = (f64::from_bits(a), f64::from_bits(b));
+    Ok(match op {
+        0x46 => u64::from(a32 == b32),
+        0x47 => u64::from(a32 != b32),
+        0x48 => u64::from((a32 as i32) < (b32 as i32)),
+        0x49 => u64::from(a32 < b32),
+        0x4a => u64::from((a32 as i32) > (b32 as i32)),
+        0x4b => u64::from(a32 > b32),
+        0x4c => u64::from((a32 as i32) <= (b32 as i32)),
+        0x4d => u64::from(a32 <= b32),
+        0x4e => u64::from((a32 as i32) >= (b32 as i32)),
+        0x4f => u64::from(a32 >= b32),
+        0x51 => u64::from(a == b),
+        0x52 => u64::from(a != b),
+        0x53 => u64::from((a as i64) < (b as i64)),
+        0x54 => u64::from(a < b),
+        0x55 => u64::from((a as i64) > (b as i64)),
+        0x56 => u64::from(a > b),
+        0x57 => u64::from((a as i64) <= (b as i64)),
+        0x58 => u64::from(a <= b),
+        0x59 => u64::from((a as i64) >= (b as i64)),
+        0x5a => u64::from(a >= b),
+        0x5b => u64::from(af32 == bf32),
+        0x5c => u64::from(af32 != bf32),
+        0x5d => u64::from(af32 < bf32),
+        0x5e => u64::from(af32 > bf32),
+        0x5f => u64::from(af32 <= bf32),
+        0x60 => u64::from(af32 >= bf32),
+        0x61 => u64::from(af64 == bf64),
+        0x62 => u64::from(af64 != bf64),
+        0x63 => u64::from(af64 < bf64),
+        0x64 => u64::from(af64 > bf64),
+        0x65 => u64::from(af64 <= bf64),
+        0x66 => u64::from(af64 >= bf64),
+        0x6a => u64::from(a32.wrapping_add(b32)),
+        0x6b => u64::from(a32.wrapping_sub(b32)),
+        0x6c => u64::from(a32.wrapping_mul(b32)),
+        0x6d => {
+            if b32 == 0 {
+                return Err(InterpreterError::DivisionByZero);
+            }
+            let result = (a32 as i32).checked_div(b32 as i32).ok_or(InterpreterError::IntegerOverflow)?;
+            u64::from(result as u32)
+        }
+        0x6e => u64::from(a32.checked_div(b32).ok_or(InterpreterError::DivisionByZero)?),
+        0x6f => {
+            if b32 == 0 {
+                return Err(InterpreterError::DivisionByZero);
+            }
+            // unlike the division, the remainder of `MIN / -1` fits
+            u64::from((a32 as i32).wrapping_rem(b32 as i32) as u32)
+        }
+        0x70 => u64::from(a32.checked_rem(b32).ok_or(InterpreterError::DivisionByZero)?),
+        0x71 => u64::from(a32 & b32),
+        0x72 => u64::from(a32 | b32),
+        0x73 => u64::from(a32 ^ b32),
+        0x74 => u64::from(a32.wrapping_shl(b32)),
+        0x75 => u64::from((a32 as i32).wrapping_shr(b32) as u32),
+        0x76 => u64::from(a32.wrapping_shr(b32)),
+        0x77 => u64::from(a32.rotate_left(b32 % 32)),
+        0x78 => u64::from(a32.rotate_right(b32 % 32)),
+        0x7c => a.wrapping_add(b),
+        0x7d => a.wrapping_sub(b),
+        0x7e => a.wrapping_mul(b),
+        0x7f => {
+            if b == 0 {
+                return Err(InterpreterError::DivisionByZero);
+            }
+            (a as i64).checked_div(b as i64).ok_or(InterpreterError::IntegerOverflow)? as u64
+        }
+        0x80 => a.checked_div(b).ok_or(InterpreterError::DivisionByZero)?,
+        0x81 => {
+            if b == 0 {
+                return Err(InterpreterError::DivisionByZero);
+            }
+            (a as i64).wrapping_rem(b as i64) as u64
+        }
+        0x82 => a.checked_rem(b).ok_or(InterpreterError::DivisionByZero)?,
+        0x83 => a & b,
+        0x84 => a | b,
+        0x85 => a ^ b,
+        0x86 => a.wrapping_shl(b as u32),
+        0x87 => (a as i64).wrapping_shr(b as u32) as u64,
+        0x88 => a.wrapping_shr(b as u32),
+        0x89 => a.rotate_left((b % 64) as u32),
+        0x8a => a.rotate_right((b % 64) as u32),
+        0x92 => u64::from((af32 + bf32).to_bits()),
+        0x93 => u64::from((af32 - bf32).to_bits()),
+        0x94 => u64::from((af32 * bf32).to_bits()),
+        0x95 => u64::from((af32 / bf32).to_bits()),
+        0x96 => u64::from(min_f32(af32, bf32).to_bits()),
+        0x97 => u64::from(max_f32(af32, bf32).to_bits()),
+        0x98 => u64::from(a32 & !F32_SIGN | b32 & F32_SIGN),
+        0xa0 => (af64 + bf64).to_bits(),
+        0xa1 => (af64 - bf64).to_bits(),
+        0xa2 => (af64 * bf64).to_bits(),
+        0xa3 => (af64 / bf64).to_bits(),
+        0xa4 => min_f64(af64, bf64).to_bits(),
+        0xa5 => max_f64(af64, bf64).to_bits(),
+        0xa6 => a & !F64_SIGN | b & F64_SIGN,
+        _ => unreachable!("{:02x} is not a supported binary operation", op),
+    })
+}
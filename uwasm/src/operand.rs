@@ -23,7 +23,7 @@ impl Operand for i32 {
     }
 
     fn write_to(&self, serializer: &mut Serializer) {
-        serializer.write_bytes(&self.to_ne_bytes());
+        serializer.write(*self);
     }
 }
 
@@ -39,7 +39,7 @@ impl Operand for u32 {
     }
 
     fn write_to(&self, serializer: &mut Serializer) {
-        serializer.write_bytes(&self.to_ne_bytes());
+        serializer.write(*self);
     }
 }
 
@@ -55,7 +55,7 @@ impl Operand for i64 {
     }
 
     fn write_to(&self, serializer: &mut Serializer) {
-        serializer.write_bytes(&self.to_ne_bytes());
+        serializer.write(*self);
     }
 }
 
@@ -71,7 +71,7 @@ impl Operand for u64 {
     }
 
     fn write_to(&self, serializer: &mut Serializer) {
-        serializer.write_bytes(&self.to_ne_bytes());
+        serializer.write(*self);
     }
 }
 
@@ -87,7 +87,7 @@ impl Operand for f32 {
     }
 
     fn write_to(&self, serializer: &mut Serializer) {
-        serializer.write_bytes(&self.to_ne_bytes());
+        serializer.write(*self);
     }
 }
 
@@ -102,12 +102,12 @@ impl Operand for f64 {
     }
 
     fn write_to(&self, serializer: &mut Serializer) {
-        serializer.write_bytes(&self.to_ne_bytes());
+        serializer.write(*self);
     }
 }
 
 impl Operand for bool {
-    const TYPE: TypeKind = TypeKind::Void;
+    const TYPE: TypeKind = TypeKind::I32;
 
     fn pop(stack: &mut VmStack) -> Result<Self, InterpreterError> {
         stack.pop_i32().map(|s| s != 0)
@@ -118,6 +118,19 @@ impl Operand for bool {
     }
 
     fn write_to(&self, serializer: &mut Serializer) {
-        serializer.write_bytes(&[*self as u8]);
+        serializer.write(*self as i32);
+    }
+}
+
+/// No value, the result of functions that don't return anything.
+impl Operand for () {
+    const TYPE: TypeKind = TypeKind::Void;
+
+    fn pop(_stack: &mut VmStack) -> Result<Self, InterpreterError> {
+        Ok(())
     }
-}
\ No newline at end of file
+
+    fn push(_stack: &mut VmStack, _value: Self) {}
+
+    fn write_to(&self, _serializer: &mut Serializer) {}
+}
@@ -1,8 +1,9 @@
+use alloc::collections::TryReserveError;
 use core::fmt;
 use core::fmt::{Display, Formatter};
 use crate::str::ByteStr;
 
-#[derive(Clone)]
+#[derive(Clone, Copy)]
 pub(crate) struct Reader<'code> {
     data: &'code [u8],
     pos: usize,
@@ -23,14 +24,6 @@ impl<'code> Reader<'code> {
         self.pos
     }
 
-    pub(crate) fn skip_to(&mut self, target_offset: usize) {
-        self.pos = target_offset;
-    }
-
-    pub(crate) fn skip_to_end(&mut self) {
-        self.pos = self.data.len();
-    }
-
     pub(crate) fn read_bytes<const N: usize>(&mut self) -> Result<&'code [u8; N], ParserError> {
         if let Some(bytes) = self.data[self.pos..].first_chunk() {
             self.pos += N;
@@ -76,24 +69,6 @@ impl<'code> Reader<'code> {
         self.read_bytes::<4>().map(|b| u32::from_le_bytes(*b))
     }
 
-    #[inline]
-    #[cfg(debug_assertions)]
-    pub(crate) fn read_i32(&mut self) -> Result<i32, ParserError> {
-        self.read_u32().map(|v| v as i32)
-    }
-
-    #[inline]
-    #[cfg(debug_assertions)]
-    pub(crate) fn read_u64(&mut self) -> Result<u64, ParserError> {
-        self.read_bytes::<8>().map(|b| u64::from_le_bytes(*b))
-    }
-
-    #[inline]
-    #[cfg(debug_assertions)]
-    pub(crate) fn read_i64(&mut self) -> Result<i64, ParserError> {
-        self.read_u64().map(|v| v as i64)
-    }
-
     #[inline]
     pub(crate) fn read_usize(&mut self) -> Result<usize, ParserError> {
         let val = self.read_unsigned()?;
@@ -174,10 +149,6 @@ pub(crate) struct Marker<'code> {
 }
 
 impl<'code> Marker<'code> {
-    pub(crate) fn pos(&self) -> usize {
-        self.start
-    }
-
     pub(crate) fn into_slice(self, reader: &mut Reader<'code>) -> &'code [u8] {
         &self.data[self.start..reader.pos]
     }
@@ -229,6 +200,7 @@ pub enum TypeKind {
     Void = 0x40,
     Func = 0x60,
     FuncRef = 0x70,
+    ExternRef = 0x6F,
     F64 = 0x7C,
     F32 = 0x7D,
     I64 = 0x7E,
@@ -240,7 +212,8 @@ impl TypeKind {
         match *self {
             TypeKind::Void => todo!(),
             TypeKind::Func => todo!(),
-            TypeKind::FuncRef => todo!(),
+            // references take a whole slot, so that null differs from any index
+            TypeKind::FuncRef | TypeKind::ExternRef => 8,
             TypeKind::F64 => 8,
             TypeKind::I64 => 8,
             TypeKind::I32 => 4,
@@ -255,6 +228,7 @@ impl Item for TypeKind {
             0x40 => Ok(TypeKind::Void),
             0x60 => Ok(TypeKind::Func),
             0x70 => Ok(TypeKind::FuncRef),
+            0x6F => Ok(TypeKind::ExternRef),
             0x7C => Ok(TypeKind::F64),
             0x7D => Ok(TypeKind::F32),
             0x7E => Ok(TypeKind::I64),
@@ -270,6 +244,26 @@ pub enum ParserError {
     InvalidValue { offset: usize, found: u8 },
     UnexpectedBytes { offset: usize },
     NotEnoughBytes { offset: usize },
+    /// Function body doesn't type check.
+    InvalidCode { offset: usize },
+    /// Function body contains an instruction that is not known to the parser or that can't be executed.
+    UnsupportedOpcode { offset: usize, opcode: u8 },
+    /// The module needs an arena of at least `required` bytes.
+    ArenaTooSmall { required: usize },
+    /// Memory for the module couldn't be allocated.
+    OutOfMemory,
+    /// Precompiled image is damaged, misaligned or has been written by another version of the crate.
+    InvalidImage,
+    /// Precompiled image has been written for a different module.
+    StaleImage,
+    /// Function has more locals or a deeper stack than the translated code can address.
+    FunctionTooLarge,
+}
+
+impl From<TryReserveError> for ParserError {
+    fn from(_: TryReserveError) -> Self {
+        ParserError::OutOfMemory
+    }
 }
 
 impl Display for ParserError {
@@ -0,0 +1,740 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+
+use crate::arena::ModuleAlloc;
+use crate::bytecode::{numeric_type, slot_count, Instr, ModuleTypes, Slots, Translator};
+use crate::interpreter::{indirect_callee, poll_stop_requests, signature_results, Execution, HostFunc, ImportOutcome, InterpreterError, InterruptHandle, MemoryAccessError, PauseReason, VmContext, VmStack};
+use crate::parser::{ParserError, Reader, TypeKind};
+use crate::log::{debug, trace, Log, NoLog};
+use crate::numeric;
+use crate::{Environment, FuncSignature, WasmModule};
+
+/// Instruction of the register engine.
+///
+/// Operands are indices of the 64-bit registers of the current frame. Locals take the first registers,
+/// they are followed by the values that the stack interpreter would keep on the operand stack.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum RegInstr {
+    Unreachable,
+    Copy { dst: u16, src: u16 },
+    Const { dst: u16, val: u64 },
+    Jump { target: u32 },
+    JumpIfZero { cond: u16, target: u32 },
+    Br(RegBranch),
+    BrIf { cond: u16, br: RegBranch },
+    /// Branches are stored in `RegFunc::br_table[start..][..len]`, the last one is the default.
+    BrTable { index: u16, start: u32, len: u32 },
+    /// Moves `count` results starting at `from` to the beginning of the frame and returns.
+    Return { from: u16, count: u16 },
+    /// Arguments start at `base`, which becomes the first register of the callee.
+    Call { func_idx: u32, base: u16 },
+    CallIndirect { type_idx: u32, index: u16, base: u16 },
+    Select { dst: u16, a: u16, b: u16, cond: u16 },
+    GlobalGet { dst: u16, offset: u32, ty: TypeKind },
+    GlobalSet { src: u16, offset: u32, ty: TypeKind },
+    Load { op: u8, dst: u16, addr: u16, offset: u32 },
+    Store { op: u8, addr: u16, src: u16, offset: u32 },
+    Unary { op: u8, dst: u16, src: u16 },
+    Binary { op: u8, dst: u16, a: u16, b: u16 },
+    MemorySize { dst: u16 },
+    MemoryGrow { dst: u16, delta: u16 },
+    MemoryCopy { dst: u16, src: u16, len: u16 },
+    MemoryFill { dst: u16, val: u16, len: u16 },
+    /// Saturating conversion of a float into an integer, `op` follows the `0xfc` prefix.
+    TruncSat { op: u8, dst: u16, src: u16 },
+}
+
+impl RegInstr {
+    /// Opcode of the wasm instruction that is the closest to this one.
+    fn opcode(&self) -> u8 {
+        match *self {
+            RegInstr::Unreachable => 0x00,
+            RegInstr::Copy { .. } => 0x20,
+            RegInstr::Const { .. } => 0x41,
+            RegInstr::Jump { .. } => 0x05,
+            RegInstr::JumpIfZero { .. } => 0x04,
+            RegInstr::Br(_) => 0x0c,
+            RegInstr::BrIf { .. } => 0x0d,
+            RegInstr::BrTable { .. } => 0x0e,
+            RegInstr::Return { .. } => 0x0f,
+            RegInstr::Call { .. } => 0x10,
+            RegInstr::CallIndirect { .. } => 0x11,
+            RegInstr::Select { .. } => 0x1b,
+            RegInstr::GlobalGet { .. } => 0x23,
+            RegInstr::GlobalSet { .. } => 0x24,
+            RegInstr::Load { op, .. }
+            | RegInstr::Store { op, .. }
+            | RegInstr::Unary { op, .. }
+            | RegInstr::Binary { op, .. } => op,
+            RegInstr::MemorySize { .. } => 0x3f,
+            RegInstr::MemoryGrow { .. } => 0x40,
+            RegInstr::MemoryCopy { .. } | RegInstr::MemoryFill { .. } | RegInstr::TruncSat { .. } => 0xfc,
+        }
+    }
+
+    // register written by instructions without side effects, so they can write straight into a local instead
+    fn dst_mut(&mut self) -> Option<&mut u16> {
+        match self {
+            RegInstr::Const { dst, .. }
+            | RegInstr::Select { dst, .. }
+            | RegInstr::GlobalGet { dst, .. }
+            | RegInstr::Load { dst, .. }
+            | RegInstr::Unary { dst, .. }
+            | RegInstr::Binary { dst, .. }
+            | RegInstr::MemorySize { dst }
+            | RegInstr::TruncSat { dst, .. } => Some(dst),
+            _ => None,
+        }
+    }
+}
+
+/// Copies `count` registers from `from` to `to` and jumps to `target`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RegBranch {
+    pub(crate) target: u32,
+    pub(crate) from: u16,
+    pub(crate) to: u16,
+    pub(crate) count: u16,
+}
+
+/// Function translated for the register engine.
+pub(crate) struct RegFunc {
+    pub(crate) code: Vec<RegInstr>,
+    br_table: Vec<RegBranch>,
+    params: u16,
+    locals: u16,
+    // registers used by the function, including its locals
+    frame_size: u16,
+}
+
+#[derive(Clone, Copy)]
+struct RegFrame {
+    func_idx: u32,
+    // index of the next instruction to execute
+    pc: u32,
+    // index of the first register of the frame
+    base: u32,
+}
+
+/// State of the register engine kept by a [`VmContext`].
+pub(crate) struct Registers<'code> {
+    // module that `funcs` have been translated from
+    module: Option<&'code WasmModule<'code>>,
+    // `None` for imported functions
+    funcs: Vec<Option<RegFunc>>,
+    regs: Vec<u64>,
+    frames: Vec<RegFrame>,
+    // imported function waiting for its results and the register they go to
+    pending_call: Option<(usize, usize)>,
+    #[cfg(feature = "jit")]
+    pub(crate) jit: crate::jit::Jit,
+}
+
+impl<'code> Registers<'code> {
+    pub(crate) fn new() -> Self {
+        Self {
+            module: None,
+            funcs: Vec::new(),
+            regs: Vec::new(),
+            frames: Vec::new(),
+            pending_call: None,
+            #[cfg(feature = "jit")]
+            jit: crate::jit::Jit::new(),
+        }
+    }
+
+    pub(crate) fn is_running(&self) -> bool {
+        !self.frames.is_empty()
+    }
+
+    pub(crate) fn abort(&mut self) {
+        self.regs.clear();
+        self.frames.clear();
+        self.pending_call = None;
+    }
+}
+
+/// Translates all functions of `module` into register code.
+fn compile(module: &WasmModule) -> Result<Vec<Option<RegFunc>>, ParserError> {
+    let mut translator = Translator::new(ModuleAlloc::Global);
+    let types = ModuleTypes {
+        signatures: &module.signatures,
+        functions: &module.functions,
+        globals: &module.globals,
+    };
+    let mut funcs = Vec::with_capacity(module.functions.len());
+    for func in &module.functions {
+        let Some(body) = &func.body else {
+            funcs.push(None);
+            continue;
+        };
+
+        let locals_types = body.locals_types(ModuleAlloc::Global)?;
+        let translated = translator.translate(
+            &mut Reader::new(body.code),
+            &body.signature,
+            &locals_types,
+            &types,
+            &mut NoLog,
+        )?;
+        let locals = locals_types.len();
+        let params = slot_count(body.signature.params.len())?;
+        // every register index is below the size of the frame, so it fits as well
+        let frame_size = slot_count(locals + translator.max_height() + 1)?;
+        let mut compiler = Compiler {
+            module,
+            locals,
+            code: Vec::with_capacity(translated.instrs.len()),
+            aliases: vec![None; translator.max_height() + 2],
+        };
+        let (code, br_table) = compiler.compile(&translated.instrs, &translated.br_table, translator.slots(), translator.table_slots());
+        funcs.push(Some(RegFunc {
+            code,
+            br_table,
+            params,
+            locals: slot_count(locals)?,
+            frame_size,
+        }));
+    }
+    Ok(funcs)
+}
+
+struct Compiler<'a, 'code> {
+    module: &'a WasmModule<'code>,
+    locals: usize,
+    code: Vec<RegInstr>,
+    // stack slots that still hold their value in a local, as they haven't been copied yet
+    aliases: Vec<Option<u16>>,
+}
+
+impl Compiler<'_, '_> {
+    fn compile(
+        &mut self,
+        instrs: &[Instr],
+        br_table: &[crate::bytecode::BrTarget],
+        slots: &[Slots],
+        table_slots: &[Slots],
+    ) -> (Vec<RegInstr>, Vec<RegBranch>) {
+        let mut is_target = vec![false; instrs.len() + 1];
+        for instr in instrs {
+            match *instr {
+                Instr::Jump { target } | Instr::JumpIfZero { target } => is_target[target as usize] = true,
+                Instr::Br(br) | Instr::BrIf(br) => is_target[br.target as usize] = true,
+                _ => {}
+            }
+        }
+        for br in br_table {
+            is_target[br.target as usize] = true;
+        }
+
+        // index of the first register instruction of each `Instr`
+        let mut map = vec![0u32; instrs.len() + 1];
+        let mut table = Vec::with_capacity(br_table.len());
+        // instruction that has just written the value on top of the stack
+        let mut last_dst = None;
+
+        for (i, (&instr, &slots)) in instrs.iter().zip(slots).enumerate() {
+            let h = usize::from(slots.height);
+            let top = h.saturating_sub(1);
+            let prev_dst = last_dst.take();
+            if is_target[i] {
+                // values have to be in their slots no matter from where the code is entered
+                self.flush();
+            }
+            map[i] = self.code.len() as u32;
+
+            match instr {
+                Instr::Unreachable => self.code.push(RegInstr::Unreachable),
+                Instr::Jump { target } => {
+                    self.flush();
+                    self.code.push(RegInstr::Jump { target });
+                }
+                Instr::JumpIfZero { target } => {
+                    let cond = self.take(top);
+                    self.flush();
+                    self.code.push(RegInstr::JumpIfZero { cond, target });
+                }
+                Instr::Br(br) => {
+                    self.flush();
+                    let br = self.branch(br.target, h, slots);
+                    self.code.push(RegInstr::Br(br));
+                }
+                Instr::BrIf(br) => {
+                    let cond = self.take(top);
+                    self.flush();
+                    let br = self.branch(br.target, top, slots);
+                    self.code.push(RegInstr::BrIf { cond, br });
+                }
+                Instr::BrTable { start, len } => {
+                    let index = self.take(top);
+                    self.flush();
+                    let new_start = table.len() as u32;
+                    for idx in start..start + len {
+                        let idx = idx as usize;
+                        table.push(self.branch(br_table[idx].target, top, table_slots[idx]));
+                    }
+                    self.code.push(RegInstr::BrTable { index, start: new_start, len });
+                }
+                Instr::Return { .. } => {
+                    self.flush();
+                    let count = slots.keep;
+                    self.code.push(RegInstr::Return { from: self.slot(h.saturating_sub(count.into())), count });
+                }
+                Instr::Call { func_idx } => {
+                    let signature = signature_of(self.module, func_idx as usize);
+                    let base = h.saturating_sub(signature.params.len());
+                    self.flush_from(base);
+                    self.code.push(RegInstr::Call { func_idx, base: self.slot(base) });
+                }
+                Instr::CallIndirect { type_idx } => {
+                    let index = self.take(top);
+                    let signature = &self.module.signatures[type_idx as usize];
+                    let base = top.saturating_sub(signature.params.len());
+                    self.flush_from(base);
+                    self.code.push(RegInstr::CallIndirect { type_idx, index, base: self.slot(base) });
+                }
+                Instr::Drop => {
+                    self.take(top);
+                }
+                Instr::Select => {
+                    let cond = self.take(top);
+                    let b = self.take(h.saturating_sub(2));
+                    let a = self.take(h.saturating_sub(3));
+                    let dst = self.slot(h.saturating_sub(3));
+                    last_dst = Some(self.code.len());
+                    self.code.push(RegInstr::Select { dst, a, b, cond });
+                }
+                Instr::LocalGet { idx } => {
+                    // copying is deferred until the local changes or the value has to be in its slot
+                    let local = self.reg(idx as usize);
+                    if let Some(alias) = self.aliases.get_mut(h) {
+                        *alias = Some(local);
+                    }
+                }
+                Instr::LocalSet { idx } | Instr::LocalTee { idx } => {
+                    let local = self.reg(idx as usize);
+                    let aliased = self.aliases[..top.min(self.aliases.len())].contains(&Some(local));
+                    match prev_dst {
+                        // previous instruction can write straight into the local, unless a branch
+                        // brings the value here as well
+                        Some(idx) if idx + 1 == self.code.len() && !is_target[i] && !aliased && self.aliases.get(top) == Some(&None) => {
+                            *self.code[idx].dst_mut().expect("set only for instructions with a destination") = local;
+                        }
+                        _ => {
+                            let src = self.take(top);
+                            // slots that still refer to the old value of the local need their own copy
+                            for slot in 0..top.min(self.aliases.len()) {
+                                if self.aliases[slot] == Some(local) {
+                                    self.materialize(slot);
+                                }
+                            }
+                            if src != local {
+                                self.code.push(RegInstr::Copy { dst: local, src });
+                            }
+                        }
+                    }
+                    if let Some(alias) = self.aliases.get_mut(top) {
+                        // value left by `local.tee` is the same as the one in the local
+                        *alias = matches!(instr, Instr::LocalTee { .. }).then_some(local);
+                    }
+                }
+                Instr::GlobalGet { offset, ty } => {
+                    last_dst = Some(self.code.len());
+                    self.code.push(RegInstr::GlobalGet { dst: self.slot(h), offset, ty });
+                }
+                Instr::GlobalSet { offset, ty } => {
+                    let src = self.take(top);
+                    self.code.push(RegInstr::GlobalSet { src, offset, ty });
+                }
+                Instr::Load { op, offset } => {
+                    let addr = self.take(top);
+                    last_dst = Some(self.code.len());
+                    self.code.push(RegInstr::Load { op, dst: self.slot(top), addr, offset });
+                }
+                Instr::Store { op, offset } => {
+                    let src = self.take(top);
+                    let addr = self.take(h.saturating_sub(2));
+                    self.code.push(RegInstr::Store { op, addr, src, offset });
+                }
+                Instr::I32Const(val) => self.constant(h, val as u32 as u64, &mut last_dst),
+                Instr::I64Const(val) => self.constant(h, val as u64, &mut last_dst),
+                Instr::F32Const(val) => self.constant(h, val.to_bits() as u64, &mut last_dst),
+                Instr::F64Const(val) => self.constant(h, val.to_bits(), &mut last_dst),
+                Instr::MemorySize => {
+                    last_dst = Some(self.code.len());
+                    self.code.push(RegInstr::MemorySize { dst: self.slot(h) });
+                }
+                Instr::MemoryGrow => {
+                    let delta = self.take(top);
+                    self.code.push(RegInstr::MemoryGrow { dst: self.slot(top), delta });
+                }
+                Instr::MemoryCopy | Instr::MemoryFill => {
+                    let len = self.take(top);
+                    let val = self.take(h.saturating_sub(2));
+                    let dst = self.take(h.saturating_sub(3));
+                    self.code.push(match instr {
+                        Instr::MemoryCopy => RegInstr::MemoryCopy { dst, src: val, len },
+                        _ => RegInstr::MemoryFill { dst, val, len },
+                    });
+                }
+                Instr::TruncSat { op } => {
+                    let src = self.take(top);
+                    last_dst = Some(self.code.len());
+                    self.code.push(RegInstr::TruncSat { op, dst: self.slot(top), src });
+                }
+                Instr::LocalI32AddConst { .. } | Instr::LocalsLtUBrIf { .. } | Instr::LocalLoad { .. } => {
+                    unreachable!("superinstructions are only used by the stack interpreter")
+                }
+                _ => {
+                    let op = instr.opcode();
+                    let (params, _) = numeric_type(op).expect("numeric instruction");
+                    last_dst = Some(self.code.len());
+                    if params.len() == 1 {
+                        let src = self.take(top);
+                        self.code.push(RegInstr::Unary { op, dst: self.slot(top), src });
+                    } else {
+                        let b = self.take(top);
+                        let a = self.take(h.saturating_sub(2));
+                        self.code.push(RegInstr::Binary { op, dst: self.slot(h.saturating_sub(2)), a, b });
+                    }
+                }
+            }
+        }
+        map[instrs.len()] = self.code.len() as u32;
+
+        for instr in &mut self.code {
+            match instr {
+                RegInstr::Jump { target }
+                | RegInstr::JumpIfZero { target, .. }
+                | RegInstr::Br(RegBranch { target, .. })
+                | RegInstr::BrIf { br: RegBranch { target, .. }, .. } => *target = map[*target as usize],
+                _ => {}
+            }
+        }
+        for br in &mut table {
+            br.target = map[br.target as usize];
+        }
+
+        (core::mem::take(&mut self.code), table)
+    }
+
+    fn slot(&self, slot: usize) -> u16 {
+        self.reg(self.locals + slot)
+    }
+
+    fn reg(&self, idx: usize) -> u16 {
+        u16::try_from(idx).expect("registers are within the frame, whose size has been checked")
+    }
+
+    // register holding the value of a slot that is popped from the stack
+    fn take(&mut self, slot: usize) -> u16 {
+        match self.aliases.get_mut(slot).and_then(Option::take) {
+            Some(local) => local,
+            None => self.slot(slot),
+        }
+    }
+
+    fn materialize(&mut self, slot: usize) {
+        if let Some(local) = self.aliases[slot].take() {
+            self.code.push(RegInstr::Copy { dst: self.slot(slot), src: local });
+        }
+    }
+
+    fn flush(&mut self) {
+        self.flush_from(0);
+    }
+
+    fn flush_from(&mut self, slot: usize) {
+        for slot in slot..self.aliases.len() {
+            self.materialize(slot);
+        }
+    }
+
+    fn constant(&mut self, slot: usize, val: u64, last_dst: &mut Option<usize>) {
+        *last_dst = Some(self.code.len());
+        self.code.push(RegInstr::Const { dst: self.slot(slot), val });
+    }
+
+    // `top` is the height of the stack with the values passed to the target on top of it
+    fn branch(&self, target: u32, top: usize, slots: Slots) -> RegBranch {
+        let count = slots.keep;
+        let from = self.slot(top.saturating_sub(count.into()));
+        let to = self.slot(slots.base.into());
+        RegBranch {
+            target,
+            from,
+            to,
+            count: if from == to { 0 } else { count },
+        }
+    }
+}
+
+fn signature_of<'m, 'code>(module: &'m WasmModule<'code>, func_idx: usize) -> &'m FuncSignature<'code> {
+    let sig_idx = module.functions[func_idx].signature.expect("function with a signature");
+    &module.signatures[sig_idx]
+}
+
+/// Prepares the register engine to execute given function, its arguments are moved from the stack.
+pub(crate) fn enter<'code>(
+    ctx: &mut VmContext<'code>,
+    module: &'code WasmModule<'code>,
+    func_idx: usize,
+) -> Result<(), InterpreterError> {
+    // code and registers are always kept on the heap
+    if ctx.call_stack.capacity().is_some() {
+        return Err(InterpreterError::EngineNeedsHeap);
+    }
+    let registers = &mut ctx.registers;
+    if !registers.module.is_some_and(|it| core::ptr::eq(it, module)) {
+        registers.funcs = compile(module)?;
+        registers.module = Some(module);
+        #[cfg(feature = "jit")]
+        registers.jit.reset(registers.funcs.len());
+    }
+
+    let Some(Some(func)) = registers.funcs.get(func_idx) else {
+        return Err(InterpreterError::FunctionNotFound);
+    };
+    registers.regs.resize(usize::from(func.frame_size), 0);
+    let params = usize::from(func.params);
+    ctx.stack.pop_slots(&mut registers.regs[..params])?;
+    registers.frames.push(RegFrame { func_idx: func_idx as u32, pc: 0, base: 0 });
+    Ok(())
+}
+
+pub(crate) fn run<'code, TEnv: Environment>(
+    ctx: &mut VmContext<'code>,
+    module: &'code WasmModule<'code>,
+    memory: &mut [u8],
+    globals: &mut [u8],
+    imports: &mut [HostFunc<'_, '_, TEnv>],
+    env: &mut TEnv,
+) -> Result<Execution, InterpreterError> {
+    let Registers { funcs, regs, frames, pending_call, #[cfg(feature = "jit")] jit, .. } = &mut ctx.registers;
+    // native code doesn't count instructions, so it can't run when they are limited
+    #[cfg(feature = "jit")]
+    let native = ctx.engine == crate::Engine::Jit && ctx.instruction_limit.is_none() && ctx.fuel.is_none();
+    if let Some((func_idx, base)) = pending_call.take() {
+        // results supplied with `VmContext::complete_import`
+        ctx.stack.pop_slots(&mut regs[base..][..signature_of(module, func_idx).results.len()])?;
+    }
+
+    let mut executed_instr_count = 0u64;
+    while let Some(frame) = frames.last_mut() {
+        if ctx.instruction_limit.is_some_and(|limit| executed_instr_count >= limit) {
+            return Ok(Execution::Paused(PauseReason::InstructionLimit));
+        }
+        if let Some(fuel) = &mut ctx.fuel {
+            if *fuel == 0 {
+                return Ok(Execution::Paused(PauseReason::OutOfFuel));
+            }
+            *fuel -= 1;
+        }
+        executed_instr_count += 1;
+
+        let start = env.ticks();
+        if ctx.time_slice_end.is_some_and(|end| start >= end) {
+            return Ok(Execution::Paused(PauseReason::TimeSliceElapsed));
+        }
+
+        let func_idx = frame.func_idx as usize;
+        let func = funcs[func_idx].as_ref().expect("only functions with code have frames");
+        #[cfg(feature = "jit")]
+        if native {
+            // native code stops at an instruction it can't execute, which is then interpreted
+            if let Some(pc) = jit.run(func_idx, func, frame.pc, &mut regs[frame.base as usize..], memory) {
+                frame.pc = pc;
+            }
+        }
+        let pc = frame.pc as usize;
+        let instr = func.code[pc];
+        frame.pc += 1;
+        let base = frame.base as usize;
+        let op = instr.opcode();
+
+        #[cfg(debug_assertions)]
+        trace!(ctx.log, "{pc:04} ({func_idx}) :: {:?}", instr);
+
+        ctx.profile.executed_instr_count[op as usize] += 1;
+
+        let r = &mut regs[base..];
+        let mut outcome = ImportOutcome::Return;
+        match instr {
+            RegInstr::Unreachable => {
+                trace!(ctx.log, "entered unreachable");
+                return Err(InterpreterError::Unreachable);
+            }
+            RegInstr::Copy { dst, src } => r[usize::from(dst)] = r[usize::from(src)],
+            RegInstr::Const { dst, val } => r[usize::from(dst)] = val,
+            RegInstr::Jump { target } => frame.pc = target,
+            RegInstr::JumpIfZero { cond, target } => {
+                if r[usize::from(cond)] as u32 == 0 {
+                    frame.pc = target;
+                }
+            }
+            RegInstr::Br(br) => {
+                do_branch(frame, r, br, &ctx.interrupt, ctx.deadline, env)?;
+            }
+            RegInstr::BrIf { cond, br } => {
+                if r[usize::from(cond)] as u32 != 0 {
+                    do_branch(frame, r, br, &ctx.interrupt, ctx.deadline, env)?;
+                }
+            }
+            RegInstr::BrTable { index, start, len } => {
+                let idx = (r[usize::from(index)] as u32).min(len - 1);
+                let br = func.br_table[(start + idx) as usize];
+                do_branch(frame, r, br, &ctx.interrupt, ctx.deadline, env)?;
+            }
+            RegInstr::Return { from, count } => {
+                let from = usize::from(from);
+                let count = usize::from(count);
+                r.copy_within(from..from + count, 0);
+                frames.pop();
+                if frames.is_empty() {
+                    // hand the results over the same way as the stack interpreter does
+                    ctx.stack.push_slots(&r[..count]);
+                }
+            }
+            RegInstr::Call { func_idx, base: args } => {
+                poll_stop_requests(&ctx.interrupt, ctx.deadline, env)?;
+                let callee = Callee { func_idx: func_idx as usize, base: base + usize::from(args) };
+                outcome = call(funcs, regs, frames, pending_call, &mut ctx.stack, module, callee, memory, imports, ctx.log.as_deref_mut(), ctx.data.as_deref_mut(), env)?;
+            }
+            RegInstr::CallIndirect { type_idx, index, base: args } => {
+                poll_stop_requests(&ctx.interrupt, ctx.deadline, env)?;
+                // the registers of the callee are laid out by its own signature, so it has to match
+                let func_idx = indirect_callee(&ctx.tables, module, r[usize::from(index)] as u32, type_idx)?;
+                let callee = Callee { func_idx, base: base + usize::from(args) };
+                outcome = call(funcs, regs, frames, pending_call, &mut ctx.stack, module, callee, memory, imports, ctx.log.as_deref_mut(), ctx.data.as_deref_mut(), env)?;
+            }
+            RegInstr::Select { dst, a, b, cond } => {
+                r[usize::from(dst)] = if r[usize::from(cond)] as u32 != 0 { r[usize::from(a)] } else { r[usize::from(b)] };
+            }
+            RegInstr::GlobalGet { dst, offset, ty } => {
+                let offset = offset as usize;
+                let bytes = globals.get(offset..offset + ty.len_bytes())
+                    .ok_or(MemoryAccessError::InvalidOffset { offset })?;
+                r[usize::from(dst)] = match *bytes {
+                    [a, b, c, d] => u64::from(u32::from_ne_bytes([a, b, c, d])),
+                    [a, b, c, d, e, f, g, h] => u64::from_ne_bytes([a, b, c, d, e, f, g, h]),
+                    _ => unreachable!("values are 4 or 8 bytes long"),
+                };
+            }
+            RegInstr::GlobalSet { src, offset, ty } => {
+                let offset = offset as usize;
+                let val = r[usize::from(src)];
+                let bytes = globals.get_mut(offset..offset + ty.len_bytes())
+                    .ok_or(MemoryAccessError::InvalidOffset { offset })?;
+                match bytes.len() {
+                    4 => bytes.copy_from_slice(&(val as u32).to_ne_bytes()),
+                    _ => bytes.copy_from_slice(&val.to_ne_bytes()),
+                }
+            }
+            RegInstr::Load { op, dst, addr, offset } => {
+                r[usize::from(dst)] = numeric::load(memory, op, r[usize::from(addr)] as u32, offset)?;
+            }
+            RegInstr::Store { op, addr, src, offset } => {
+                numeric::store(memory, op, r[usize::from(addr)] as u32, offset, r[usize::from(src)])?;
+            }
+            RegInstr::Unary { op, dst, src } => r[usize::from(dst)] = numeric::unary(op, r[usize::from(src)])?,
+            RegInstr::Binary { op, dst, a, b } => r[usize::from(dst)] = numeric::binary(op, r[usize::from(a)], r[usize::from(b)])?,
+            RegInstr::MemorySize { dst } => r[usize::from(dst)] = numeric::memory_size(memory),
+            RegInstr::MemoryGrow { dst, delta } => r[usize::from(dst)] = numeric::memory_grow(memory, r[usize::from(delta)] as u32),
+            RegInstr::MemoryCopy { dst, src, len } => {
+                numeric::memory_copy(memory, r[usize::from(dst)] as u32, r[usize::from(src)] as u32, r[usize::from(len)] as u32)?;
+            }
+            RegInstr::MemoryFill { dst, val, len } => {
+                numeric::memory_fill(memory, r[usize::from(dst)] as u32, r[usize::from(val)] as u32, r[usize::from(len)] as u32)?;
+            }
+            RegInstr::TruncSat { op, dst, src } => r[usize::from(dst)] = numeric::trunc_sat(op, r[usize::from(src)]),
+        }
+
+        ctx.profile.executed_instr_time[op as usize] += env.ticks() - start;
+
+        if ctx.stack.overflowed {
+            return Err(InterpreterError::StackOverflow);
+        }
+
+        match outcome {
+            ImportOutcome::Return => {}
+            ImportOutcome::Yield => return Ok(Execution::Paused(PauseReason::Yield)),
+            ImportOutcome::Sleep { until } => return Ok(Execution::Paused(PauseReason::Sleep { until })),
+            ImportOutcome::Pending => {
+                let (func_idx, _) = pending_call.expect("set by call");
+                ctx.pending_import = Some(func_idx);
+                ctx.pending_results = signature_results(module, func_idx);
+                return Ok(Execution::Paused(PauseReason::ImportPending { func_idx }));
+            }
+        }
+    }
+
+    Ok(Execution::Finished)
+}
+
+struct Callee {
+    func_idx: usize,
+    // first register of the arguments
+    base: usize,
+}
+
+#[allow(clippy::too_many_arguments)]
+fn call<TEnv: Environment>(
+    funcs: &[Option<RegFunc>],
+    regs: &mut Vec<u64>,
+    frames: &mut Vec<RegFrame>,
+    pending_call: &mut Option<(usize, usize)>,
+    stack: &mut VmStack,
+    module: &WasmModule,
+    Callee { func_idx, base }: Callee,
+    memory: &mut [u8],
+    imports: &mut [HostFunc<'_, '_, TEnv>],
+    mut log: Option<&mut (dyn Log + '_)>,
+    data: Option<&mut dyn Any>,
+    env: &mut TEnv,
+) -> Result<ImportOutcome, InterpreterError> {
+    match funcs.get(func_idx).ok_or(InterpreterError::FunctionNotFound)? {
+        Some(callee) => {
+            debug!(log, "calling function {}", func_idx);
+            // arguments are already in place, they become the first locals of the callee
+            let end = base + usize::from(callee.frame_size);
+            if regs.len() < end {
+                regs.resize(end, 0);
+            }
+            regs[base + usize::from(callee.params)..base + usize::from(callee.locals)].fill(0);
+            frames.push(RegFrame { func_idx: func_idx as u32, pc: 0, base: base as u32 });
+            Ok(ImportOutcome::Return)
+        }
+        None => {
+            debug!(log, "calling imported function {}", func_idx);
+            let signature = signature_of(module, func_idx);
+            stack.push_slots(&regs[base..][..signature.params.len()]);
+            let outcome = imports[func_idx].call(env, stack, memory, data)?;
+            if outcome == ImportOutcome::Pending {
+                *pending_call = Some((func_idx, base));
+            } else {
+                stack.pop_slots(&mut regs[base..][..signature.results.len()])?;
+            }
+            Ok(outcome)
+        }
+    }
+}
+
+#[inline]
+fn do_branch(
+    frame: &mut RegFrame,
+    regs: &mut [u64],
+    br: RegBranch,
+    interrupt: &Option<InterruptHandle>,
+    deadline: Option<u64>,
+    env: &impl Environment,
+) -> Result<(), InterpreterError> {
+    // only loops can run forever, so it's enough to look for stop requests on backward branches
+    if br.target < frame.pc {
+        poll_stop_requests(interrupt, deadline, env)?;
+    }
+    let from = usize::from(br.from);
+    regs.copy_within(from..from + usize::from(br.count), usize::from(br.to));
+    frame.pc = br.target;
+    Ok(())
+}
@@ -0,0 +1,239 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::{ByteStr, Environment, WasmModule};
+use crate::interpreter::{enter_function, init_globals, init_memory, resume, Execution, HostFunc, ImportResults, InterpreterError, PauseReason, VmContext};
+
+/// Index of an app within a [`Scheduler`].
+pub type AppId = usize;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AppState {
+    /// App can be picked by the scheduler.
+    Ready,
+    /// App won't be picked before `Environment::ticks` reaches `until`.
+    Sleeping { until: u64 },
+    /// App waits for the result of an imported function, see [`Scheduler::complete_import`].
+    Waiting { func_idx: usize },
+    /// Entry function of the app has returned.
+    Finished,
+    /// App has trapped, see [`App::fault`].
+    Faulted,
+}
+
+/// Scheduling parameters of a single app.
+#[derive(Debug, Clone, Copy)]
+pub struct AppConfig {
+    /// Ready apps with higher priority are picked before the ones with lower priority, so a busy app
+    /// starves the ones below it unless [`Scheduler::set_starvation_limit`] is used.
+    pub priority: u8,
+    /// Max number of ticks an app may run before it is preempted.
+    pub quantum: u64,
+    /// Size of the linear memory given to the app.
+    pub memory_size: usize,
+}
+
+/// Guest instance managed by a [`Scheduler`].
+pub struct App<'code, TEnv> {
+    module: &'code WasmModule<'code>,
+    ctx: VmContext<'code>,
+    memory: Vec<u8>,
+    globals: Vec<u8>,
+    imports: Vec<HostFunc<'code, 'code, TEnv>>,
+    config: AppConfig,
+    state: AppState,
+    fault: Option<InterpreterError>,
+    // steps since the app has been passed over while it was ready
+    skipped: u32,
+}
+
+impl<'code, TEnv> App<'code, TEnv> {
+    pub fn state(&self) -> AppState {
+        self.state
+    }
+
+    /// Error that has stopped the app, if it is [`AppState::Faulted`].
+    pub fn fault(&self) -> Option<&InterpreterError> {
+        self.fault.as_ref()
+    }
+
+    /// Context of the app, results of a finished app can be popped from its stack.
+    pub fn ctx(&mut self) -> &mut VmContext<'code> {
+        &mut self.ctx
+    }
+
+    pub fn memory(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+}
+
+/// Outcome of a single [`Scheduler::step`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SchedulerStep {
+    /// Given app has been running for up to its quantum.
+    Ran(AppId),
+    /// All apps are sleeping, the earliest one wakes up at `until`.
+    Idle { until: u64 },
+    /// All remaining apps wait for imported functions to complete.
+    Blocked,
+    /// There is nothing more to run.
+    Done,
+}
+
+/// Cooperative scheduler time-slicing several guest instances using [`Environment::ticks`] as the clock.
+///
+/// Apps are picked by strict priority, see [`AppConfig::priority`].
+pub struct Scheduler<'code, TEnv> {
+    apps: Vec<App<'code, TEnv>>,
+    last_run: usize,
+    starvation_limit: Option<u32>,
+}
+
+impl<'code, TEnv: Environment> Scheduler<'code, TEnv> {
+    pub fn new() -> Self {
+        Self {
+            apps: Vec::new(),
+            last_run: 0,
+            starvation_limit: None,
+        }
+    }
+
+    /// Bounds how many steps in a row a ready app may be passed over for apps with higher priority,
+    /// then it runs for one quantum regardless of its priority. `None` keeps priorities strict.
+    pub fn set_starvation_limit(&mut self, limit: Option<u32>) {
+        self.starvation_limit = limit;
+    }
+
+    /// Creates a new instance of `module` which will start by calling `entry` with `args`.
+    pub fn spawn(
+        &mut self,
+        module: &'code WasmModule<'code>,
+        imports: Vec<HostFunc<'code, 'code, TEnv>>,
+        entry: &ByteStr,
+        args: &[u8],
+        config: AppConfig,
+    ) -> Result<AppId, InterpreterError> {
+        let func_idx = module.get_function_index_by_name(entry)
+            .ok_or(InterpreterError::FunctionNotFound)?;
+
+        let mut globals = Vec::new();
+        init_globals(&mut globals, module)?;
+        let mut memory = vec![0; config.memory_size];
+        init_memory(&mut memory, module)?;
+
+        let mut ctx = VmContext::new();
+        enter_function(&mut ctx, module, func_idx, args)?;
+
+        self.apps.push(App {
+            module,
+            ctx,
+            memory,
+            globals,
+            imports,
+            config,
+            state: AppState::Ready,
+            fault: None,
+            skipped: 0,
+        });
+        Ok(self.apps.len() - 1)
+    }
+
+    pub fn app(&mut self, id: AppId) -> Option<&mut App<'code, TEnv>> {
+        self.apps.get_mut(id)
+    }
+
+    /// Wakes up sleeping apps and runs the next ready app for up to its quantum.
+    pub fn step(&mut self, env: &mut TEnv) -> SchedulerStep {
+        let now = env.ticks();
+        let mut earliest_wakeup = None;
+        let mut any_waiting = false;
+        for app in &mut self.apps {
+            match app.state {
+                AppState::Sleeping { until } if until <= now => app.state = AppState::Ready,
+                AppState::Sleeping { until } => {
+                    earliest_wakeup = Some(earliest_wakeup.map_or(until, |it: u64| it.min(until)));
+                }
+                AppState::Waiting { .. } => any_waiting = true,
+                _ => {}
+            }
+        }
+
+        let Some(id) = self.pick_next() else {
+            return match (earliest_wakeup, any_waiting) {
+                (Some(until), _) => SchedulerStep::Idle { until },
+                (None, true) => SchedulerStep::Blocked,
+                (None, false) => SchedulerStep::Done,
+            };
+        };
+
+        self.last_run = id;
+        for (other, app) in self.apps.iter_mut().enumerate() {
+            if app.state == AppState::Ready {
+                app.skipped = if other == id { 0 } else { app.skipped.saturating_add(1) };
+            }
+        }
+        let app = &mut self.apps[id];
+        app.ctx.set_time_slice_end(Some(now.saturating_add(app.config.quantum)));
+        let result = resume(&mut app.ctx, app.module, &mut app.memory, &mut app.globals, &mut app.imports, env);
+        app.state = match result {
+            Ok(Execution::Finished) => AppState::Finished,
+            Ok(Execution::Paused(PauseReason::Sleep { until })) => AppState::Sleeping { until },
+            Ok(Execution::Paused(PauseReason::ImportPending { func_idx })) => AppState::Waiting { func_idx },
+            Ok(Execution::Paused(_)) => AppState::Ready,
+            Err(e) => {
+                app.fault = Some(e);
+                AppState::Faulted
+            }
+        };
+        SchedulerStep::Ran(id)
+    }
+
+    /// Supplies results of the imported function the app is waiting for and makes it ready again.
+    pub fn complete_import(&mut self, id: AppId, push_results: impl FnOnce(&mut ImportResults)) -> Result<(), InterpreterError> {
+        let app = self.apps.get_mut(id).ok_or(InterpreterError::InvalidApp)?;
+        app.ctx.complete_import(push_results)?;
+        app.state = AppState::Ready;
+        Ok(())
+    }
+
+    /// Keeps stepping until every app has either finished or faulted, or all the remaining ones
+    /// wait for imported functions. Returns either [`SchedulerStep::Done`] or [`SchedulerStep::Blocked`].
+    ///
+    /// `idle` is called with the tick at which the earliest sleeping app wakes up, whenever all apps sleep.
+    pub fn run(&mut self, env: &mut TEnv, mut idle: impl FnMut(&mut TEnv, u64)) -> SchedulerStep {
+        loop {
+            match self.step(env) {
+                SchedulerStep::Ran(_) => continue,
+                SchedulerStep::Idle { until } => idle(env, until),
+                step @ (SchedulerStep::Blocked | SchedulerStep::Done) => return step,
+            }
+        }
+    }
+
+    // Ready app with the highest priority, round-robin among the apps with the same priority.
+    // Apps that have been passed over for too long come first, the longest waiting one before the others.
+    fn pick_next(&self) -> Option<AppId> {
+        let n = self.apps.len();
+        let starved = |app: &App<'code, TEnv>| self.starvation_limit.is_some_and(|limit| app.skipped >= limit);
+        let mut best: Option<AppId> = None;
+        for offset in 1..=n {
+            let id = (self.last_run + offset) % n;
+            let app = &self.apps[id];
+            if app.state != AppState::Ready {
+                continue;
+            }
+            let better = best.map_or(true, |b| {
+                let b = &self.apps[b];
+                match (starved(app), starved(b)) {
+                    (true, true) => app.skipped > b.skipped,
+                    (starved, starved_b) if starved != starved_b => starved,
+                    _ => app.config.priority > b.config.priority,
+                }
+            });
+            if better {
+                best = Some(id);
+            }
+        }
+        best
+    }
+}
@@ -0,0 +1,129 @@
+use alloc::vec::Vec;
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+
+/// Stack of values kept either on the heap or in a fixed-size buffer provided by the caller.
+pub(crate) enum Storage<'buf, T> {
+    Heap(Vec<T>),
+    Fixed {
+        buf: &'buf mut [MaybeUninit<T>],
+        len: usize,
+    },
+}
+
+#[derive(Debug)]
+pub(crate) struct CapacityExceeded;
+
+impl<'buf, T: Copy> Storage<'buf, T> {
+    pub(crate) fn new() -> Self {
+        Self::Heap(Vec::new())
+    }
+
+    /// Uses the properly aligned part of `bytes` as a fixed-size buffer.
+    pub(crate) fn from_bytes(bytes: &'buf mut [u8]) -> Self {
+        // SAFETY: every bit pattern is valid for `MaybeUninit<T>`
+        let (_, buf, _) = unsafe { bytes.align_to_mut::<MaybeUninit<T>>() };
+        Self::Fixed { buf, len: 0 }
+    }
+
+    /// Max number of items, `None` when the storage can grow.
+    pub(crate) fn capacity(&self) -> Option<usize> {
+        match self {
+            Storage::Heap(_) => None,
+            Storage::Fixed { buf, .. } => Some(buf.len()),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn push(&mut self, value: T) -> Result<(), CapacityExceeded> {
+        match self {
+            Storage::Heap(vec) => vec.push(value),
+            Storage::Fixed { buf, len } => {
+                buf.get_mut(*len).ok_or(CapacityExceeded)?.write(value);
+                *len += 1;
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn pop(&mut self) -> Option<T> {
+        match self {
+            Storage::Heap(vec) => vec.pop(),
+            Storage::Fixed { buf, len } => {
+                *len = len.checked_sub(1)?;
+                // SAFETY: all items below `len` have been initialized
+                Some(unsafe { buf[*len].assume_init() })
+            }
+        }
+    }
+
+    #[inline]
+    pub(crate) fn extend_from_slice(&mut self, values: &[T]) -> Result<(), CapacityExceeded> {
+        match self {
+            Storage::Heap(vec) => vec.extend_from_slice(values),
+            Storage::Fixed { buf, len } => {
+                let dst = buf.get_mut(*len..*len + values.len()).ok_or(CapacityExceeded)?;
+                for (dst, value) in dst.iter_mut().zip(values) {
+                    dst.write(*value);
+                }
+                *len += values.len();
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn resize(&mut self, new_len: usize, value: T) -> Result<(), CapacityExceeded> {
+        match self {
+            Storage::Heap(vec) => vec.resize(new_len, value),
+            Storage::Fixed { buf, len } => {
+                if new_len > buf.len() {
+                    return Err(CapacityExceeded);
+                }
+                for dst in buf.get_mut(*len..new_len).into_iter().flatten() {
+                    dst.write(value);
+                }
+                *len = new_len;
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn truncate(&mut self, new_len: usize) {
+        match self {
+            Storage::Heap(vec) => vec.truncate(new_len),
+            Storage::Fixed { len, .. } => *len = new_len.min(*len),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn clear(&mut self) {
+        self.truncate(0);
+    }
+}
+
+impl<T> Deref for Storage<'_, T> {
+    type Target = [T];
+
+    #[inline]
+    fn deref(&self) -> &[T] {
+        match self {
+            Storage::Heap(vec) => vec,
+            // SAFETY: all items below `len` have been initialized
+            Storage::Fixed { buf, len } => unsafe { core::slice::from_raw_parts(buf.as_ptr().cast(), *len) },
+        }
+    }
+}
+
+impl<T> DerefMut for Storage<'_, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut [T] {
+        match self {
+            Storage::Heap(vec) => vec,
+            // SAFETY: all items below `len` have been initialized
+            Storage::Fixed { buf, len } => unsafe { core::slice::from_raw_parts_mut(buf.as_mut_ptr().cast(), *len) },
+        }
+    }
+}
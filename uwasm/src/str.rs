@@ -1,5 +1,6 @@
 use core::{fmt, ops};
 
+#[derive(PartialEq, Eq)]
 #[repr(transparent)]
 pub struct ByteStr([u8]);
 
@@ -0,0 +1,55 @@
+use crate::parser::TypeKind;
+
+// Slot of a null reference.
+const NULL_REF: u64 = u64::MAX;
+
+/// Value of any type that a function can take or return, for callers that learn signatures at runtime.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    /// Index of a function, `None` for a null reference.
+    FuncRef(Option<u32>),
+    /// Handle of a host object, `None` for a null reference.
+    ExternRef(Option<u32>),
+}
+
+impl Value {
+    pub fn ty(&self) -> TypeKind {
+        match self {
+            Value::I32(_) => TypeKind::I32,
+            Value::I64(_) => TypeKind::I64,
+            Value::F32(_) => TypeKind::F32,
+            Value::F64(_) => TypeKind::F64,
+            Value::FuncRef(_) => TypeKind::FuncRef,
+            Value::ExternRef(_) => TypeKind::ExternRef,
+        }
+    }
+
+    /// Value as kept in a slot of the stack.
+    pub(crate) fn to_slot(self) -> u64 {
+        match self {
+            Value::I32(value) => u64::from(value as u32),
+            Value::I64(value) => value as u64,
+            Value::F32(value) => u64::from(value.to_bits()),
+            Value::F64(value) => value.to_bits(),
+            Value::FuncRef(value) | Value::ExternRef(value) => value.map_or(NULL_REF, u64::from),
+        }
+    }
+
+    /// Reads a value of type `ty` from a slot of the stack, `None` for types that values can't have.
+    pub(crate) fn from_slot(ty: TypeKind, slot: u64) -> Option<Self> {
+        let reference = (slot != NULL_REF).then_some(slot as u32);
+        Some(match ty {
+            TypeKind::I32 => Value::I32(slot as u32 as i32),
+            TypeKind::I64 => Value::I64(slot as i64),
+            TypeKind::F32 => Value::F32(f32::from_bits(slot as u32)),
+            TypeKind::F64 => Value::F64(f64::from_bits(slot)),
+            TypeKind::FuncRef => Value::FuncRef(reference),
+            TypeKind::ExternRef => Value::ExternRef(reference),
+            TypeKind::Void | TypeKind::Func => return None,
+        })
+    }
+}
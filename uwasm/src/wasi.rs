@@ -0,0 +1,247 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::caller::{Caller, MemoryError, MemoryView, WasmPtr, WasmSlice};
+use crate::interpreter::{HostError, ImportOutcome};
+use crate::linker::Linker;
+use crate::Environment;
+
+const MODULE: &str = "wasi_snapshot_preview1";
+
+// errno values returned by the calls
+const SUCCESS: u32 = 0;
+const EBADF: u32 = 8;
+const EFAULT: u32 = 21;
+const EINVAL: u32 = 28;
+const EOVERFLOW: u32 = 61;
+const ENOSYS: u32 = 52;
+const ENOTSUP: u32 = 58;
+
+// calls that only return `ENOSYS`, with their numbers of parameters
+const UNSUPPORTED: &[(&str, usize)] = &[
+    ("fd_advise", 4),
+    ("fd_allocate", 3),
+    ("fd_close", 1),
+    ("fd_datasync", 1),
+    ("fd_fdstat_get", 2),
+    ("fd_fdstat_set_flags", 2),
+    ("fd_fdstat_set_rights", 3),
+    ("fd_filestat_get", 2),
+    ("fd_filestat_set_size", 2),
+    ("fd_filestat_set_times", 4),
+    ("fd_pread", 5),
+    ("fd_prestat_dir_name", 3),
+    ("fd_pwrite", 5),
+    ("fd_read", 4),
+    ("fd_readdir", 5),
+    ("fd_renumber", 2),
+    ("fd_seek", 4),
+    ("fd_sync", 1),
+    ("fd_tell", 2),
+    ("path_create_directory", 3),
+    ("path_filestat_get", 5),
+    ("path_filestat_set_times", 7),
+    ("path_link", 7),
+    ("path_open", 9),
+    ("path_readlink", 6),
+    ("path_remove_directory", 3),
+    ("path_rename", 6),
+    ("path_symlink", 5),
+    ("path_unlink_file", 3),
+    ("poll_oneoff", 4),
+    ("proc_raise", 1),
+    ("sock_accept", 3),
+    ("sock_recv", 6),
+    ("sock_send", 5),
+    ("sock_shutdown", 2),
+];
+
+/// Source of random bytes for `random_get`.
+pub type RandomFill<'a> = dyn FnMut(&mut [u8]) + 'a;
+
+/// Subset of WASI preview1 for guests built for `wasm32-wasip1`, defined in a [`Linker`] by [`Wasi::link`].
+///
+/// Standard output and error go to [`Environment::write_fmt`] and clocks count [`Environment::ticks`].
+/// Files, sockets and the rest of the calls return `ENOSYS`. Guests using `std` work as long as their
+/// heap fits into the initial memory of the module, which can't grow.
+pub struct Wasi<'a> {
+    args: Vec<String>,
+    env: Vec<String>,
+    random: Option<Box<RandomFill<'a>>>,
+}
+
+impl<'a> Wasi<'a> {
+    pub fn new() -> Self {
+        Self { args: Vec::new(), env: Vec::new(), random: None }
+    }
+
+    /// Appends an argument, the first one is usually the name of the program.
+    pub fn arg(&mut self, arg: &str) -> &mut Self {
+        self.args.push(String::from(arg));
+        self
+    }
+
+    pub fn env(&mut self, key: &str, value: &str) -> &mut Self {
+        self.env.push(format!("{key}={value}"));
+        self
+    }
+
+    /// Source of random bytes for `random_get`, without it the call returns `ENOSYS`.
+    pub fn random(&mut self, fill: impl FnMut(&mut [u8]) + 'a) -> &mut Self {
+        self.random = Some(Box::new(fill));
+        self
+    }
+
+    /// Defines all calls of `wasi_snapshot_preview1` in the linker.
+    ///
+    /// `proc_exit` traps the guest with [`ProcExit`] as the payload of [`HostError`].
+    pub fn link<TEnv: Environment>(self, linker: &mut Linker<'a, TEnv>) {
+        let Wasi { args, env, mut random } = self;
+        let args_len = strings_len(&args);
+        let env_len = strings_len(&env);
+
+        linker
+            .func(MODULE, "fd_write", |mut caller: Caller<TEnv>, fd: u32, iovs: WasmPtr<WasmSlice<u8>>, iovs_len: u32, written: WasmPtr<u32>| {
+                if fd != 1 && fd != 2 {
+                    // there is no standard input nor any files
+                    return EBADF;
+                }
+                fd_write(&mut caller, WasmSlice::new(iovs, iovs_len), written).unwrap_or(EFAULT)
+            })
+            // wasi-libc looks for preopened directories until it gets `EBADF`, any other error is fatal
+            .func(MODULE, "fd_prestat_get", |_: &mut TEnv, _fd: u32, _prestat: WasmPtr<u8>| EBADF)
+            .func(MODULE, "proc_exit", |_: &mut TEnv, code: u32| -> Result<(), HostError> {
+                Err(HostError::new(ProcExit(code)))
+            })
+            .func(MODULE, "args_sizes_get", move |caller: Caller<TEnv>, count: WasmPtr<u32>, size: WasmPtr<u32>| {
+                errno(write_sizes(caller.memory, args_len, count, size))
+            })
+            .func(MODULE, "args_get", move |caller: Caller<TEnv>, ptrs: WasmPtr<WasmPtr<u8>>, buf: WasmPtr<u8>| {
+                errno(write_strings(caller.memory, &args, ptrs, buf))
+            })
+            .func(MODULE, "environ_sizes_get", move |caller: Caller<TEnv>, count: WasmPtr<u32>, size: WasmPtr<u32>| {
+                errno(write_sizes(caller.memory, env_len, count, size))
+            })
+            .func(MODULE, "environ_get", move |caller: Caller<TEnv>, ptrs: WasmPtr<WasmPtr<u8>>, buf: WasmPtr<u8>| {
+                errno(write_strings(caller.memory, &env, ptrs, buf))
+            })
+            .func(MODULE, "clock_res_get", |mut caller: Caller<TEnv>, clock: u32, resolution: WasmPtr<u64>| {
+                if clock > 3 {
+                    return EINVAL;
+                }
+                let ticks_per_second = caller.env.ticks_per_second();
+                if ticks_per_second == 0 {
+                    return ENOTSUP;
+                }
+                let nanos = (1_000_000_000 / ticks_per_second).max(1);
+                errno(caller.memory.write(resolution, nanos))
+            })
+            .func(MODULE, "clock_time_get", |mut caller: Caller<TEnv>, clock: u32, _precision: u64, time: WasmPtr<u64>| {
+                // all clocks count from the start of the host
+                if clock > 3 {
+                    return EINVAL;
+                }
+                let ticks_per_second = caller.env.ticks_per_second();
+                if ticks_per_second == 0 {
+                    return ENOTSUP;
+                }
+                let nanos = u128::from(caller.env.ticks()) * 1_000_000_000 / u128::from(ticks_per_second);
+                errno(caller.memory.write(time, nanos as u64))
+            })
+            .func(MODULE, "random_get", move |mut caller: Caller<TEnv>, buf: WasmPtr<u8>, len: u32| {
+                let Some(random) = &mut random else {
+                    return ENOSYS;
+                };
+                match caller.memory.bytes_mut(WasmSlice::new(buf, len)) {
+                    Ok(bytes) => {
+                        random(bytes);
+                        SUCCESS
+                    }
+                    Err(_) => EFAULT,
+                }
+            })
+            .func(MODULE, "sched_yield", |_: &mut TEnv| SUCCESS);
+
+        for &(name, params) in UNSUPPORTED {
+            linker.func_raw(MODULE, name, move |_, stack, _| {
+                for _ in 0..params {
+                    stack.pop_slot().map_err(HostError::new)?;
+                }
+                stack.push_i32(ENOSYS as i32);
+                Ok(ImportOutcome::Return)
+            });
+        }
+    }
+}
+
+impl Default for Wasi<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Payload of the [`HostError`] that `proc_exit` stops the guest with, holds its exit code.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProcExit(pub u32);
+
+fn errno(result: Result<(), MemoryError>) -> u32 {
+    match result {
+        Ok(()) => SUCCESS,
+        Err(_) => EFAULT,
+    }
+}
+
+// returns errno of the call unless the memory of the guest can't be accessed
+fn fd_write<TEnv: Environment>(
+    caller: &mut Caller<TEnv>,
+    iovs: WasmSlice<WasmSlice<u8>>,
+    written: WasmPtr<u32>,
+) -> Result<u32, MemoryError> {
+    // total size is returned to the guest as `u32`, nothing is written when it doesn't fit
+    let mut len = 0u32;
+    for idx in 0..iovs.len {
+        let iov = caller.memory.read(iovs.at(idx)?)?;
+        let Some(total) = len.checked_add(iov.len) else {
+            return Ok(EOVERFLOW);
+        };
+        len = total;
+    }
+    for idx in 0..iovs.len {
+        let iov = caller.memory.read(iovs.at(idx)?)?;
+        for chunk in caller.memory.bytes(iov)?.utf8_chunks() {
+            write!(caller.env, "{}", chunk.valid());
+            if !chunk.invalid().is_empty() {
+                write!(caller.env, "{}", char::REPLACEMENT_CHARACTER);
+            }
+        }
+    }
+    caller.memory.write(written, len)?;
+    Ok(SUCCESS)
+}
+
+// number of strings and their total size including terminating nulls
+fn strings_len(strings: &[String]) -> (u32, u32) {
+    (strings.len() as u32, strings.iter().map(|it| it.len() as u32 + 1).sum())
+}
+
+fn write_sizes(mut memory: MemoryView, (count, size): (u32, u32), count_ptr: WasmPtr<u32>, size_ptr: WasmPtr<u32>) -> Result<(), MemoryError> {
+    memory.write(count_ptr, count)?;
+    memory.write(size_ptr, size)
+}
+
+// null-terminated strings one after another in `buf`, with pointers to each of them in `ptrs`
+fn write_strings(mut memory: MemoryView, strings: &[String], ptrs: WasmPtr<WasmPtr<u8>>, buf: WasmPtr<u8>) -> Result<(), MemoryError> {
+    let ptrs = WasmSlice::new(ptrs, strings.len() as u32);
+    let mut offset = buf.offset();
+    for (idx, string) in strings.iter().enumerate() {
+        memory.write(ptrs.at(idx as u32)?, WasmPtr::new(offset))?;
+        let len = string.len() as u32 + 1;
+        let bytes = memory.bytes_mut(WasmSlice::new(WasmPtr::new(offset), len))?;
+        bytes[..string.len()].copy_from_slice(string.as_bytes());
+        bytes[string.len()] = 0;
+        offset += len;
+    }
+    Ok(())
+}